@@ -0,0 +1,248 @@
+//! Validates an append-only file, or all files listed by the manifest of a
+//! multi-part AOF, and optionally repairs them.
+//!
+//! Usage: `feredis-check-aof [--fix] <manifest or file>`
+//!
+//! Only the last file, which is the one being appended to, can be repaired.
+//! It is truncated right before its first incomplete or malformed command,
+//! dropping everything after it.
+
+use std::fs::{self, OpenOptions};
+use std::path::Path;
+use std::process::ExitCode;
+
+use feredis_core::aof::{AofError, AofReader, Manifest};
+use feredis_core::rdb::RdbReader;
+
+/// The result of checking a single file.
+enum Outcome {
+    Valid {
+        commands: usize,
+    },
+    Broken {
+        /// the offset up to which the file is valid
+        valid: usize,
+        reason: String,
+        /// whether dropping everything after `valid` repairs the file
+        fixable: bool,
+    },
+}
+
+fn check(data: &[u8]) -> Outcome {
+    // the file may start with a snapshot
+    let mut offset = 0;
+    if data.starts_with(b"REDIS") {
+        let mut reader = match RdbReader::new(data) {
+            Ok(reader) => reader,
+            Err(err) => {
+                return Outcome::Broken {
+                    valid: 0,
+                    reason: format!("invalid RDB preamble: {}", err),
+                    fixable: false,
+                }
+            }
+        };
+        loop {
+            match reader.next_record() {
+                Ok(Some(_)) => {}
+                Ok(None) => break,
+                Err(err) => {
+                    return Outcome::Broken {
+                        valid: 0,
+                        reason: format!("invalid RDB preamble: {}", err),
+                        fixable: false,
+                    }
+                }
+            }
+        }
+        offset = reader.position();
+    }
+    let mut reader = AofReader::new(&data[offset..]);
+    let mut commands = 0;
+    loop {
+        match reader.next_command() {
+            Ok(Some(_)) => commands += 1,
+            Ok(None) => return Outcome::Valid { commands },
+            Err(err) => {
+                let pos = match err {
+                    AofError::Truncated(pos) | AofError::Invalid(pos) => pos,
+                };
+                let reason = match err {
+                    AofError::Truncated(_) => "unexpected end of file",
+                    AofError::Invalid(_) => "invalid command",
+                };
+                return Outcome::Broken {
+                    valid: offset + pos,
+                    reason: format!("{} after {} commands", reason, commands),
+                    fixable: true,
+                };
+            }
+        }
+    }
+}
+
+/// Checks the file at `path`, repairing it if `fix` is set. Returns whether
+/// the file is valid in the end.
+fn check_file(path: &Path, last: bool, fix: bool) -> bool {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("{}: {}", path.display(), err);
+            return false;
+        }
+    };
+    let (valid, reason, fixable) = match check(&data) {
+        Outcome::Valid { commands } => {
+            println!("{}: valid, {} commands", path.display(), commands);
+            return true;
+        }
+        Outcome::Broken {
+            valid,
+            reason,
+            fixable,
+        } => (valid, reason, fixable),
+    };
+    println!(
+        "{}: {}, valid up to offset {} of {}",
+        path.display(),
+        reason,
+        valid,
+        data.len()
+    );
+    if !fixable || !last {
+        println!("{}: cannot be repaired", path.display());
+        return false;
+    }
+    if !fix {
+        println!("Use the --fix option to truncate it to the valid part");
+        return false;
+    }
+    let res = OpenOptions::new()
+        .write(true)
+        .open(path)
+        .and_then(|file| file.set_len(valid as u64));
+    match res {
+        Ok(()) => {
+            println!(
+                "{}: truncated from {} to {} bytes",
+                path.display(),
+                data.len(),
+                valid
+            );
+            true
+        }
+        Err(err) => {
+            println!("{}: failed to truncate: {}", path.display(), err);
+            false
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let fix = args.first().is_some_and(|arg| arg == "--fix");
+    if fix {
+        args.remove(0);
+    }
+    let [path] = &args[..] else {
+        eprintln!("Usage: feredis-check-aof [--fix] <manifest or file>");
+        return ExitCode::from(2);
+    };
+    let path = Path::new(path);
+    let valid = if path.extension().is_some_and(|ext| ext == "manifest") {
+        let manifest = match fs::read_to_string(path)
+            .map_err(|err| err.to_string())
+            .and_then(|data| Manifest::parse(&data).map_err(|err| err.to_string()))
+        {
+            Ok(manifest) => manifest,
+            Err(err) => {
+                println!("{}: {}", path.display(), err);
+                return ExitCode::FAILURE;
+            }
+        };
+        if manifest.base.is_none() {
+            println!("{}: no base file listed", path.display());
+        }
+        let dir = path.parent().unwrap_or(Path::new("."));
+        let files: Vec<_> = manifest.files().collect();
+        // every file is checked, even after an invalid one
+        files
+            .iter()
+            .enumerate()
+            .map(|(i, file)| check_file(&dir.join(&file.name), i + 1 == files.len(), fix))
+            .fold(manifest.base.is_some(), |valid, file_valid| {
+                valid && file_valid
+            })
+    } else {
+        check_file(path, true, fix)
+    };
+    if valid {
+        println!("The AOF is valid");
+        ExitCode::SUCCESS
+    } else {
+        println!("The AOF is not valid");
+        ExitCode::FAILURE
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SET: &[u8] = b"*3\r\n$3\r\nset\r\n$1\r\nk\r\n$1\r\nv\r\n";
+    const DEL: &[u8] = b"*2\r\n$3\r\ndel\r\n$1\r\nk\r\n";
+
+    /// Writes `data` to a file which is unique to the test `name`.
+    fn write_temp(name: &str, data: &[u8]) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "feredis-check-aof-{}-{}.aof",
+            name,
+            std::process::id()
+        ));
+        fs::write(&path, data).unwrap();
+        path
+    }
+
+    #[test]
+    pub fn test_check() {
+        let data = [SET, DEL].concat();
+        assert!(matches!(check(&data), Outcome::Valid { commands: 2 }));
+        assert!(matches!(check(b""), Outcome::Valid { commands: 0 }));
+
+        let truncated = [SET, &DEL[..DEL.len() - 3]].concat();
+        assert!(matches!(
+            check(&truncated),
+            Outcome::Broken { valid, fixable: true, .. } if valid == SET.len()
+        ));
+        let corrupt = [SET, b"*2\r\n$3\r\ndel\r\n$x\r\n", DEL].concat();
+        assert!(matches!(
+            check(&corrupt),
+            Outcome::Broken { valid, fixable: true, .. } if valid == SET.len()
+        ));
+        let preamble = [b"REDIS0011".as_slice(), SET].concat();
+        assert!(matches!(
+            check(&preamble),
+            Outcome::Broken { fixable: false, .. }
+        ));
+    }
+
+    #[test]
+    pub fn test_fix() {
+        for (name, data) in [
+            ("tail", [SET, DEL, &SET[..10]].concat()),
+            ("middle", [SET, DEL, b"*1\r\nset\r\n", SET].concat()),
+        ] {
+            let path = write_temp(name, &data);
+            // without --fix, or in a file which is not the last one, nothing
+            // is changed
+            assert!(!check_file(&path, true, false));
+            assert!(!check_file(&path, false, true));
+            assert_eq!(fs::read(&path).unwrap(), data);
+
+            assert!(check_file(&path, true, true));
+            assert_eq!(fs::read(&path).unwrap(), [SET, DEL].concat());
+            assert!(check_file(&path, true, false));
+            fs::remove_file(&path).unwrap();
+        }
+    }
+}
@@ -0,0 +1,316 @@
+//! Inspects an RDB snapshot without a running server.
+//!
+//! Usage: `feredis-rdb [--format keys|json|resp] [--db <n>] [--min-size <bytes>] <file>`
+//!
+//! - `keys` lists the database, type, key, TTL in milliseconds (-1 without
+//!   an expiry), number of elements and size of each key, where the size is
+//!   the length of the value in the RDB encoding.
+//! - `json` exports the keys along with their values. Data which is not
+//!   valid UTF-8 is converted lossily.
+//! - `resp` emits the commands which recreate the dataset, in the form of
+//!   an append-only file.
+
+use std::fmt::Write as _;
+use std::io::{self, BufWriter, Write};
+use std::process::ExitCode;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use feredis_core::item::RedisItem;
+use feredis_core::rdb::{self, RdbReader, RdbValue, Record, StreamId};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Keys,
+    Json,
+    Resp,
+}
+
+struct Options {
+    format: Format,
+    db: Option<u64>,
+    min_size: usize,
+    path: String,
+}
+
+fn parse_options(args: &[String]) -> Option<Options> {
+    let mut options = Options {
+        format: Format::Keys,
+        db: None,
+        min_size: 0,
+        path: String::new(),
+    };
+    let mut args = args.iter();
+    let mut path = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--format" => {
+                options.format = match args.next()?.as_str() {
+                    "keys" => Format::Keys,
+                    "json" => Format::Json,
+                    "resp" => Format::Resp,
+                    _ => return None,
+                }
+            }
+            "--db" => options.db = Some(args.next()?.parse().ok()?),
+            "--min-size" => options.min_size = args.next()?.parse().ok()?,
+            _ if path.is_none() && !arg.starts_with("--") => path = Some(arg.clone()),
+            _ => return None,
+        }
+    }
+    options.path = path?;
+    Some(options)
+}
+
+fn type_name(value: &RdbValue) -> &'static str {
+    match value {
+        RdbValue::String(_) => "string",
+        RdbValue::List(_) => "list",
+        RdbValue::Set(_) => "set",
+        RdbValue::SortedSet(_) => "zset",
+        RdbValue::Hash(_) => "hash",
+        RdbValue::Stream(_) => "stream",
+    }
+}
+
+/// Returns the number of elements of a value, or the length of a string.
+fn element_count(value: &RdbValue) -> usize {
+    match value {
+        RdbValue::String(val) => val.len(),
+        RdbValue::List(items) | RdbValue::Set(items) => items.len(),
+        RdbValue::SortedSet(members) => members.len(),
+        RdbValue::Hash(fields) => fields.len(),
+        RdbValue::Stream(stream) => stream.entries.len(),
+    }
+}
+
+/// Returns the length of a value in the RDB encoding.
+fn encoded_size(value: &RdbValue) -> usize {
+    // a dump payload is the encoded value and its type, version and checksum
+    rdb::encode_dump(value).len() - 11
+}
+
+/// Quotes a key for display, escaping anything which is not printable.
+fn quote(bytes: &[u8]) -> String {
+    let mut out = String::from("\"");
+    for &byte in bytes {
+        match byte {
+            b'"' | b'\\' => {
+                out.push('\\');
+                out.push(byte as char);
+            }
+            b'\n' => out.push_str("\\n"),
+            b'\r' => out.push_str("\\r"),
+            b'\t' => out.push_str("\\t"),
+            0x20..=0x7e => out.push(byte as char),
+            _ => write!(out, "\\x{:02x}", byte).unwrap(),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn json_string(out: &mut String, bytes: &[u8]) {
+    out.push('"');
+    for c in String::from_utf8_lossy(bytes).chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Writes `items` as a JSON array, formatting each with `item`.
+fn json_array<T>(out: &mut String, items: &[T], mut item: impl FnMut(&mut String, &T)) {
+    out.push('[');
+    for (i, val) in items.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        item(out, val);
+    }
+    out.push(']');
+}
+
+/// Writes `pairs` as a JSON object of strings.
+fn json_object(out: &mut String, pairs: &[(Vec<u8>, Vec<u8>)]) {
+    out.push('{');
+    for (i, (key, val)) in pairs.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        json_string(out, key);
+        out.push(':');
+        json_string(out, val);
+    }
+    out.push('}');
+}
+
+fn json_score(out: &mut String, score: f64) {
+    // JSON has no infinities, so those are written as strings
+    if score.is_finite() {
+        write!(out, "{}", score).unwrap();
+    } else {
+        write!(out, "\"{}\"", score).unwrap();
+    }
+}
+
+fn json_id(out: &mut String, id: StreamId) {
+    write!(out, "\"{}-{}\"", id.ms, id.seq).unwrap();
+}
+
+fn json_value(out: &mut String, value: &RdbValue) {
+    match value {
+        RdbValue::String(val) => json_string(out, val),
+        RdbValue::List(items) | RdbValue::Set(items) => {
+            json_array(out, items, |out, item| json_string(out, item))
+        }
+        RdbValue::SortedSet(members) => json_array(out, members, |out, (member, score)| {
+            out.push('[');
+            json_string(out, member);
+            out.push(',');
+            json_score(out, *score);
+            out.push(']');
+        }),
+        RdbValue::Hash(fields) => json_object(out, fields),
+        RdbValue::Stream(stream) => {
+            out.push_str("{\"entries\":");
+            json_array(out, &stream.entries, |out, (id, fields)| {
+                out.push_str("{\"id\":");
+                json_id(out, *id);
+                out.push_str(",\"fields\":");
+                json_object(out, fields);
+                out.push('}');
+            });
+            out.push_str(",\"last_id\":");
+            json_id(out, stream.last_id);
+            write!(out, ",\"entries_added\":{}", stream.entries_added).unwrap();
+            out.push_str(",\"groups\":");
+            json_array(out, &stream.groups, |out, group| {
+                out.push_str("{\"name\":");
+                json_string(out, &group.name);
+                out.push_str(",\"last_id\":");
+                json_id(out, group.last_id);
+                write!(out, ",\"pending\":{}", group.pending.len()).unwrap();
+                out.push_str(",\"consumers\":");
+                json_array(out, &group.consumers, |out, consumer| {
+                    json_string(out, &consumer.name)
+                });
+                out.push('}');
+            });
+            out.push('}');
+        }
+    }
+}
+
+fn write_command(out: &mut impl Write, args: Vec<Vec<u8>>) -> io::Result<()> {
+    let mut buf = Vec::new();
+    RedisItem::Array(args.into_iter().map(RedisItem::bulk).collect()).serialize(&mut buf);
+    out.write_all(&buf)
+}
+
+fn run(options: &Options) -> Result<(), String> {
+    let data = std::fs::read(&options.path).map_err(|err| err.to_string())?;
+    let mut reader = RdbReader::new(&data).map_err(|err| err.to_string())?;
+    let now_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    let mut out = BufWriter::new(io::stdout().lock());
+    let mut db = 0;
+    let mut selected = None;
+    let mut first = true;
+    if options.format == Format::Json {
+        writeln!(out, "[").map_err(|err| err.to_string())?;
+    }
+    while let Some(record) = reader.next_record().map_err(|err| err.to_string())? {
+        let entry = match record {
+            Record::SelectDb(index) => {
+                db = index;
+                continue;
+            }
+            Record::Entry(entry) => entry,
+            Record::Aux { .. } | Record::ResizeDb { .. } => continue,
+        };
+        if options.db.is_some_and(|only| only != db) {
+            continue;
+        }
+        let size = encoded_size(&entry.value);
+        if size < options.min_size {
+            continue;
+        }
+        let res = match options.format {
+            Format::Keys => {
+                let ttl = entry
+                    .expire_ms
+                    .map_or(-1, |expire_ms| expire_ms.saturating_sub(now_ms) as i64);
+                writeln!(
+                    out,
+                    "{} {} {} {} {} {}",
+                    db,
+                    type_name(&entry.value),
+                    quote(&entry.key),
+                    ttl,
+                    element_count(&entry.value),
+                    size
+                )
+            }
+            Format::Json => {
+                let mut line = String::new();
+                if !first {
+                    line.push_str(",\n");
+                }
+                write!(line, "{{\"db\":{},\"key\":", db).unwrap();
+                json_string(&mut line, &entry.key);
+                write!(line, ",\"type\":\"{}\"", type_name(&entry.value)).unwrap();
+                match entry.expire_ms {
+                    Some(expire_ms) => write!(line, ",\"expire_ms\":{}", expire_ms).unwrap(),
+                    None => line.push_str(",\"expire_ms\":null"),
+                }
+                write!(line, ",\"size\":{},\"value\":", size).unwrap();
+                json_value(&mut line, &entry.value);
+                line.push('}');
+                out.write_all(line.as_bytes())
+            }
+            Format::Resp => {
+                let mut res = Ok(());
+                if selected != Some(db) {
+                    selected = Some(db);
+                    res = write_command(&mut out, vec![b"select".to_vec(), db.to_string().into()]);
+                }
+                for command in entry.commands() {
+                    res = res.and_then(|()| write_command(&mut out, command));
+                }
+                res
+            }
+        };
+        res.map_err(|err| err.to_string())?;
+        first = false;
+    }
+    if options.format == Format::Json {
+        writeln!(out, "\n]").map_err(|err| err.to_string())?;
+    }
+    out.flush().map_err(|err| err.to_string())
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let Some(options) = parse_options(&args) else {
+        eprintln!(
+            "Usage: feredis-rdb [--format keys|json|resp] [--db <n>] [--min-size <bytes>] <file>"
+        );
+        return ExitCode::from(2);
+    };
+    match run(&options) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("{}: {}", options.path, err);
+            ExitCode::FAILURE
+        }
+    }
+}
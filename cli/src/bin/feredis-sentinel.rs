@@ -0,0 +1,1143 @@
+//! Monitors a master and its replicas, and promotes a replica once enough
+//! sentinels agree that the master is down.
+//!
+//! Usage: `feredis-sentinel [-p <port>] [--announce-ip <ip>] [--down-after <ms>]
+//! [--failover-timeout <ms>] [--sentinel <host:port>]... <name> <host> <port> <quorum>`
+//!
+//! Every instance is sent `PING` each second and `INFO` every ten seconds,
+//! or each second while the master is down. Replicas are discovered from the
+//! `INFO` of the master. A master which does not reply within the down-after
+//! time is subjectively down; it is objectively down once `quorum` sentinels,
+//! asked with `SENTINEL is-master-down-by-addr`, consider it down. One of
+//! them is then elected by a majority of the sentinels to promote the best
+//! replica and to repoint the other replicas at it.
+//!
+//! Sentinels know about each other from `--sentinel` and from the
+//! `SENTINEL HELLO` each of them sends to the others every two seconds,
+//! which carries the current master and is answered with the sentinels the
+//! receiver knows. After a failover, the master with the highest
+//! configuration epoch wins.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::future::Future;
+use std::io;
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
+
+use feredis_core::client::{command, Connection};
+use feredis_core::item::{ItemParser, ParseError, RedisItem};
+use smol::channel::{self, Sender};
+use smol::io::{AsyncWriteExt, BufReader};
+use smol::net::{TcpListener, TcpStream};
+use smol::Timer;
+
+const PING_PERIOD: Duration = Duration::from_secs(1);
+const INFO_PERIOD: Duration = Duration::from_secs(10);
+const HELLO_PERIOD: Duration = Duration::from_secs(2);
+/// How often the state of the master is checked.
+const CHECK_PERIOD: Duration = Duration::from_millis(100);
+/// How long the replies of other sentinels about the master are valid.
+const ASK_VALIDITY: Duration = Duration::from_secs(5);
+/// How long an instance has to report the wrong role before it is
+/// reconfigured.
+const RECONFIGURE_DELAY: Duration = Duration::from_secs(4);
+
+struct Options {
+    port: u16,
+    announce_ip: String,
+    down_after: Duration,
+    failover_timeout: Duration,
+    sentinels: Vec<String>,
+    name: String,
+    master: String,
+    quorum: usize,
+}
+
+fn parse_options(args: &[String]) -> Option<Options> {
+    let mut options = Options {
+        port: 26379,
+        announce_ip: "127.0.0.1".to_string(),
+        down_after: Duration::from_secs(30),
+        failover_timeout: Duration::from_secs(180),
+        sentinels: Vec::new(),
+        name: String::new(),
+        master: String::new(),
+        quorum: 0,
+    };
+    let mut args = args.iter();
+    let mut positional = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-p" => options.port = args.next()?.parse().ok()?,
+            "--announce-ip" => options.announce_ip = args.next()?.clone(),
+            "--down-after" => {
+                options.down_after = Duration::from_millis(args.next()?.parse().ok()?)
+            }
+            "--failover-timeout" => {
+                options.failover_timeout = Duration::from_millis(args.next()?.parse().ok()?)
+            }
+            "--sentinel" => options.sentinels.push(args.next()?.clone()),
+            _ if !arg.starts_with('-') => positional.push(arg.clone()),
+            _ => return None,
+        }
+    }
+    let [name, host, port, quorum] = &positional[..] else {
+        return None;
+    };
+    let port: u16 = port.parse().ok()?;
+    options.name = name.clone();
+    options.master = format!("{}:{}", host, port);
+    options.quorum = quorum.parse().ok().filter(|quorum| *quorum > 0)?;
+    Some(options)
+}
+
+/// A master or replica, as last seen by this sentinel.
+struct Instance {
+    run_id: String,
+    /// when the instance last replied to `PING`
+    last_pong: Instant,
+    /// when the instance last replied to `INFO`
+    last_info: Option<Instant>,
+    /// the master the instance replicates, if it is a replica
+    master: Option<String>,
+    link_up: bool,
+    priority: u32,
+    offset: u64,
+    /// when the instance last reported a different role or master
+    role_changed: Instant,
+}
+
+impl Instance {
+    fn new() -> Self {
+        let now = Instant::now();
+        Self {
+            run_id: String::new(),
+            last_pong: now,
+            last_info: None,
+            master: None,
+            link_up: false,
+            priority: 100,
+            offset: 0,
+            role_changed: now,
+        }
+    }
+}
+
+/// Another sentinel monitoring the same master.
+#[derive(Default)]
+struct Peer {
+    run_id: String,
+    last_hello: Option<Instant>,
+    /// whether the sentinel last replied that the master is down
+    master_down: bool,
+    last_reply: Option<Instant>,
+    /// the sentinel it voted for, and in which epoch
+    leader: Option<(String, u64)>,
+}
+
+struct Failover {
+    epoch: u64,
+    started: Instant,
+    /// forced with `SENTINEL FAILOVER`, without asking the other sentinels
+    forced: bool,
+}
+
+enum Worker {
+    Instance(String),
+    Peer(String),
+}
+
+struct Sentinel {
+    run_id: String,
+    addr: String,
+    name: String,
+    quorum: usize,
+    down_after: Duration,
+    failover_timeout: Duration,
+    /// the address of the master
+    master: String,
+    /// the epoch of the failover which promoted the master
+    config_epoch: u64,
+    current_epoch: u64,
+    /// the master and its replicas, by address
+    instances: HashMap<String, Instance>,
+    /// the other sentinels, by address
+    peers: HashMap<String, Peer>,
+    /// the sentinel this one voted for, and in which epoch
+    leader_vote: Option<(String, u64)>,
+    failover: Option<Failover>,
+    /// the earliest time for this sentinel to start a failover
+    next_failover: Option<Instant>,
+    force_failover: bool,
+    sdown: bool,
+    odown: bool,
+    workers: Sender<Worker>,
+}
+
+impl Sentinel {
+    fn new(options: &Options, workers: Sender<Worker>) -> Self {
+        let mut sentinel = Self {
+            run_id: random_id(),
+            addr: format!("{}:{}", options.announce_ip, options.port),
+            name: options.name.clone(),
+            quorum: options.quorum,
+            down_after: options.down_after,
+            failover_timeout: options.failover_timeout,
+            master: options.master.clone(),
+            config_epoch: 0,
+            current_epoch: 0,
+            instances: HashMap::new(),
+            peers: HashMap::new(),
+            leader_vote: None,
+            failover: None,
+            next_failover: None,
+            force_failover: false,
+            sdown: false,
+            odown: false,
+            workers,
+        };
+        sentinel.add_instance(&options.master);
+        for peer in &options.sentinels {
+            sentinel.add_peer(peer);
+        }
+        sentinel
+    }
+
+    fn add_instance(&mut self, addr: &str) {
+        if !self.instances.contains_key(addr) {
+            self.instances.insert(addr.to_string(), Instance::new());
+            self.workers
+                .try_send(Worker::Instance(addr.to_string()))
+                .unwrap();
+        }
+    }
+
+    fn add_peer(&mut self, addr: &str) {
+        if addr != self.addr && !self.peers.contains_key(addr) {
+            println!("+sentinel {}", addr);
+            self.peers.insert(addr.to_string(), Peer::default());
+            self.workers
+                .try_send(Worker::Peer(addr.to_string()))
+                .unwrap();
+        }
+    }
+
+    fn is_sdown(&self, addr: &str) -> bool {
+        self.instances
+            .get(addr)
+            .is_none_or(|instance| instance.last_pong.elapsed() > self.down_after)
+    }
+
+    /// Returns the number of sentinels, including this one, which consider
+    /// the master down.
+    fn down_votes(&self) -> usize {
+        let peers = self.peers.values().filter(|peer| {
+            peer.master_down
+                && peer
+                    .last_reply
+                    .is_some_and(|time| time.elapsed() < ASK_VALIDITY)
+        });
+        self.sdown as usize + peers.count()
+    }
+
+    /// Returns the number of sentinels which voted for this one in `epoch`.
+    fn leader_votes(&self, epoch: u64) -> usize {
+        let me = Some((self.run_id.clone(), epoch));
+        let peers = self.peers.values().filter(|peer| peer.leader == me);
+        (self.leader_vote == me) as usize + peers.count()
+    }
+
+    fn replicas(&self) -> impl Iterator<Item = (&String, &Instance)> {
+        self.instances
+            .iter()
+            .filter(|(addr, _)| **addr != self.master)
+    }
+
+    /// Returns the replica to promote: the one with the lowest priority,
+    /// then the most data, then the lowest run id.
+    fn select_replica(&self) -> Option<String> {
+        let validity = if self.sdown {
+            5 * PING_PERIOD
+        } else {
+            3 * INFO_PERIOD
+        };
+        self.replicas()
+            .filter(|(addr, instance)| {
+                !self.is_sdown(addr)
+                    && instance.master.as_ref() == Some(&self.master)
+                    && instance.priority != 0
+                    && instance
+                        .last_info
+                        .is_some_and(|time| time.elapsed() < validity)
+            })
+            .min_by(|(_, a), (_, b)| {
+                (a.priority, std::cmp::Reverse(a.offset), &a.run_id).cmp(&(
+                    b.priority,
+                    std::cmp::Reverse(b.offset),
+                    &b.run_id,
+                ))
+            })
+            .map(|(addr, _)| addr.clone())
+    }
+
+    /// Updates whether the master is down, and starts a failover if it is.
+    /// Returns whether this sentinel was elected to promote a replica.
+    fn check_master(&mut self) -> bool {
+        let sdown = self.is_sdown(&self.master);
+        if sdown != self.sdown {
+            println!(
+                "{}sdown master {}",
+                if sdown { "+" } else { "-" },
+                self.master
+            );
+            self.sdown = sdown;
+        }
+        let odown = sdown && self.down_votes() >= self.quorum;
+        if odown != self.odown {
+            println!(
+                "{}odown master {} #quorum {}/{}",
+                if odown { "+" } else { "-" },
+                self.master,
+                self.down_votes(),
+                self.quorum
+            );
+            self.odown = odown;
+            // sentinels which notice at the same time are unlikely to try at
+            // the same time, which would split the vote
+            if odown && self.next_failover.is_none_or(|time| time < Instant::now()) {
+                self.next_failover = Some(Instant::now() + desync());
+            }
+        }
+        let Some(failover) = &self.failover else {
+            let forced = std::mem::take(&mut self.force_failover);
+            if forced || (odown && self.next_failover.is_none_or(|time| time <= Instant::now())) {
+                self.current_epoch += 1;
+                let epoch = self.current_epoch;
+                println!("+new-epoch {}", epoch);
+                println!("+try-failover master {}", self.master);
+                self.leader_vote = Some((self.run_id.clone(), epoch));
+                self.failover = Some(Failover {
+                    epoch,
+                    started: Instant::now(),
+                    forced,
+                });
+                self.next_failover = Some(Instant::now() + 2 * self.failover_timeout + desync());
+            }
+            return false;
+        };
+        // the leader needs the votes of a majority and at least the quorum
+        let sentinels = self.peers.len() + 1;
+        let needed = self.quorum.max(sentinels / 2 + 1);
+        let votes = self.leader_votes(failover.epoch);
+        if failover.forced || votes >= needed {
+            println!("+elected-leader master {} #votes {}", self.master, votes);
+            return true;
+        }
+        if failover.started.elapsed() > self.failover_timeout {
+            println!("-failover-abort-not-elected master {}", self.master);
+            self.failover = None;
+        } else if !odown {
+            println!("-failover-abort-master-up master {}", self.master);
+            self.failover = None;
+        }
+        false
+    }
+
+    /// Updates an instance from its `INFO`, and returns the master it
+    /// should be reconfigured to replicate, if any.
+    fn refresh_info(&mut self, addr: &str, info: &str) -> Option<String> {
+        let field = |name: &str| {
+            info.lines()
+                .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+        };
+        let master = match field("role") {
+            Some("slave") => Some(format!(
+                "{}:{}",
+                field("master_host")?,
+                field("master_port")?
+            )),
+            _ => None,
+        };
+        let replicas: Vec<String> = info
+            .lines()
+            .filter(|line| line.starts_with("slave") && line.contains(":ip="))
+            .filter_map(|line| {
+                let (_, attrs) = line.split_once(':')?;
+                let attr = |name: &str| {
+                    attrs
+                        .split(',')
+                        .find_map(|attr| attr.strip_prefix(name)?.strip_prefix('='))
+                };
+                Some(format!("{}:{}", attr("ip")?, attr("port")?))
+            })
+            .collect();
+        let instance = self.instances.get_mut(addr)?;
+        instance.last_info = Some(Instant::now());
+        instance.run_id = field("run_id").unwrap_or_default().to_string();
+        instance.link_up = field("master_link_status") == Some("up");
+        instance.priority = field("slave_priority")
+            .and_then(|p| p.parse().ok())
+            .unwrap_or(100);
+        instance.offset = field("slave_repl_offset")
+            .and_then(|offset| offset.parse().ok())
+            .unwrap_or(0);
+        if instance.master != master {
+            instance.master = master;
+            instance.role_changed = Instant::now();
+        }
+        let reconfigure = addr != self.master
+            && instance.master.as_ref() != Some(&self.master)
+            && instance.role_changed.elapsed() > RECONFIGURE_DELAY
+            && self.failover.is_none();
+        if addr == self.master {
+            for replica in replicas {
+                if !self.instances.contains_key(&replica) {
+                    println!("+slave {} of {}", replica, self.master);
+                    self.add_instance(&replica);
+                }
+            }
+        }
+        // an instance is only moved to a master which is reachable
+        (reconfigure && !self.is_sdown(&self.master)).then(|| self.master.clone())
+    }
+}
+
+/// A random delay, to keep sentinels from acting in lockstep.
+fn desync() -> Duration {
+    Duration::from_millis(fastrand::u64(..1000))
+}
+
+fn split_addr(addr: &str) -> (&str, &str) {
+    addr.rsplit_once(':').unwrap_or((addr, ""))
+}
+
+async fn timeout<T>(duration: Duration, fut: impl Future<Output = io::Result<T>>) -> io::Result<T> {
+    smol::future::or(fut, async {
+        Timer::after(duration).await;
+        Err(io::Error::new(io::ErrorKind::TimedOut, "timed out"))
+    })
+    .await
+}
+
+/// Sends a single command to the instance at `addr`.
+async fn send_command(addr: &str, args: &[&str], duration: Duration) -> io::Result<RedisItem> {
+    timeout(duration, async {
+        Connection::connect(addr).await?.send(&command(args)).await
+    })
+    .await
+}
+
+/// Pings the instance at `addr` and asks for its `INFO`, for as long as
+/// the sentinel runs.
+async fn instance_worker(addr: String, state: &RefCell<Sentinel>) {
+    let mut connection = None;
+    let mut last_info: Option<Instant> = None;
+    loop {
+        Timer::after(PING_PERIOD).await;
+        let (request_timeout, info_period) = {
+            let state = state.borrow();
+            let info_period = if state.sdown || state.failover.is_some() {
+                PING_PERIOD
+            } else {
+                INFO_PERIOD
+            };
+            (state.down_after, info_period)
+        };
+        if connection.is_none() {
+            connection = timeout(request_timeout, Connection::connect(&addr))
+                .await
+                .ok();
+        }
+        let Some(conn) = &mut connection else {
+            continue;
+        };
+        match timeout(request_timeout, conn.send(&command(&["ping"]))).await {
+            // a server which is loading or has lost its master still works
+            Ok(RedisItem::SimpleString(_)) => {}
+            Ok(RedisItem::SimpleError(err))
+                if err.starts_with("LOADING") || err.starts_with("MASTERDOWN") => {}
+            _ => {
+                connection = None;
+                continue;
+            }
+        }
+        if let Some(instance) = state.borrow_mut().instances.get_mut(&addr) {
+            instance.last_pong = Instant::now();
+        }
+        if last_info.is_some_and(|time| time.elapsed() < info_period) {
+            continue;
+        }
+        let info = match timeout(request_timeout, conn.send(&command(&["info"]))).await {
+            Ok(RedisItem::BulkString(info)) => info,
+            _ => {
+                connection = None;
+                continue;
+            }
+        };
+        last_info = Some(Instant::now());
+        let reconfigure = state.borrow_mut().refresh_info(&addr, &info);
+        if let Some(master) = reconfigure {
+            println!("+convert-to-slave {} of {}", addr, master);
+            let (host, port) = split_addr(&master);
+            let args = command(&["replicaof", host, port]);
+            if timeout(request_timeout, conn.send(&args)).await.is_err() {
+                connection = None;
+            }
+        }
+    }
+}
+
+/// Announces this sentinel and its master to the sentinel at `addr`, and
+/// asks it about the master while the master seems down.
+async fn peer_worker(addr: String, state: &RefCell<Sentinel>) {
+    let mut connection = None;
+    let mut last_connect: Option<Instant> = None;
+    let mut last_hello: Option<Instant> = None;
+    let mut last_ask: Option<(Instant, Vec<RedisItem>)> = None;
+    loop {
+        Timer::after(CHECK_PERIOD).await;
+        let (request_timeout, ask) = {
+            let state = state.borrow();
+            let ask = state.sdown.then(|| {
+                let (ip, port) = split_addr(&state.master);
+                // the vote of the sentinel is only asked for during an election
+                let candidate = match &state.failover {
+                    Some(failover) if !failover.forced => state.run_id.as_str(),
+                    _ => "*",
+                };
+                command(&[
+                    "sentinel",
+                    "is-master-down-by-addr",
+                    ip,
+                    port,
+                    &state.current_epoch.to_string(),
+                    candidate,
+                ])
+            });
+            (state.down_after, ask)
+        };
+        if ask.is_none() {
+            if let Some(peer) = state.borrow_mut().peers.get_mut(&addr) {
+                peer.master_down = false;
+            }
+        }
+        let hello_due = last_hello.is_none_or(|time| time.elapsed() >= HELLO_PERIOD);
+        // a new election is announced right away
+        let ask = ask.filter(|ask| {
+            last_ask
+                .as_ref()
+                .is_none_or(|(time, last)| time.elapsed() >= PING_PERIOD || last != ask)
+        });
+        if !hello_due && ask.is_none() {
+            continue;
+        }
+        if connection.is_none() {
+            if last_connect.is_some_and(|time| time.elapsed() < PING_PERIOD) {
+                continue;
+            }
+            last_connect = Some(Instant::now());
+            connection = timeout(request_timeout, Connection::connect(&addr))
+                .await
+                .ok();
+        }
+        let Some(conn) = &mut connection else {
+            continue;
+        };
+        if hello_due {
+            let hello = {
+                let state = state.borrow();
+                let (ip, port) = split_addr(&state.addr);
+                let (master_ip, master_port) = split_addr(&state.master);
+                command(&[
+                    "sentinel",
+                    "hello",
+                    ip,
+                    port,
+                    &state.run_id,
+                    &state.current_epoch.to_string(),
+                    &state.name,
+                    master_ip,
+                    master_port,
+                    &state.config_epoch.to_string(),
+                ])
+            };
+            match timeout(request_timeout, conn.send(&hello)).await {
+                // the sentinels known to the other one
+                Ok(RedisItem::Array(peers)) => {
+                    let mut state = state.borrow_mut();
+                    for peer in peers.iter().filter_map(RedisItem::as_bytes) {
+                        state.add_peer(&String::from_utf8_lossy(peer));
+                    }
+                }
+                Ok(_) => {}
+                Err(_) => {
+                    connection = None;
+                    continue;
+                }
+            }
+            last_hello = Some(Instant::now());
+        }
+        let Some(ask) = ask else {
+            continue;
+        };
+        let reply = match timeout(request_timeout, conn.send(&ask)).await {
+            Ok(RedisItem::Array(reply)) => reply,
+            _ => {
+                connection = None;
+                continue;
+            }
+        };
+        last_ask = Some((Instant::now(), ask));
+        let mut state = state.borrow_mut();
+        let Some(peer) = state.peers.get_mut(&addr) else {
+            continue;
+        };
+        if let [RedisItem::Integer(down), leader, RedisItem::Integer(epoch)] = &reply[..] {
+            peer.master_down = *down == 1;
+            peer.last_reply = Some(Instant::now());
+            peer.leader = match leader.as_bytes() {
+                Some(b"*") | None => None,
+                Some(leader) => Some((String::from_utf8_lossy(leader).into_owned(), *epoch as u64)),
+            };
+        }
+    }
+}
+
+/// Promotes a replica to replace the master, once this sentinel has been
+/// elected to do so.
+async fn promote(state: &RefCell<Sentinel>) {
+    // the replicas are asked for their INFO more often once the master is
+    // down, so it may take a moment until one of them qualifies
+    let started = Instant::now();
+    let replica = loop {
+        if let Some(replica) = state.borrow().select_replica() {
+            break replica;
+        }
+        if started.elapsed() > 5 * PING_PERIOD {
+            let mut state = state.borrow_mut();
+            println!("-failover-abort-no-good-slave {}", state.master);
+            state.failover = None;
+            return;
+        }
+        Timer::after(CHECK_PERIOD).await;
+    };
+    println!("+selected-slave {}", replica);
+    let (request_timeout, failover_timeout) = {
+        let state = state.borrow();
+        (state.down_after, state.failover_timeout)
+    };
+    let promoted = async {
+        let mut connection = timeout(request_timeout, Connection::connect(&replica)).await?;
+        let reply = timeout(
+            request_timeout,
+            connection.send(&command(&["replicaof", "no", "one"])),
+        )
+        .await?;
+        if let RedisItem::SimpleError(err) = reply {
+            return Err(io::Error::other(err));
+        }
+        println!("+failover-state-wait-promotion {}", replica);
+        let started = Instant::now();
+        while started.elapsed() < failover_timeout {
+            let info = timeout(
+                request_timeout,
+                connection.send(&command(&["info", "replication"])),
+            )
+            .await?;
+            if let RedisItem::BulkString(info) = info {
+                if info.lines().any(|line| line == "role:master") {
+                    return Ok(());
+                }
+            }
+            Timer::after(CHECK_PERIOD).await;
+        }
+        Err(io::Error::new(io::ErrorKind::TimedOut, "timed out"))
+    };
+    if let Err(err) = promoted.await {
+        println!("-failover-abort-slave-timeout {}: {}", replica, err);
+        state.borrow_mut().failover = None;
+        return;
+    }
+    let replicas: Vec<String> = {
+        let mut state = state.borrow_mut();
+        let epoch = state
+            .failover
+            .take()
+            .map_or(state.current_epoch, |f| f.epoch);
+        println!("+promoted-slave {}", replica);
+        println!("+switch-master {} {} {}", state.name, state.master, replica);
+        state.master = replica.clone();
+        state.config_epoch = epoch;
+        state.sdown = false;
+        state.odown = false;
+        state.replicas().map(|(addr, _)| addr.clone()).collect()
+    };
+    let (host, port) = split_addr(&replica);
+    for addr in replicas {
+        // unreachable replicas are reconfigured once they are back
+        match send_command(&addr, &["replicaof", host, port], request_timeout).await {
+            Ok(_) => println!("+slave-reconf-sent {}", addr),
+            Err(err) => println!("-slave-reconf-failed {}: {}", addr, err),
+        }
+    }
+}
+
+/// Checks whether the master is down, and runs a failover if it is.
+async fn failover_worker(state: &RefCell<Sentinel>) {
+    loop {
+        Timer::after(CHECK_PERIOD).await;
+        let elected = state.borrow_mut().check_master();
+        if elected {
+            promote(state).await;
+        }
+    }
+}
+
+fn master_fields(state: &Sentinel) -> RedisItem {
+    let (ip, port) = split_addr(&state.master);
+    let mut flags = "master".to_string();
+    if state.sdown {
+        flags.push_str(",s_down");
+    }
+    if state.odown {
+        flags.push_str(",o_down");
+    }
+    if state.failover.is_some() {
+        flags.push_str(",failover_in_progress");
+    }
+    let run_id = state
+        .instances
+        .get(&state.master)
+        .map_or("", |instance| instance.run_id.as_str());
+    fields(&[
+        ("name", &state.name),
+        ("ip", ip),
+        ("port", port),
+        ("runid", run_id),
+        ("flags", &flags),
+        ("num-slaves", &state.replicas().count().to_string()),
+        ("num-other-sentinels", &state.peers.len().to_string()),
+        ("quorum", &state.quorum.to_string()),
+        ("config-epoch", &state.config_epoch.to_string()),
+        (
+            "down-after-milliseconds",
+            &state.down_after.as_millis().to_string(),
+        ),
+        (
+            "failover-timeout",
+            &state.failover_timeout.as_millis().to_string(),
+        ),
+    ])
+}
+
+fn fields(fields: &[(&str, &str)]) -> RedisItem {
+    RedisItem::Array(
+        fields
+            .iter()
+            .flat_map(|(name, val)| {
+                [
+                    RedisItem::BulkString(name.to_string()),
+                    RedisItem::BulkString(val.to_string()),
+                ]
+            })
+            .collect(),
+    )
+}
+
+fn do_sentinel(args: &[String], state: &RefCell<Sentinel>) -> RedisItem {
+    use RedisItem::*;
+    let Some(subcommand) = args.first() else {
+        return SimpleError("ERR wrong number of arguments for 'sentinel' command".to_string());
+    };
+    let known = |name: &str| name == state.borrow().name;
+    let no_such_master = || SimpleError("ERR No such master with that name".to_string());
+    match (subcommand.to_ascii_lowercase().as_str(), &args[1..]) {
+        ("myid", []) => BulkString(state.borrow().run_id.clone()),
+        ("get-master-addr-by-name", [name]) => {
+            if !known(name) {
+                return Null;
+            }
+            let state = state.borrow();
+            let (ip, port) = split_addr(&state.master);
+            Array(vec![
+                BulkString(ip.to_string()),
+                BulkString(port.to_string()),
+            ])
+        }
+        ("masters", []) => Array(vec![master_fields(&state.borrow())]),
+        ("master", [name]) if known(name) => master_fields(&state.borrow()),
+        ("replicas" | "slaves", [name]) if known(name) => {
+            let state = state.borrow();
+            let replicas = state.replicas().map(|(addr, instance)| {
+                let (ip, port) = split_addr(addr);
+                let (master_host, master_port) =
+                    split_addr(instance.master.as_deref().unwrap_or("?:0"));
+                let flags = if state.is_sdown(addr) {
+                    "slave,s_down"
+                } else {
+                    "slave"
+                };
+                fields(&[
+                    ("name", addr),
+                    ("ip", ip),
+                    ("port", port),
+                    ("runid", &instance.run_id),
+                    ("flags", flags),
+                    ("master-host", master_host),
+                    ("master-port", master_port),
+                    (
+                        "master-link-status",
+                        if instance.link_up { "ok" } else { "err" },
+                    ),
+                    ("slave-priority", &instance.priority.to_string()),
+                    ("slave-repl-offset", &instance.offset.to_string()),
+                ])
+            });
+            Array(replicas.collect())
+        }
+        ("sentinels", [name]) if known(name) => {
+            let state = state.borrow();
+            let peers = state.peers.iter().map(|(addr, peer)| {
+                let (ip, port) = split_addr(addr);
+                let last_hello = peer
+                    .last_hello
+                    .map_or(-1, |time| time.elapsed().as_millis() as i64);
+                fields(&[
+                    ("name", addr),
+                    ("ip", ip),
+                    ("port", port),
+                    ("runid", &peer.run_id),
+                    ("flags", "sentinel"),
+                    ("last-hello-message", &last_hello.to_string()),
+                ])
+            });
+            Array(peers.collect())
+        }
+        ("failover", [name]) if known(name) => {
+            let mut state = state.borrow_mut();
+            if state.failover.is_some() {
+                return SimpleError("INPROG Failover already in progress".to_string());
+            }
+            if state.select_replica().is_none() {
+                return SimpleError("NOGOODSLAVE No suitable replica to promote".to_string());
+            }
+            state.force_failover = true;
+            SimpleString("OK".to_string())
+        }
+        ("master" | "replicas" | "slaves" | "sentinels" | "failover", [_]) => no_such_master(),
+        ("is-master-down-by-addr", [ip, port, epoch, run_id]) => {
+            let Ok(epoch) = epoch.parse::<u64>() else {
+                return SimpleError("ERR invalid epoch".to_string());
+            };
+            let mut state = state.borrow_mut();
+            let down = state.master == format!("{}:{}", ip, port) && state.sdown;
+            let mut leader = (BulkString("*".to_string()), Integer(0));
+            if run_id != "*" {
+                if epoch > state.current_epoch {
+                    state.current_epoch = epoch;
+                    println!("+new-epoch {}", epoch);
+                }
+                // the first sentinel to ask in an epoch gets the vote
+                if state
+                    .leader_vote
+                    .as_ref()
+                    .is_none_or(|(_, voted)| *voted < epoch)
+                {
+                    println!("+vote-for-leader {} {}", run_id, epoch);
+                    state.leader_vote = Some((run_id.clone(), epoch));
+                    // to not split the vote, this sentinel waits before
+                    // trying a failover of its own
+                    if *run_id != state.run_id {
+                        state.next_failover =
+                            Some(Instant::now() + 2 * state.failover_timeout + desync());
+                    }
+                }
+                if let Some((voted, epoch)) = &state.leader_vote {
+                    leader = (BulkString(voted.clone()), Integer(*epoch as i64));
+                }
+            }
+            Array(vec![Integer(down as i64), leader.0, leader.1])
+        }
+        (
+            "hello",
+            [ip, port, run_id, current_epoch, name, master_ip, master_port, config_epoch],
+        ) => {
+            let (Ok(current_epoch), Ok(config_epoch)) =
+                (current_epoch.parse::<u64>(), config_epoch.parse::<u64>())
+            else {
+                return SimpleError("ERR invalid epoch".to_string());
+            };
+            let mut state = state.borrow_mut();
+            let addr = format!("{}:{}", ip, port);
+            // a sentinel may be configured as a peer of itself
+            if *run_id == state.run_id || addr == state.addr {
+                return Array(Vec::new());
+            }
+            state.add_peer(&addr);
+            let Some(peer) = state.peers.get_mut(&addr) else {
+                return Array(Vec::new());
+            };
+            peer.run_id = run_id.clone();
+            peer.last_hello = Some(Instant::now());
+            if current_epoch > state.current_epoch {
+                state.current_epoch = current_epoch;
+                println!("+new-epoch {}", current_epoch);
+            }
+            // the master promoted in the latest failover wins
+            let master = format!("{}:{}", master_ip, master_port);
+            if *name == state.name && config_epoch > state.config_epoch {
+                if master != state.master {
+                    println!("+switch-master {} {} {}", name, state.master, master);
+                    state.master = master.clone();
+                    state.add_instance(&master);
+                    state.failover = None;
+                    state.sdown = false;
+                    state.odown = false;
+                }
+                state.config_epoch = config_epoch;
+            }
+            let peers = state.peers.keys().filter(|peer| **peer != addr);
+            Array(peers.map(|peer| BulkString(peer.clone())).collect())
+        }
+        _ => SimpleError(format!(
+            "ERR unknown subcommand or wrong number of arguments for '{}'",
+            subcommand
+        )),
+    }
+}
+
+fn do_info(state: &RefCell<Sentinel>) -> RedisItem {
+    let state = state.borrow();
+    let mut out = String::new();
+    let status = if state.odown {
+        "odown"
+    } else if state.sdown {
+        "sdown"
+    } else {
+        "ok"
+    };
+    write!(
+        out,
+        "# Server\r\n\
+         feredis_version:{}\r\n\
+         redis_mode:sentinel\r\n\
+         process_id:{}\r\n\
+         run_id:{}\r\n\
+         tcp_port:{}\r\n\
+         \r\n\
+         # Sentinel\r\n\
+         sentinel_masters:1\r\n\
+         master0:name={},status={},address={},slaves={},sentinels={}\r\n",
+        env!("CARGO_PKG_VERSION"),
+        std::process::id(),
+        state.run_id,
+        split_addr(&state.addr).1,
+        state.name,
+        status,
+        state.master,
+        state.replicas().count(),
+        state.peers.len() + 1,
+    )
+    .unwrap();
+    RedisItem::BulkString(out)
+}
+
+fn handle_command(items: Vec<RedisItem>, state: &RefCell<Sentinel>) -> RedisItem {
+    let args: Option<Vec<String>> = items
+        .iter()
+        .map(|item| Some(String::from_utf8_lossy(item.as_bytes()?).into_owned()))
+        .collect();
+    let Some(args) = args.filter(|args| !args.is_empty()) else {
+        return RedisItem::SimpleError("ERR invalid request".to_string());
+    };
+    match args[0].to_ascii_lowercase().as_str() {
+        "ping" => RedisItem::SimpleString("PONG".to_string()),
+        "info" => do_info(state),
+        "sentinel" => do_sentinel(&args[1..], state),
+        _ => RedisItem::SimpleError(format!("ERR unknown command '{}'", args[0])),
+    }
+}
+
+async fn connection_worker(stream: TcpStream, state: &RefCell<Sentinel>) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.clone());
+    let mut writer = stream;
+    let mut parser = ItemParser::new();
+    let mut out_buffer = Vec::new();
+    loop {
+        let res = match parser.parse(&mut reader).await {
+            Ok(RedisItem::Array(items)) => handle_command(items, state),
+            Ok(_) | Err(ParseError::Invalid) => RedisItem::SimpleError("ERR".to_string()),
+            Err(ParseError::Incomplete) => return Ok(()),
+            Err(ParseError::IoError(err)) => return Err(err),
+        };
+        out_buffer.clear();
+        res.serialize(&mut out_buffer);
+        writer.write_all(&out_buffer).await?;
+    }
+}
+
+fn random_id() -> String {
+    (0..40)
+        .map(|_| char::from_digit(fastrand::u32(..16), 16).unwrap())
+        .collect()
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let Some(options) = parse_options(&args) else {
+        eprintln!(
+            "Usage: feredis-sentinel [-p <port>] [--announce-ip <ip>] [--down-after <ms>] \
+             [--failover-timeout <ms>] [--sentinel <host:port>]... <name> <host> <port> <quorum>"
+        );
+        return ExitCode::from(2);
+    };
+    let (workers, new_workers) = channel::unbounded();
+    let state = RefCell::new(Sentinel::new(&options, workers));
+    let exec = smol::LocalExecutor::new();
+    exec.spawn(failover_worker(&state)).detach();
+    let res: io::Result<()> = smol::block_on(exec.run(async {
+        let listener = TcpListener::bind(("0.0.0.0", options.port)).await?;
+        println!(
+            "Sentinel {} listening on {}, monitoring {} at {}",
+            state.borrow().run_id,
+            listener.local_addr()?,
+            options.name,
+            options.master
+        );
+        // workers for newly discovered instances and sentinels
+        let spawn_workers = async {
+            while let Ok(worker) = new_workers.recv().await {
+                match worker {
+                    Worker::Instance(addr) => exec.spawn(instance_worker(addr, &state)),
+                    Worker::Peer(addr) => exec.spawn(peer_worker(addr, &state)),
+                }
+                .detach();
+            }
+            Ok(())
+        };
+        let accept = async {
+            loop {
+                let (stream, _) = listener.accept().await?;
+                exec.spawn(connection_worker(stream, &state)).detach();
+            }
+        };
+        smol::future::or(accept, spawn_workers).await
+    }));
+    match res {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("{}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sentinel(args: &[&str]) -> (RefCell<Sentinel>, channel::Receiver<Worker>) {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        let options = parse_options(&args).unwrap();
+        let (workers, new_workers) = channel::unbounded();
+        let state = RefCell::new(Sentinel::new(&options, workers));
+        (state, new_workers)
+    }
+
+    fn sentinel_command(state: &RefCell<Sentinel>, args: &[&str]) -> RedisItem {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        do_sentinel(&args, state)
+    }
+
+    #[test]
+    pub fn test_hello() {
+        use RedisItem::*;
+        let (state, _workers) = sentinel(&["-p", "5000", "m", "127.0.0.1", "6379", "2"]);
+        let hello = |addr: (&str, &str), run_id: &str, epoch: &str, master: &str| {
+            let (ip, port) = split_addr(master);
+            #[rustfmt::skip]
+            let args = ["hello", addr.0, addr.1, run_id, epoch, "m", ip, port, epoch];
+            sentinel_command(&state, &args)
+        };
+
+        // a sentinel which is its own peer ignores its hellos
+        let res = hello(("127.0.0.1", "5000"), "other", "1", "127.0.0.1:6379");
+        assert_eq!(res, Array(Vec::new()));
+        assert!(state.borrow().peers.is_empty());
+
+        let res = hello(("127.0.0.1", "5001"), "a", "0", "127.0.0.1:6379");
+        assert_eq!(res, Array(Vec::new()));
+        let res = hello(("127.0.0.1", "5002"), "b", "0", "127.0.0.1:6379");
+        assert_eq!(res, Array(vec![BulkString("127.0.0.1:5001".to_string())]));
+        assert_eq!(state.borrow().peers["127.0.0.1:5002"].run_id, "b");
+
+        // the master promoted in a later failover is adopted
+        hello(("127.0.0.1", "5001"), "a", "3", "127.0.0.1:6380");
+        let state = state.borrow();
+        assert_eq!(state.master, "127.0.0.1:6380");
+        assert_eq!((state.config_epoch, state.current_epoch), (3, 3));
+        assert!(state.instances.contains_key("127.0.0.1:6380"));
+    }
+
+    #[test]
+    pub fn test_vote() {
+        use RedisItem::*;
+        let (state, _workers) = sentinel(&["m", "127.0.0.1", "6379", "2"]);
+        let ask = |run_id: &str, epoch: &str| {
+            let args = ["is-master-down-by-addr", "127.0.0.1", "6379", epoch, run_id];
+            sentinel_command(&state, &args)
+        };
+        let vote = |down: i64, leader: &str, epoch: i64| {
+            Array(vec![
+                Integer(down),
+                BulkString(leader.to_string()),
+                Integer(epoch),
+            ])
+        };
+        assert_eq!(ask("*", "0"), vote(0, "*", 0));
+        // the first sentinel to ask in an epoch gets the vote
+        assert_eq!(ask("a", "1"), vote(0, "a", 1));
+        assert_eq!(ask("b", "1"), vote(0, "a", 1));
+        assert_eq!(ask("b", "2"), vote(0, "b", 2));
+        assert_eq!(state.borrow().current_epoch, 2);
+    }
+
+    #[test]
+    pub fn test_election() {
+        let (state, _workers) = sentinel(&[
+            "--down-after",
+            "0",
+            "--sentinel",
+            "127.0.0.1:5001",
+            "--sentinel",
+            "127.0.0.1:5002",
+            "m",
+            "127.0.0.1",
+            "6379",
+            "2",
+        ]);
+        let mut state = state.borrow_mut();
+        std::thread::sleep(Duration::from_millis(1));
+        assert!(!state.check_master());
+        assert!(state.sdown && !state.odown);
+
+        // another sentinel agrees that the master is down
+        let peer = state.peers.get_mut("127.0.0.1:5001").unwrap();
+        peer.master_down = true;
+        peer.last_reply = Some(Instant::now());
+        assert!(!state.check_master());
+        assert!(state.odown);
+        state.next_failover = None;
+        assert!(!state.check_master());
+        let epoch = state.failover.as_ref().unwrap().epoch;
+        assert_eq!(state.leader_vote, Some((state.run_id.clone(), epoch)));
+
+        // a majority of the three sentinels has to vote for this one
+        assert!(!state.check_master());
+        let me = Some((state.run_id.clone(), epoch));
+        state.peers.get_mut("127.0.0.1:5002").unwrap().leader = me;
+        assert!(state.check_master());
+    }
+}
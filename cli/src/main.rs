@@ -1,45 +1,221 @@
+//! A command line client.
+//!
+//! Usage: `feredis-cli [-h <host>] [-p <port>] [-c] [command [arg ...]]`
+//!
+//! Without a command, commands are read line by line from standard input.
+//! Arguments may be quoted with `"` or `'`. With `-c`, the server is the
+//! entry point to a cluster, and each command is sent to the node serving
+//! its keys.
+
+use std::fmt::Write as _;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::process::ExitCode;
+
+use feredis_core::client::{command, ClusterClient, Connection};
 use feredis_core::item::RedisItem;
-use std::io::Write;
-use std::io::Read;
-
-fn main() {
-    let n = 1; // Number of threads to spawn
-    let mut handles = vec![];
-
-    for _ in 0..n {
-        handles.push(std::thread::spawn(|| {
-            let mut stream = std::net::TcpStream::connect("localhost:9000").unwrap();
-            let mut buf = Vec::new();
-
-            let iters  = 1000;
-
-            let begin = std::time::Instant::now();
-            
-            let mut discard = Vec::new();
-            println!("Sending {} SET commands", iters);
-            for i in 0..iters {
-                // let cmd = RedisItem::Array(vec![
-                //     RedisItem::BulkString("SET".to_string()),
-                //     RedisItem::BulkString("foo".to_string()),
-                //     RedisItem::BulkString("bar".to_string()),
-                // ]);
-                let cmd = RedisItem::Array(vec![RedisItem::BulkString("PING".to_string())]);
-                cmd.serialize(&mut buf);
-                stream.write_all(buf.as_slice()).unwrap();
-                let read = stream.read(&mut discard).unwrap();
-                println!("read: {}", read);
+
+struct Options {
+    host: String,
+    port: u16,
+    cluster: bool,
+    command: Vec<String>,
+}
+
+fn parse_options(args: &[String]) -> Option<Options> {
+    let mut options = Options {
+        host: "127.0.0.1".to_string(),
+        port: 9000,
+        cluster: false,
+        command: Vec::new(),
+    };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" => options.host = args.next()?.clone(),
+            "-p" => options.port = args.next()?.parse().ok()?,
+            "-c" => options.cluster = true,
+            _ if arg.starts_with('-') => return None,
+            _ => {
+                options.command.push(arg.clone());
+                options.command.extend(args.cloned());
+                break;
             }
+        }
+    }
+    Some(options)
+}
 
-            let end = std::time::Instant::now();
-            let elapsed = end.duration_since(begin);
-            let elapsed_ms = elapsed.as_secs() * 1000 + elapsed.subsec_millis() as u64;
-            println!("{} ms", elapsed_ms);
-            println!("{} ops/s", iters * 1000 / elapsed_ms);
-        }));
+enum Client {
+    Single(Box<Connection>),
+    Cluster(ClusterClient),
+}
+
+impl Client {
+    async fn send(&mut self, args: &[RedisItem]) -> io::Result<RedisItem> {
+        match self {
+            Client::Single(connection) => connection.send(args).await,
+            Client::Cluster(client) => client.send(args).await,
+        }
     }
+}
 
-    for handle in handles {
-        handle.join().unwrap();
+/// Splits a line into arguments, which may be quoted.
+fn split_line(line: &str) -> Option<Vec<String>> {
+    let mut args = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let Some(first) = chars.next() else {
+            return Some(args);
+        };
+        let mut arg = String::new();
+        match first {
+            '"' => loop {
+                match chars.next()? {
+                    '"' => break,
+                    '\\' => arg.push(match chars.next()? {
+                        'n' => '\n',
+                        'r' => '\r',
+                        't' => '\t',
+                        c => c,
+                    }),
+                    c => arg.push(c),
+                }
+            },
+            '\'' => loop {
+                match chars.next()? {
+                    '\'' => break,
+                    c => arg.push(c),
+                }
+            },
+            c => {
+                arg.push(c);
+                while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                    arg.push(c);
+                }
+            }
+        }
+        // a closing quote must end the argument
+        if chars.peek().is_some_and(|c| !c.is_whitespace()) {
+            return None;
+        }
+        args.push(arg);
     }
+}
 
+/// Quotes a string for display, escaping anything which is not printable.
+fn quote(bytes: &[u8]) -> String {
+    let mut out = String::from("\"");
+    for &byte in bytes {
+        match byte {
+            b'"' | b'\\' => {
+                out.push('\\');
+                out.push(byte as char);
+            }
+            b'\n' => out.push_str("\\n"),
+            b'\r' => out.push_str("\\r"),
+            b'\t' => out.push_str("\\t"),
+            0x20..=0x7e => out.push(byte as char),
+            _ => write!(out, "\\x{:02x}", byte).unwrap(),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Formats a reply the way redis-cli does, indenting nested elements by
+/// `indent` columns.
+fn format_reply(out: &mut String, reply: &RedisItem, indent: usize) {
+    match reply {
+        RedisItem::SimpleString(val) => out.push_str(val),
+        RedisItem::SimpleError(err) => write!(out, "(error) {}", err).unwrap(),
+        RedisItem::Integer(val) => write!(out, "(integer) {}", val).unwrap(),
+        RedisItem::BulkString(val) => out.push_str(&quote(val.as_bytes())),
+        RedisItem::BulkBytes(val) => out.push_str(&quote(val)),
+        RedisItem::Null => out.push_str("(nil)"),
+        RedisItem::Boolean(val) => write!(out, "({})", val).unwrap(),
+        RedisItem::Double(val) => write!(out, "(double) {}", val).unwrap(),
+        RedisItem::Array(items) if items.is_empty() => out.push_str("(empty array)"),
+        RedisItem::Map(entries) if entries.is_empty() => out.push_str("(empty hash)"),
+        RedisItem::Array(items) => {
+            let width = items.len().to_string().len();
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push('\n');
+                    out.push_str(&" ".repeat(indent));
+                }
+                write!(out, "{:>width$}) ", i + 1).unwrap();
+                format_reply(out, item, indent + width + 2);
+            }
+        }
+        RedisItem::Map(entries) => {
+            let width = entries.len().to_string().len();
+            for (i, (key, val)) in entries.iter().enumerate() {
+                if i > 0 {
+                    out.push('\n');
+                    out.push_str(&" ".repeat(indent));
+                }
+                write!(out, "{:>width$}# ", i + 1).unwrap();
+                format_reply(out, key, indent + width + 2);
+                out.push_str(" => ");
+                format_reply(out, val, indent + width + 2);
+            }
+        }
+    }
+}
+
+/// Sends the command `args` and prints the reply.
+async fn run_command(client: &mut Client, args: &[String]) -> io::Result<()> {
+    let reply = client.send(&command(args)).await?;
+    let mut out = String::new();
+    format_reply(&mut out, &reply, 0);
+    println!("{}", out);
+    Ok(())
+}
+
+async fn run(options: &Options) -> io::Result<()> {
+    let addr = format!("{}:{}", options.host, options.port);
+    let mut client = if options.cluster {
+        Client::Cluster(ClusterClient::connect(std::slice::from_ref(&addr)).await?)
+    } else {
+        Client::Single(Box::new(Connection::connect(&addr).await?))
+    };
+    if !options.command.is_empty() {
+        return run_command(&mut client, &options.command).await;
+    }
+    let stdin = io::stdin();
+    let interactive = stdin.is_terminal();
+    let mut lines = stdin.lock().lines();
+    loop {
+        if interactive {
+            print!("{}> ", addr);
+            io::stdout().flush()?;
+        }
+        let Some(line) = lines.next().transpose()? else {
+            return Ok(());
+        };
+        match split_line(&line) {
+            Some(args) if args.is_empty() => {}
+            Some(args) if interactive && ["quit", "exit"].contains(&args[0].as_str()) => {
+                return Ok(())
+            }
+            Some(args) => run_command(&mut client, &args).await?,
+            None => eprintln!("Invalid argument(s)"),
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let Some(options) = parse_options(&args) else {
+        eprintln!("Usage: feredis-cli [-h <host>] [-p <port>] [-c] [command [arg ...]]");
+        return ExitCode::from(2);
+    };
+    match smol::block_on(run(&options)) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("{}:{}: {}", options.host, options.port, err);
+            ExitCode::FAILURE
+        }
+    }
 }
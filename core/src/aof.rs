@@ -0,0 +1,310 @@
+//! Reading the commands stored in an append-only file, and the manifest
+//! of a multi-part AOF.
+//!
+//! Commands are stored as RESP arrays of bulk strings, the same way clients
+//! send them. Lines starting with `#` are annotations and are skipped.
+//!
+//! A multi-part AOF consists of a base file, holding a snapshot in either
+//! the RDB or the AOF format, and incremental files of the commands executed
+//! since. The manifest lists them in the order they are loaded, one per line:
+//!
+//! ```text
+//! file appendonly.aof.1.base.rdb seq 1 type b
+//! file appendonly.aof.1.incr.aof seq 1 type i
+//! ```
+
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AofError {
+    /// the data ends in the middle of the command starting at the offset
+    Truncated(usize),
+    /// the command starting at the offset is malformed
+    Invalid(usize),
+}
+
+impl fmt::Display for AofError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AofError::Truncated(offset) => {
+                write!(f, "unexpected end of file in the command at offset {}", offset)
+            }
+            AofError::Invalid(offset) => write!(f, "invalid command at offset {}", offset),
+        }
+    }
+}
+
+impl std::error::Error for AofError {}
+
+enum Fault {
+    Truncated,
+    Invalid,
+}
+
+/// Reads the commands of an append-only file held in memory.
+pub struct AofReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> AofReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        AofReader { data, pos: 0 }
+    }
+
+    /// Returns the offset of the next command, which is where the valid
+    /// part of the data ends once an error has been returned.
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    /// Reads the arguments of the next command, or returns `None` at the end
+    /// of the data.
+    pub fn next_command(&mut self) -> Result<Option<Vec<Vec<u8>>>, AofError> {
+        let start = self.pos;
+        self.read_command().map_err(|fault| {
+            self.pos = start;
+            match fault {
+                Fault::Truncated => AofError::Truncated(start),
+                Fault::Invalid => AofError::Invalid(start),
+            }
+        })
+    }
+
+    fn line(&mut self) -> Result<&'a [u8], Fault> {
+        let rest = &self.data[self.pos..];
+        let len = rest
+            .windows(2)
+            .position(|end| end == b"\r\n")
+            .ok_or(Fault::Truncated)?;
+        self.pos += len + 2;
+        Ok(&rest[..len])
+    }
+
+    fn read_command(&mut self) -> Result<Option<Vec<Vec<u8>>>, Fault> {
+        loop {
+            match self.data.get(self.pos) {
+                None => return Ok(None),
+                Some(b'#') => {
+                    self.line()?;
+                }
+                Some(_) => break,
+            }
+        }
+        let argc = number(self.line()?, b'*')?;
+        if argc == 0 {
+            return Err(Fault::Invalid);
+        }
+        let mut args = Vec::with_capacity(argc.min(1024));
+        for _ in 0..argc {
+            let len = number(self.line()?, b'$')?;
+            let rest = &self.data[self.pos..];
+            if rest.len() < len + 2 {
+                return Err(Fault::Truncated);
+            }
+            if &rest[len..len + 2] != b"\r\n" {
+                return Err(Fault::Invalid);
+            }
+            args.push(rest[..len].to_vec());
+            self.pos += len + 2;
+        }
+        Ok(Some(args))
+    }
+}
+
+/// Parses a line holding a length after the given type prefix.
+fn number(line: &[u8], prefix: u8) -> Result<usize, Fault> {
+    match line.split_first() {
+        Some((first, rest)) if *first == prefix => std::str::from_utf8(rest)
+            .ok()
+            .and_then(|len| len.parse().ok())
+            .ok_or(Fault::Invalid),
+        _ => Err(Fault::Invalid),
+    }
+}
+
+/// The role of a file listed in a manifest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AofFileType {
+    /// the snapshot the other files are applied to
+    Base,
+    /// a file replaced by a rewrite, which is no longer loaded
+    History,
+    /// commands executed after the base was written
+    Incr,
+}
+
+impl AofFileType {
+    fn tag(self) -> char {
+        match self {
+            AofFileType::Base => 'b',
+            AofFileType::History => 'h',
+            AofFileType::Incr => 'i',
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AofFile {
+    pub name: String,
+    pub seq: u64,
+    pub file_type: AofFileType,
+}
+
+impl fmt::Display for AofFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "file {} seq {} type {}",
+            self.name,
+            self.seq,
+            self.file_type.tag()
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ManifestError {
+    /// the number of the offending line, starting at 1
+    pub line: usize,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for ManifestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid manifest at line {}: {}", self.line, self.reason)
+    }
+}
+
+impl std::error::Error for ManifestError {}
+
+/// The files making up a multi-part AOF.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Manifest {
+    pub base: Option<AofFile>,
+    /// incremental files, in the order they were written
+    pub incrs: Vec<AofFile>,
+    pub history: Vec<AofFile>,
+}
+
+impl Manifest {
+    pub fn parse(data: &str) -> Result<Self, ManifestError> {
+        let mut manifest = Manifest::default();
+        for (i, line) in data.lines().enumerate() {
+            let error = |reason| ManifestError { line: i + 1, reason };
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let words: Vec<&str> = line.split_whitespace().collect();
+            if !words.len().is_multiple_of(2) {
+                return Err(error("expected pairs of keys and values"));
+            }
+            let (mut name, mut seq, mut file_type) = (None, None, None);
+            for pair in words.chunks(2) {
+                match pair[0] {
+                    "file" => name = Some(pair[1].to_string()),
+                    "seq" => seq = Some(pair[1].parse().map_err(|_| error("invalid seq"))?),
+                    "type" => {
+                        file_type = Some(match pair[1] {
+                            "b" => AofFileType::Base,
+                            "h" => AofFileType::History,
+                            "i" => AofFileType::Incr,
+                            _ => return Err(error("invalid type")),
+                        })
+                    }
+                    // unknown keys are left for newer versions
+                    _ => {}
+                }
+            }
+            let (Some(name), Some(seq), Some(file_type)) = (name, seq, file_type) else {
+                return Err(error("missing file, seq or type"));
+            };
+            let file = AofFile {
+                name,
+                seq,
+                file_type,
+            };
+            match file_type {
+                AofFileType::Base if manifest.base.is_some() => {
+                    return Err(error("more than one base file"))
+                }
+                AofFileType::Base => manifest.base = Some(file),
+                AofFileType::History => manifest.history.push(file),
+                AofFileType::Incr => {
+                    if manifest.incrs.last().is_some_and(|last| last.seq >= seq) {
+                        return Err(error("incremental files out of order"));
+                    }
+                    manifest.incrs.push(file);
+                }
+            }
+        }
+        Ok(manifest)
+    }
+
+    /// Returns the files which make up the dataset, in the order they are
+    /// loaded.
+    pub fn files(&self) -> impl Iterator<Item = &AofFile> {
+        self.base.iter().chain(&self.incrs)
+    }
+}
+
+impl fmt::Display for Manifest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for file in self.files().chain(&self.history) {
+            writeln!(f, "{}", file)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_read_commands() {
+        let data = b"*2\r\n$6\r\nSELECT\r\n$1\r\n0\r\n#TS:1\r\n*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$2\r\na\n\r\n";
+        let mut reader = AofReader::new(data);
+        assert_eq!(
+            reader.next_command(),
+            Ok(Some(vec![b"SELECT".to_vec(), b"0".to_vec()]))
+        );
+        assert_eq!(
+            reader.next_command(),
+            Ok(Some(vec![b"SET".to_vec(), b"k".to_vec(), b"a\n".to_vec()]))
+        );
+        assert_eq!(reader.next_command(), Ok(None));
+        assert_eq!(reader.position(), data.len());
+    }
+
+    #[test]
+    pub fn test_read_errors() {
+        let data = b"*1\r\n$4\r\nPING\r\n*2\r\n$3\r\nDEL\r\n$5\r\nab";
+        let mut reader = AofReader::new(data);
+        assert!(reader.next_command().unwrap().is_some());
+        assert_eq!(reader.next_command(), Err(AofError::Truncated(14)));
+        assert_eq!(reader.position(), 14);
+
+        let mut reader = AofReader::new(b"*1\r\n$4\r\nPINGxx*1\r\n");
+        assert_eq!(reader.next_command(), Err(AofError::Invalid(0)));
+        let mut reader = AofReader::new(b"+OK\r\n");
+        assert_eq!(reader.next_command(), Err(AofError::Invalid(0)));
+    }
+
+    #[test]
+    pub fn test_manifest() {
+        let data = "file appendonly.aof.2.base.rdb seq 2 type b\n\
+                    file appendonly.aof.3.incr.aof seq 3 type i\n\
+                    file appendonly.aof.4.incr.aof seq 4 type i\n";
+        let manifest = Manifest::parse(data).unwrap();
+        assert_eq!(manifest.base.as_ref().unwrap().seq, 2);
+        assert_eq!(manifest.incrs.len(), 2);
+        assert_eq!(manifest.to_string(), data);
+
+        let err = Manifest::parse("# comment\nfile a seq 1 type b\nfile b seq 2 type b\n");
+        assert_eq!(err.unwrap_err().line, 3);
+        assert!(Manifest::parse("file a seq x type i\n").is_err());
+        assert!(Manifest::parse("file a seq 1\n").is_err());
+    }
+}
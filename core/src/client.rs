@@ -0,0 +1,313 @@
+//! A client for feredis and redis servers, on its own or as a cluster.
+//!
+//! `ClusterClient` keeps a map of which node serves each hash slot, which
+//! it loads with `CLUSTER SLOTS`. Commands are sent to the node serving the
+//! slot of their keys. A `MOVED` redirect means the slot map is outdated,
+//! so it is loaded again before the command is retried on the new node. An
+//! `ASK` redirect only applies to the one command, which is retried on the
+//! given node after `ASKING`.
+
+use std::collections::HashMap;
+use std::io;
+use std::time::Duration;
+
+use smol::io::{AsyncWriteExt, BufReader};
+use smol::net::TcpStream;
+use smol::Timer;
+
+use crate::cluster::{key_positions, key_slot, SLOTS};
+use crate::item::{ItemParser, ParseError, RedisItem};
+
+/// How often a command is redirected or retried before giving up.
+const MAX_REDIRECTS: usize = 16;
+/// How long to wait before retrying a command the cluster cannot serve yet.
+const RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Creates the command `args` from strings.
+pub fn command<S: AsRef<str>>(args: &[S]) -> Vec<RedisItem> {
+    args.iter()
+        .map(|arg| RedisItem::BulkString(arg.as_ref().to_string()))
+        .collect()
+}
+
+/// A connection to a single server.
+pub struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    parser: ItemParser,
+}
+
+impl Connection {
+    /// Connects to `addr`, given as `host:port`.
+    pub async fn connect(addr: &str) -> io::Result<Self> {
+        let stream = TcpStream::connect(addr).await?;
+        Ok(Self {
+            reader: BufReader::new(stream.clone()),
+            writer: stream,
+            parser: ItemParser::new(),
+        })
+    }
+
+    /// Sends the command `args` and returns the reply.
+    pub async fn send(&mut self, args: &[RedisItem]) -> io::Result<RedisItem> {
+        let mut buf = Vec::new();
+        RedisItem::Array(args.to_vec()).serialize(&mut buf);
+        self.writer.write_all(&buf).await?;
+        match self.parser.parse(&mut self.reader).await {
+            Ok(reply) => Ok(reply),
+            Err(ParseError::IoError(err)) => Err(err),
+            Err(ParseError::Incomplete | ParseError::Invalid) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid reply from the server",
+            )),
+        }
+    }
+}
+
+/// A redirect to another node, given in an error reply.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Redirect {
+    /// the slot is served by the node at the address from now on
+    Moved { slot: u16, addr: String },
+    /// the command is to be sent to the node at the address once
+    Ask { slot: u16, addr: String },
+}
+
+impl Redirect {
+    /// Parses the error `err` as a redirect, if it is one.
+    pub fn parse(err: &str) -> Option<Self> {
+        let mut parts = err.split(' ');
+        let kind = parts.next()?;
+        let slot = parts.next()?.parse().ok()?;
+        let addr = parts.next()?.to_string();
+        match kind {
+            "MOVED" => Some(Redirect::Moved { slot, addr }),
+            "ASK" => Some(Redirect::Ask { slot, addr }),
+            _ => None,
+        }
+    }
+}
+
+/// Parses a reply to `CLUSTER SLOTS` into the address serving each slot.
+/// Nodes without a host are the node at `queried`.
+pub fn parse_slots(reply: &RedisItem, queried: &str) -> Option<Vec<Option<String>>> {
+    let RedisItem::Array(ranges) = reply else {
+        return None;
+    };
+    let queried_host = queried.rsplit_once(':').map_or(queried, |(host, _)| host);
+    let mut slots = vec![None; SLOTS as usize];
+    for range in ranges {
+        let RedisItem::Array(range) = range else {
+            return None;
+        };
+        // the master comes first, followed by its replicas
+        let [RedisItem::Integer(start), RedisItem::Integer(end), RedisItem::Array(master), ..] =
+            &range[..]
+        else {
+            return None;
+        };
+        let [host, RedisItem::Integer(port), ..] = &master[..] else {
+            return None;
+        };
+        let host = match host.as_bytes()? {
+            b"" | b"?" => queried_host.to_string(),
+            host => String::from_utf8_lossy(host).into_owned(),
+        };
+        let start = u16::try_from(*start).ok()?;
+        let end = u16::try_from(*end).ok().filter(|end| *end < SLOTS)?;
+        for slot in start..=end {
+            slots[slot as usize] = Some(format!("{}:{}", host, port));
+        }
+    }
+    Some(slots)
+}
+
+/// A client of a cluster, which sends every command to the node serving
+/// its keys.
+pub struct ClusterClient {
+    /// the nodes to load the slot map from if no other node is reachable
+    seeds: Vec<String>,
+    connections: HashMap<String, Connection>,
+    /// the address of the node serving each slot
+    slots: Vec<Option<String>>,
+}
+
+impl ClusterClient {
+    /// Connects to the cluster, loading the slot map from the first of
+    /// `seeds` which answers.
+    pub async fn connect(seeds: &[String]) -> io::Result<Self> {
+        let mut client = Self {
+            seeds: seeds.to_vec(),
+            connections: HashMap::new(),
+            slots: vec![None; SLOTS as usize],
+        };
+        client.refresh_slots().await?;
+        Ok(client)
+    }
+
+    async fn connection(&mut self, addr: &str) -> io::Result<&mut Connection> {
+        if !self.connections.contains_key(addr) {
+            let connection = Connection::connect(addr).await?;
+            self.connections.insert(addr.to_string(), connection);
+        }
+        Ok(self.connections.get_mut(addr).unwrap())
+    }
+
+    /// Loads the slot map from any node which answers, trying the nodes of
+    /// the current map before the seeds.
+    pub async fn refresh_slots(&mut self) -> io::Result<()> {
+        let mut candidates: Vec<String> = self.slots.iter().flatten().cloned().collect();
+        candidates.sort();
+        candidates.dedup();
+        candidates.extend(self.seeds.iter().cloned());
+        let mut last_err = io::Error::new(io::ErrorKind::NotFound, "no nodes to connect to");
+        for addr in candidates {
+            let reply = match self.connection(&addr).await {
+                Ok(connection) => connection.send(&command(&["cluster", "slots"])).await,
+                Err(err) => Err(err),
+            };
+            match reply {
+                Ok(reply) => match parse_slots(&reply, &addr) {
+                    Some(slots) => {
+                        self.slots = slots;
+                        // connections to nodes which left the cluster are dropped
+                        let slots = &self.slots;
+                        self.connections
+                            .retain(|addr, _| slots.iter().flatten().any(|slot| slot == addr));
+                        return Ok(());
+                    }
+                    None => {
+                        last_err = io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("{} replied to CLUSTER SLOTS with {:?}", addr, reply),
+                        )
+                    }
+                },
+                Err(err) => {
+                    self.connections.remove(&addr);
+                    last_err = err;
+                }
+            }
+        }
+        Err(last_err)
+    }
+
+    /// Returns the address of the node to send the command `args` to.
+    fn route(&self, args: &[RedisItem]) -> Option<String> {
+        // commands without keys can go to any node
+        let slot = key_positions(args)
+            .first()
+            .and_then(|pos| args[*pos].as_bytes())
+            .map_or(0, key_slot);
+        self.slots[slot as usize]
+            .clone()
+            .or_else(|| self.slots.iter().flatten().next().cloned())
+    }
+
+    /// Sends the command `args` to the node serving its keys, following
+    /// redirects, and returns the reply.
+    pub async fn send(&mut self, args: &[RedisItem]) -> io::Result<RedisItem> {
+        let mut addr = self.route(args);
+        let mut asking = false;
+        let mut last_err = None;
+        for _ in 0..MAX_REDIRECTS {
+            let Some(target) = addr.take() else {
+                self.refresh_slots().await?;
+                addr = self.route(args);
+                continue;
+            };
+            let reply = async {
+                let connection = self.connection(&target).await?;
+                if std::mem::take(&mut asking) {
+                    connection.send(&command(&["asking"])).await?;
+                }
+                connection.send(args).await
+            }
+            .await;
+            let reply = match reply {
+                Ok(reply) => reply,
+                Err(err) => {
+                    // the node may have gone away, in which case another one
+                    // took over its slots
+                    self.connections.remove(&target);
+                    last_err = Some(err);
+                    Timer::after(RETRY_DELAY).await;
+                    let _ = self.refresh_slots().await;
+                    addr = self.route(args);
+                    continue;
+                }
+            };
+            let RedisItem::SimpleError(err) = &reply else {
+                return Ok(reply);
+            };
+            match Redirect::parse(err) {
+                Some(Redirect::Moved { slot, addr: moved }) => {
+                    self.slots[slot as usize] = Some(moved.clone());
+                    // the topology changed, so other slots may have moved as well
+                    let _ = self.refresh_slots().await;
+                    addr = Some(moved);
+                }
+                Some(Redirect::Ask { addr: ask, .. }) => {
+                    asking = true;
+                    addr = Some(ask);
+                }
+                None if err.starts_with("TRYAGAIN") || err.starts_with("CLUSTERDOWN") => {
+                    Timer::after(RETRY_DELAY).await;
+                    addr = Some(target);
+                }
+                None => return Ok(reply),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| io::Error::other("too many cluster redirects")))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_redirect() {
+        assert_eq!(
+            Redirect::parse("MOVED 3999 127.0.0.1:6381"),
+            Some(Redirect::Moved {
+                slot: 3999,
+                addr: "127.0.0.1:6381".to_string()
+            })
+        );
+        assert_eq!(
+            Redirect::parse("ASK 3999 127.0.0.1:6381"),
+            Some(Redirect::Ask {
+                slot: 3999,
+                addr: "127.0.0.1:6381".to_string()
+            })
+        );
+        assert_eq!(Redirect::parse("ERR unknown command"), None);
+        assert_eq!(Redirect::parse("MOVED x 127.0.0.1:6381"), None);
+    }
+
+    #[test]
+    pub fn test_parse_slots() {
+        use RedisItem::*;
+        let node = |host: &str, port| Array(vec![BulkString(host.to_string()), Integer(port)]);
+        let reply = Array(vec![
+            Array(vec![
+                Integer(0),
+                Integer(5460),
+                node("10.0.0.1", 7000),
+                node("10.0.0.4", 7003),
+            ]),
+            Array(vec![Integer(5461), Integer(16383), node("", 7001)]),
+        ]);
+        let slots = parse_slots(&reply, "10.0.0.2:7001").unwrap();
+        assert_eq!(slots[0].as_deref(), Some("10.0.0.1:7000"));
+        assert_eq!(slots[5460].as_deref(), Some("10.0.0.1:7000"));
+        assert_eq!(slots[5461].as_deref(), Some("10.0.0.2:7001"));
+        assert_eq!(slots[16383].as_deref(), Some("10.0.0.2:7001"));
+
+        let partial = Array(vec![Array(vec![Integer(0), Integer(10), node("a", 1)])]);
+        assert_eq!(parse_slots(&partial, "a:1").unwrap()[11], None);
+        assert!(parse_slots(&Array(vec![Array(vec![Integer(0)])]), "a:1").is_none());
+        assert!(parse_slots(&SimpleError("ERR".to_string()), "a:1").is_none());
+    }
+}
@@ -0,0 +1,292 @@
+//! Hash slots of keys, as used by Redis Cluster to shard the keyspace, and
+//! the positions of keys among the arguments of commands.
+//!
+//! Every key belongs to one of 16384 slots, given by the CRC16 of the key.
+//! If the key contains a `{hashtag}`, only the hashtag is hashed, so that
+//! related keys can be kept in the same slot.
+
+use crate::item::RedisItem;
+
+/// The number of hash slots.
+pub const SLOTS: u16 = 16384;
+
+/// The CRC16 variant used for hash slots (XMODEM: polynomial 0x1021, no
+/// reflection, initial value 0).
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Returns the hash slot of `key`.
+pub fn key_slot(key: &[u8]) -> u16 {
+    // only the part between the first `{` and the following `}` is hashed,
+    // if it is not empty
+    let tag = key.iter().position(|b| *b == b'{').and_then(|open| {
+        let len = key[open + 1..].iter().position(|b| *b == b'}')?;
+        (len > 0).then(|| &key[open + 1..open + 1 + len])
+    });
+    crc16(tag.unwrap_or(key)) % SLOTS
+}
+
+/// Commands whose only key is their first argument.
+const SINGLE_KEY_COMMANDS: &[&str] = &[
+    "set",
+    "get",
+    "incr",
+    "decr",
+    "incrby",
+    "decrby",
+    "incrbyfloat",
+    "append",
+    "strlen",
+    "getrange",
+    "setrange",
+    "getset",
+    "getdel",
+    "getex",
+    "setnx",
+    "setex",
+    "psetex",
+    "expire",
+    "pexpireat",
+    "persist",
+    "ttl",
+    "pttl",
+    "type",
+    "move",
+    "dump",
+    "restore",
+    "restore-asking",
+    "lpush",
+    "rpush",
+    "lpushx",
+    "rpushx",
+    "lpop",
+    "rpop",
+    "llen",
+    "lrange",
+    "lindex",
+    "lset",
+    "linsert",
+    "lrem",
+    "ltrim",
+    "lpos",
+    "hset",
+    "hmset",
+    "hsetnx",
+    "hget",
+    "hmget",
+    "hdel",
+    "hexists",
+    "hlen",
+    "hstrlen",
+    "hkeys",
+    "hvals",
+    "hgetall",
+    "hincrby",
+    "hincrbyfloat",
+    "hrandfield",
+    "hscan",
+    "sadd",
+    "srem",
+    "smembers",
+    "sismember",
+    "smismember",
+    "scard",
+    "spop",
+    "srandmember",
+    "sscan",
+    "zadd",
+    "zincrby",
+    "zrem",
+    "zcard",
+    "zscore",
+    "zmscore",
+    "zrank",
+    "zrevrank",
+    "zrange",
+    "zrevrange",
+    "zrangebyscore",
+    "zrevrangebyscore",
+    "zrangebylex",
+    "zrevrangebylex",
+    "zcount",
+    "zlexcount",
+    "zpopmin",
+    "zpopmax",
+    "zremrangebyrank",
+    "zremrangebyscore",
+    "zremrangebylex",
+    "zscan",
+    "setbit",
+    "getbit",
+    "bitcount",
+    "bitpos",
+    "bitfield",
+    "bitfield_ro",
+    "pfadd",
+    "xadd",
+    "xtrim",
+    "xsetid",
+    "xlen",
+    "xdel",
+    "xrange",
+    "xrevrange",
+    "xack",
+    "xpending",
+    "xclaim",
+    "xautoclaim",
+    "geoadd",
+    "geodist",
+    "geohash",
+    "geopos",
+    "geosearch",
+];
+
+/// Returns the positions of the keys among the arguments of the command
+/// `args`, where argument 0 is the command name.
+pub fn key_positions(args: &[RedisItem]) -> Vec<usize> {
+    let Some(name) = args.first().and_then(RedisItem::as_bytes) else {
+        return Vec::new();
+    };
+    let name = String::from_utf8_lossy(name).to_ascii_lowercase();
+    let len = args.len();
+    let is = |pos: usize, keyword: &str| {
+        args.get(pos)
+            .and_then(RedisItem::as_bytes)
+            .is_some_and(|arg| arg.eq_ignore_ascii_case(keyword.as_bytes()))
+    };
+    // the keys from `first` on, except for the last `skip` arguments
+    let range = |first: usize, skip: usize, step: usize| -> Vec<usize> {
+        (first..len.saturating_sub(skip)).step_by(step).collect()
+    };
+    // the keys after the number of keys at argument `pos`
+    let numkeys = |pos: usize| -> Vec<usize> {
+        let count = args
+            .get(pos)
+            .and_then(RedisItem::as_bytes)
+            .and_then(|count| std::str::from_utf8(count).ok()?.parse::<usize>().ok())
+            .unwrap_or(0);
+        (pos + 1..len.min(pos + 1 + count)).collect()
+    };
+    // the key following one of `keywords`
+    let stored = |keywords: &[&str]| -> Vec<usize> {
+        (2..len)
+            .filter(|pos| keywords.iter().any(|keyword| is(pos - 1, keyword)))
+            .take(1)
+            .collect()
+    };
+    let mut keys = match name.as_str() {
+        "del" | "unlink" | "exists" | "touch" | "mget" | "sinter" | "sunion" | "sdiff"
+        | "sinterstore" | "sunionstore" | "sdiffstore" | "pfcount" | "pfmerge" => range(1, 0, 1),
+        "mset" | "msetnx" => range(1, 0, 2),
+        "bitop" => range(2, 0, 1),
+        "blpop" | "brpop" | "bzpopmin" | "bzpopmax" => range(1, 1, 1),
+        "rename" | "renamenx" | "copy" | "lmove" | "rpoplpush" | "blmove" | "brpoplpush"
+        | "smove" | "lcs" | "geosearchstore" => range(1, 0, 1).into_iter().take(2).collect(),
+        "lmpop" | "zmpop" | "sintercard" | "zinter" | "zunion" | "zdiff" => numkeys(1),
+        "blmpop" | "bzmpop" => numkeys(2),
+        "zinterstore" | "zunionstore" | "zdiffstore" => {
+            let mut keys = vec![1];
+            keys.extend(numkeys(2));
+            keys
+        }
+        "xread" | "xreadgroup" => {
+            // the keys are the first half of the arguments after STREAMS
+            match (1..len).find(|pos| is(*pos, "streams")) {
+                Some(pos) => {
+                    let count = (len - pos - 1) / 2;
+                    (pos + 1..pos + 1 + count).collect()
+                }
+                None => Vec::new(),
+            }
+        }
+        "xgroup" | "xinfo" => range(2, 0, 1).into_iter().take(1).collect(),
+        "sort" | "sort_ro" => {
+            let mut keys = vec![1];
+            keys.extend(stored(&["store"]));
+            keys
+        }
+        "georadius" | "georadiusbymember" | "georadius_ro" | "georadiusbymember_ro" => {
+            let mut keys = vec![1];
+            keys.extend(stored(&["store", "storedist"]));
+            keys
+        }
+        "migrate" => {
+            if args
+                .get(3)
+                .and_then(RedisItem::as_bytes)
+                .is_some_and(|key| !key.is_empty())
+            {
+                vec![3]
+            } else {
+                match (6..len).find(|pos| is(*pos, "keys")) {
+                    Some(pos) => range(pos + 1, 0, 1),
+                    None => Vec::new(),
+                }
+            }
+        }
+        name if SINGLE_KEY_COMMANDS.contains(&name) => vec![1],
+        _ => Vec::new(),
+    };
+    keys.retain(|pos| *pos < len);
+    keys
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn command(args: &str) -> Vec<RedisItem> {
+        args.split(' ')
+            .map(|arg| RedisItem::BulkString(arg.to_string()))
+            .collect()
+    }
+
+    #[test]
+    pub fn test_key_slot() {
+        assert_eq!(crc16(b"123456789"), 0x31c3);
+        assert_eq!(key_slot(b"foo"), 12182);
+        assert_eq!(key_slot(b"bar"), 5061);
+        assert_eq!(key_slot(b"{user1000}.following"), key_slot(b"user1000"));
+        assert_eq!(key_slot(b"{user1000}.followers"), key_slot(b"user1000"));
+        // an empty hashtag hashes the whole key
+        assert_eq!(key_slot(b"foo{}{bar}"), crc16(b"foo{}{bar}") % SLOTS);
+        assert_eq!(key_slot(b"foo{{bar}}zap"), key_slot(b"{bar"));
+        assert_eq!(key_slot(b"foo{bar}{zap}"), key_slot(b"bar"));
+    }
+
+    #[test]
+    pub fn test_key_positions() {
+        assert_eq!(key_positions(&command("GET a")), vec![1]);
+        assert_eq!(key_positions(&command("ping")), Vec::<usize>::new());
+        assert_eq!(key_positions(&command("mset a 1 b 2")), vec![1, 3]);
+        assert_eq!(key_positions(&command("del a b c")), vec![1, 2, 3]);
+        assert_eq!(key_positions(&command("blpop a b 0")), vec![1, 2]);
+        assert_eq!(key_positions(&command("lmove a b left right")), vec![1, 2]);
+        assert_eq!(
+            key_positions(&command("zunionstore d 2 a b weights 1 2")),
+            vec![1, 3, 4]
+        );
+        assert_eq!(key_positions(&command("blmpop 0 2 a b left")), vec![3, 4]);
+        assert_eq!(
+            key_positions(&command("xreadgroup group g c count 1 streams a b > >")),
+            vec![7, 8]
+        );
+        assert_eq!(key_positions(&command("sort a by w store d")), vec![1, 5]);
+        assert_eq!(key_positions(&command("migrate h 1 a 0 100 copy")), vec![3]);
+        let mut migrate = command("migrate h 1 x 0 100 keys a b");
+        migrate[3] = RedisItem::BulkString(String::new());
+        assert_eq!(key_positions(&migrate), vec![7, 8]);
+    }
+}
@@ -1,20 +1,50 @@
 use std::io;
 
-use smol::io::{AsyncBufRead, AsyncBufReadExt};
+use smol::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt};
 
-#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum RedisItem {
     SimpleString(String),
     SimpleError(String),
     Integer(i64),
     BulkString(String),
+    /// A bulk string which is not valid UTF-8.
+    BulkBytes(Vec<u8>),
     Array(Vec<RedisItem>),
+    Map(Vec<(RedisItem, RedisItem)>),
     Null,
     Boolean(bool),
-    // Double(f64),
+    Double(f64),
 }
 
 impl RedisItem {
+    /// Creates a bulk string from raw bytes, which are only kept as
+    /// `BulkBytes` if they are not valid UTF-8.
+    pub fn bulk(bytes: Vec<u8>) -> Self {
+        match String::from_utf8(bytes) {
+            Ok(val) => RedisItem::BulkString(val),
+            Err(err) => RedisItem::BulkBytes(err.into_bytes()),
+        }
+    }
+
+    /// Returns the contents of a bulk string as bytes.
+    pub fn into_bytes(self) -> Option<Vec<u8>> {
+        match self {
+            RedisItem::BulkString(val) => Some(val.into_bytes()),
+            RedisItem::BulkBytes(val) => Some(val),
+            _ => None,
+        }
+    }
+
+    /// Returns the contents of a bulk string as bytes, without taking it.
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            RedisItem::BulkString(val) => Some(val.as_bytes()),
+            RedisItem::BulkBytes(val) => Some(val),
+            _ => None,
+        }
+    }
+
     pub fn serialize(&self, target: &mut Vec<u8>) {
         use RedisItem::*;
         match self {
@@ -33,13 +63,8 @@ impl RedisItem {
                 target.extend_from_slice(val.to_string().as_bytes());
                 target.extend_from_slice(b"\r\n");
             }
-            BulkString(val) => {
-                target.push(b'$');
-                target.extend_from_slice(val.len().to_string().as_bytes());
-                target.extend_from_slice(b"\r\n");
-                target.extend_from_slice(val.as_bytes());
-                target.extend_from_slice(b"\r\n");
-            }
+            BulkString(val) => serialize_bulk(val.as_bytes(), target),
+            BulkBytes(val) => serialize_bulk(val, target),
             Array(val) => {
                 target.push(b'*');
                 target.extend_from_slice(val.len().to_string().as_bytes());
@@ -48,6 +73,15 @@ impl RedisItem {
                     item.serialize(target);
                 }
             }
+            Map(val) => {
+                target.push(b'%');
+                target.extend_from_slice(val.len().to_string().as_bytes());
+                target.extend_from_slice(b"\r\n");
+                for (key, value) in val {
+                    key.serialize(target);
+                    value.serialize(target);
+                }
+            }
             Null => target.extend_from_slice(b"_\r\n"),
             Boolean(val) => {
                 if *val {
@@ -56,25 +90,72 @@ impl RedisItem {
                     target.extend_from_slice(b"#f\r\n");
                 }
             }
-            // Double(val) => {
-            //     target.push(b',');
-            //     target.extend_from_slice(val.to_string().as_bytes());
-            //     target.extend_from_slice(b"\r\n");
-            // },
+            Double(val) => {
+                target.push(b',');
+                if val.is_nan() {
+                    target.extend_from_slice(b"nan");
+                } else {
+                    target.extend_from_slice(val.to_string().as_bytes());
+                }
+                target.extend_from_slice(b"\r\n");
+            }
         }
     }
 }
 
+fn serialize_bulk(val: &[u8], target: &mut Vec<u8>) {
+    target.push(b'$');
+    target.extend_from_slice(val.len().to_string().as_bytes());
+    target.extend_from_slice(b"\r\n");
+    target.extend_from_slice(val);
+    target.extend_from_slice(b"\r\n");
+}
+
 #[derive(Debug)]
 enum ParseState {
     List {
         remaining: usize,
         items: Vec<RedisItem>,
     },
-    // Map {
-    //     remaining: usize,
-    //     items: HashMap<String, RedisItem>,
-    // },
+    Map {
+        remaining: usize,
+        items: Vec<RedisItem>,
+    },
+}
+
+impl ParseState {
+    fn remaining(&self) -> usize {
+        match self {
+            ParseState::List { remaining, .. } | ParseState::Map { remaining, .. } => *remaining,
+        }
+    }
+
+    fn push(&mut self, item: RedisItem) -> Result<(), ParseError> {
+        match self {
+            ParseState::List { remaining, items } | ParseState::Map { remaining, items } => {
+                if *remaining == 0 {
+                    return Err(ParseError::Invalid);
+                }
+                *remaining -= 1;
+                items.push(item);
+                Ok(())
+            }
+        }
+    }
+
+    fn finish(self) -> RedisItem {
+        match self {
+            ParseState::List { items, .. } => RedisItem::Array(items),
+            ParseState::Map { items, .. } => {
+                let mut items = items.into_iter();
+                let mut pairs = Vec::new();
+                while let (Some(key), Some(value)) = (items.next(), items.next()) {
+                    pairs.push((key, value));
+                }
+                RedisItem::Map(pairs)
+            }
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -88,6 +169,9 @@ pub struct ItemParser {
     stack: Vec<ParseState>,
 }
 
+/// The longest bulk string accepted, as `proto-max-bulk-len` in redis.
+pub const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+
 #[derive(Debug)]
 pub enum ParseError {
     Incomplete,
@@ -101,6 +185,12 @@ impl From<io::Error> for ParseError {
     }
 }
 
+impl Default for ItemParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ItemParser {
     pub fn new() -> Self {
         Self {
@@ -126,21 +216,36 @@ impl ItemParser {
                 _ => Err(ParseError::Invalid),
             },
             b'$' => {
-                self.buffer.clear();
-                let read1 = stream.read_until(b'\n', &mut self.buffer).await?;
-                if read1 < 2 {
-                    return Err(ParseError::Incomplete);
+                let len = std::str::from_utf8(&self.buffer[1..read0 - 2])
+                    .map_err(|_| ParseError::Invalid)?
+                    .parse::<i64>()
+                    .map_err(|_| ParseError::Invalid)?;
+                if len == -1 {
+                    return Ok(ParseResult::Complete(RedisItem::Null));
                 }
-                let Ok(strval) = std::str::from_utf8(&self.buffer[..read1-2]) else {
-                    return Err(ParseError::Invalid)
-                };
-                Ok(ParseResult::Complete(RedisItem::BulkString(
-                    strval.to_string(),
-                )))
+                let len = usize::try_from(len)
+                    .ok()
+                    .filter(|len| *len <= MAX_BULK_LEN)
+                    .ok_or(ParseError::Invalid)?;
+                // the contents are read by length, so they may contain any bytes;
+                // the buffer only grows as they arrive, whatever the length claims
+                let mut val = Vec::new();
+                (&mut *stream)
+                    .take(len as u64 + 2)
+                    .read_to_end(&mut val)
+                    .await?;
+                if val.len() < len + 2 {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                }
+                if !val.ends_with(b"\r\n") {
+                    return Err(ParseError::Invalid);
+                }
+                val.truncate(len);
+                Ok(ParseResult::Complete(RedisItem::bulk(val)))
             }
-            x @ (b'-' | b'+' | b':') => {
-                let Ok(strval) = std::str::from_utf8(&self.buffer[1..read0-2]) else {
-                    return Err(ParseError::Invalid)
+            x @ (b'-' | b'+' | b':' | b',') => {
+                let Ok(strval) = std::str::from_utf8(&self.buffer[1..read0 - 2]) else {
+                    return Err(ParseError::Invalid);
                 };
                 let str = strval.to_string();
                 Ok(ParseResult::Complete(match x {
@@ -153,6 +258,15 @@ impl ItemParser {
                             return Err(ParseError::Invalid);
                         }
                     }
+                    b',' => match str.as_str() {
+                        "inf" => RedisItem::Double(f64::INFINITY),
+                        "-inf" => RedisItem::Double(f64::NEG_INFINITY),
+                        "nan" => RedisItem::Double(f64::NAN),
+                        _ => match str.parse::<f64>() {
+                            Ok(val) if val.is_finite() => RedisItem::Double(val),
+                            _ => return Err(ParseError::Invalid),
+                        },
+                    },
                     _ => unreachable!(),
                 }))
             }
@@ -166,6 +280,16 @@ impl ItemParser {
                     items: Vec::new(),
                 }))
             }
+            b'%' => {
+                let len = std::str::from_utf8(&self.buffer[1..read0 - 2])
+                    .map_err(|_| ParseError::Invalid)?
+                    .parse::<u32>()
+                    .map_err(|_| ParseError::Invalid)?;
+                Ok(ParseResult::Partial(ParseState::Map {
+                    remaining: 2 * len as usize,
+                    items: Vec::new(),
+                }))
+            }
             _ => Err(ParseError::Invalid),
         }
     }
@@ -188,12 +312,8 @@ impl ItemParser {
         }
 
         while let Some(mut state) = self.stack.pop() {
-            let res = if let ParseState::List {
-                remaining: 0,
-                items,
-            } = state
-            {
-                let res = RedisItem::Array(items);
+            let res = if state.remaining() == 0 {
+                let res = state.finish();
                 if let Some(newstate) = self.stack.pop() {
                     state = newstate;
                 } else {
@@ -203,27 +323,14 @@ impl ItemParser {
             } else {
                 self.parse_partial(stream).await?
             };
-            match (res, state) {
-                (ParseResult::Partial(new_state), s) => {
-                    self.stack.push(s);
+            match res {
+                ParseResult::Partial(new_state) => {
+                    self.stack.push(state);
                     self.stack.push(new_state);
                 }
-                (
-                    ParseResult::Complete(value),
-                    ParseState::List {
-                        remaining,
-                        mut items,
-                    },
-                ) => {
-                    items.push(value);
-                    if remaining == 0 {
-                        return Err(ParseError::Invalid);
-                    } else {
-                        self.stack.push(ParseState::List {
-                            remaining: remaining - 1,
-                            items,
-                        });
-                    }
+                ParseResult::Complete(value) => {
+                    state.push(value)?;
+                    self.stack.push(state);
                 }
             }
         }
@@ -265,6 +372,35 @@ mod test {
         assert_eq!(res, RedisItem::BulkString("foobar".to_string()));
     }
 
+    #[test]
+    pub fn test_parse_binary_bulk_string() {
+        let res = parse(b"$4\r\na\r\n\xff\r\n").unwrap();
+        assert_eq!(res, RedisItem::BulkBytes(b"a\r\n\xff".to_vec()));
+        assert_eq!(
+            parse(b"$4\r\na\r\nb\r\n").unwrap(),
+            RedisItem::BulkString("a\r\nb".to_string())
+        );
+        assert!(parse(b"$3\r\nabcd\r\n").is_err());
+
+        let mut out = Vec::new();
+        res.serialize(&mut out);
+        assert_eq!(out, b"$4\r\na\r\n\xff\r\n");
+    }
+
+    #[test]
+    pub fn test_parse_bulk_length() {
+        // a huge length is rejected rather than allocated
+        assert!(matches!(
+            parse(b"*1\r\n$4000000000000\r\n"),
+            Err(ParseError::Invalid)
+        ));
+        let len = format!("${}\r\n", MAX_BULK_LEN + 1);
+        assert!(matches!(parse(len.as_bytes()), Err(ParseError::Invalid)));
+        // a length which is not followed by that much data
+        let len = format!("${}\r\nabc\r\n", MAX_BULK_LEN);
+        assert!(matches!(parse(len.as_bytes()), Err(ParseError::IoError(_))));
+    }
+
     #[test]
     pub fn test_parse_array() {
         let res = parse(b"*2\r\n$3\r\nfoo\r\n$3\r\nbar\r\n").unwrap();
@@ -292,6 +428,36 @@ mod test {
         );
     }
 
+    #[test]
+    pub fn test_parse_map() {
+        let res = parse(b"%2\r\n$3\r\nfoo\r\n:1\r\n$3\r\nbar\r\n*1\r\n:2\r\n").unwrap();
+        assert_eq!(
+            res,
+            RedisItem::Map(vec![
+                (
+                    RedisItem::BulkString("foo".to_string()),
+                    RedisItem::Integer(1)
+                ),
+                (
+                    RedisItem::BulkString("bar".to_string()),
+                    RedisItem::Array(vec![RedisItem::Integer(2)])
+                ),
+            ])
+        );
+    }
+
+    #[test]
+    pub fn test_serialize_map() {
+        let item = RedisItem::Map(vec![(
+            RedisItem::BulkString("foo".to_string()),
+            RedisItem::Integer(1),
+        )]);
+        let mut out = Vec::new();
+        item.serialize(&mut out);
+        assert_eq!(out, b"%1\r\n$3\r\nfoo\r\n:1\r\n");
+        assert_eq!(parse(&out).unwrap(), item);
+    }
+
     #[test]
     pub fn test_parse_null() {
         let res = parse(b"_\r\n").unwrap();
@@ -306,4 +472,19 @@ mod test {
         let res_false = parse(b"#f\r\n").unwrap();
         assert_eq!(res_false, RedisItem::Boolean(false));
     }
+
+    #[test]
+    pub fn test_double() {
+        assert_eq!(parse(b",1.5\r\n").unwrap(), RedisItem::Double(1.5));
+        assert_eq!(
+            parse(b",-inf\r\n").unwrap(),
+            RedisItem::Double(f64::NEG_INFINITY)
+        );
+        assert!(parse(b",abc\r\n").is_err());
+
+        let mut out = Vec::new();
+        RedisItem::Double(3.0).serialize(&mut out);
+        RedisItem::Double(f64::INFINITY).serialize(&mut out);
+        assert_eq!(out, b",3\r\n,inf\r\n");
+    }
 }
@@ -1 +1,5 @@
-pub mod item;
\ No newline at end of file
+pub mod aof;
+pub mod client;
+pub mod cluster;
+pub mod item;
+pub mod rdb;
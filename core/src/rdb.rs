@@ -0,0 +1,1466 @@
+//! Reading and writing snapshots in the redis RDB format.
+//!
+//! Files are written in RDB version 11 using the simplest encoding of each
+//! type, which any recent redis can load. Reading supports the versions up
+//! to 12, including the compact encodings (intsets, ziplists, listpacks and
+//! quicklists) and LZF compressed strings redis itself writes.
+//!
+//! Single values are encoded the same way in the payloads of `DUMP` and
+//! `RESTORE`, which end with the RDB version and a checksum instead.
+
+use std::fmt;
+use std::io::{self, Write};
+
+/// The RDB version of the files written.
+pub const RDB_VERSION: u32 = 11;
+const MAX_RDB_VERSION: u32 = 12;
+
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_SET: u8 = 2;
+const TYPE_ZSET: u8 = 3;
+const TYPE_HASH: u8 = 4;
+const TYPE_ZSET_2: u8 = 5;
+const TYPE_LIST_ZIPLIST: u8 = 10;
+const TYPE_SET_INTSET: u8 = 11;
+const TYPE_ZSET_ZIPLIST: u8 = 12;
+const TYPE_HASH_ZIPLIST: u8 = 13;
+const TYPE_LIST_QUICKLIST: u8 = 14;
+const TYPE_STREAM_LISTPACKS: u8 = 15;
+const TYPE_HASH_LISTPACK: u8 = 16;
+const TYPE_ZSET_LISTPACK: u8 = 17;
+const TYPE_LIST_QUICKLIST_2: u8 = 18;
+const TYPE_STREAM_LISTPACKS_2: u8 = 19;
+const TYPE_SET_LISTPACK: u8 = 20;
+const TYPE_STREAM_LISTPACKS_3: u8 = 21;
+
+const OPCODE_SLOT_INFO: u8 = 0xf4;
+const OPCODE_FUNCTION2: u8 = 0xf5;
+const OPCODE_IDLE: u8 = 0xf8;
+const OPCODE_FREQ: u8 = 0xf9;
+const OPCODE_AUX: u8 = 0xfa;
+const OPCODE_RESIZEDB: u8 = 0xfb;
+const OPCODE_EXPIRETIME_MS: u8 = 0xfc;
+const OPCODE_EXPIRETIME: u8 = 0xfd;
+const OPCODE_SELECTDB: u8 = 0xfe;
+const OPCODE_EOF: u8 = 0xff;
+
+/// The number of entries stored in each listpack of a stream.
+const STREAM_NODE_ENTRIES: usize = 100;
+
+const STREAM_ITEM_FLAG_DELETED: i64 = 1;
+const STREAM_ITEM_FLAG_SAMEFIELDS: i64 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RdbError {
+    /// the data ended in the middle of a record
+    Truncated,
+    /// the data does not start with an RDB header
+    InvalidHeader,
+    UnsupportedVersion(u32),
+    /// a value type or opcode which is not supported
+    UnsupportedType(u8),
+    /// the data is malformed
+    Invalid(&'static str),
+    ChecksumMismatch,
+}
+
+impl fmt::Display for RdbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RdbError::Truncated => write!(f, "unexpected end of data"),
+            RdbError::InvalidHeader => write!(f, "not an RDB file"),
+            RdbError::UnsupportedVersion(version) => {
+                write!(f, "unsupported RDB version {}", version)
+            }
+            RdbError::UnsupportedType(value_type) => {
+                write!(f, "unsupported value type or opcode {}", value_type)
+            }
+            RdbError::Invalid(reason) => write!(f, "invalid data: {}", reason),
+            RdbError::ChecksumMismatch => write!(f, "checksum mismatch"),
+        }
+    }
+}
+
+impl std::error::Error for RdbError {}
+
+impl From<RdbError> for io::Error {
+    fn from(err: RdbError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+/// The id of a stream entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamPendingEntry {
+    pub id: StreamId,
+    pub consumer: Vec<u8>,
+    pub delivery_time: u64,
+    pub delivery_count: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamConsumer {
+    pub name: Vec<u8>,
+    pub seen_time: u64,
+    pub active_time: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamGroup {
+    pub name: Vec<u8>,
+    pub last_id: StreamId,
+    pub entries_read: Option<u64>,
+    pub pending: Vec<StreamPendingEntry>,
+    pub consumers: Vec<StreamConsumer>,
+}
+
+/// A stream entry with its fields and values.
+pub type StreamEntry = (StreamId, Vec<(Vec<u8>, Vec<u8>)>);
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Stream {
+    /// the entries in order
+    pub entries: Vec<StreamEntry>,
+    pub last_id: StreamId,
+    pub max_deleted_id: StreamId,
+    pub entries_added: u64,
+    pub groups: Vec<StreamGroup>,
+}
+
+/// A value stored in a snapshot.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RdbValue {
+    String(Vec<u8>),
+    List(Vec<Vec<u8>>),
+    Set(Vec<Vec<u8>>),
+    SortedSet(Vec<(Vec<u8>, f64)>),
+    Hash(Vec<(Vec<u8>, Vec<u8>)>),
+    Stream(Stream),
+}
+
+/// A key and its value, as stored in a snapshot.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub key: Vec<u8>,
+    pub value: RdbValue,
+    /// the expiry time as a unix timestamp in milliseconds
+    pub expire_ms: Option<u64>,
+}
+
+impl Entry {
+    /// Returns the commands which recreate the entry, as emitted into an
+    /// append-only file.
+    pub fn commands(&self) -> Vec<Vec<Vec<u8>>> {
+        let key = &self.key;
+        let command = |name: &str, args: &[&[u8]]| {
+            let mut command = vec![name.as_bytes().to_vec(), key.clone()];
+            command.extend(args.iter().map(|arg| arg.to_vec()));
+            command
+        };
+        let id = |id: StreamId| format!("{}-{}", id.ms, id.seq).into_bytes();
+        let mut commands = Vec::new();
+        match &self.value {
+            RdbValue::String(val) => commands.push(command("set", &[val])),
+            RdbValue::List(items) => {
+                let items: Vec<&[u8]> = items.iter().map(Vec::as_slice).collect();
+                commands.push(command("rpush", &items));
+            }
+            RdbValue::Set(members) => {
+                let members: Vec<&[u8]> = members.iter().map(Vec::as_slice).collect();
+                commands.push(command("sadd", &members));
+            }
+            RdbValue::SortedSet(members) => {
+                let mut zadd = command("zadd", &[]);
+                for (member, score) in members {
+                    zadd.push(score.to_string().into_bytes());
+                    zadd.push(member.clone());
+                }
+                commands.push(zadd);
+            }
+            RdbValue::Hash(fields) => {
+                let mut hset = command("hset", &[]);
+                for (field, val) in fields {
+                    hset.extend([field.clone(), val.clone()]);
+                }
+                commands.push(hset);
+            }
+            RdbValue::Stream(stream) => {
+                for (entry_id, fields) in &stream.entries {
+                    let mut xadd = command("xadd", &[&id(*entry_id)]);
+                    for (field, val) in fields {
+                        xadd.extend([field.clone(), val.clone()]);
+                    }
+                    commands.push(xadd);
+                }
+                if stream.entries.is_empty() {
+                    // an empty stream is created by adding an entry which is
+                    // trimmed right away, XSETID then restores its last id
+                    let first = stream.last_id.max(StreamId { ms: 0, seq: 1 });
+                    commands.push(command("xadd", &[b"maxlen", b"0", &id(first), b"x", b"y"]));
+                }
+                commands.push(command(
+                    "xsetid",
+                    &[
+                        &id(stream.last_id),
+                        b"entriesadded",
+                        stream.entries_added.to_string().as_bytes(),
+                        b"maxdeletedid",
+                        &id(stream.max_deleted_id),
+                    ],
+                ));
+                for group in &stream.groups {
+                    let mut create = command("xgroup", &[&group.name, &id(group.last_id)]);
+                    create.insert(1, b"create".to_vec());
+                    if let Some(entries_read) = group.entries_read {
+                        create.extend([
+                            b"entriesread".to_vec(),
+                            entries_read.to_string().into_bytes(),
+                        ]);
+                    }
+                    commands.push(create);
+                    for consumer in &group.consumers {
+                        let mut create = command("xgroup", &[&group.name, &consumer.name]);
+                        create.insert(1, b"createconsumer".to_vec());
+                        commands.push(create);
+                    }
+                    for pending in &group.pending {
+                        commands.push(command(
+                            "xclaim",
+                            &[
+                                &group.name,
+                                &pending.consumer,
+                                b"0",
+                                &id(pending.id),
+                                b"time",
+                                pending.delivery_time.to_string().as_bytes(),
+                                b"retrycount",
+                                pending.delivery_count.to_string().as_bytes(),
+                                b"force",
+                                b"justid",
+                            ],
+                        ));
+                    }
+                }
+            }
+        }
+        if let Some(expire_ms) = self.expire_ms {
+            commands.push(command("pexpireat", &[expire_ms.to_string().as_bytes()]));
+        }
+        commands
+    }
+}
+
+/// A record read from a snapshot.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Record {
+    /// auxiliary information about the snapshot, like the redis version
+    Aux {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    /// the following entries belong to database `db`
+    SelectDb(u64),
+    /// the sizes of the hash tables of the database
+    ResizeDb {
+        size: u64,
+        expires: u64,
+    },
+    Entry(Entry),
+}
+
+const fn crc64_table() -> [u64; 256] {
+    // the reflected form of the Jones polynomial used by redis
+    const POLY: u64 = 0x95ac9329ac4bc9b5;
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u64;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ POLY
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+const CRC64_TABLE: [u64; 256] = crc64_table();
+
+/// Updates the CRC-64 checksum `crc` with `data`, as used by RDB files and
+/// `DUMP` payloads. Checksums start out as zero.
+pub fn crc64(mut crc: u64, data: &[u8]) -> u64 {
+    for &byte in data {
+        crc = CRC64_TABLE[((crc ^ byte as u64) & 0xff) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+/// Parses an integer in its canonical decimal representation.
+fn canonical_int(val: &[u8]) -> Option<i64> {
+    let int = std::str::from_utf8(val).ok()?.parse::<i64>().ok()?;
+    (int.to_string().as_bytes() == val).then_some(int)
+}
+
+fn write_length(out: &mut Vec<u8>, len: u64) {
+    if len < 1 << 6 {
+        out.push(len as u8);
+    } else if len < 1 << 14 {
+        out.push(0x40 | (len >> 8) as u8);
+        out.push(len as u8);
+    } else if len <= u32::MAX as u64 {
+        out.push(0x80);
+        out.extend_from_slice(&(len as u32).to_be_bytes());
+    } else {
+        out.push(0x81);
+        out.extend_from_slice(&len.to_be_bytes());
+    }
+}
+
+fn write_string(out: &mut Vec<u8>, val: &[u8]) {
+    // small integers are stored in binary, like redis does
+    if let Some(int) = canonical_int(val) {
+        if let Ok(int) = i8::try_from(int) {
+            out.push(0xc0);
+            out.extend_from_slice(&int.to_le_bytes());
+            return;
+        } else if let Ok(int) = i16::try_from(int) {
+            out.push(0xc1);
+            out.extend_from_slice(&int.to_le_bytes());
+            return;
+        } else if let Ok(int) = i32::try_from(int) {
+            out.push(0xc2);
+            out.extend_from_slice(&int.to_le_bytes());
+            return;
+        }
+    }
+    write_length(out, val.len() as u64);
+    out.extend_from_slice(val);
+}
+
+fn write_stream_id(out: &mut Vec<u8>, id: StreamId) {
+    write_length(out, id.ms);
+    write_length(out, id.seq);
+}
+
+/// Writes a stream id in the 128 bit big endian form used for keys.
+fn write_raw_stream_id(out: &mut Vec<u8>, id: StreamId) {
+    out.extend_from_slice(&id.ms.to_be_bytes());
+    out.extend_from_slice(&id.seq.to_be_bytes());
+}
+
+/// An element of a listpack or ziplist.
+#[derive(Debug, Clone, PartialEq)]
+enum Element {
+    Int(i64),
+    Str(Vec<u8>),
+}
+
+impl Element {
+    fn from_bytes(val: &[u8]) -> Self {
+        match canonical_int(val) {
+            Some(int) => Element::Int(int),
+            None => Element::Str(val.to_vec()),
+        }
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        match self {
+            Element::Int(int) => int.to_string().into_bytes(),
+            Element::Str(val) => val,
+        }
+    }
+
+    fn to_int(&self) -> Result<i64, RdbError> {
+        match self {
+            Element::Int(int) => Ok(*int),
+            Element::Str(val) => {
+                canonical_int(val).ok_or(RdbError::Invalid("expected an integer in listpack"))
+            }
+        }
+    }
+}
+
+/// Encodes elements into a listpack.
+fn encode_listpack(elements: &[Element]) -> Vec<u8> {
+    let mut out = vec![0; 6];
+    for element in elements {
+        let start = out.len();
+        match element {
+            Element::Int(int @ 0..=127) => out.push(*int as u8),
+            Element::Int(int @ -4096..=4095) => {
+                let val = (*int as u16) & 0x1fff;
+                out.push(0xc0 | (val >> 8) as u8);
+                out.push(val as u8);
+            }
+            Element::Int(int) if i16::try_from(*int).is_ok() => {
+                out.push(0xf1);
+                out.extend_from_slice(&(*int as i16).to_le_bytes());
+            }
+            Element::Int(int @ -8388608..=8388607) => {
+                out.push(0xf2);
+                out.extend_from_slice(&(*int as i32).to_le_bytes()[..3]);
+            }
+            Element::Int(int) if i32::try_from(*int).is_ok() => {
+                out.push(0xf3);
+                out.extend_from_slice(&(*int as i32).to_le_bytes());
+            }
+            Element::Int(int) => {
+                out.push(0xf4);
+                out.extend_from_slice(&int.to_le_bytes());
+            }
+            Element::Str(val) if val.len() < 64 => {
+                out.push(0x80 | val.len() as u8);
+                out.extend_from_slice(val);
+            }
+            Element::Str(val) if val.len() < 4096 => {
+                out.push(0xe0 | (val.len() >> 8) as u8);
+                out.push(val.len() as u8);
+                out.extend_from_slice(val);
+            }
+            Element::Str(val) => {
+                out.push(0xf0);
+                out.extend_from_slice(&(val.len() as u32).to_le_bytes());
+                out.extend_from_slice(val);
+            }
+        }
+        // each element ends with its length, for iterating backwards
+        let len = (out.len() - start) as u64;
+        let backlen_size = backlen_size(len);
+        for i in (0..backlen_size).rev() {
+            let byte = ((len >> (7 * i)) & 127) as u8;
+            out.push(if i == backlen_size - 1 {
+                byte
+            } else {
+                byte | 128
+            });
+        }
+    }
+    out.push(0xff);
+    let total = out.len() as u32;
+    out[..4].copy_from_slice(&total.to_le_bytes());
+    let count = elements.len().min(u16::MAX as usize) as u16;
+    out[4..6].copy_from_slice(&count.to_le_bytes());
+    out
+}
+
+/// Returns the number of bytes used to store the length of a listpack
+/// element of `len` bytes.
+fn backlen_size(len: u64) -> usize {
+    match len {
+        0..=127 => 1,
+        128..=16382 => 2,
+        16383..=2097150 => 3,
+        2097151..=268435454 => 4,
+        _ => 5,
+    }
+}
+
+/// Encodes `value`, returning its type.
+fn write_value(out: &mut Vec<u8>, value: &RdbValue) -> u8 {
+    match value {
+        RdbValue::String(val) => {
+            write_string(out, val);
+            TYPE_STRING
+        }
+        RdbValue::List(items) => {
+            write_length(out, items.len() as u64);
+            for item in items {
+                write_string(out, item);
+            }
+            TYPE_LIST
+        }
+        RdbValue::Set(members) => {
+            write_length(out, members.len() as u64);
+            for member in members {
+                write_string(out, member);
+            }
+            TYPE_SET
+        }
+        RdbValue::SortedSet(members) => {
+            write_length(out, members.len() as u64);
+            for (member, score) in members {
+                write_string(out, member);
+                out.extend_from_slice(&score.to_le_bytes());
+            }
+            TYPE_ZSET_2
+        }
+        RdbValue::Hash(fields) => {
+            write_length(out, fields.len() as u64);
+            for (field, val) in fields {
+                write_string(out, field);
+                write_string(out, val);
+            }
+            TYPE_HASH
+        }
+        RdbValue::Stream(stream) => {
+            write_stream(out, stream);
+            TYPE_STREAM_LISTPACKS_3
+        }
+    }
+}
+
+fn write_stream(out: &mut Vec<u8>, stream: &Stream) {
+    let nodes = stream.entries.chunks(STREAM_NODE_ENTRIES);
+    write_length(out, nodes.len() as u64);
+    for node in nodes {
+        let (master_id, master_fields) = &node[0];
+        let mut elements = vec![
+            Element::Int(node.len() as i64),
+            Element::Int(0),
+            Element::Int(master_fields.len() as i64),
+        ];
+        elements.extend(
+            master_fields
+                .iter()
+                .map(|(field, _)| Element::from_bytes(field)),
+        );
+        elements.push(Element::Int(0));
+        for (id, fields) in node {
+            let same_fields = fields.len() == master_fields.len()
+                && fields
+                    .iter()
+                    .zip(master_fields)
+                    .all(|((a, _), (b, _))| a == b);
+            let flags = if same_fields {
+                STREAM_ITEM_FLAG_SAMEFIELDS
+            } else {
+                0
+            };
+            elements.push(Element::Int(flags));
+            elements.push(Element::Int(id.ms.wrapping_sub(master_id.ms) as i64));
+            elements.push(Element::Int(id.seq.wrapping_sub(master_id.seq) as i64));
+            if same_fields {
+                elements.extend(fields.iter().map(|(_, val)| Element::from_bytes(val)));
+            } else {
+                elements.push(Element::Int(fields.len() as i64));
+                for (field, val) in fields {
+                    elements.push(Element::from_bytes(field));
+                    elements.push(Element::from_bytes(val));
+                }
+            }
+            // the number of elements of the entry, for iterating backwards
+            let mut count = fields.len() as i64 + 3;
+            if !same_fields {
+                count += fields.len() as i64 + 1;
+            }
+            elements.push(Element::Int(count));
+        }
+        let mut key = Vec::new();
+        write_raw_stream_id(&mut key, *master_id);
+        write_length(out, key.len() as u64);
+        out.extend_from_slice(&key);
+        let listpack = encode_listpack(&elements);
+        write_length(out, listpack.len() as u64);
+        out.extend_from_slice(&listpack);
+    }
+    write_length(out, stream.entries.len() as u64);
+    write_stream_id(out, stream.last_id);
+    let first_id = stream
+        .entries
+        .first()
+        .map_or_else(StreamId::default, |(id, _)| *id);
+    write_stream_id(out, first_id);
+    write_stream_id(out, stream.max_deleted_id);
+    write_length(out, stream.entries_added);
+
+    write_length(out, stream.groups.len() as u64);
+    for group in &stream.groups {
+        write_length(out, group.name.len() as u64);
+        out.extend_from_slice(&group.name);
+        write_stream_id(out, group.last_id);
+        write_length(out, group.entries_read.unwrap_or(u64::MAX));
+        write_length(out, group.pending.len() as u64);
+        for entry in &group.pending {
+            write_raw_stream_id(out, entry.id);
+            out.extend_from_slice(&entry.delivery_time.to_le_bytes());
+            write_length(out, entry.delivery_count);
+        }
+        write_length(out, group.consumers.len() as u64);
+        for consumer in &group.consumers {
+            write_length(out, consumer.name.len() as u64);
+            out.extend_from_slice(&consumer.name);
+            out.extend_from_slice(&consumer.seen_time.to_le_bytes());
+            let active_time = consumer.active_time.map_or(-1, |time| time as i64);
+            out.extend_from_slice(&active_time.to_le_bytes());
+            let pending: Vec<_> = group
+                .pending
+                .iter()
+                .filter(|entry| entry.consumer == consumer.name)
+                .collect();
+            write_length(out, pending.len() as u64);
+            for entry in pending {
+                write_raw_stream_id(out, entry.id);
+            }
+        }
+    }
+}
+
+/// Writes a snapshot to `out`, keeping track of the checksum.
+pub struct RdbWriter<W: Write> {
+    out: W,
+    crc: u64,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> RdbWriter<W> {
+    /// Starts a snapshot by writing its header.
+    pub fn new(out: W) -> io::Result<Self> {
+        let mut writer = RdbWriter {
+            out,
+            crc: 0,
+            buf: Vec::new(),
+        };
+        writer
+            .buf
+            .extend_from_slice(format!("REDIS{:04}", RDB_VERSION).as_bytes());
+        writer.flush_buf()?;
+        Ok(writer)
+    }
+
+    fn flush_buf(&mut self) -> io::Result<()> {
+        self.crc = crc64(self.crc, &self.buf);
+        self.out.write_all(&self.buf)?;
+        self.buf.clear();
+        Ok(())
+    }
+
+    pub fn write_aux(&mut self, key: &str, value: &[u8]) -> io::Result<()> {
+        self.buf.push(OPCODE_AUX);
+        write_string(&mut self.buf, key.as_bytes());
+        write_string(&mut self.buf, value);
+        self.flush_buf()
+    }
+
+    /// Starts the entries of database `db`, which holds `size` keys of which
+    /// `expires` have an expiry time.
+    pub fn select_db(&mut self, db: u64, size: u64, expires: u64) -> io::Result<()> {
+        self.buf.push(OPCODE_SELECTDB);
+        write_length(&mut self.buf, db);
+        self.buf.push(OPCODE_RESIZEDB);
+        write_length(&mut self.buf, size);
+        write_length(&mut self.buf, expires);
+        self.flush_buf()
+    }
+
+    pub fn write_entry(&mut self, entry: &Entry) -> io::Result<()> {
+        if let Some(expire_ms) = entry.expire_ms {
+            self.buf.push(OPCODE_EXPIRETIME_MS);
+            self.buf.extend_from_slice(&expire_ms.to_le_bytes());
+        }
+        let mut value = Vec::new();
+        let value_type = write_value(&mut value, &entry.value);
+        self.buf.push(value_type);
+        write_string(&mut self.buf, &entry.key);
+        self.buf.extend_from_slice(&value);
+        self.flush_buf()
+    }
+
+    /// Ends the snapshot with its checksum, returning the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.buf.push(OPCODE_EOF);
+        self.flush_buf()?;
+        let crc = self.crc;
+        self.out.write_all(&crc.to_le_bytes())?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+/// The most bytes LZF produces from one byte of compressed data: a back
+/// reference of three bytes copies up to 264 bytes.
+const LZF_MAX_EXPANSION: usize = 88;
+
+/// Decompresses LZF compressed data of the given length.
+fn lzf_decompress(input: &[u8], len: usize) -> Result<Vec<u8>, RdbError> {
+    const INVALID: RdbError = RdbError::Invalid("invalid LZF data");
+    // the length is checked before it is trusted with an allocation
+    if len > input.len().saturating_mul(LZF_MAX_EXPANSION) {
+        return Err(INVALID);
+    }
+    let mut out = Vec::with_capacity(len);
+    let mut i = 0;
+    while i < input.len() {
+        let ctrl = input[i] as usize;
+        i += 1;
+        if ctrl < 32 {
+            // a run of literal bytes
+            let literal = input.get(i..i + ctrl + 1).ok_or(INVALID)?;
+            out.extend_from_slice(literal);
+            i += ctrl + 1;
+        } else {
+            // a back reference into the output
+            let mut run = ctrl >> 5;
+            if run == 7 {
+                run += *input.get(i).ok_or(INVALID)? as usize;
+                i += 1;
+            }
+            let offset = ((ctrl & 0x1f) << 8) + *input.get(i).ok_or(INVALID)? as usize + 1;
+            i += 1;
+            let start = out.len().checked_sub(offset).ok_or(INVALID)?;
+            for k in 0..run + 2 {
+                out.push(out[start + k]);
+            }
+        }
+        if out.len() > len {
+            return Err(INVALID);
+        }
+    }
+    if out.len() != len {
+        return Err(INVALID);
+    }
+    Ok(out)
+}
+
+/// A cursor over serialized data.
+struct Input<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+/// A length, or the special encoding of a string.
+enum Length {
+    Len(u64),
+    Encoded(u8),
+}
+
+impl<'a> Input<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Input { data, pos: 0 }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], RdbError> {
+        let end = self.pos.checked_add(len).ok_or(RdbError::Truncated)?;
+        let bytes = self.data.get(self.pos..end).ok_or(RdbError::Truncated)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], RdbError> {
+        Ok(self.bytes(N)?.try_into().unwrap())
+    }
+
+    fn byte(&mut self) -> Result<u8, RdbError> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    fn u64_le(&mut self) -> Result<u64, RdbError> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    fn length_or_encoding(&mut self) -> Result<Length, RdbError> {
+        let first = self.byte()?;
+        Ok(match first >> 6 {
+            0 => Length::Len((first & 0x3f) as u64),
+            1 => Length::Len((((first & 0x3f) as u64) << 8) | self.byte()? as u64),
+            2 if first == 0x80 => Length::Len(u32::from_be_bytes(self.array()?) as u64),
+            2 if first == 0x81 => Length::Len(u64::from_be_bytes(self.array()?)),
+            3 => Length::Encoded(first & 0x3f),
+            _ => return Err(RdbError::Invalid("unknown length encoding")),
+        })
+    }
+
+    fn length(&mut self) -> Result<u64, RdbError> {
+        match self.length_or_encoding()? {
+            Length::Len(len) => Ok(len),
+            Length::Encoded(_) => Err(RdbError::Invalid("expected a length")),
+        }
+    }
+
+    /// Reads a length which counts items stored in the remaining data, so
+    /// that corrupt lengths can not cause huge allocations.
+    fn count(&mut self) -> Result<usize, RdbError> {
+        self.count_of(1)
+    }
+
+    /// Reads a length which counts items of at least `min_size` bytes each
+    /// stored in the remaining data.
+    fn count_of(&mut self, min_size: u64) -> Result<usize, RdbError> {
+        let len = self.length()?;
+        if len.saturating_mul(min_size) > (self.data.len() - self.pos) as u64 {
+            return Err(RdbError::Truncated);
+        }
+        Ok(len as usize)
+    }
+
+    fn string(&mut self) -> Result<Vec<u8>, RdbError> {
+        match self.length_or_encoding()? {
+            Length::Len(len) => Ok(self.bytes(len as usize)?.to_vec()),
+            Length::Encoded(0) => Ok((self.byte()? as i8).to_string().into_bytes()),
+            Length::Encoded(1) => Ok(i16::from_le_bytes(self.array()?).to_string().into_bytes()),
+            Length::Encoded(2) => Ok(i32::from_le_bytes(self.array()?).to_string().into_bytes()),
+            Length::Encoded(3) => {
+                let compressed_len = self.length()? as usize;
+                let len = self.length()? as usize;
+                lzf_decompress(self.bytes(compressed_len)?, len)
+            }
+            Length::Encoded(_) => Err(RdbError::Invalid("unknown string encoding")),
+        }
+    }
+
+    /// Reads a score in the textual form used by the original sorted set
+    /// encoding.
+    fn text_double(&mut self) -> Result<f64, RdbError> {
+        match self.byte()? {
+            253 => Ok(f64::NAN),
+            254 => Ok(f64::INFINITY),
+            255 => Ok(f64::NEG_INFINITY),
+            len => parse_double(self.bytes(len as usize)?),
+        }
+    }
+
+    fn stream_id(&mut self) -> Result<StreamId, RdbError> {
+        Ok(StreamId {
+            ms: self.length()?,
+            seq: self.length()?,
+        })
+    }
+
+    fn raw_stream_id(&mut self) -> Result<StreamId, RdbError> {
+        Ok(StreamId {
+            ms: u64::from_be_bytes(self.array()?),
+            seq: u64::from_be_bytes(self.array()?),
+        })
+    }
+}
+
+fn parse_double(val: &[u8]) -> Result<f64, RdbError> {
+    std::str::from_utf8(val)
+        .ok()
+        .and_then(|val| val.parse().ok())
+        .ok_or(RdbError::Invalid("invalid score"))
+}
+
+/// Decodes the elements of a listpack.
+fn decode_listpack(data: &[u8]) -> Result<Vec<Element>, RdbError> {
+    let mut input = Input::new(data);
+    let total = u32::from_le_bytes(input.array()?);
+    if total as usize != data.len() {
+        return Err(RdbError::Invalid("listpack size mismatch"));
+    }
+    input.bytes(2)?;
+    let mut elements = Vec::new();
+    loop {
+        let start = input.pos;
+        let first = input.byte()?;
+        let element = match first {
+            0xff => break,
+            0x00..=0x7f => Element::Int(first as i64),
+            0x80..=0xbf => Element::Str(input.bytes((first & 0x3f) as usize)?.to_vec()),
+            0xc0..=0xdf => {
+                let val = (((first & 0x1f) as u16) << 8) | input.byte()? as u16;
+                // sign extend the 13 bit integer
+                Element::Int(((val << 3) as i16 >> 3) as i64)
+            }
+            0xe0..=0xef => {
+                let len = (((first & 0x0f) as usize) << 8) | input.byte()? as usize;
+                Element::Str(input.bytes(len)?.to_vec())
+            }
+            0xf0 => {
+                let len = u32::from_le_bytes(input.array()?) as usize;
+                Element::Str(input.bytes(len)?.to_vec())
+            }
+            0xf1 => Element::Int(i16::from_le_bytes(input.array()?) as i64),
+            0xf2 => {
+                let [a, b, c] = input.array()?;
+                Element::Int((i32::from_le_bytes([0, a, b, c]) >> 8) as i64)
+            }
+            0xf3 => Element::Int(i32::from_le_bytes(input.array()?) as i64),
+            0xf4 => Element::Int(i64::from_le_bytes(input.array()?)),
+            _ => return Err(RdbError::Invalid("unknown listpack encoding")),
+        };
+        let len = (input.pos - start) as u64;
+        input.bytes(backlen_size(len))?;
+        elements.push(element);
+    }
+    Ok(elements)
+}
+
+/// Decodes the elements of a ziplist, the encoding used before listpacks.
+fn decode_ziplist(data: &[u8]) -> Result<Vec<Element>, RdbError> {
+    let mut input = Input::new(data);
+    let total = u32::from_le_bytes(input.array()?);
+    if total as usize != data.len() {
+        return Err(RdbError::Invalid("ziplist size mismatch"));
+    }
+    input.bytes(6)?;
+    let mut elements = Vec::new();
+    loop {
+        // the length of the previous entry
+        match input.byte()? {
+            0xff => break,
+            0xfe => {
+                input.bytes(4)?;
+            }
+            _ => {}
+        }
+        let first = input.byte()?;
+        let element = match first {
+            0x00..=0x3f => Element::Str(input.bytes(first as usize)?.to_vec()),
+            0x40..=0x7f => {
+                let len = (((first & 0x3f) as usize) << 8) | input.byte()? as usize;
+                Element::Str(input.bytes(len)?.to_vec())
+            }
+            0x80 => {
+                let len = u32::from_be_bytes(input.array()?) as usize;
+                Element::Str(input.bytes(len)?.to_vec())
+            }
+            0xc0 => Element::Int(i16::from_le_bytes(input.array()?) as i64),
+            0xd0 => Element::Int(i32::from_le_bytes(input.array()?) as i64),
+            0xe0 => Element::Int(i64::from_le_bytes(input.array()?)),
+            0xf0 => {
+                let [a, b, c] = input.array()?;
+                Element::Int((i32::from_le_bytes([0, a, b, c]) >> 8) as i64)
+            }
+            0xfe => Element::Int(input.byte()? as i8 as i64),
+            0xf1..=0xfd => Element::Int((first & 0x0f) as i64 - 1),
+            _ => return Err(RdbError::Invalid("unknown ziplist encoding")),
+        };
+        elements.push(element);
+    }
+    Ok(elements)
+}
+
+fn decode_intset(data: &[u8]) -> Result<Vec<Vec<u8>>, RdbError> {
+    let mut input = Input::new(data);
+    let width = u32::from_le_bytes(input.array()?) as usize;
+    let len = u32::from_le_bytes(input.array()?) as usize;
+    (0..len)
+        .map(|_| {
+            let int = match width {
+                2 => i16::from_le_bytes(input.array()?) as i64,
+                4 => i32::from_le_bytes(input.array()?) as i64,
+                8 => i64::from_le_bytes(input.array()?),
+                _ => return Err(RdbError::Invalid("unknown intset encoding")),
+            };
+            Ok(int.to_string().into_bytes())
+        })
+        .collect()
+}
+
+/// Splits a flat list of elements into pairs.
+fn pairs(elements: Vec<Element>) -> Result<Vec<(Vec<u8>, Element)>, RdbError> {
+    if !elements.len().is_multiple_of(2) {
+        return Err(RdbError::Invalid("odd number of elements"));
+    }
+    let mut elements = elements.into_iter();
+    let mut pairs = Vec::new();
+    while let (Some(a), Some(b)) = (elements.next(), elements.next()) {
+        pairs.push((a.into_bytes(), b));
+    }
+    Ok(pairs)
+}
+
+fn sorted_set_pairs(elements: Vec<Element>) -> Result<RdbValue, RdbError> {
+    let members = pairs(elements)?
+        .into_iter()
+        .map(|(member, score)| {
+            let score = match score {
+                Element::Int(int) => int as f64,
+                Element::Str(val) => parse_double(&val)?,
+            };
+            Ok((member, score))
+        })
+        .collect::<Result<_, RdbError>>()?;
+    Ok(RdbValue::SortedSet(members))
+}
+
+fn hash_pairs(elements: Vec<Element>) -> Result<RdbValue, RdbError> {
+    Ok(RdbValue::Hash(
+        pairs(elements)?
+            .into_iter()
+            .map(|(field, val)| (field, val.into_bytes()))
+            .collect(),
+    ))
+}
+
+fn read_value(input: &mut Input, value_type: u8) -> Result<RdbValue, RdbError> {
+    let strings = |input: &mut Input| -> Result<Vec<Vec<u8>>, RdbError> {
+        let len = input.count()?;
+        (0..len).map(|_| input.string()).collect()
+    };
+    let elements = |val: Vec<Element>| val.into_iter().map(Element::into_bytes).collect();
+    Ok(match value_type {
+        TYPE_STRING => RdbValue::String(input.string()?),
+        TYPE_LIST => RdbValue::List(strings(input)?),
+        TYPE_SET => RdbValue::Set(strings(input)?),
+        TYPE_ZSET | TYPE_ZSET_2 => {
+            // a member and a score take at least two bytes, or nine with a
+            // binary score
+            let len = input.count_of(if value_type == TYPE_ZSET_2 { 9 } else { 2 })?;
+            let mut members = Vec::with_capacity(len);
+            for _ in 0..len {
+                let member = input.string()?;
+                let score = if value_type == TYPE_ZSET_2 {
+                    f64::from_le_bytes(input.array()?)
+                } else {
+                    input.text_double()?
+                };
+                members.push((member, score));
+            }
+            RdbValue::SortedSet(members)
+        }
+        TYPE_HASH => {
+            let len = input.count_of(2)?;
+            let mut fields = Vec::with_capacity(len);
+            for _ in 0..len {
+                fields.push((input.string()?, input.string()?));
+            }
+            RdbValue::Hash(fields)
+        }
+        TYPE_LIST_ZIPLIST => RdbValue::List(elements(decode_ziplist(&input.string()?)?)),
+        TYPE_SET_INTSET => RdbValue::Set(decode_intset(&input.string()?)?),
+        TYPE_SET_LISTPACK => RdbValue::Set(elements(decode_listpack(&input.string()?)?)),
+        TYPE_ZSET_ZIPLIST => sorted_set_pairs(decode_ziplist(&input.string()?)?)?,
+        TYPE_ZSET_LISTPACK => sorted_set_pairs(decode_listpack(&input.string()?)?)?,
+        TYPE_HASH_ZIPLIST => hash_pairs(decode_ziplist(&input.string()?)?)?,
+        TYPE_HASH_LISTPACK => hash_pairs(decode_listpack(&input.string()?)?)?,
+        TYPE_LIST_QUICKLIST | TYPE_LIST_QUICKLIST_2 => {
+            let nodes = input.count()?;
+            let mut items = Vec::new();
+            for _ in 0..nodes {
+                if value_type == TYPE_LIST_QUICKLIST {
+                    items.extend(elements(decode_ziplist(&input.string()?)?));
+                    continue;
+                }
+                // nodes are either a single plain element or a listpack
+                match input.length()? {
+                    1 => items.push(input.string()?),
+                    2 => items.extend(elements(decode_listpack(&input.string()?)?)),
+                    _ => return Err(RdbError::Invalid("unknown quicklist container")),
+                }
+            }
+            RdbValue::List(items)
+        }
+        TYPE_STREAM_LISTPACKS | TYPE_STREAM_LISTPACKS_2 | TYPE_STREAM_LISTPACKS_3 => {
+            RdbValue::Stream(read_stream(input, value_type)?)
+        }
+        _ => return Err(RdbError::UnsupportedType(value_type)),
+    })
+}
+
+fn read_stream(input: &mut Input, value_type: u8) -> Result<Stream, RdbError> {
+    let mut stream = Stream::default();
+    let nodes = input.count()?;
+    for _ in 0..nodes {
+        let key = input.string()?;
+        let master_id = Input::new(&key).raw_stream_id()?;
+        let mut elements = decode_listpack(&input.string()?)?.into_iter();
+        let mut next = || {
+            elements
+                .next()
+                .ok_or(RdbError::Invalid("truncated stream listpack"))
+        };
+        let count = next()?.to_int()?;
+        let deleted = next()?.to_int()?;
+        let master_fields = (0..next()?.to_int()?)
+            .map(|_| next().map(Element::into_bytes))
+            .collect::<Result<Vec<_>, _>>()?;
+        // the terminator of the master entry
+        next()?;
+        for _ in 0..count + deleted {
+            let flags = next()?.to_int()?;
+            let id = StreamId {
+                ms: master_id.ms.wrapping_add(next()?.to_int()? as u64),
+                seq: master_id.seq.wrapping_add(next()?.to_int()? as u64),
+            };
+            let mut fields = Vec::new();
+            if flags & STREAM_ITEM_FLAG_SAMEFIELDS != 0 {
+                for field in &master_fields {
+                    fields.push((field.clone(), next()?.into_bytes()));
+                }
+            } else {
+                for _ in 0..next()?.to_int()? {
+                    fields.push((next()?.into_bytes(), next()?.into_bytes()));
+                }
+            }
+            // the number of elements of the entry
+            next()?;
+            if flags & STREAM_ITEM_FLAG_DELETED == 0 {
+                stream.entries.push((id, fields));
+            }
+        }
+    }
+    let len = input.length()?;
+    stream.last_id = input.stream_id()?;
+    if value_type >= TYPE_STREAM_LISTPACKS_2 {
+        // the first id is implied by the entries
+        input.stream_id()?;
+        stream.max_deleted_id = input.stream_id()?;
+        stream.entries_added = input.length()?;
+    } else {
+        stream.entries_added = len;
+    }
+
+    let groups = input.count()?;
+    for _ in 0..groups {
+        let name = input.string()?;
+        let last_id = input.stream_id()?;
+        let entries_read = if value_type >= TYPE_STREAM_LISTPACKS_2 {
+            Some(input.length()?).filter(|read| *read != u64::MAX)
+        } else {
+            None
+        };
+        // an id, a delivery time and a delivery count
+        let pending_len = input.count_of(25)?;
+        let mut pending = Vec::with_capacity(pending_len);
+        for _ in 0..pending_len {
+            pending.push(StreamPendingEntry {
+                id: input.raw_stream_id()?,
+                consumer: Vec::new(),
+                delivery_time: input.u64_le()?,
+                delivery_count: input.length()?,
+            });
+        }
+        // a name, a seen time and the length of its pending entries
+        let consumers_len = input.count_of(10)?;
+        let mut consumers = Vec::with_capacity(consumers_len);
+        for _ in 0..consumers_len {
+            let name = input.string()?;
+            let seen_time = input.u64_le()?;
+            let active_time = if value_type >= TYPE_STREAM_LISTPACKS_3 {
+                Some(input.u64_le()?).filter(|time| *time as i64 != -1)
+            } else {
+                Some(seen_time)
+            };
+            // the entries pending for this consumer
+            for _ in 0..input.count()? {
+                let id = input.raw_stream_id()?;
+                let entry = pending
+                    .iter_mut()
+                    .find(|entry| entry.id == id)
+                    .ok_or(RdbError::Invalid("consumer entry missing from group"))?;
+                entry.consumer = name.clone();
+            }
+            consumers.push(StreamConsumer {
+                name,
+                seen_time,
+                active_time,
+            });
+        }
+        stream.groups.push(StreamGroup {
+            name,
+            last_id,
+            entries_read,
+            pending,
+            consumers,
+        });
+    }
+    Ok(stream)
+}
+
+/// Reads the records of a snapshot held in memory.
+pub struct RdbReader<'a> {
+    input: Input<'a>,
+    version: u32,
+    done: bool,
+}
+
+impl<'a> RdbReader<'a> {
+    /// Checks the header of the snapshot in `data`.
+    pub fn new(data: &'a [u8]) -> Result<Self, RdbError> {
+        let mut input = Input::new(data);
+        let header = input.bytes(9).map_err(|_| RdbError::InvalidHeader)?;
+        if &header[..5] != b"REDIS" {
+            return Err(RdbError::InvalidHeader);
+        }
+        let version = std::str::from_utf8(&header[5..])
+            .ok()
+            .and_then(|version| version.parse().ok())
+            .ok_or(RdbError::InvalidHeader)?;
+        if !(1..=MAX_RDB_VERSION).contains(&version) {
+            return Err(RdbError::UnsupportedVersion(version));
+        }
+        Ok(RdbReader {
+            input,
+            version,
+            done: false,
+        })
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Returns the number of bytes read so far, which is the length of the
+    /// snapshot once its end has been reached.
+    pub fn position(&self) -> usize {
+        self.input.pos
+    }
+
+    /// Reads the next record, returning `None` once the end of the snapshot
+    /// has been reached and its checksum has been verified.
+    pub fn next_record(&mut self) -> Result<Option<Record>, RdbError> {
+        if self.done {
+            return Ok(None);
+        }
+        let input = &mut self.input;
+        let mut expire_ms = None;
+        loop {
+            let opcode = input.byte()?;
+            match opcode {
+                OPCODE_AUX => {
+                    return Ok(Some(Record::Aux {
+                        key: input.string()?,
+                        value: input.string()?,
+                    }))
+                }
+                OPCODE_SELECTDB => return Ok(Some(Record::SelectDb(input.length()?))),
+                OPCODE_RESIZEDB => {
+                    return Ok(Some(Record::ResizeDb {
+                        size: input.length()?,
+                        expires: input.length()?,
+                    }))
+                }
+                OPCODE_EXPIRETIME_MS => expire_ms = Some(input.u64_le()?),
+                OPCODE_EXPIRETIME => {
+                    let secs = u32::from_le_bytes(input.array()?);
+                    expire_ms = Some(secs as u64 * 1000);
+                }
+                // eviction information is not used
+                OPCODE_IDLE => {
+                    input.length()?;
+                }
+                OPCODE_FREQ => {
+                    input.byte()?;
+                }
+                OPCODE_SLOT_INFO => {
+                    for _ in 0..3 {
+                        input.length()?;
+                    }
+                }
+                // there are no functions, so their code is skipped
+                OPCODE_FUNCTION2 => {
+                    input.string()?;
+                }
+                OPCODE_EOF => {
+                    self.done = true;
+                    if self.version >= 5 {
+                        let end = input.pos;
+                        let expected = input.u64_le()?;
+                        // a zero checksum means checksums were disabled
+                        if expected != 0 && crc64(0, &input.data[..end]) != expected {
+                            return Err(RdbError::ChecksumMismatch);
+                        }
+                    }
+                    return Ok(None);
+                }
+                value_type => {
+                    let key = input.string()?;
+                    let value = read_value(input, value_type)?;
+                    return Ok(Some(Record::Entry(Entry {
+                        key,
+                        value,
+                        expire_ms,
+                    })));
+                }
+            }
+        }
+    }
+}
+
+/// Encodes `value` as a `DUMP` payload: the value in the RDB encoding,
+/// followed by the RDB version and a checksum of everything before it.
+pub fn encode_dump(value: &RdbValue) -> Vec<u8> {
+    let mut out = vec![0];
+    out[0] = write_value(&mut out, value);
+    out.extend_from_slice(&(RDB_VERSION as u16).to_le_bytes());
+    let crc = crc64(0, &out);
+    out.extend_from_slice(&crc.to_le_bytes());
+    out
+}
+
+/// Decodes a `DUMP` payload, checking its version and checksum.
+pub fn decode_dump(payload: &[u8]) -> Result<RdbValue, RdbError> {
+    let Some(body_len) = payload.len().checked_sub(10) else {
+        return Err(RdbError::Truncated);
+    };
+    let (body, footer) = payload.split_at(body_len);
+    let version = u16::from_le_bytes([footer[0], footer[1]]) as u32;
+    if version > MAX_RDB_VERSION {
+        return Err(RdbError::UnsupportedVersion(version));
+    }
+    let expected = u64::from_le_bytes(footer[2..].try_into().unwrap());
+    if crc64(0, &payload[..body_len + 2]) != expected {
+        return Err(RdbError::ChecksumMismatch);
+    }
+    let mut input = Input::new(body);
+    let value_type = input.byte()?;
+    let value = read_value(&mut input, value_type)?;
+    if input.pos != body.len() {
+        return Err(RdbError::Invalid("trailing data after the value"));
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_crc64() {
+        assert_eq!(crc64(0, b"123456789"), 0xe9c6d914c4b8d9ca);
+    }
+
+    #[test]
+    pub fn test_lzf_decompress() {
+        // the literal "abc" followed by a back reference of six bytes
+        let compressed = [2, b'a', b'b', b'c', 0x80, 2];
+        assert_eq!(lzf_decompress(&compressed, 9).unwrap(), b"abcabcabc");
+        assert!(lzf_decompress(&compressed, 8).is_err());
+        // a length no LZF data of that size can expand to
+        assert!(lzf_decompress(&compressed, usize::MAX).is_err());
+        assert!(lzf_decompress(&compressed, 6 * LZF_MAX_EXPANSION + 1).is_err());
+    }
+
+    #[test]
+    pub fn test_listpack() {
+        let elements = vec![
+            Element::Int(0),
+            Element::Int(127),
+            Element::Int(-4096),
+            Element::Int(4095),
+            Element::Int(-30000),
+            Element::Int(8388607),
+            Element::Int(-2147483648),
+            Element::Int(i64::MIN),
+            Element::Str(b"short".to_vec()),
+            Element::Str(vec![b'x'; 200]),
+            Element::Str(vec![b'y'; 5000]),
+        ];
+        assert_eq!(
+            decode_listpack(&encode_listpack(&elements)).unwrap(),
+            elements
+        );
+    }
+
+    #[test]
+    pub fn test_dump() {
+        let value = RdbValue::Hash(vec![(b"field".to_vec(), b"value".to_vec())]);
+        let payload = encode_dump(&value);
+        assert_eq!(decode_dump(&payload), Ok(value));
+        // the example from the redis documentation of DUMP
+        let redis = b"\x00\xc0\n\n\x00n\x9fWE\x0e\xaec\xbb";
+        assert_eq!(decode_dump(redis), Ok(RdbValue::String(b"10".to_vec())));
+
+        let mut corrupted = payload.clone();
+        corrupted[1] ^= 1;
+        assert_eq!(decode_dump(&corrupted), Err(RdbError::ChecksumMismatch));
+        assert_eq!(decode_dump(&payload[1..]), Err(RdbError::ChecksumMismatch));
+        assert_eq!(decode_dump(b"\x00"), Err(RdbError::Truncated));
+
+        // huge lengths in otherwise valid payloads are rejected
+        let with_footer = |body: &[u8]| {
+            let mut payload = body.to_vec();
+            payload.extend_from_slice(&(RDB_VERSION as u16).to_le_bytes());
+            let crc = crc64(0, &payload);
+            payload.extend_from_slice(&crc.to_le_bytes());
+            payload
+        };
+        // an LZF string of 6 compressed bytes claiming 2^62 bytes
+        let mut lzf = vec![TYPE_STRING, 0xc3, 6, 0x81];
+        lzf.extend_from_slice(&(1u64 << 62).to_be_bytes());
+        lzf.extend_from_slice(&[2, b'a', b'b', b'c', 0x80, 2]);
+        assert!(decode_dump(&with_footer(&lzf)).is_err());
+        // a hash and a sorted set claiming more fields than there are bytes
+        assert!(decode_dump(&with_footer(&[TYPE_HASH, 2, 1, b'a'])).is_err());
+        assert!(decode_dump(&with_footer(&[TYPE_ZSET_2, 1, 1, b'a', 0])).is_err());
+    }
+
+    #[test]
+    pub fn test_round_trip() {
+        let stream = Stream {
+            entries: vec![
+                (
+                    StreamId { ms: 1, seq: 0 },
+                    vec![(b"a".to_vec(), b"1".to_vec())],
+                ),
+                (
+                    StreamId { ms: 2, seq: 5 },
+                    vec![(b"b".to_vec(), b"x".to_vec())],
+                ),
+            ],
+            last_id: StreamId { ms: 2, seq: 5 },
+            max_deleted_id: StreamId { ms: 1, seq: 1 },
+            entries_added: 3,
+            groups: vec![StreamGroup {
+                name: b"group".to_vec(),
+                last_id: StreamId { ms: 2, seq: 5 },
+                entries_read: Some(3),
+                pending: vec![StreamPendingEntry {
+                    id: StreamId { ms: 1, seq: 0 },
+                    consumer: b"alice".to_vec(),
+                    delivery_time: 1000,
+                    delivery_count: 2,
+                }],
+                consumers: vec![StreamConsumer {
+                    name: b"alice".to_vec(),
+                    seen_time: 1000,
+                    active_time: None,
+                }],
+            }],
+        };
+        let entries = vec![
+            Entry {
+                key: b"string".to_vec(),
+                value: RdbValue::String(b"\xffvalue".to_vec()),
+                expire_ms: Some(1234),
+            },
+            Entry {
+                key: b"int".to_vec(),
+                value: RdbValue::String(b"-123456".to_vec()),
+                expire_ms: None,
+            },
+            Entry {
+                key: b"list".to_vec(),
+                value: RdbValue::List(vec![b"a".to_vec(), b"1".to_vec()]),
+                expire_ms: None,
+            },
+            Entry {
+                key: b"zset".to_vec(),
+                value: RdbValue::SortedSet(vec![(b"m".to_vec(), 1.5)]),
+                expire_ms: None,
+            },
+            Entry {
+                key: b"stream".to_vec(),
+                value: RdbValue::Stream(stream),
+                expire_ms: None,
+            },
+        ];
+        let mut writer = RdbWriter::new(Vec::new()).unwrap();
+        writer.write_aux("redis-bits", b"64").unwrap();
+        writer.select_db(0, entries.len() as u64, 1).unwrap();
+        for entry in &entries {
+            writer.write_entry(entry).unwrap();
+        }
+        let data = writer.finish().unwrap();
+
+        let mut reader = RdbReader::new(&data).unwrap();
+        assert_eq!(reader.version(), RDB_VERSION);
+        let mut records = Vec::new();
+        while let Some(record) = reader.next_record().unwrap() {
+            records.push(record);
+        }
+        assert_eq!(records.len(), entries.len() + 3);
+        for (record, entry) in records[3..].iter().zip(entries) {
+            assert_eq!(record, &Record::Entry(entry));
+        }
+
+        let mut corrupt = data.clone();
+        corrupt[20] ^= 1;
+        let mut reader = RdbReader::new(&corrupt).unwrap();
+        assert!(std::iter::from_fn(|| reader.next_record().transpose()).any(|res| res.is_err()));
+    }
+}
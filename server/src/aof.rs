@@ -0,0 +1,765 @@
+//! Persistence through an append-only file of write commands.
+//!
+//! Every command which modifies the dataset is appended in RESP form, and
+//! the files are replayed when the server starts. Commands whose effect
+//! depends on when they run are logged in a form which replays the same way,
+//! for example relative expiry times become absolute `PEXPIREAT`s.
+//!
+//! The AOF is made up of several files in its own directory, listed by a
+//! manifest: a base file holding a snapshot, and incremental files of the
+//! commands executed since. `BGREWRITEAOF` starts a new incremental file and
+//! writes a new base in the background. Once it is written, the manifest is
+//! replaced in one step, so a crash at any point leaves a complete AOF.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use feredis_core::aof::{AofError, AofFile, AofFileType, AofReader, Manifest};
+use feredis_core::item::RedisItem;
+use smol::Timer;
+
+use crate::{expire, rdb, RedisError, State};
+
+/// When the file is synced to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// after every write, before replying
+    Always,
+    /// once per second, in the background
+    EverySec,
+    /// never, leaving it to the operating system
+    No,
+}
+
+impl FsyncPolicy {
+    pub fn parse(val: &str) -> Option<Self> {
+        match val.to_ascii_lowercase().as_str() {
+            "always" => Some(FsyncPolicy::Always),
+            "everysec" => Some(FsyncPolicy::EverySec),
+            "no" => Some(FsyncPolicy::No),
+            _ => None,
+        }
+    }
+}
+
+/// A rewrite running in the background.
+#[derive(Debug)]
+struct Rewrite {
+    child: JoinHandle<io::Result<()>>,
+    temp_path: PathBuf,
+    /// the number of incremental files the new base replaces
+    replaced: usize,
+}
+
+#[derive(Debug)]
+pub struct Aof {
+    /// the directory holding the files and the manifest
+    dir: PathBuf,
+    /// the prefix of the file names
+    filename: String,
+    /// the single file used before multi-part AOFs, which is upgraded on
+    /// startup
+    legacy_path: PathBuf,
+    fsync: FsyncPolicy,
+    manifest: Manifest,
+    /// the incremental file commands are appended to, or `None` if the AOF
+    /// is disabled
+    file: Option<File>,
+    /// the size of the file, up to which it is known to be valid
+    size: u64,
+    /// commands which have not been written to the file yet
+    buf: Vec<u8>,
+    /// the database of the last command appended, as `SELECT` is only
+    /// logged when it changes
+    db: Option<usize>,
+    /// whether data was written since the last fsync
+    unsynced: bool,
+    last_fsync: Instant,
+    /// the thread of a running `everysec` fsync
+    fsync_child: Option<JoinHandle<io::Result<()>>>,
+    rewrite: Option<Rewrite>,
+}
+
+impl Default for Aof {
+    fn default() -> Self {
+        Self::new(
+            Path::new("."),
+            "appendonlydir",
+            "appendonly.aof",
+            FsyncPolicy::EverySec,
+        )
+    }
+}
+
+impl Aof {
+    pub fn new(dir: &Path, dirname: &str, filename: &str, fsync: FsyncPolicy) -> Self {
+        Self {
+            dir: dir.join(dirname),
+            filename: filename.to_string(),
+            legacy_path: dir.join(filename),
+            fsync,
+            manifest: Manifest::default(),
+            file: None,
+            size: 0,
+            buf: Vec::new(),
+            db: None,
+            unsynced: false,
+            last_fsync: Instant::now(),
+            fsync_child: None,
+            rewrite: None,
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.file.is_some()
+    }
+
+    fn manifest_path(&self) -> PathBuf {
+        self.dir.join(format!("{}.manifest", self.filename))
+    }
+
+    fn temp_path(&self) -> PathBuf {
+        self.dir
+            .join(format!("temp-rewriteaof-bg-{}.aof", std::process::id()))
+    }
+
+    /// Replaces the manifest on disk with `manifest`, which is then in effect.
+    fn write_manifest(&mut self, manifest: Manifest) -> io::Result<()> {
+        let path = self.manifest_path();
+        let temp_path = self.dir.join(format!("temp-{}.manifest", self.filename));
+        let mut file = File::create(&temp_path)?;
+        file.write_all(manifest.to_string().as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&temp_path, path)?;
+        // make the rename itself durable
+        File::open(&self.dir)?.sync_all()?;
+        self.manifest = manifest;
+        Ok(())
+    }
+
+    /// Opens the file for appending, which enables the AOF.
+    fn open(&mut self, name: &str) -> io::Result<()> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dir.join(name))?;
+        self.size = file.metadata()?.len();
+        self.file = Some(file);
+        // each file is replayed starting in database 0
+        self.db = None;
+        Ok(())
+    }
+
+    /// Starts a new incremental file and appends to it from now on.
+    fn open_new_incr(&mut self) -> io::Result<()> {
+        // pending commands belong to the previous file
+        self.flush();
+        let seq = self.manifest.incrs.last().map_or(1, |last| last.seq + 1);
+        let name = format!("{}.{}.incr.aof", self.filename, seq);
+        File::create(self.dir.join(&name))?.sync_all()?;
+        let mut manifest = self.manifest.clone();
+        manifest.incrs.push(AofFile {
+            name: name.clone(),
+            seq,
+            file_type: AofFileType::Incr,
+        });
+        self.write_manifest(manifest)?;
+        self.open(&name)
+    }
+
+    /// Appends a command executed in database `db`.
+    pub fn append(&mut self, db: usize, args: Vec<RedisItem>) {
+        if self.file.is_none() {
+            return;
+        }
+        if self.db != Some(db) {
+            let select = vec![
+                RedisItem::BulkString("select".to_string()),
+                RedisItem::BulkString(db.to_string()),
+            ];
+            RedisItem::Array(select).serialize(&mut self.buf);
+            self.db = Some(db);
+        }
+        RedisItem::Array(args).serialize(&mut self.buf);
+    }
+
+    /// Writes the appended commands to the file, syncing it to disk if the
+    /// policy is `always`.
+    pub fn flush(&mut self) {
+        let Some(file) = &mut self.file else {
+            return;
+        };
+        if self.buf.is_empty() {
+            return;
+        }
+        if let Err(err) = file.write_all(&self.buf) {
+            // drop a partial write, so that it is retried cleanly
+            println!("Error writing to the AOF: {}", err);
+            let _ = file.set_len(self.size);
+            return;
+        }
+        self.size += self.buf.len() as u64;
+        self.buf.clear();
+        if self.fsync == FsyncPolicy::Always {
+            if let Err(err) = file.sync_data() {
+                println!("Error syncing the AOF: {}", err);
+            }
+        } else {
+            self.unsynced = true;
+        }
+    }
+
+    /// Syncs the file on a background thread once a second, if the policy
+    /// is `everysec`.
+    fn fsync_in_background(&mut self) {
+        if let Some(child) = &self.fsync_child {
+            if !child.is_finished() {
+                return;
+            }
+            if let Ok(Err(err)) = self.fsync_child.take().unwrap().join() {
+                println!("Error syncing the AOF: {}", err);
+            }
+        }
+        if self.fsync != FsyncPolicy::EverySec
+            || !self.unsynced
+            || self.last_fsync.elapsed() < Duration::from_secs(1)
+        {
+            return;
+        }
+        let Some(file) = &self.file else {
+            return;
+        };
+        match file.try_clone() {
+            Ok(file) => {
+                self.fsync_child = Some(std::thread::spawn(move || file.sync_data()));
+                self.unsynced = false;
+                self.last_fsync = Instant::now();
+            }
+            Err(err) => println!("Error syncing the AOF: {}", err),
+        }
+    }
+
+    /// Makes the snapshot at `temp_path` the new base, replacing the old base
+    /// and the first `replaced` incremental files.
+    fn install_base(&mut self, temp_path: &Path, replaced: usize) -> io::Result<()> {
+        let seq = self.manifest.base.as_ref().map_or(1, |base| base.seq + 1);
+        let name = format!("{}.{}.base.rdb", self.filename, seq);
+        fs::rename(temp_path, self.dir.join(&name))?;
+        let mut manifest = self.manifest.clone();
+        let mut old: Vec<AofFile> = manifest.incrs.drain(..replaced).collect();
+        old.extend(manifest.base.replace(AofFile {
+            name,
+            seq,
+            file_type: AofFileType::Base,
+        }));
+        old.append(&mut manifest.history);
+        self.write_manifest(manifest)?;
+        for file in old {
+            let _ = fs::remove_file(self.dir.join(file.name));
+        }
+        Ok(())
+    }
+
+    /// Completes a rewrite whose snapshot has been written.
+    fn finish_rewrite(&mut self, rewrite: Rewrite) -> io::Result<()> {
+        rewrite
+            .child
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::other("AOF rewrite panicked")))?;
+        self.install_base(&rewrite.temp_path, rewrite.replaced)
+    }
+}
+
+/// Returns whether a write command which was executed with `args` and
+/// replied with `res` left the dataset as it was, so there is nothing to log.
+fn is_noop(command: &str, args: &[RedisItem], res: &RedisItem) -> bool {
+    use RedisItem::*;
+    // whether one of `flags` is among the arguments from `start` on
+    let has_flag = |start: usize, flags: &[&str]| {
+        args.iter().skip(start).any(|arg| match arg {
+            BulkString(arg) => flags.iter().any(|flag| arg.eq_ignore_ascii_case(flag)),
+            _ => false,
+        })
+    };
+    match command {
+        "del" | "unlink" | "setnx" | "msetnx" | "pexpireat" | "persist" | "renamenx" | "copy"
+        | "move" | "lpushx" | "rpushx" | "lrem" | "hsetnx" | "hdel" | "sadd" | "srem" | "smove"
+        | "zrem" | "zremrangebyrank" | "zremrangebyscore" | "zremrangebylex" | "pfadd" | "xdel"
+        | "xtrim" | "xack" => *res == Integer(0),
+        "linsert" => matches!(res, Integer(0 | -1)),
+        "lpop" | "rpop" | "lmpop" | "lmove" | "rpoplpush" | "zpopmin" | "zpopmax" | "zmpop"
+        | "getdel" => matches!(res, Null) || matches!(res, Array(items) if items.is_empty()),
+        // with GET, the old value is returned whether or not it was replaced
+        "set" => *res == Null && !has_flag(2, &["get"]),
+        "getex" => args.len() == 1,
+        "xgroup" => {
+            let counted = match args.first() {
+                Some(BulkString(sub)) => ["destroy", "createconsumer"]
+                    .iter()
+                    .any(|name| sub.eq_ignore_ascii_case(name)),
+                _ => false,
+            };
+            counted && *res == Integer(0)
+        }
+        "bitfield" => !has_flag(1, &["set", "incrby"]),
+        // without STORE, these only read
+        "sort" => !sort_stores(args),
+        "georadius" => !has_flag(5, &["store", "storedist"]),
+        "georadiusbymember" => !has_flag(4, &["store", "storedist"]),
+        _ => false,
+    }
+}
+
+/// Returns whether the `SORT` arguments `args` include `STORE`, skipping the
+/// patterns, which may be called anything.
+fn sort_stores(args: &[RedisItem]) -> bool {
+    let mut i = 1;
+    while let Some(RedisItem::BulkString(arg)) = args.get(i) {
+        match arg.to_ascii_lowercase().as_str() {
+            "by" | "get" => i += 2,
+            "limit" => i += 3,
+            "store" => return true,
+            _ => i += 1,
+        }
+    }
+    false
+}
+
+/// Returns the commands to append for a write command which was executed
+/// with `args` and replied with `res`. Commands whose effect depends on when
+/// they run, or on chance, are turned into ones which replay the same way,
+/// and commands which changed nothing are left out.
+pub fn translate(
+    state: &State,
+    command: &str,
+    mut args: Vec<RedisItem>,
+    res: &RedisItem,
+) -> Vec<Vec<RedisItem>> {
+    use RedisItem::*;
+    let bulk = |val: &str| BulkString(val.to_string());
+    // the absolute expiry time of `key` once the command has run
+    let expire_at = |key: &RedisItem| {
+        let BulkString(key) = key else {
+            return None;
+        };
+        let (_, tag) = state.items.get(key)?;
+        let time = expire::to_unix_ms(state.expire.get_expiry(*tag)?);
+        Some(vec![
+            bulk("pexpireat"),
+            BulkString(key.clone()),
+            BulkString(time.to_string()),
+        ])
+    };
+    let has_flag = |args: &[RedisItem], flag: &str| {
+        args.iter()
+            .any(|arg| matches!(arg, BulkString(arg) if arg.eq_ignore_ascii_case(flag)))
+    };
+    if is_noop(command, &args, res) {
+        return Vec::new();
+    }
+    match command {
+        "expire" => return expire_at(&args[0]).into_iter().collect(),
+        "set" => {
+            // relative expiry times are logged as absolute ones
+            let relative = args.iter().skip(2).position(|arg| {
+                matches!(arg, BulkString(arg) if arg.eq_ignore_ascii_case("ex") || arg.eq_ignore_ascii_case("px"))
+            });
+            if let Some(i) = relative.map(|i| i + 2) {
+                // without an expiry, the key was not set
+                let Some(mut pexpireat) = expire_at(&args[0]) else {
+                    return Vec::new();
+                };
+                args[i] = bulk("pxat");
+                args[i + 1] = pexpireat.pop().unwrap();
+            }
+        }
+        "setex" | "psetex" => {
+            let set = vec![bulk("set"), args[0].clone(), args[2].clone()];
+            return [Some(set), expire_at(&args[0])]
+                .into_iter()
+                .flatten()
+                .collect();
+        }
+        "getex" => {
+            if has_flag(&args[1..], "persist") {
+                return vec![vec![bulk("persist"), args[0].clone()]];
+            }
+            return expire_at(&args[0]).into_iter().collect();
+        }
+        "restore" | "restore-asking" => {
+            // relative TTLs are logged as absolute ones
+            let BulkString(key) = &args[0] else {
+                return Vec::new();
+            };
+            if !state.items.contains_key(key) {
+                // the key had already expired
+                return vec![vec![bulk("del"), args[0].clone()]];
+            }
+            if let Some(mut pexpireat) = expire_at(&args[0]) {
+                args[1] = pexpireat.pop().unwrap();
+                if !has_flag(&args[3..], "absttl") {
+                    args.push(bulk("absttl"));
+                }
+            }
+        }
+        "spop" => {
+            // the randomly chosen members are removed explicitly
+            let members = match res {
+                Array(members) => members.clone(),
+                Null => Vec::new(),
+                member => vec![member.clone()],
+            };
+            if members.is_empty() {
+                return Vec::new();
+            }
+            let mut srem = vec![bulk("srem"), args[0].clone()];
+            srem.extend(members);
+            return vec![srem];
+        }
+        "xadd" => {
+            // generated ids are logged explicitly
+            if *res == Null {
+                return Vec::new();
+            }
+            let mut i = 1;
+            while let Some(BulkString(arg)) = args.get(i) {
+                match arg.to_ascii_lowercase().as_str() {
+                    "nomkstream" => i += 1,
+                    "maxlen" | "minid" => {
+                        i += 1;
+                        if matches!(args.get(i), Some(BulkString(op)) if op == "=" || op == "~") {
+                            i += 1;
+                        }
+                        i += 1;
+                    }
+                    "limit" => i += 2,
+                    _ => break,
+                }
+            }
+            if i < args.len() {
+                args[i] = res.clone();
+            }
+        }
+        "xclaim" | "xautoclaim" => {
+            // claiming depends on the idle time, so the claimed entries are
+            // logged explicitly along with the time they were claimed at
+            let claimed = match (command, res) {
+                ("xclaim", Array(claimed)) => claimed,
+                ("xautoclaim", Array(res)) => match res.get(1) {
+                    Some(Array(claimed)) => claimed,
+                    _ => return Vec::new(),
+                },
+                _ => return Vec::new(),
+            };
+            let ids: Vec<RedisItem> = claimed
+                .iter()
+                .filter_map(|entry| match entry {
+                    BulkString(id) => Some(BulkString(id.clone())),
+                    Array(entry) => entry.first().cloned(),
+                    _ => None,
+                })
+                .collect();
+            if ids.is_empty() {
+                return Vec::new();
+            }
+            let mut xclaim = vec![bulk("xclaim")];
+            xclaim.extend_from_slice(&args[..3]);
+            xclaim.push(bulk("0"));
+            xclaim.extend(ids);
+            let now = expire::to_unix_ms(Instant::now());
+            xclaim.extend([bulk("time"), BulkString(now.to_string())]);
+            if has_flag(&args[4..], "justid") {
+                xclaim.push(bulk("justid"));
+            }
+            return vec![xclaim];
+        }
+        _ => {}
+    }
+    let mut full = vec![bulk(command)];
+    full.extend(args);
+    vec![full]
+}
+
+/// Writes a new base from the dataset right away.
+fn rewrite_now(state: &mut State) -> io::Result<()> {
+    let temp_path = state.aof.temp_path();
+    let replaced = state.aof.manifest.incrs.len();
+    let res = rdb::write_file(&temp_path, &rdb::snapshot(state))
+        .and_then(|()| state.aof.install_base(&temp_path, replaced));
+    if res.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    res
+}
+
+/// Starts writing a new base on a background thread, while commands are
+/// appended to a new incremental file.
+fn start_rewrite(state: &mut State) -> io::Result<()> {
+    fs::create_dir_all(&state.aof.dir)?;
+    if state.aof.file.is_some() {
+        state.aof.open_new_incr()?;
+    }
+    // the new incremental file, if any, stays after the rewrite
+    let replaced = state.aof.manifest.incrs.len() - state.aof.file.is_some() as usize;
+    // copied on the event loop, like for BGSAVE
+    let snapshot = rdb::snapshot(state);
+    let temp_path = state.aof.temp_path();
+    let path = temp_path.clone();
+    let child = std::thread::spawn(move || rdb::write_file(&path, &snapshot));
+    state.aof.rewrite = Some(Rewrite {
+        child,
+        temp_path,
+        replaced,
+    });
+    println!("Background append only file rewriting started");
+    Ok(())
+}
+
+/// Replays the AOF and starts appending to it. Returns the number of
+/// commands replayed, or `None` if there was no AOF yet, in which case it is
+/// created from the RDB snapshot, if there is one.
+pub fn load_and_enable(state: &RefCell<State>) -> io::Result<Option<usize>> {
+    let (dir, manifest_path, legacy_path, filename) = {
+        let aof = &state.borrow().aof;
+        let manifest_path = aof.manifest_path();
+        (
+            aof.dir.clone(),
+            manifest_path,
+            aof.legacy_path.clone(),
+            aof.filename.clone(),
+        )
+    };
+    let replayed = match fs::read_to_string(&manifest_path) {
+        Ok(data) => {
+            let manifest = Manifest::parse(&data)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            state.borrow_mut().aof.manifest = manifest;
+            Some(replay_all(state)?)
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            fs::create_dir_all(&dir)?;
+            if legacy_path.exists() {
+                // a single file from before becomes the base
+                let name = format!("{}.1.base.aof", filename);
+                fs::rename(&legacy_path, dir.join(&name))?;
+                let manifest = Manifest {
+                    base: Some(AofFile {
+                        name,
+                        seq: 1,
+                        file_type: AofFileType::Base,
+                    }),
+                    ..Manifest::default()
+                };
+                state.borrow_mut().aof.write_manifest(manifest)?;
+                println!("Upgraded {} to a multi-part AOF", legacy_path.display());
+                Some(replay_all(state)?)
+            } else {
+                let mut state = state.borrow_mut();
+                if let Some(keys) = rdb::load(&mut state)? {
+                    println!("Loaded {} keys from the snapshot", keys);
+                }
+                rewrite_now(&mut state)?;
+                None
+            }
+        }
+        Err(err) => return Err(err),
+    };
+    let mut state = state.borrow_mut();
+    let aof = &mut state.aof;
+    // files replaced by a rewrite which were not deleted yet
+    for file in std::mem::take(&mut aof.manifest.history) {
+        let _ = fs::remove_file(aof.dir.join(file.name));
+    }
+    match aof.manifest.incrs.last() {
+        Some(last) => {
+            let name = last.name.clone();
+            aof.open(&name)?;
+        }
+        None => aof.open_new_incr()?,
+    }
+    Ok(replayed)
+}
+
+/// Replays the files listed in the manifest, returning the number of
+/// commands replayed.
+fn replay_all(state: &RefCell<State>) -> io::Result<usize> {
+    let (dir, files) = {
+        let aof = &state.borrow().aof;
+        let files: Vec<AofFile> = aof.manifest.files().cloned().collect();
+        (aof.dir.clone(), files)
+    };
+    let mut replayed = 0;
+    for (i, file) in files.iter().enumerate() {
+        let path = dir.join(&file.name);
+        let data = fs::read(&path)?;
+        replayed += replay(state, &data, &path, i + 1 == files.len())?;
+    }
+    Ok(replayed)
+}
+
+/// Replays a single file. Only the `last` file may end with an incomplete
+/// command, which is cut off.
+fn replay(state: &RefCell<State>, data: &[u8], path: &Path, last: bool) -> io::Result<usize> {
+    // base files may hold a snapshot, followed by commands
+    let mut offset = 0;
+    if data.starts_with(b"REDIS") {
+        (_, offset) = rdb::load_data(&mut state.borrow_mut(), data)?;
+    }
+    let mut reader = AofReader::new(&data[offset..]);
+    let mut db = 0;
+    let mut replayed = 0;
+    loop {
+        match reader.next_command() {
+            Ok(Some(args)) => {
+                let command = RedisItem::Array(args.into_iter().map(RedisItem::bulk).collect());
+                smol::block_on(crate::handle_command(command, state, &mut db));
+                replayed += 1;
+            }
+            Ok(None) => break,
+            Err(AofError::Truncated(_)) if last => {
+                // a write cut short by a crash is dropped
+                let valid = offset + reader.position();
+                println!(
+                    "{} ends with an incomplete command, truncating it to {} bytes",
+                    path.display(),
+                    valid
+                );
+                OpenOptions::new()
+                    .write(true)
+                    .open(path)?
+                    .set_len(valid as u64)?;
+                break;
+            }
+            Err(err) => {
+                let err = match err {
+                    AofError::Truncated(pos) => AofError::Truncated(offset + pos),
+                    AofError::Invalid(pos) => AofError::Invalid(offset + pos),
+                };
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}: {}", path.display(), err),
+                ));
+            }
+        }
+    }
+    Ok(replayed)
+}
+
+/// Periodically syncs the file and completes background rewrites.
+pub async fn aof_worker(state: &RefCell<State>) {
+    loop {
+        Timer::after(Duration::from_millis(100)).await;
+        let mut state = state.borrow_mut();
+        if state.stop {
+            break;
+        }
+        state.aof.flush();
+        state.aof.fsync_in_background();
+        if state
+            .aof
+            .rewrite
+            .as_ref()
+            .is_some_and(|rewrite| rewrite.child.is_finished())
+        {
+            let rewrite = state.aof.rewrite.take().unwrap();
+            let temp_path = rewrite.temp_path.clone();
+            match state.aof.finish_rewrite(rewrite) {
+                Ok(()) => println!("Background AOF rewrite finished successfully"),
+                Err(err) => {
+                    println!("Background AOF rewrite failed: {}", err);
+                    let _ = fs::remove_file(temp_path);
+                }
+            }
+        }
+    }
+}
+
+pub fn do_bgrewriteaof(_: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    let mut state = state.borrow_mut();
+    if state.aof.rewrite.is_some() {
+        return RedisError::Custom("ERR Background append only file rewriting already in progress")
+            .into();
+    }
+    if let Err(err) = start_rewrite(&mut state) {
+        return RedisItem::SimpleError(format!("ERR Can't start the AOF rewrite: {}", err));
+    }
+    RedisItem::SimpleString("Background append only file rewriting started".to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn bulks(args: &[&str]) -> Vec<RedisItem> {
+        args.iter()
+            .map(|arg| RedisItem::BulkString(arg.to_string()))
+            .collect()
+    }
+
+    #[test]
+    pub fn test_translate() {
+        let state = State::new(1);
+        let id = RedisItem::BulkString("5-0".to_string());
+        let args = bulks(&["s", "maxlen", "~", "10", "*", "f", "v"]);
+        assert_eq!(
+            translate(&state, "xadd", args, &id),
+            vec![bulks(&["xadd", "s", "maxlen", "~", "10", "5-0", "f", "v"])]
+        );
+        let popped = RedisItem::Array(bulks(&["a", "b"]));
+        assert_eq!(
+            translate(&state, "spop", bulks(&["set", "2"]), &popped),
+            vec![bulks(&["srem", "set", "a", "b"])]
+        );
+        let mut state = State::new(1);
+        let time = Instant::now() + Duration::from_secs(10);
+        let tag = state.next_tag();
+        state
+            .items
+            .insert("t".to_string(), (crate::value::Value::Integer(1), tag));
+        state.set_expiry("t", time);
+        let ok = RedisItem::SimpleString("OK".to_string());
+        let pxat = expire::to_unix_ms(time).to_string();
+        assert_eq!(
+            translate(&state, "set", bulks(&["t", "1", "EX", "10", "nx"]), &ok),
+            vec![bulks(&["set", "t", "1", "pxat", &pxat, "nx"])]
+        );
+        // an expiry on a key which does not exist is not logged
+        let res = RedisItem::Integer(0);
+        assert!(translate(&state, "expire", bulks(&["k", "10"]), &res).is_empty());
+    }
+
+    #[test]
+    pub fn test_noop_not_logged() {
+        let state = RefCell::new(State::new(1));
+        let run = |args: &[&str]| crate::run_command(&state, &mut 0, args);
+        run(&["rpush", "list", "b", "a"]);
+        run(&["sadd", "set", "a"]);
+        run(&["psetex", "str", "100000", "v"]);
+        let dirty = state.borrow().dirty;
+        for args in [
+            &["del", "missing"][..],
+            &["sadd", "set", "a"],
+            &["srem", "set", "b"],
+            &["lpop", "missing"],
+            &["lrem", "list", "0", "c"],
+            &["linsert", "list", "before", "c", "d"],
+            &["set", "list", "v", "nx"],
+            &["getex", "str"],
+            &["sort", "list", "alpha"],
+            &["sort", "list", "by", "store", "alpha"],
+            &["pexpireat", "missing", "1"],
+        ] {
+            run(args);
+            assert_eq!(state.borrow().dirty, dirty, "{:?}", args);
+        }
+        run(&["sort", "list", "alpha", "store", "sorted"]);
+        assert_eq!(state.borrow().dirty, dirty + 1);
+        run(&["sadd", "set", "b"]);
+        assert_eq!(state.borrow().dirty, dirty + 2);
+    }
+}
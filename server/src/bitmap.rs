@@ -0,0 +1,568 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+
+use feredis_core::item::RedisItem;
+
+use crate::list::normalize_range;
+use crate::string::get_string;
+use crate::value::{parse_int, Value};
+use crate::{RedisError, State};
+
+/// The highest addressable bit, which keeps strings below 512MB.
+const MAX_BIT_OFFSET: u64 = 512 * 1024 * 1024 * 8 - 1;
+
+const BIT_OFFSET_ERR: &str = "ERR bit offset is not an integer or out of range";
+
+fn parse_bit_offset(arg: &str) -> Result<u64, RedisError> {
+    match parse_int(arg) {
+        Some(offset) if (0..=MAX_BIT_OFFSET as i64).contains(&offset) => Ok(offset as u64),
+        _ => Err(RedisError::Custom(BIT_OFFSET_ERR)),
+    }
+}
+
+/// Returns the string at `key` for modification, converting integers into
+/// raw strings and creating the key if needed. The string is zero-padded to
+/// hold at least `len` bytes.
+fn get_bytes_mut<'a>(
+    state: &'a mut State,
+    key: &str,
+    len: usize,
+) -> Result<&'a mut Vec<u8>, RedisError> {
+    if !state.items.contains_key(key) {
+        let tag = state.next_tag();
+        state
+            .items
+            .insert(key.to_string(), (Value::String(Vec::new()), tag));
+    }
+    let (val, _) = state.items.get_mut(key).unwrap();
+    if let Value::Integer(int) = val {
+        *val = Value::String(int.to_string().into_bytes());
+    }
+    let Value::String(bytes) = val else {
+        return Err(RedisError::WrongType);
+    };
+    if bytes.len() < len {
+        bytes.resize(len, 0);
+    }
+    Ok(bytes)
+}
+
+/// Reads the bit at `offset`, where bit 0 is the most significant bit of the
+/// first byte. Bits past the end of the string are zero.
+fn get_bit(bytes: &[u8], offset: u64) -> bool {
+    let Some(byte) = bytes.get((offset / 8) as usize) else {
+        return false;
+    };
+    byte & (0x80 >> (offset % 8)) != 0
+}
+
+fn set_bit(bytes: &mut [u8], offset: u64, bit: bool) {
+    let byte = &mut bytes[(offset / 8) as usize];
+    let mask = 0x80 >> (offset % 8);
+    if bit {
+        *byte |= mask;
+    } else {
+        *byte &= !mask;
+    }
+}
+
+/// Counts the set bits between the bit offsets `start` and `end`, inclusive.
+fn count_bits(bytes: &[u8], start: u64, end: u64) -> u64 {
+    let (first, last) = ((start / 8) as usize, (end / 8) as usize);
+    if first == last {
+        return (start..=end).filter(|&bit| get_bit(bytes, bit)).count() as u64;
+    }
+    let head = (start..(first as u64 + 1) * 8)
+        .filter(|&bit| get_bit(bytes, bit))
+        .count();
+    let tail = (last as u64 * 8..=end)
+        .filter(|&bit| get_bit(bytes, bit))
+        .count();
+    let middle: u32 = bytes[first + 1..last]
+        .iter()
+        .map(|byte| byte.count_ones())
+        .sum();
+    (head + tail) as u64 + middle as u64
+}
+
+/// The unit of the ranges of `BITCOUNT` and `BITPOS`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RangeUnit {
+    Byte,
+    Bit,
+}
+
+fn parse_unit(arg: Option<RedisItem>) -> Result<RangeUnit, RedisError> {
+    match arg {
+        None => Ok(RangeUnit::Byte),
+        Some(RedisItem::BulkString(unit)) if unit.eq_ignore_ascii_case("byte") => {
+            Ok(RangeUnit::Byte)
+        }
+        Some(RedisItem::BulkString(unit)) if unit.eq_ignore_ascii_case("bit") => Ok(RangeUnit::Bit),
+        _ => Err(RedisError::Syntax),
+    }
+}
+
+fn parse_range_arg(arg: Option<RedisItem>) -> Result<i64, RedisError> {
+    let Some(RedisItem::BulkString(arg)) = arg else {
+        return Err(RedisError::InvalidArguments);
+    };
+    parse_int(&arg).ok_or(RedisError::NotInteger)
+}
+
+/// Normalizes a range given in `unit` into inclusive bit offsets into a
+/// string of `len` bytes.
+fn bit_range(start: i64, end: i64, unit: RangeUnit, len: usize) -> Option<(u64, u64)> {
+    match unit {
+        RangeUnit::Byte => normalize_range(start, end, len)
+            .map(|(start, end)| (start as u64 * 8, end as u64 * 8 + 7)),
+        RangeUnit::Bit => {
+            normalize_range(start, end, len * 8).map(|(start, end)| (start as u64, end as u64))
+        }
+    }
+}
+
+pub fn do_setbit(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let (Some(BulkString(key)), Some(BulkString(offset)), Some(BulkString(bit))) =
+        (args.pop_front(), args.pop_front(), args.pop_front())
+    else {
+        return RedisError::InvalidArguments.into();
+    };
+    let offset = match parse_bit_offset(&offset) {
+        Ok(offset) => offset,
+        Err(err) => return err.into(),
+    };
+    let bit = match bit.as_str() {
+        "0" => false,
+        "1" => true,
+        _ => return SimpleError("ERR bit is not an integer or out of range".to_string()),
+    };
+    let mut state = state.borrow_mut();
+    let bytes = match get_bytes_mut(&mut state, &key, (offset / 8) as usize + 1) {
+        Ok(bytes) => bytes,
+        Err(err) => return err.into(),
+    };
+    let old = get_bit(bytes, offset);
+    set_bit(bytes, offset, bit);
+    Integer(old as i64)
+}
+
+pub fn do_getbit(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let (Some(BulkString(key)), Some(BulkString(offset))) = (args.pop_front(), args.pop_front())
+    else {
+        return RedisError::InvalidArguments.into();
+    };
+    let offset = match parse_bit_offset(&offset) {
+        Ok(offset) => offset,
+        Err(err) => return err.into(),
+    };
+    match get_string(&state.borrow(), &key) {
+        Ok(bytes) => Integer(get_bit(&bytes.unwrap_or_default(), offset) as i64),
+        Err(err) => err.into(),
+    }
+}
+
+pub fn do_bitcount(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let Some(BulkString(key)) = args.pop_front() else {
+        return RedisError::InvalidArguments.into();
+    };
+    let range = match args.len() {
+        0 => None,
+        1 => return RedisError::Syntax.into(),
+        2 | 3 => {
+            let range = (
+                parse_range_arg(args.pop_front()),
+                parse_range_arg(args.pop_front()),
+                parse_unit(args.pop_front()),
+            );
+            match range {
+                (Ok(start), Ok(end), Ok(unit)) => Some((start, end, unit)),
+                (Err(err), _, _) | (_, Err(err), _) | (_, _, Err(err)) => return err.into(),
+            }
+        }
+        _ => return RedisError::Syntax.into(),
+    };
+    let bytes = match get_string(&state.borrow(), &key) {
+        Ok(bytes) => bytes.unwrap_or_default(),
+        Err(err) => return err.into(),
+    };
+    let (start, end, unit) = range.unwrap_or((0, -1, RangeUnit::Byte));
+    match bit_range(start, end, unit, bytes.len()) {
+        Some((start, end)) => Integer(count_bits(&bytes, start, end) as i64),
+        None => Integer(0),
+    }
+}
+
+pub fn do_bitpos(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let (Some(BulkString(key)), Some(BulkString(bit))) = (args.pop_front(), args.pop_front())
+    else {
+        return RedisError::InvalidArguments.into();
+    };
+    let bit = match bit.as_str() {
+        "0" => false,
+        "1" => true,
+        _ => return SimpleError("ERR The bit argument must be 1 or 0.".to_string()),
+    };
+    if args.len() > 3 {
+        return RedisError::Syntax.into();
+    }
+    let start = match args.pop_front() {
+        Some(arg) => match parse_range_arg(Some(arg)) {
+            Ok(start) => start,
+            Err(err) => return err.into(),
+        },
+        None => 0,
+    };
+    let end_given = !args.is_empty();
+    let end = match args.pop_front() {
+        Some(arg) => match parse_range_arg(Some(arg)) {
+            Ok(end) => end,
+            Err(err) => return err.into(),
+        },
+        None => -1,
+    };
+    let unit = match parse_unit(args.pop_front()) {
+        Ok(unit) => unit,
+        Err(err) => return err.into(),
+    };
+    let bytes = match get_string(&state.borrow(), &key) {
+        Ok(Some(bytes)) => bytes,
+        Ok(None) => return Integer(if bit { -1 } else { 0 }),
+        Err(err) => return err.into(),
+    };
+    let Some((start, end)) = bit_range(start, end, unit, bytes.len()) else {
+        return Integer(-1);
+    };
+    match (start..=end).find(|&offset| get_bit(&bytes, offset) == bit) {
+        Some(offset) => Integer(offset as i64),
+        // without an explicit end, the string is treated as padded with
+        // zeros, so the first clear bit is the one right after it
+        None if !bit && !end_given => Integer(end as i64 + 1),
+        None => Integer(-1),
+    }
+}
+
+pub fn do_bitop(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let (Some(BulkString(mut op)), Some(BulkString(dest))) = (args.pop_front(), args.pop_front())
+    else {
+        return RedisError::InvalidArguments.into();
+    };
+    op.make_ascii_lowercase();
+    if !matches!(op.as_str(), "and" | "or" | "xor" | "not") {
+        return RedisError::Syntax.into();
+    }
+    if args.is_empty() {
+        return RedisError::InvalidArguments.into();
+    }
+    if op == "not" && args.len() != 1 {
+        return SimpleError("ERR BITOP NOT must be called with a single source key.".to_string());
+    }
+    let mut state = state.borrow_mut();
+    let mut sources = Vec::with_capacity(args.len());
+    for arg in args {
+        let BulkString(key) = arg else {
+            return RedisError::InvalidArguments.into();
+        };
+        match get_string(&state, &key) {
+            Ok(bytes) => sources.push(bytes.unwrap_or_default()),
+            Err(err) => return err.into(),
+        }
+    }
+    let len = sources.iter().map(Vec::len).max().unwrap_or(0);
+    // missing bytes of shorter strings count as zeros
+    let byte = |source: &Vec<u8>, i: usize| source.get(i).copied().unwrap_or(0);
+    let result: Vec<u8> = (0..len)
+        .map(|i| {
+            let mut bytes = sources.iter().map(|source| byte(source, i));
+            let first = bytes.next().unwrap();
+            match op.as_str() {
+                "and" => bytes.fold(first, |acc, byte| acc & byte),
+                "or" => bytes.fold(first, |acc, byte| acc | byte),
+                "xor" => bytes.fold(first, |acc, byte| acc ^ byte),
+                _ => !first,
+            }
+        })
+        .collect();
+    if result.is_empty() {
+        state.items.remove(&dest);
+    } else {
+        let tag = state.next_tag();
+        state.items.insert(dest, (Value::from_bytes(result), tag));
+    }
+    Integer(len as i64)
+}
+
+/// The type of a `BITFIELD` integer, such as `i8` or `u16`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FieldType {
+    signed: bool,
+    bits: u32,
+}
+
+impl FieldType {
+    fn parse(arg: &str) -> Result<Self, RedisError> {
+        let err = RedisError::Custom(
+            "ERR Invalid bitfield type. Use something like i16 u8. Note that u64 is not supported but i64 is.",
+        );
+        let signed = match arg.as_bytes().first() {
+            Some(b'i' | b'I') => true,
+            Some(b'u' | b'U') => false,
+            _ => return Err(err),
+        };
+        let max = if signed { 64 } else { 63 };
+        match arg[1..].parse::<u32>() {
+            Ok(bits) if (1..=max).contains(&bits) => Ok(FieldType { signed, bits }),
+            _ => Err(err),
+        }
+    }
+
+    fn min(self) -> i128 {
+        if self.signed {
+            -(1 << (self.bits - 1))
+        } else {
+            0
+        }
+    }
+
+    fn max(self) -> i128 {
+        if self.signed {
+            (1 << (self.bits - 1)) - 1
+        } else {
+            (1 << self.bits) - 1
+        }
+    }
+
+    /// Interprets the low `bits` of `raw` as a value of this type.
+    fn decode(self, raw: u64) -> i64 {
+        if self.signed && self.bits < 64 && raw & (1 << (self.bits - 1)) != 0 {
+            (raw | (u64::MAX << self.bits)) as i64
+        } else {
+            raw as i64
+        }
+    }
+
+    /// Wraps `val` around to fit into this type.
+    fn wrap(self, val: i128) -> i64 {
+        let raw = (val as u128 as u64) & (u64::MAX >> (64 - self.bits));
+        self.decode(raw)
+    }
+}
+
+fn read_field(bytes: &[u8], offset: u64, ty: FieldType) -> i64 {
+    let raw = (offset..offset + ty.bits as u64)
+        .fold(0u64, |acc, bit| (acc << 1) | get_bit(bytes, bit) as u64);
+    ty.decode(raw)
+}
+
+fn write_field(bytes: &mut [u8], offset: u64, ty: FieldType, val: i64) {
+    for i in 0..ty.bits as u64 {
+        let bit = (val as u64 >> (ty.bits as u64 - 1 - i)) & 1 != 0;
+        set_bit(bytes, offset + i, bit);
+    }
+}
+
+/// How `BITFIELD` handles values which do not fit into their type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Overflow {
+    Wrap,
+    Sat,
+    Fail,
+}
+
+/// Fits `val` into `ty`, returning `None` if it overflows in `Fail` mode.
+fn handle_overflow(val: i128, ty: FieldType, overflow: Overflow) -> Option<i64> {
+    if (ty.min()..=ty.max()).contains(&val) {
+        return Some(val as i64);
+    }
+    match overflow {
+        Overflow::Wrap => Some(ty.wrap(val)),
+        Overflow::Sat => Some(val.clamp(ty.min(), ty.max()) as i64),
+        Overflow::Fail => None,
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum FieldOp {
+    Get,
+    Set(i64),
+    IncrBy(i64),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Field {
+    op: FieldOp,
+    ty: FieldType,
+    offset: u64,
+    overflow: Overflow,
+}
+
+/// Parses the subcommands of `BITFIELD`, where `read_only` only allows `GET`.
+fn parse_fields(args: VecDeque<RedisItem>, read_only: bool) -> Result<Vec<Field>, RedisError> {
+    let mut args = args.into_iter();
+    let mut next = || match args.next() {
+        Some(RedisItem::BulkString(arg)) => Ok(arg),
+        _ => Err(RedisError::Syntax),
+    };
+    let mut fields = Vec::new();
+    let mut overflow = Overflow::Wrap;
+    while let Ok(mut subcommand) = next() {
+        subcommand.make_ascii_lowercase();
+        if subcommand == "overflow" {
+            let mut mode = next()?;
+            mode.make_ascii_lowercase();
+            overflow = match mode.as_str() {
+                "wrap" => Overflow::Wrap,
+                "sat" => Overflow::Sat,
+                "fail" => Overflow::Fail,
+                _ => return Err(RedisError::Custom("ERR Invalid OVERFLOW type specified")),
+            };
+            continue;
+        }
+        if !matches!(subcommand.as_str(), "get" | "set" | "incrby") {
+            return Err(RedisError::Syntax);
+        }
+        let ty = FieldType::parse(&next()?)?;
+        let offset = next()?;
+        // offsets prefixed with `#` are multiplied by the width of the type
+        let offset = match offset.strip_prefix('#') {
+            Some(index) => parse_bit_offset(index)?
+                .checked_mul(ty.bits as u64)
+                .filter(|&offset| offset <= MAX_BIT_OFFSET)
+                .ok_or(RedisError::Custom(BIT_OFFSET_ERR))?,
+            None => parse_bit_offset(&offset)?,
+        };
+        let op = match subcommand.as_str() {
+            "get" => FieldOp::Get,
+            _ if read_only => {
+                return Err(RedisError::Custom(
+                    "ERR BITFIELD_RO only supports the GET subcommand",
+                ))
+            }
+            "set" => FieldOp::Set(parse_int(&next()?).ok_or(RedisError::NotInteger)?),
+            _ => FieldOp::IncrBy(parse_int(&next()?).ok_or(RedisError::NotInteger)?),
+        };
+        fields.push(Field {
+            op,
+            ty,
+            offset,
+            overflow,
+        });
+    }
+    Ok(fields)
+}
+
+fn bitfield(mut args: VecDeque<RedisItem>, state: &RefCell<State>, read_only: bool) -> RedisItem {
+    use RedisItem::*;
+    let Some(BulkString(key)) = args.pop_front() else {
+        return RedisError::InvalidArguments.into();
+    };
+    let fields = match parse_fields(args, read_only) {
+        Ok(fields) => fields,
+        Err(err) => return err.into(),
+    };
+    let mut state = state.borrow_mut();
+    // the string is grown to hold every written field up front
+    let write_end = fields
+        .iter()
+        .filter(|field| !matches!(field.op, FieldOp::Get))
+        .map(|field| (field.offset + field.ty.bits as u64).div_ceil(8) as usize)
+        .max();
+    let Some(len) = write_end else {
+        let bytes = match get_string(&state, &key) {
+            Ok(bytes) => bytes.unwrap_or_default(),
+            Err(err) => return err.into(),
+        };
+        return Array(
+            fields
+                .iter()
+                .map(|field| Integer(read_field(&bytes, field.offset, field.ty)))
+                .collect(),
+        );
+    };
+    let bytes = match get_bytes_mut(&mut state, &key, len) {
+        Ok(bytes) => bytes,
+        Err(err) => return err.into(),
+    };
+    let mut res = Vec::with_capacity(fields.len());
+    for field in fields {
+        let old = read_field(bytes, field.offset, field.ty);
+        let (new, reply) = match field.op {
+            FieldOp::Get => {
+                res.push(Integer(old));
+                continue;
+            }
+            FieldOp::Set(val) => {
+                // like redis, unsigned fields interpret negative values as
+                // huge unsigned ones
+                let val = if field.ty.signed {
+                    val as i128
+                } else {
+                    val as u64 as i128
+                };
+                let new = handle_overflow(val, field.ty, field.overflow);
+                (new, new.map(|_| old))
+            }
+            FieldOp::IncrBy(incr) => {
+                let new = handle_overflow(old as i128 + incr as i128, field.ty, field.overflow);
+                (new, new)
+            }
+        };
+        if let Some(new) = new {
+            write_field(bytes, field.offset, field.ty, new);
+        }
+        res.push(reply.map_or(Null, Integer));
+    }
+    Array(res)
+}
+
+pub fn do_bitfield(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    bitfield(args, state, false)
+}
+
+pub fn do_bitfield_ro(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    bitfield(args, state, true)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_bits() {
+        let mut bytes = vec![0u8; 2];
+        set_bit(&mut bytes, 1, true);
+        set_bit(&mut bytes, 15, true);
+        assert_eq!(bytes, [0x40, 0x01]);
+        assert!(get_bit(&bytes, 1));
+        assert!(!get_bit(&bytes, 2));
+        assert!(!get_bit(&bytes, 100));
+        assert_eq!(count_bits(&bytes, 0, 15), 2);
+        assert_eq!(count_bits(&bytes, 2, 14), 0);
+        assert_eq!(count_bits(&[0xff, 0xff, 0xff], 4, 19), 16);
+    }
+
+    #[test]
+    pub fn test_fields() {
+        let i8 = FieldType::parse("i8").unwrap();
+        let u4 = FieldType::parse("u4").unwrap();
+        assert!(FieldType::parse("u64").is_err());
+        assert!(FieldType::parse("i0").is_err());
+
+        let mut bytes = vec![0u8; 2];
+        write_field(&mut bytes, 4, i8, -2);
+        assert_eq!(bytes, [0x0f, 0xe0]);
+        assert_eq!(read_field(&bytes, 4, i8), -2);
+        assert_eq!(read_field(&bytes, 4, u4), 15);
+
+        assert_eq!(handle_overflow(130, i8, Overflow::Wrap), Some(-126));
+        assert_eq!(handle_overflow(130, i8, Overflow::Sat), Some(127));
+        assert_eq!(handle_overflow(-3, u4, Overflow::Sat), Some(0));
+        assert_eq!(handle_overflow(17, u4, Overflow::Wrap), Some(1));
+        assert_eq!(handle_overflow(16, u4, Overflow::Fail), None);
+    }
+}
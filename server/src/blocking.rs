@@ -0,0 +1,670 @@
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet, VecDeque},
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+use feredis_core::item::RedisItem;
+use smol::Timer;
+
+use crate::list::{self, End};
+use crate::stream::{self, ReadOp};
+use crate::value::parse_float;
+use crate::zset;
+use crate::{RedisError, State};
+
+/// An operation a client is blocked on.
+///
+/// When one of the keys the operation waits on becomes ready, the operation is
+/// attempted again, just as if the client had re-sent its command.
+#[derive(Debug, Clone)]
+pub enum BlockingOp {
+    /// `BLPOP` and `BRPOP`
+    Pop { keys: Vec<String>, end: End },
+    /// `BLMPOP`
+    MultiPop {
+        keys: Vec<String>,
+        end: End,
+        count: usize,
+    },
+    /// `BLMOVE` and `BRPOPLPUSH`
+    Move {
+        src: String,
+        dst: String,
+        from: End,
+        to: End,
+    },
+    /// `BZPOPMIN` and `BZPOPMAX`
+    ZPop { keys: Vec<String>, max: bool },
+    /// `BZMPOP`
+    ZMultiPop {
+        keys: Vec<String>,
+        max: bool,
+        count: usize,
+    },
+    /// `XREAD` and `XREADGROUP`
+    Read(ReadOp),
+}
+
+impl BlockingOp {
+    fn keys(&self) -> Vec<String> {
+        use BlockingOp::*;
+        match self {
+            Pop { keys, .. }
+            | MultiPop { keys, .. }
+            | ZPop { keys, .. }
+            | ZMultiPop { keys, .. } => keys.clone(),
+            Move { src, .. } => vec![src.clone()],
+            Read(op) => op.keys().to_vec(),
+        }
+    }
+
+    /// Attempts to perform the operation, returning `None` if the client
+    /// has to keep waiting.
+    fn try_serve(&self, state: &mut State) -> Result<Option<RedisItem>, RedisError> {
+        use BlockingOp::*;
+        use RedisItem::*;
+        let res = match self {
+            Pop { keys, end } => list::multi_pop(state, keys, *end, 1).map(|res| {
+                res.map(|(key, mut items)| {
+                    Array(vec![BulkString(key), RedisItem::bulk(items.pop().unwrap())])
+                })
+            }),
+            MultiPop { keys, end, count } => list::multi_pop(state, keys, *end, *count)
+                .map(|res| res.map(|res| list::mpop_reply(Some(res)))),
+            Move { src, dst, from, to } => {
+                list::move_item(state, src, dst, *from, *to).map(|res| res.map(RedisItem::bulk))
+            }
+            ZPop { keys, max } => zset::multi_pop(state, keys, *max, 1).map(|res| {
+                res.map(|(key, mut popped)| {
+                    let (member, score) = popped.pop().unwrap();
+                    Array(vec![BulkString(key), BulkString(member), Double(score)])
+                })
+            }),
+            ZMultiPop { keys, max, count } => zset::multi_pop(state, keys, *max, *count)
+                .map(|res| res.map(|res| zset::mpop_reply(Some(res)))),
+            Read(op) => op.serve(state),
+        };
+        let Some(reply) = res? else {
+            return Ok(None);
+        };
+        if let Some(command) = self.to_command(&reply) {
+            state.propagate(command.into_iter().map(BulkString).collect());
+        }
+        Ok(Some(reply))
+    }
+
+    /// Returns the arguments of the non-blocking command which has the same
+    /// effect as serving the operation with `reply`, which is what gets
+    /// propagated.
+    fn to_command(&self, reply: &RedisItem) -> Option<Vec<String>> {
+        use BlockingOp::*;
+        use RedisItem::*;
+        let end_name = |end: End| match end {
+            End::Left => "left".to_string(),
+            End::Right => "right".to_string(),
+        };
+        let items = match (self, reply) {
+            (Move { src, dst, from, to }, _) => {
+                let (src, dst) = (src.clone(), dst.clone());
+                return Some(vec![
+                    "lmove".to_string(),
+                    src,
+                    dst,
+                    end_name(*from),
+                    end_name(*to),
+                ]);
+            }
+            (Read(op), _) => return op.to_command(),
+            (_, Array(items)) => items,
+            _ => return None,
+        };
+        // the replies of pops start with the key which was served
+        let key = match items.first() {
+            Some(BulkString(key)) => Some(key.clone()),
+            _ => None,
+        };
+        let popped = match items.get(1) {
+            Some(Array(popped)) => popped.len(),
+            _ => 1,
+        };
+        let pop = |end: End| match end {
+            End::Left => "lpop".to_string(),
+            End::Right => "rpop".to_string(),
+        };
+        let zpop = |max: bool| if max { "zpopmax" } else { "zpopmin" }.to_string();
+        Some(match self {
+            Pop { end, .. } => vec![pop(*end), key?],
+            MultiPop { end, .. } => vec![pop(*end), key?, popped.to_string()],
+            ZPop { max, .. } => vec![zpop(*max), key?],
+            ZMultiPop { max, .. } => vec![zpop(*max), key?, popped.to_string()],
+            Move { .. } | Read(_) => unreachable!(),
+        })
+    }
+}
+
+#[derive(Debug)]
+struct BlockedClient {
+    db: usize,
+    op: BlockingOp,
+    reply: Option<RedisItem>,
+    waker: Option<Waker>,
+}
+
+/// Bookkeeping for clients blocked on keys.
+#[derive(Debug, Default)]
+pub struct Blocking {
+    /// the selected database, which the keys passed in refer to
+    db: usize,
+    next_id: u64,
+    clients: HashMap<u64, BlockedClient>,
+    /// ids of the clients waiting on each key of each database, in the order
+    /// they blocked
+    waiting: HashMap<(usize, String), VecDeque<u64>>,
+    /// keys which received data since the blocked clients were last served
+    ready: VecDeque<(usize, String)>,
+    ready_set: HashSet<(usize, String)>,
+}
+
+impl Blocking {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the database which the keys passed in refer to.
+    pub fn select(&mut self, db: usize) {
+        self.db = db;
+    }
+
+    /// Marks `key` as possibly ready, if any clients are waiting on it.
+    pub fn signal(&mut self, key: &str) {
+        self.signal_in(self.db, key);
+    }
+
+    /// Marks `key` in database `db` as possibly ready, if any clients are
+    /// waiting on it.
+    pub fn signal_in(&mut self, db: usize, key: &str) {
+        let key = (db, key.to_string());
+        if self.waiting.contains_key(&key) && !self.ready_set.contains(&key) {
+            self.ready_set.insert(key.clone());
+            self.ready.push_back(key);
+        }
+    }
+
+    /// Marks all keys of database `db` which clients are waiting on as
+    /// possibly ready.
+    pub fn signal_db(&mut self, db: usize) {
+        let keys: Vec<String> = self
+            .waiting
+            .keys()
+            .filter(|(key_db, _)| *key_db == db)
+            .map(|(_, key)| key.clone())
+            .collect();
+        for key in keys {
+            self.signal_in(db, &key);
+        }
+    }
+
+    fn block(&mut self, op: BlockingOp) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        for key in op.keys() {
+            self.waiting
+                .entry((self.db, key))
+                .or_default()
+                .push_back(id);
+        }
+        self.clients.insert(
+            id,
+            BlockedClient {
+                db: self.db,
+                op,
+                reply: None,
+                waker: None,
+            },
+        );
+        id
+    }
+
+    /// Removes the client from the queues of all keys it is waiting on.
+    fn unqueue(&mut self, id: u64, db: usize, op: &BlockingOp) {
+        for key in op.keys() {
+            let key = (db, key);
+            if let Some(queue) = self.waiting.get_mut(&key) {
+                queue.retain(|other| *other != id);
+                if queue.is_empty() {
+                    self.waiting.remove(&key);
+                }
+            }
+        }
+    }
+}
+
+/// Serves blocked clients waiting on keys which have become ready, in the
+/// order in which the clients blocked.
+pub fn serve_ready(state: &mut State) {
+    // clients are served in their own database
+    let selected = state.db;
+    while let Some(ready) = state.blocking.ready.pop_front() {
+        state.blocking.ready_set.remove(&ready);
+        let ids: Vec<u64> = state
+            .blocking
+            .waiting
+            .get(&ready)
+            .map(|queue| queue.iter().copied().collect())
+            .unwrap_or_default();
+        let (db, key) = ready;
+        state.select(db);
+        for id in ids {
+            if !state.items.contains_key(&key) {
+                break;
+            }
+            let Some(op) = state.blocking.clients.get(&id).map(|c| c.op.clone()) else {
+                continue;
+            };
+            // serving a client may push to other keys, marking them as ready.
+            // a client finding a key of another type keeps waiting, as the
+            // key may hold the right type again later
+            let reply = match op.try_serve(state) {
+                Ok(Some(reply)) => reply,
+                Ok(None) | Err(RedisError::WrongType) => continue,
+                Err(err) => err.into(),
+            };
+            state.blocking.unqueue(id, db, &op);
+            let client = state.blocking.clients.get_mut(&id).unwrap();
+            client.reply = Some(reply);
+            if let Some(waker) = client.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+    state.select(selected);
+}
+
+/// Resolves to the reply for a blocked client once it has been served.
+struct BlockFuture<'a> {
+    state: &'a RefCell<State>,
+    id: u64,
+}
+
+impl<'a> Future for BlockFuture<'a> {
+    type Output = RedisItem;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+
+        let mut state = this.state.borrow_mut();
+        let client = state.blocking.clients.get_mut(&this.id).unwrap();
+        if let Some(reply) = client.reply.take() {
+            state.blocking.clients.remove(&this.id);
+            Poll::Ready(reply)
+        } else {
+            if client
+                .waker
+                .as_ref()
+                .map(|w| !w.will_wake(cx.waker()))
+                .unwrap_or(true)
+            {
+                client.waker = Some(cx.waker().clone());
+            }
+            Poll::Pending
+        }
+    }
+}
+
+impl<'a> Drop for BlockFuture<'a> {
+    fn drop(&mut self) {
+        // unregister clients which timed out or disconnected
+        let mut state = self.state.borrow_mut();
+        if let Some(client) = state.blocking.clients.remove(&self.id) {
+            state.blocking.unqueue(self.id, client.db, &client.op);
+        }
+    }
+}
+
+/// Performs `op`, blocking until it can be served or `timeout` has passed.
+pub async fn block_on(
+    op: BlockingOp,
+    timeout: Option<Duration>,
+    state: &RefCell<State>,
+) -> RedisItem {
+    {
+        let mut state = state.borrow_mut();
+        match op.try_serve(&mut state) {
+            Ok(Some(reply)) => {
+                serve_ready(&mut state);
+                return reply;
+            }
+            Ok(None) => {}
+            Err(err) => return err.into(),
+        }
+    }
+    let id = state.borrow_mut().blocking.block(op);
+    let wait = BlockFuture { state, id };
+    match timeout {
+        Some(timeout) => {
+            smol::future::or(wait, async {
+                Timer::after(timeout).await;
+                RedisItem::Null
+            })
+            .await
+        }
+        None => wait.await,
+    }
+}
+
+/// Parses a timeout in seconds, where zero means waiting forever.
+pub fn parse_timeout(arg: Option<RedisItem>) -> Result<Option<Duration>, RedisError> {
+    let Some(RedisItem::BulkString(arg)) = arg else {
+        return Err(RedisError::InvalidArguments);
+    };
+    let timeout = parse_float(&arg)
+        .filter(|timeout| timeout.is_finite())
+        .ok_or(RedisError::Custom(
+            "ERR timeout is not a float or out of range",
+        ))?;
+    if timeout < 0.0 {
+        return Err(RedisError::Custom("ERR timeout is negative"));
+    }
+    if timeout == 0.0 {
+        Ok(None)
+    } else {
+        Ok(Some(Duration::from_secs_f64(timeout)))
+    }
+}
+
+/// Parses the `key [key ...] timeout` arguments of the single-key pops.
+fn parse_keys_timeout(
+    mut args: VecDeque<RedisItem>,
+) -> Result<(Vec<String>, Option<Duration>), RedisError> {
+    let timeout = parse_timeout(args.pop_back())?;
+    let mut keys = Vec::with_capacity(args.len());
+    for arg in args {
+        let RedisItem::BulkString(key) = arg else {
+            return Err(RedisError::InvalidArguments);
+        };
+        keys.push(key);
+    }
+    if keys.is_empty() {
+        return Err(RedisError::InvalidArguments);
+    }
+    Ok((keys, timeout))
+}
+
+fn parse_pop(
+    args: VecDeque<RedisItem>,
+    end: End,
+) -> Result<(BlockingOp, Option<Duration>), RedisError> {
+    let (keys, timeout) = parse_keys_timeout(args)?;
+    Ok((BlockingOp::Pop { keys, end }, timeout))
+}
+
+fn parse_bzpop(
+    args: VecDeque<RedisItem>,
+    max: bool,
+) -> Result<(BlockingOp, Option<Duration>), RedisError> {
+    let (keys, timeout) = parse_keys_timeout(args)?;
+    Ok((BlockingOp::ZPop { keys, max }, timeout))
+}
+
+fn parse_blmpop(
+    mut args: VecDeque<RedisItem>,
+) -> Result<(BlockingOp, Option<Duration>), RedisError> {
+    let timeout = parse_timeout(args.pop_front())?;
+    let keys = list::parse_keys(&mut args)?;
+    let end = End::parse(args.pop_front())?;
+    let count = list::parse_mpop_count(&mut args)?;
+    Ok((BlockingOp::MultiPop { keys, end, count }, timeout))
+}
+
+fn parse_bzmpop(
+    mut args: VecDeque<RedisItem>,
+) -> Result<(BlockingOp, Option<Duration>), RedisError> {
+    let timeout = parse_timeout(args.pop_front())?;
+    let keys = list::parse_keys(&mut args)?;
+    let max = zset::parse_min_max(args.pop_front())?;
+    let count = list::parse_mpop_count(&mut args)?;
+    Ok((BlockingOp::ZMultiPop { keys, max, count }, timeout))
+}
+
+fn parse_blmove(
+    mut args: VecDeque<RedisItem>,
+) -> Result<(BlockingOp, Option<Duration>), RedisError> {
+    let (Some(RedisItem::BulkString(src)), Some(RedisItem::BulkString(dst))) =
+        (args.pop_front(), args.pop_front())
+    else {
+        return Err(RedisError::InvalidArguments);
+    };
+    let from = End::parse(args.pop_front())?;
+    let to = End::parse(args.pop_front())?;
+    let timeout = parse_timeout(args.pop_front())?;
+    Ok((BlockingOp::Move { src, dst, from, to }, timeout))
+}
+
+fn parse_brpoplpush(
+    mut args: VecDeque<RedisItem>,
+) -> Result<(BlockingOp, Option<Duration>), RedisError> {
+    let (Some(RedisItem::BulkString(src)), Some(RedisItem::BulkString(dst))) =
+        (args.pop_front(), args.pop_front())
+    else {
+        return Err(RedisError::InvalidArguments);
+    };
+    let timeout = parse_timeout(args.pop_front())?;
+    let op = BlockingOp::Move {
+        src,
+        dst,
+        from: End::Right,
+        to: End::Left,
+    };
+    Ok((op, timeout))
+}
+
+/// Handles `XREAD` and `XREADGROUP`, which only block if asked to.
+async fn handle_read(
+    args: VecDeque<RedisItem>,
+    state: &RefCell<State>,
+    grouped: bool,
+) -> RedisItem {
+    let parsed = stream::parse_read(args, &mut state.borrow_mut(), grouped);
+    match parsed {
+        Ok((op, Some(timeout))) => block_on(BlockingOp::Read(op), timeout, state).await,
+        Ok((op, None)) => match BlockingOp::Read(op).try_serve(&mut state.borrow_mut()) {
+            Ok(reply) => reply.unwrap_or(RedisItem::Null),
+            Err(err) => err.into(),
+        },
+        Err(err) => err.into(),
+    }
+}
+
+/// Handles one of the blocking commands.
+pub async fn handle_blocking(
+    command: &str,
+    args: VecDeque<RedisItem>,
+    state: &RefCell<State>,
+) -> RedisItem {
+    let parsed = match command {
+        "blpop" => parse_pop(args, End::Left),
+        "brpop" => parse_pop(args, End::Right),
+        "blmpop" => parse_blmpop(args),
+        "blmove" => parse_blmove(args),
+        "brpoplpush" => parse_brpoplpush(args),
+        "bzpopmin" => parse_bzpop(args, false),
+        "bzpopmax" => parse_bzpop(args, true),
+        "bzmpop" => parse_bzmpop(args),
+        "xread" => return handle_read(args, state, false).await,
+        "xreadgroup" => return handle_read(args, state, true).await,
+        _ => return RedisError::UnknownCommand.into(),
+    };
+    match parsed {
+        Ok((op, timeout)) => block_on(op, timeout, state).await,
+        Err(err) => err.into(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use smol::{LocalExecutor, Task};
+
+    fn bulks(args: &[&str]) -> Vec<RedisItem> {
+        args.iter()
+            .map(|arg| RedisItem::BulkString(arg.to_string()))
+            .collect()
+    }
+
+    /// Starts the command `args` on a connection of its own.
+    fn spawn_command<'a>(
+        exec: &LocalExecutor<'a>,
+        state: &'a RefCell<State>,
+        args: &[&str],
+    ) -> Task<RedisItem> {
+        let command = RedisItem::Array(bulks(args));
+        exec.spawn(async move { crate::handle_command(command, state, &mut 0).await })
+    }
+
+    fn run(state: &RefCell<State>, args: &[&str]) -> RedisItem {
+        crate::run_command(state, &mut 0, args)
+    }
+
+    #[test]
+    pub fn test_wake_up_order() {
+        use RedisItem::*;
+        let state = RefCell::new(State::new(16));
+        let exec = LocalExecutor::new();
+        let first = spawn_command(&exec, &state, &["blpop", "a", "b", "0"]);
+        let second = spawn_command(&exec, &state, &["brpop", "b", "0"]);
+        let third = spawn_command(&exec, &state, &["blpop", "b", "0"]);
+        while exec.try_tick() {}
+        assert!(!first.is_finished());
+
+        // the clients which blocked first are served first
+        run(&state, &["rpush", "b", "x", "y"]);
+        while exec.try_tick() {}
+        assert!(first.is_finished() && second.is_finished());
+        assert_eq!(smol::block_on(first), Array(bulks(&["b", "x"])));
+        assert_eq!(smol::block_on(second), Array(bulks(&["b", "y"])));
+        assert!(!third.is_finished());
+        assert_eq!(run(&state, &["llen", "b"]), Integer(0));
+
+        // a client moving an item serves the clients waiting on its target
+        let mover = spawn_command(&exec, &state, &["blmove", "c", "b", "left", "left", "0"]);
+        while exec.try_tick() {}
+        run(&state, &["lpush", "c", "z"]);
+        while exec.try_tick() {}
+        assert_eq!(smol::block_on(mover), BulkString("z".to_string()));
+        assert_eq!(smol::block_on(third), Array(bulks(&["b", "z"])));
+        assert!(state.borrow().blocking.waiting.is_empty());
+    }
+
+    #[test]
+    pub fn test_timeout() {
+        use RedisItem::*;
+        let state = RefCell::new(State::new(16));
+        assert_eq!(run(&state, &["blpop", "k", "0.01"]), Null);
+        assert_eq!(run(&state, &["bzpopmin", "k", "0.01"]), Null);
+        assert_eq!(
+            run(&state, &["blmove", "k", "d", "left", "left", "0.01"]),
+            Null
+        );
+        {
+            let blocking = &state.borrow().blocking;
+            assert!(blocking.waiting.is_empty() && blocking.clients.is_empty());
+        }
+
+        let res = run(&state, &["blpop", "k", "-1"]);
+        assert!(matches!(res, SimpleError(_)));
+        let res = run(&state, &["blpop", "k", "x"]);
+        assert!(matches!(res, SimpleError(_)));
+    }
+
+    #[test]
+    pub fn test_wrong_type() {
+        use RedisItem::*;
+        let state = RefCell::new(State::new(16));
+        let exec = LocalExecutor::new();
+        let pop = spawn_command(&exec, &state, &["blpop", "k", "0"]);
+        let zpop = spawn_command(&exec, &state, &["bzpopmin", "k", "0"]);
+        let mover = spawn_command(&exec, &state, &["blmove", "src", "k", "left", "left", "0"]);
+        while exec.try_tick() {}
+
+        // a client waiting for a list keeps waiting while the key holds
+        // another type
+        run(&state, &["zadd", "k", "1", "m"]);
+        while exec.try_tick() {}
+        assert!(!pop.is_finished());
+        assert_eq!(
+            smol::block_on(zpop),
+            Array(vec![bulk("k"), bulk("m"), Double(1.0)])
+        );
+        run(&state, &["set", "k", "v"]);
+        run(&state, &["rpush", "src", "x"]);
+        while exec.try_tick() {}
+        assert!(!pop.is_finished() && !mover.is_finished());
+        assert_eq!(run(&state, &["llen", "src"]), Integer(1));
+
+        run(&state, &["del", "k"]);
+        run(&state, &["rpush", "src", "y"]);
+        while exec.try_tick() {}
+        assert_eq!(smol::block_on(mover), bulk("x"));
+        assert_eq!(smol::block_on(pop), Array(bulks(&["k", "x"])));
+
+        // a key of the wrong type is still an error when the client blocks
+        let res = run(&state, &["bzpopmin", "src", "0"]);
+        assert!(matches!(res, SimpleError(_)));
+    }
+
+    #[test]
+    pub fn test_zset_pops() {
+        use RedisItem::*;
+        let state = RefCell::new(State::new(16));
+        run(&state, &["zadd", "z", "1", "a", "2", "b"]);
+        // members available right away are popped without blocking
+        assert_eq!(
+            run(&state, &["bzpopmax", "none", "z", "0"]),
+            Array(vec![bulk("z"), bulk("b"), Double(2.0)])
+        );
+        run(&state, &["del", "z"]);
+
+        let exec = LocalExecutor::new();
+        let min = spawn_command(&exec, &state, &["bzpopmin", "z", "0"]);
+        let max = spawn_command(&exec, &state, &["bzpopmax", "y", "z", "0"]);
+        let mpop = spawn_command(
+            &exec,
+            &state,
+            &["bzmpop", "0", "1", "z", "min", "count", "5"],
+        );
+        while exec.try_tick() {}
+        assert!(!min.is_finished());
+
+        run(
+            &state,
+            &["zadd", "z", "1", "a", "2", "b", "3", "c", "4", "d"],
+        );
+        while exec.try_tick() {}
+        assert_eq!(
+            smol::block_on(min),
+            Array(vec![bulk("z"), bulk("a"), Double(1.0)])
+        );
+        assert_eq!(
+            smol::block_on(max),
+            Array(vec![bulk("z"), bulk("d"), Double(4.0)])
+        );
+        let entry = |member, score| Array(vec![bulk(member), Double(score)]);
+        assert_eq!(
+            smol::block_on(mpop),
+            Array(vec![
+                bulk("z"),
+                Array(vec![entry("b", 2.0), entry("c", 3.0)])
+            ])
+        );
+        assert_eq!(run(&state, &["zcard", "z"]), Integer(0));
+        assert!(state.borrow().blocking.waiting.is_empty());
+    }
+
+    fn bulk(val: &str) -> RedisItem {
+        RedisItem::BulkString(val.to_string())
+    }
+}
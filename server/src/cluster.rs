@@ -0,0 +1,1518 @@
+//! Cluster mode, which shards the keyspace across nodes by hash slot.
+//!
+//! Every node owns a set of slots and only serves the keys in them, sending
+//! clients to the owner of any other slot with a `MOVED` redirect. While a
+//! slot migrates to another node, keys which were already moved are served
+//! by the target, which clients are sent to with an `ASK` redirect.
+//!
+//! Nodes learn about each other over the cluster bus, which listens on the
+//! client port plus 10000. Every node pings every other node once a second
+//! with the slots it owns and what it knows about the rest of the cluster,
+//! and gets the same back in the pong. A node which leaves a ping unanswered
+//! for the node timeout is suspected to fail, and is marked as failed once
+//! most of the masters suspect it. Conflicting claims on a slot are settled
+//! in favor of the node with the higher config epoch, which a node bumps
+//! when it takes over a migrated slot.
+//!
+//! The configuration is kept in the format of `CLUSTER NODES`, so that a
+//! node keeps its id and slots across restarts.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt::Write as _;
+use std::fs::{self, File};
+use std::io::{self, Write as _};
+use std::net::{IpAddr, TcpListener};
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use feredis_core::cluster::{key_positions, key_slot, SLOTS};
+use feredis_core::item::{ItemParser, RedisItem};
+use smol::io::{AsyncWriteExt, BufReader};
+use smol::{Async, Timer};
+
+use crate::keyspace::Keyspace;
+use crate::value::parse_int;
+use crate::{rdb, replication, RedisError, State};
+
+/// The offset of the bus port from the client port.
+pub const BUS_PORT_OFFSET: u16 = 10000;
+pub const DEFAULT_NODE_TIMEOUT: Duration = Duration::from_secs(15);
+/// How often every node is pinged.
+const PING_PERIOD: Duration = Duration::from_secs(1);
+/// How long a connection on the bus may take to exchange a message.
+const BUS_TIMEOUT: Duration = Duration::from_secs(1);
+/// How long a forgotten node is kept from being added back by gossip.
+const FORGET_TTL: Duration = Duration::from_secs(60);
+
+/// Returns the unix time in milliseconds of an instant in the past.
+fn unix_ms(time: Instant) -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    now.saturating_sub(time.elapsed()).as_millis() as u64
+}
+
+#[derive(Debug)]
+struct Node {
+    id: String,
+    host: String,
+    port: u16,
+    bus_port: u16,
+    config_epoch: u64,
+    /// whether the node was added by `CLUSTER MEET` or gossip, and its real
+    /// id is not known yet
+    handshake: bool,
+    created: Instant,
+    /// when a ping was last sent
+    last_ping: Option<Instant>,
+    /// when the oldest unanswered ping was sent
+    ping_sent: Option<Instant>,
+    /// when the node was last heard from
+    pong_received: Option<Instant>,
+    /// whether the node is suspected to fail
+    pfail: bool,
+    fail: bool,
+    /// the nodes which reported this one as failing, with when they did
+    fail_reports: HashMap<String, Instant>,
+}
+
+impl Node {
+    fn new(id: String, host: String, port: u16, bus_port: u16) -> Self {
+        Self {
+            id,
+            host,
+            port,
+            bus_port,
+            config_epoch: 0,
+            handshake: false,
+            created: Instant::now(),
+            last_ping: None,
+            ping_sent: None,
+            pong_received: None,
+            pfail: false,
+            fail: false,
+            fail_reports: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MessageKind {
+    Ping,
+    Pong,
+    /// a ping which makes the receiver add the sender
+    Meet,
+}
+
+/// What a message tells about a node other than its sender.
+#[derive(Debug)]
+struct Gossip {
+    id: String,
+    host: String,
+    port: u16,
+    bus_port: u16,
+    pfail: bool,
+    fail: bool,
+}
+
+/// A message on the cluster bus, sent as an array of bulk strings.
+#[derive(Debug)]
+struct Message {
+    kind: MessageKind,
+    sender: String,
+    port: u16,
+    bus_port: u16,
+    current_epoch: u64,
+    config_epoch: u64,
+    /// the slot ranges owned by the sender
+    slots: Vec<(u16, u16)>,
+    gossip: Vec<Gossip>,
+}
+
+impl Message {
+    fn to_item(&self) -> RedisItem {
+        let kind = match self.kind {
+            MessageKind::Ping => "ping",
+            MessageKind::Pong => "pong",
+            MessageKind::Meet => "meet",
+        };
+        let slots = self
+            .slots
+            .iter()
+            .map(|(start, end)| format!("{}-{}", start, end))
+            .collect::<Vec<_>>()
+            .join(",");
+        let mut args = vec![
+            kind.to_string(),
+            self.sender.clone(),
+            self.port.to_string(),
+            self.bus_port.to_string(),
+            self.current_epoch.to_string(),
+            self.config_epoch.to_string(),
+            slots,
+        ];
+        for node in &self.gossip {
+            let flags = match (node.fail, node.pfail) {
+                (true, _) => "fail",
+                (false, true) => "pfail",
+                (false, false) => "ok",
+            };
+            args.extend([
+                node.id.clone(),
+                node.host.clone(),
+                node.port.to_string(),
+                node.bus_port.to_string(),
+                flags.to_string(),
+            ]);
+        }
+        RedisItem::Array(args.into_iter().map(RedisItem::BulkString).collect())
+    }
+
+    fn from_item(item: RedisItem) -> Option<Self> {
+        let RedisItem::Array(args) = item else {
+            return None;
+        };
+        let args = args
+            .into_iter()
+            .map(|arg| match arg {
+                RedisItem::BulkString(arg) => Some(arg),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()?;
+        if args.len() < 7 || (args.len() - 7) % 5 != 0 {
+            return None;
+        }
+        let kind = match args[0].as_str() {
+            "ping" => MessageKind::Ping,
+            "pong" => MessageKind::Pong,
+            "meet" => MessageKind::Meet,
+            _ => return None,
+        };
+        let slots = if args[6].is_empty() {
+            Vec::new()
+        } else {
+            args[6]
+                .split(',')
+                .map(parse_slot_range)
+                .collect::<Option<Vec<_>>>()?
+        };
+        let gossip = args[7..]
+            .chunks(5)
+            .map(|node| {
+                Some(Gossip {
+                    id: node[0].clone(),
+                    host: node[1].clone(),
+                    port: node[2].parse().ok()?,
+                    bus_port: node[3].parse().ok()?,
+                    pfail: node[4] == "pfail",
+                    fail: node[4] == "fail",
+                })
+            })
+            .collect::<Option<Vec<_>>>()?;
+        Some(Self {
+            kind,
+            sender: args[1].clone(),
+            port: args[2].parse().ok()?,
+            bus_port: args[3].parse().ok()?,
+            current_epoch: args[4].parse().ok()?,
+            config_epoch: args[5].parse().ok()?,
+            slots,
+            gossip,
+        })
+    }
+}
+
+/// Parses a slot, which has to be in range.
+fn parse_slot(slot: &str) -> Option<u16> {
+    slot.parse::<u16>().ok().filter(|slot| *slot < SLOTS)
+}
+
+/// Parses a slot range given as `start-end` or as a single slot.
+fn parse_slot_range(range: &str) -> Option<(u16, u16)> {
+    match range.split_once('-') {
+        Some((start, end)) => {
+            let (start, end) = (parse_slot(start)?, parse_slot(end)?);
+            (start <= end).then_some((start, end))
+        }
+        None => parse_slot(range).map(|slot| (slot, slot)),
+    }
+}
+
+#[derive(Debug)]
+pub struct Cluster {
+    myself: String,
+    nodes: HashMap<String, Node>,
+    /// the owner of every slot
+    slots: Vec<Option<String>>,
+    /// the slots this node moves to other nodes, with their targets
+    migrating: BTreeMap<u16, String>,
+    /// the slots this node takes over from other nodes, with their sources
+    importing: BTreeMap<u16, String>,
+    current_epoch: u64,
+    node_timeout: Duration,
+    config_path: PathBuf,
+    /// whether every slot is served, which is updated periodically
+    ok: bool,
+    /// whether the configuration changed since it was saved
+    save: bool,
+    /// nodes removed with `CLUSTER FORGET`, which gossip does not add back
+    forgotten: HashMap<String, Instant>,
+}
+
+impl Cluster {
+    /// Loads the configuration at `config_path`, or starts a new cluster of
+    /// just this node if there is none.
+    pub fn open(
+        config_path: PathBuf,
+        host: &str,
+        port: u16,
+        node_timeout: Duration,
+    ) -> io::Result<Self> {
+        let mut cluster = match fs::read_to_string(&config_path) {
+            Ok(data) => {
+                Self::from_config(&data, config_path.clone(), node_timeout).map_err(|err| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("{}: {}", config_path.display(), err),
+                    )
+                })?
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                let myself = replication::random_id();
+                let mut cluster = Self {
+                    myself: myself.clone(),
+                    nodes: HashMap::new(),
+                    slots: vec![None; SLOTS as usize],
+                    migrating: BTreeMap::new(),
+                    importing: BTreeMap::new(),
+                    current_epoch: 0,
+                    node_timeout,
+                    config_path,
+                    ok: false,
+                    save: true,
+                    forgotten: HashMap::new(),
+                };
+                let node = Node::new(myself.clone(), String::new(), 0, 0);
+                cluster.nodes.insert(myself, node);
+                cluster
+            }
+            Err(err) => return Err(err),
+        };
+        // the address may have changed since the configuration was saved
+        let myself = cluster.nodes.get_mut(&cluster.myself).unwrap();
+        myself.host = host.to_string();
+        myself.port = port;
+        myself.bus_port = port.wrapping_add(BUS_PORT_OFFSET);
+        cluster.update_state();
+        Ok(cluster)
+    }
+
+    /// Parses a configuration in the format of `CLUSTER NODES`, followed by
+    /// a line with the epochs.
+    fn from_config(
+        data: &str,
+        config_path: PathBuf,
+        node_timeout: Duration,
+    ) -> Result<Self, String> {
+        let mut myself = None;
+        let mut nodes = HashMap::new();
+        let mut slots = vec![None; SLOTS as usize];
+        let mut migrating = BTreeMap::new();
+        let mut importing = BTreeMap::new();
+        let mut current_epoch = 0;
+        for (i, line) in data.lines().enumerate() {
+            let invalid = |reason: &str| format!("line {}: {}", i + 1, reason);
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields[..] {
+                [] => continue,
+                ["vars", ref vars @ ..] => {
+                    for var in vars.chunks(2) {
+                        if let ["currentEpoch", epoch] = var {
+                            current_epoch = epoch.parse().map_err(|_| invalid("invalid epoch"))?;
+                        }
+                    }
+                    continue;
+                }
+                _ if fields.len() < 8 => return Err(invalid("missing fields")),
+                _ => {}
+            }
+            let id = fields[0].to_string();
+            let (host, ports) = fields[1]
+                .rsplit_once(':')
+                .ok_or_else(|| invalid("invalid address"))?;
+            let (port, bus_port) = ports
+                .split_once('@')
+                .and_then(|(port, bus_port)| Some((port.parse().ok()?, bus_port.parse().ok()?)))
+                .ok_or_else(|| invalid("invalid address"))?;
+            let mut node = Node::new(id.clone(), host.to_string(), port, bus_port);
+            for flag in fields[2].split(',') {
+                match flag {
+                    "myself" => myself = Some(id.clone()),
+                    "fail" => node.fail = true,
+                    _ => {}
+                }
+            }
+            node.config_epoch = fields[6].parse().map_err(|_| invalid("invalid epoch"))?;
+            for slot in &fields[8..] {
+                if let Some(slot) = slot.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+                    let parsed = if let Some((slot, target)) = slot.split_once("->-") {
+                        parse_slot(slot).map(|slot| migrating.insert(slot, target.to_string()))
+                    } else if let Some((slot, source)) = slot.split_once("-<-") {
+                        parse_slot(slot).map(|slot| importing.insert(slot, source.to_string()))
+                    } else {
+                        None
+                    };
+                    parsed.ok_or_else(|| invalid("invalid migrating slot"))?;
+                    continue;
+                }
+                let (start, end) = parse_slot_range(slot).ok_or_else(|| invalid("invalid slot"))?;
+                for slot in start..=end {
+                    slots[slot as usize] = Some(id.clone());
+                }
+            }
+            nodes.insert(id, node);
+        }
+        let myself = myself.ok_or("no node is flagged as myself")?;
+        Ok(Self {
+            myself,
+            nodes,
+            slots,
+            migrating,
+            importing,
+            current_epoch,
+            node_timeout,
+            config_path,
+            ok: false,
+            save: false,
+            forgotten: HashMap::new(),
+        })
+    }
+
+    pub fn bus_port(&self) -> u16 {
+        self.nodes[&self.myself].bus_port
+    }
+
+    fn myself(&self) -> &Node {
+        &self.nodes[&self.myself]
+    }
+
+    /// Returns the contiguous ranges of slots owned by the node `id`.
+    fn slot_ranges(&self, id: &str) -> Vec<(u16, u16)> {
+        let mut ranges: Vec<(u16, u16)> = Vec::new();
+        for slot in 0..SLOTS {
+            if self.slots[slot as usize].as_deref() != Some(id) {
+                continue;
+            }
+            match ranges.last_mut() {
+                Some((_, end)) if *end + 1 == slot => *end = slot,
+                _ => ranges.push((slot, slot)),
+            }
+        }
+        ranges
+    }
+
+    /// Returns the masters which own slots, with their slot ranges.
+    fn shards(&self) -> Vec<(&Node, Vec<(u16, u16)>)> {
+        let mut ids: Vec<&String> = self.nodes.keys().collect();
+        ids.sort();
+        ids.into_iter()
+            .map(|id| (&self.nodes[id], self.slot_ranges(id)))
+            .filter(|(node, ranges)| !ranges.is_empty() && !node.handshake)
+            .collect()
+    }
+
+    /// Returns the masters which own slots, which are the ones that get a
+    /// say in failure detection.
+    fn voters(&self) -> impl Iterator<Item = &Node> + '_ {
+        self.nodes.values().filter(|node| {
+            self.slots
+                .iter()
+                .any(|owner| owner.as_ref() == Some(&node.id))
+        })
+    }
+
+    /// Updates whether every slot is served by a node which is not failing.
+    fn update_state(&mut self) {
+        let ok = self.slots.iter().all(|owner| {
+            owner
+                .as_ref()
+                .and_then(|owner| self.nodes.get(owner))
+                .is_some_and(|node| !node.fail)
+        });
+        if ok != self.ok {
+            println!("Cluster state changed: {}", if ok { "ok" } else { "fail" });
+            self.ok = ok;
+        }
+    }
+
+    /// Returns a line of `CLUSTER NODES` describing `node`.
+    fn node_line(&self, node: &Node) -> String {
+        let mut flags = Vec::new();
+        if node.id == self.myself {
+            flags.push("myself");
+        }
+        flags.push("master");
+        if node.fail {
+            flags.push("fail");
+        } else if node.pfail {
+            flags.push("fail?");
+        }
+        if node.handshake {
+            flags.push("handshake");
+        }
+        let connected = node.id == self.myself || (node.pong_received.is_some() && !node.pfail);
+        let mut line = format!(
+            "{} {}:{}@{} {} - {} {} {} {}",
+            node.id,
+            node.host,
+            node.port,
+            node.bus_port,
+            flags.join(","),
+            node.ping_sent.map_or(0, unix_ms),
+            node.pong_received.map_or(0, unix_ms),
+            node.config_epoch,
+            if connected {
+                "connected"
+            } else {
+                "disconnected"
+            },
+        );
+        for (start, end) in self.slot_ranges(&node.id) {
+            if start == end {
+                write!(line, " {}", start).unwrap();
+            } else {
+                write!(line, " {}-{}", start, end).unwrap();
+            }
+        }
+        if node.id == self.myself {
+            for (slot, target) in &self.migrating {
+                write!(line, " [{}->-{}]", slot, target).unwrap();
+            }
+            for (slot, source) in &self.importing {
+                write!(line, " [{}-<-{}]", slot, source).unwrap();
+            }
+        }
+        line
+    }
+
+    /// Returns the description of all nodes given by `CLUSTER NODES`,
+    /// starting with this one.
+    fn nodes_description(&self) -> String {
+        let mut ids: Vec<&String> = self.nodes.keys().collect();
+        ids.sort_by_key(|id| (**id != self.myself, *id));
+        let mut out = String::new();
+        for id in ids {
+            out.push_str(&self.node_line(&self.nodes[id]));
+            out.push('\n');
+        }
+        out
+    }
+
+    fn save_config(&mut self) -> io::Result<()> {
+        let mut config = self.nodes_description();
+        writeln!(
+            config,
+            "vars currentEpoch {} lastVoteEpoch 0",
+            self.current_epoch
+        )
+        .unwrap();
+        let temp_path = self.config_path.with_extension("tmp");
+        let mut file = File::create(&temp_path)?;
+        file.write_all(config.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&temp_path, &self.config_path)?;
+        self.save = false;
+        Ok(())
+    }
+
+    fn message(&self, kind: MessageKind) -> Message {
+        let myself = self.myself();
+        let gossip = self
+            .nodes
+            .values()
+            .filter(|node| node.id != self.myself && !node.handshake)
+            .map(|node| Gossip {
+                id: node.id.clone(),
+                host: node.host.clone(),
+                port: node.port,
+                bus_port: node.bus_port,
+                pfail: node.pfail,
+                fail: node.fail,
+            })
+            .collect();
+        Message {
+            kind,
+            sender: self.myself.clone(),
+            port: myself.port,
+            bus_port: myself.bus_port,
+            current_epoch: self.current_epoch,
+            config_epoch: myself.config_epoch,
+            slots: self.slot_ranges(&self.myself),
+            gossip,
+        }
+    }
+
+    /// Starts a handshake with the node at `host` and `port`, unless it is
+    /// already known.
+    fn meet(&mut self, host: &str, port: u16, bus_port: u16) {
+        let known = self
+            .nodes
+            .values()
+            .any(|node| node.host == host && node.bus_port == bus_port);
+        if known {
+            return;
+        }
+        // the node is known under a random id until it tells its own
+        let mut node = Node::new(replication::random_id(), host.to_string(), port, bus_port);
+        node.handshake = true;
+        self.nodes.insert(node.id.clone(), node);
+    }
+
+    /// Handles a message from the node at `host`. If the message is the pong
+    /// to a ping sent to the node known as `pinged`, that node answered.
+    fn process(&mut self, msg: Message, host: &str, pinged: Option<&str>) {
+        if msg.sender == self.myself {
+            return;
+        }
+        let now = Instant::now();
+        if let Some(pinged) = pinged.filter(|pinged| self.nodes[*pinged].handshake) {
+            // the handshake is done, and the node is known by its id from now on
+            self.nodes.remove(pinged);
+            if !self.nodes.contains_key(&msg.sender) {
+                println!(
+                    "Cluster node {} joined at {}:{}",
+                    msg.sender, host, msg.port
+                );
+                let node = Node::new(msg.sender.clone(), host.to_string(), msg.port, msg.bus_port);
+                self.nodes.insert(msg.sender.clone(), node);
+                self.save = true;
+            }
+        }
+        if msg.kind == MessageKind::Meet
+            && !self.nodes.contains_key(&msg.sender)
+            && !self.forgotten.contains_key(&msg.sender)
+        {
+            println!(
+                "Cluster node {} met us from {}:{}",
+                msg.sender, host, msg.port
+            );
+            let node = Node::new(msg.sender.clone(), host.to_string(), msg.port, msg.bus_port);
+            self.nodes.insert(msg.sender.clone(), node);
+            self.save = true;
+        }
+        // pings from unknown nodes are answered, but otherwise ignored
+        let Some(sender) = self.nodes.get_mut(&msg.sender) else {
+            return;
+        };
+        if msg.kind == MessageKind::Pong {
+            sender.ping_sent = None;
+        }
+        sender.pong_received = Some(now);
+        sender.pfail = false;
+        if sender.fail {
+            println!("Cluster node {} is reachable again", sender.id);
+            sender.fail = false;
+            self.save = true;
+        }
+        if (sender.host.as_str(), sender.port, sender.bus_port) != (host, msg.port, msg.bus_port) {
+            sender.host = host.to_string();
+            sender.port = msg.port;
+            sender.bus_port = msg.bus_port;
+            self.save = true;
+        }
+        if sender.config_epoch != msg.config_epoch {
+            sender.config_epoch = msg.config_epoch;
+            self.save = true;
+        }
+        let epoch = msg.current_epoch.max(msg.config_epoch);
+        if epoch > self.current_epoch {
+            self.current_epoch = epoch;
+            self.save = true;
+        }
+
+        // the claim with the higher config epoch wins
+        for (start, end) in &msg.slots {
+            for slot in *start..=*end {
+                if self.importing.contains_key(&slot) {
+                    continue;
+                }
+                let owner = &self.slots[slot as usize];
+                let claimed = match owner {
+                    None => true,
+                    Some(owner) if *owner == msg.sender => false,
+                    Some(owner) => self
+                        .nodes
+                        .get(owner)
+                        .is_none_or(|owner| owner.config_epoch < msg.config_epoch),
+                };
+                if claimed {
+                    if owner.as_ref() == Some(&self.myself) {
+                        println!("Slot {} was taken over by {}", slot, msg.sender);
+                        self.migrating.remove(&slot);
+                    }
+                    self.slots[slot as usize] = Some(msg.sender.clone());
+                    self.save = true;
+                }
+            }
+        }
+
+        for gossip in msg.gossip {
+            if gossip.id == self.myself {
+                continue;
+            }
+            let Some(node) = self.nodes.get_mut(&gossip.id) else {
+                if !gossip.fail && !self.forgotten.contains_key(&gossip.id) {
+                    self.meet(&gossip.host, gossip.port, gossip.bus_port);
+                }
+                continue;
+            };
+            if gossip.pfail || gossip.fail {
+                node.fail_reports.insert(msg.sender.clone(), now);
+            } else {
+                node.fail_reports.remove(&msg.sender);
+            }
+            // a failure is only taken over if the node is silent here as well
+            let silent = node
+                .pong_received
+                .is_none_or(|pong| pong.elapsed() >= self.node_timeout);
+            if gossip.fail && !node.fail && silent {
+                println!(
+                    "Cluster node {} failed, as reported by {}",
+                    node.id, msg.sender
+                );
+                node.fail = true;
+                self.save = true;
+            }
+        }
+    }
+
+    /// Marks nodes which do not answer as failing, and drops handshakes
+    /// which did not complete.
+    fn check_failures(&mut self) {
+        let timeout = self.node_timeout;
+        self.nodes
+            .retain(|_, node| !node.handshake || node.created.elapsed() < timeout);
+        let voters: Vec<String> = self.voters().map(|node| node.id.clone()).collect();
+        let needed = voters.len() / 2 + 1;
+        let myself_votes = voters.contains(&self.myself);
+        for node in self.nodes.values_mut() {
+            if node.id == self.myself || node.handshake {
+                continue;
+            }
+            if node
+                .ping_sent
+                .is_some_and(|ping_sent| ping_sent.elapsed() > timeout)
+            {
+                node.pfail = true;
+            }
+            node.fail_reports
+                .retain(|_, time| time.elapsed() < timeout * 2);
+            if !node.pfail || node.fail {
+                continue;
+            }
+            let reports = node
+                .fail_reports
+                .keys()
+                .filter(|id| voters.contains(id))
+                .count()
+                + myself_votes as usize;
+            if reports >= needed {
+                println!("Cluster node {} failed", node.id);
+                node.fail = true;
+                self.save = true;
+            }
+        }
+        self.forgotten.retain(|_, time| time.elapsed() < FORGET_TTL);
+        self.update_state();
+    }
+
+    /// Writes the cluster section of `INFO`.
+    fn info(&self) -> String {
+        let (mut assigned, mut pfail, mut fail) = (0, 0, 0);
+        for owner in self.slots.iter().flatten() {
+            assigned += 1;
+            match self.nodes.get(owner) {
+                Some(node) if node.fail => fail += 1,
+                Some(node) if node.pfail => pfail += 1,
+                _ => {}
+            }
+        }
+        format!(
+            "cluster_state:{}\r\n\
+             cluster_slots_assigned:{}\r\n\
+             cluster_slots_ok:{}\r\n\
+             cluster_slots_pfail:{}\r\n\
+             cluster_slots_fail:{}\r\n\
+             cluster_known_nodes:{}\r\n\
+             cluster_size:{}\r\n\
+             cluster_current_epoch:{}\r\n\
+             cluster_my_epoch:{}\r\n",
+            if self.ok { "ok" } else { "fail" },
+            assigned,
+            assigned - pfail - fail,
+            pfail,
+            fail,
+            self.nodes.len(),
+            self.voters().count(),
+            self.current_epoch,
+            self.myself().config_epoch,
+        )
+    }
+}
+
+/// Returns the keys of `db` in `slot`. The keyspace is not indexed by slot,
+/// so this goes through all keys.
+fn keys_in_slot(db: &Keyspace, slot: u16) -> impl Iterator<Item = &str> + '_ {
+    db.iter()
+        .map(|(key, _)| key)
+        .filter(move |key| key_slot(key.as_bytes()) == slot)
+}
+
+/// Writes the cluster section of `INFO`.
+pub fn info(state: &State, out: &mut String) {
+    write!(
+        out,
+        "# Cluster\r\ncluster_enabled:{}\r\n",
+        state.cluster.is_some() as u8
+    )
+    .unwrap();
+}
+
+/// Returns the error to reply with instead of running the command `args`
+/// on this node, if any. `asking` is set if the client sent `ASKING` right
+/// before the command.
+pub fn redirect(state: &State, args: &[RedisItem], asking: bool) -> Option<RedisItem> {
+    use RedisItem::*;
+    let cluster = state.cluster.as_ref()?;
+    let name = crate::command_name(args)?;
+    match name.as_str() {
+        "select" if args.get(1).and_then(RedisItem::as_bytes) != Some(b"0") => {
+            return Some(RedisError::Custom("ERR SELECT is not allowed in cluster mode").into());
+        }
+        "move" | "swapdb" => {
+            return Some(SimpleError(format!(
+                "ERR {} is not allowed in cluster mode",
+                name.to_ascii_uppercase()
+            )));
+        }
+        _ => {}
+    }
+    let keys: Vec<&[u8]> = key_positions(args)
+        .into_iter()
+        .filter_map(|pos| args[pos].as_bytes())
+        .collect();
+    let slot = key_slot(keys.first()?);
+    if keys.iter().any(|key| key_slot(key) != slot) {
+        return Some(
+            RedisError::Custom("CROSSSLOT Keys in request don't hash to the same slot").into(),
+        );
+    }
+    if !cluster.ok {
+        return Some(RedisError::Custom("CLUSTERDOWN The cluster is down").into());
+    }
+    let address = |id: &str| {
+        let node = &cluster.nodes[id];
+        format!("{}:{}", node.host, node.port)
+    };
+    let Some(owner) = &cluster.slots[slot as usize] else {
+        return Some(RedisError::Custom("CLUSTERDOWN Hash slot not served").into());
+    };
+    if *owner != cluster.myself {
+        let asking = asking || name == "restore-asking";
+        if asking && cluster.importing.contains_key(&slot) {
+            return None;
+        }
+        return Some(SimpleError(format!("MOVED {} {}", slot, address(owner))));
+    }
+    let target = cluster
+        .migrating
+        .get(&slot)
+        .filter(|target| cluster.nodes.contains_key(*target))?;
+    // keys which are gone were already moved to the target
+    let db = state.db(0);
+    let missing = keys
+        .iter()
+        .filter(|key| !std::str::from_utf8(key).is_ok_and(|key| db.contains_key(key)))
+        .count();
+    if missing == 0 {
+        None
+    } else if missing == keys.len() {
+        Some(SimpleError(format!("ASK {} {}", slot, address(target))))
+    } else {
+        Some(RedisError::Custom("TRYAGAIN Multiple keys request during rehashing of slot").into())
+    }
+}
+
+/// Handles `ASKING`, which lets the next command of the client run on a
+/// slot this node imports.
+pub fn do_asking(state: &RefCell<State>, asking: &mut bool) -> RedisItem {
+    if state.borrow().cluster.is_none() {
+        return RedisError::Custom("ERR This instance has cluster support disabled").into();
+    }
+    *asking = true;
+    RedisItem::SimpleString("OK".to_string())
+}
+
+pub fn do_cluster(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let Some(BulkString(subcommand)) = args.pop_front() else {
+        return RedisError::InvalidArguments.into();
+    };
+    let mut state = state.borrow_mut();
+    let state = &mut *state;
+    // cluster mode only uses database 0
+    let db = if state.db == 0 {
+        &state.items
+    } else {
+        &state.dbs[0]
+    };
+    let Some(cluster) = &mut state.cluster else {
+        return RedisError::Custom("ERR This instance has cluster support disabled").into();
+    };
+    let mut strings = Vec::new();
+    for arg in args {
+        let BulkString(arg) = arg else {
+            return RedisError::InvalidArguments.into();
+        };
+        strings.push(arg);
+    }
+    let args = strings;
+    let slot_arg =
+        |arg: &str| parse_slot(arg).ok_or(RedisError::Custom("ERR Invalid or out of range slot"));
+    let node_arg = |cluster: &Cluster, id: &str| match cluster.nodes.get(id) {
+        Some(node) if !node.handshake => Ok(()),
+        _ => Err(SimpleError(format!("ERR I don't know about node {}", id))),
+    };
+    let ok = || SimpleString("OK".to_string());
+    let node_item = |node: &Node| {
+        Array(vec![
+            BulkString(node.host.clone()),
+            Integer(node.port as i64),
+            BulkString(node.id.clone()),
+        ])
+    };
+    match (subcommand.to_ascii_lowercase().as_str(), &args[..]) {
+        ("info", []) => BulkString(cluster.info()),
+        ("myid", []) => BulkString(cluster.myself.clone()),
+        ("nodes", []) => BulkString(cluster.nodes_description()),
+        ("slots", []) => {
+            let mut ranges: Vec<(u16, u16, &Node)> = Vec::new();
+            for (node, node_ranges) in cluster.shards() {
+                ranges.extend(
+                    node_ranges
+                        .into_iter()
+                        .map(|(start, end)| (start, end, node)),
+                );
+            }
+            ranges.sort_by_key(|(start, _, _)| *start);
+            Array(
+                ranges
+                    .into_iter()
+                    .map(|(start, end, node)| {
+                        Array(vec![
+                            Integer(start as i64),
+                            Integer(end as i64),
+                            node_item(node),
+                        ])
+                    })
+                    .collect(),
+            )
+        }
+        ("shards", []) => Array(
+            cluster
+                .shards()
+                .into_iter()
+                .map(|(node, ranges)| {
+                    let slots = ranges
+                        .into_iter()
+                        .flat_map(|(start, end)| [Integer(start as i64), Integer(end as i64)])
+                        .collect();
+                    let health = if node.fail { "fail" } else { "online" };
+                    let node = Map(vec![
+                        (BulkString("id".to_string()), BulkString(node.id.clone())),
+                        (BulkString("port".to_string()), Integer(node.port as i64)),
+                        (BulkString("ip".to_string()), BulkString(node.host.clone())),
+                        (
+                            BulkString("endpoint".to_string()),
+                            BulkString(node.host.clone()),
+                        ),
+                        (
+                            BulkString("role".to_string()),
+                            BulkString("master".to_string()),
+                        ),
+                        (BulkString("replication-offset".to_string()), Integer(0)),
+                        (
+                            BulkString("health".to_string()),
+                            BulkString(health.to_string()),
+                        ),
+                    ]);
+                    Map(vec![
+                        (BulkString("slots".to_string()), Array(slots)),
+                        (BulkString("nodes".to_string()), Array(vec![node])),
+                    ])
+                })
+                .collect(),
+        ),
+        ("keyslot", [key]) => Integer(key_slot(key.as_bytes()) as i64),
+        ("countkeysinslot", [slot]) => {
+            let slot = match slot_arg(slot) {
+                Ok(slot) => slot,
+                Err(err) => return err.into(),
+            };
+            Integer(keys_in_slot(db, slot).count() as i64)
+        }
+        ("getkeysinslot", [slot, count]) => {
+            let slot = match slot_arg(slot) {
+                Ok(slot) => slot,
+                Err(err) => return err.into(),
+            };
+            let Some(count) = parse_int(count).and_then(|count| usize::try_from(count).ok()) else {
+                return RedisError::Custom("ERR Invalid number of keys").into();
+            };
+            let keys = keys_in_slot(db, slot)
+                .take(count)
+                .map(|key| BulkString(key.to_string()));
+            Array(keys.collect())
+        }
+        ("meet", [host, port, ref bus_port @ ..]) if bus_port.len() <= 1 => {
+            let port = port.parse::<u16>().ok();
+            let bus_port = match bus_port.first() {
+                Some(bus_port) => bus_port.parse::<u16>().ok(),
+                None => port.and_then(|port| port.checked_add(BUS_PORT_OFFSET)),
+            };
+            let (Some(port), Some(bus_port), Ok(_)) = (port, bus_port, host.parse::<IpAddr>())
+            else {
+                return SimpleError(format!(
+                    "ERR Invalid node address specified: {}:{}",
+                    host,
+                    port.map_or(String::new(), |port| port.to_string())
+                ));
+            };
+            cluster.meet(host, port, bus_port);
+            ok()
+        }
+        ("forget", [id]) => {
+            if *id == cluster.myself {
+                return RedisError::Custom("ERR I tried hard but I can't forget myself...").into();
+            }
+            if cluster.nodes.remove(id).is_none() {
+                return SimpleError(format!("ERR Unknown node {}", id));
+            }
+            for owner in &mut cluster.slots {
+                if owner.as_ref() == Some(id) {
+                    *owner = None;
+                }
+            }
+            cluster.forgotten.insert(id.clone(), Instant::now());
+            cluster.save = true;
+            ok()
+        }
+        ("addslots" | "delslots", slots) if !slots.is_empty() => {
+            let slots: Result<Vec<(u16, u16)>, _> = slots
+                .iter()
+                .map(|slot| slot_arg(slot).map(|slot| (slot, slot)))
+                .collect();
+            match slots {
+                Ok(slots) => assign_slots(cluster, &subcommand, &slots),
+                Err(err) => err.into(),
+            }
+        }
+        ("addslotsrange" | "delslotsrange", ranges)
+            if !ranges.is_empty() && ranges.len().is_multiple_of(2) =>
+        {
+            let mut slots = Vec::new();
+            for range in ranges.chunks(2) {
+                match (slot_arg(&range[0]), slot_arg(&range[1])) {
+                    (Ok(start), Ok(end)) if start <= end => slots.push((start, end)),
+                    (Ok(start), Ok(end)) => {
+                        return SimpleError(format!(
+                            "ERR start slot number {} is greater than end slot number {}",
+                            start, end
+                        ))
+                    }
+                    (Err(err), _) | (_, Err(err)) => return err.into(),
+                }
+            }
+            let subcommand = subcommand.to_ascii_lowercase().replace("range", "");
+            assign_slots(cluster, &subcommand, &slots)
+        }
+        ("setslot", [slot, action, ref node @ ..]) if node.len() <= 1 => {
+            let slot = match slot_arg(slot) {
+                Ok(slot) => slot,
+                Err(err) => return err.into(),
+            };
+            let owner = cluster.slots[slot as usize].clone();
+            let owned = owner.as_ref() == Some(&cluster.myself);
+            match (action.to_ascii_lowercase().as_str(), node) {
+                ("migrating", [target]) => {
+                    if !owned {
+                        return SimpleError(format!("ERR I'm not the owner of hash slot {}", slot));
+                    }
+                    if let Err(err) = node_arg(cluster, target) {
+                        return err;
+                    }
+                    cluster.migrating.insert(slot, target.clone());
+                }
+                ("importing", [source]) => {
+                    if owned {
+                        return SimpleError(format!(
+                            "ERR I'm already the owner of hash slot {}",
+                            slot
+                        ));
+                    }
+                    if let Err(err) = node_arg(cluster, source) {
+                        return err;
+                    }
+                    cluster.importing.insert(slot, source.clone());
+                }
+                ("stable", []) => {
+                    cluster.migrating.remove(&slot);
+                    cluster.importing.remove(&slot);
+                }
+                ("node", [id]) => {
+                    if let Err(err) = node_arg(cluster, id) {
+                        return err;
+                    }
+                    if owned && *id != cluster.myself && keys_in_slot(db, slot).next().is_some() {
+                        return SimpleError(format!("ERR Can't assign hashslot {} to a different node while I still hold keys for this hash slot.", slot));
+                    }
+                    if *id != cluster.myself {
+                        cluster.migrating.remove(&slot);
+                    }
+                    if *id == cluster.myself && cluster.importing.remove(&slot).is_some() {
+                        // the new owner needs a higher epoch than the old one,
+                        // so that its claim wins
+                        cluster.current_epoch += 1;
+                        let epoch = cluster.current_epoch;
+                        cluster.nodes.get_mut(id).unwrap().config_epoch = epoch;
+                        println!("Took over slot {} with config epoch {}", slot, epoch);
+                    }
+                    cluster.slots[slot as usize] = Some(id.clone());
+                }
+                _ => return RedisError::Syntax.into(),
+            }
+            cluster.save = true;
+            ok()
+        }
+        _ => SimpleError(format!(
+            "ERR unknown subcommand or wrong number of arguments for '{}'",
+            subcommand
+        )),
+    }
+}
+
+/// Handles `ADDSLOTS` and `DELSLOTS`, given the slots as ranges.
+fn assign_slots(cluster: &mut Cluster, subcommand: &str, ranges: &[(u16, u16)]) -> RedisItem {
+    let add = subcommand.eq_ignore_ascii_case("addslots");
+    for (start, end) in ranges {
+        for slot in *start..=*end {
+            let owner = &cluster.slots[slot as usize];
+            if add && owner.is_some() {
+                return RedisItem::SimpleError(format!("ERR Slot {} is already busy", slot));
+            }
+            if !add && owner.is_none() {
+                return RedisItem::SimpleError(format!("ERR Slot {} is already unassigned", slot));
+            }
+        }
+    }
+    let owner = add.then(|| cluster.myself.clone());
+    for (start, end) in ranges {
+        for slot in *start..=*end {
+            cluster.slots[slot as usize] = owner.clone();
+            cluster.importing.remove(&slot);
+            cluster.migrating.remove(&slot);
+        }
+    }
+    cluster.save = true;
+    RedisItem::SimpleString("OK".to_string())
+}
+
+/// Sends `msg` to the bus at `host` and `port`, returning the reply.
+async fn send_message(host: &str, port: u16, msg: &RedisItem) -> io::Result<RedisItem> {
+    let exchange = async {
+        let mut stream = smol::net::TcpStream::connect((host, port)).await?;
+        let mut buf = Vec::new();
+        msg.serialize(&mut buf);
+        stream.write_all(&buf).await?;
+        let mut reader = BufReader::new(stream);
+        ItemParser::new()
+            .parse(&mut reader)
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::InvalidData))
+    };
+    smol::future::or(exchange, async {
+        Timer::after(BUS_TIMEOUT).await;
+        Err(io::ErrorKind::TimedOut.into())
+    })
+    .await
+}
+
+/// Pings the other nodes, detects failures and saves the configuration.
+pub async fn cluster_worker(state: &RefCell<State>) {
+    loop {
+        Timer::after(Duration::from_millis(100)).await;
+        let due = {
+            let mut state = state.borrow_mut();
+            if state.stop {
+                break;
+            }
+            let Some(cluster) = &mut state.cluster else {
+                break;
+            };
+            let myself = cluster.myself.clone();
+            let mut due = Vec::new();
+            for node in cluster.nodes.values_mut() {
+                if node.id == myself
+                    || node
+                        .last_ping
+                        .is_some_and(|ping| ping.elapsed() < PING_PERIOD)
+                {
+                    continue;
+                }
+                let now = Instant::now();
+                node.last_ping = Some(now);
+                node.ping_sent.get_or_insert(now);
+                let kind = if node.handshake {
+                    MessageKind::Meet
+                } else {
+                    MessageKind::Ping
+                };
+                due.push((node.id.clone(), node.host.clone(), node.bus_port, kind));
+            }
+            due.into_iter()
+                .map(|(id, host, port, kind)| (id, host, port, cluster.message(kind).to_item()))
+                .collect::<Vec<_>>()
+        };
+        for (id, host, port, msg) in due {
+            let Ok(reply) = send_message(&host, port, &msg).await else {
+                continue;
+            };
+            let mut state = state.borrow_mut();
+            let Some(cluster) = &mut state.cluster else {
+                break;
+            };
+            if let Some(reply) = Message::from_item(reply) {
+                if cluster.nodes.contains_key(&id) {
+                    cluster.process(reply, &host, Some(&id));
+                }
+            }
+        }
+        let mut state = state.borrow_mut();
+        let Some(cluster) = &mut state.cluster else {
+            break;
+        };
+        cluster.check_failures();
+        if cluster.save {
+            if let Err(err) = cluster.save_config() {
+                println!("Failed to save the cluster configuration: {}", err);
+            }
+        }
+    }
+}
+
+/// Answers the messages other nodes send on the cluster bus.
+pub async fn bus_worker(listener: Async<TcpListener>, state: &RefCell<State>) -> io::Result<()> {
+    loop {
+        let (stream, peer_addr) = listener.accept().await?;
+        // every connection carries a single ping, which is answered right away
+        let exchange = async {
+            let mut reader = BufReader::new(&stream);
+            let msg = ItemParser::new().parse(&mut reader).await.ok()?;
+            let msg = Message::from_item(msg)?;
+            let reply = {
+                let mut state = state.borrow_mut();
+                let cluster = state.cluster.as_mut()?;
+                cluster.process(msg, &peer_addr.ip().to_string(), None);
+                cluster.message(MessageKind::Pong).to_item()
+            };
+            let mut buf = Vec::new();
+            reply.serialize(&mut buf);
+            let mut writer = &stream;
+            writer.write_all(&buf).await.ok()
+        };
+        smol::future::or(exchange, async {
+            Timer::after(BUS_TIMEOUT).await;
+            None
+        })
+        .await;
+    }
+}
+
+/// Handles `MIGRATE`, which moves keys to another node by restoring them
+/// there, where `db` is the database of the client.
+pub async fn do_migrate(
+    mut args: VecDeque<RedisItem>,
+    state: &RefCell<State>,
+    db: usize,
+) -> RedisItem {
+    use RedisItem::*;
+    let mut strings = Vec::new();
+    while let Some(arg) = args.pop_front() {
+        let BulkString(arg) = arg else {
+            return RedisError::InvalidArguments.into();
+        };
+        strings.push(arg);
+    }
+    let [host, port, key, dest_db, timeout, options @ ..] = &strings[..] else {
+        return RedisError::InvalidArguments.into();
+    };
+    let (Some(port), Some(dest_db), Some(timeout)) = (
+        port.parse::<u16>().ok(),
+        parse_int(dest_db),
+        parse_int(timeout),
+    ) else {
+        return RedisError::NotInteger.into();
+    };
+    let (mut copy, mut replace) = (false, false);
+    let mut keys = vec![key.clone()];
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match option.to_ascii_lowercase().as_str() {
+            "copy" => copy = true,
+            "replace" => replace = true,
+            "keys" => {
+                if !key.is_empty() {
+                    return RedisError::Custom("ERR When using MIGRATE KEYS option, the key argument must be set to the empty string").into();
+                }
+                keys = options.by_ref().cloned().collect();
+            }
+            _ => return RedisError::Syntax.into(),
+        }
+    }
+    let timeout = Duration::from_millis(if timeout <= 0 { 1000 } else { timeout as u64 });
+
+    // the keys which exist, with their tags to detect changes while waiting
+    let mut dumps = Vec::new();
+    {
+        let mut state = state.borrow_mut();
+        state.select(db);
+        for key in keys {
+            let Some((val, tag)) = state.items.get(&key) else {
+                continue;
+            };
+            let ttl = state.expire.get_expiry(*tag).map_or(0, |time| {
+                time.saturating_duration_since(Instant::now())
+                    .as_millis()
+                    .max(1) as u64
+            });
+            dumps.push((key, *tag, rdb::dump(val), ttl));
+        }
+    }
+    if dumps.is_empty() {
+        return SimpleString("NOKEY".to_string());
+    }
+    let command = |args: Vec<RedisItem>, buf: &mut Vec<u8>| Array(args).serialize(buf);
+    let mut select = Vec::new();
+    if dest_db != 0 {
+        let args = vec![
+            BulkString("select".to_string()),
+            BulkString(dest_db.to_string()),
+        ];
+        command(args, &mut select);
+    }
+    let mut buf = Vec::new();
+    for (key, _, payload, ttl) in &dumps {
+        let mut restore = vec![
+            BulkString("restore-asking".to_string()),
+            BulkString(key.clone()),
+            BulkString(ttl.to_string()),
+            RedisItem::bulk(payload.clone()),
+        ];
+        if replace {
+            restore.push(BulkString("replace".to_string()));
+        }
+        command(restore, &mut buf);
+    }
+    let exchange = async {
+        let mut writer = smol::net::TcpStream::connect((host.as_str(), port))
+            .await
+            .map_err(|_| "IOERR error or timeout connecting to the client".to_string())?;
+        let mut reader = BufReader::new(writer.clone());
+        let mut parser = ItemParser::new();
+        // the keys are only sent once the target is known to have selected
+        // the database, so that they cannot end up in another one
+        if !select.is_empty() {
+            writer
+                .write_all(&select)
+                .await
+                .map_err(|_| "IOERR error or timeout writing to target instance".to_string())?;
+            let reply = parser
+                .parse(&mut reader)
+                .await
+                .map_err(|_| "IOERR error or timeout reading to target instance".to_string())?;
+            if let SimpleError(err) = reply {
+                return Err(format!("ERR Target instance replied with error: {}", err));
+            }
+        }
+        writer
+            .write_all(&buf)
+            .await
+            .map_err(|_| "IOERR error or timeout writing to target instance".to_string())?;
+        let mut results = Vec::new();
+        for _ in 0..dumps.len() {
+            let reply = parser
+                .parse(&mut reader)
+                .await
+                .map_err(|_| "IOERR error or timeout reading to target instance".to_string())?;
+            results.push(reply);
+        }
+        Ok(results)
+    };
+    let results = smol::future::or(exchange, async {
+        Timer::after(timeout).await;
+        Err("IOERR error or timeout reading to target instance".to_string())
+    })
+    .await;
+    let results = match results {
+        Ok(results) => results,
+        Err(err) => return SimpleError(err),
+    };
+    let mut state = state.borrow_mut();
+    state.select(db);
+    let mut error = None;
+    for ((key, tag, _, _), result) in dumps.into_iter().zip(results) {
+        if let SimpleError(err) = result {
+            error.get_or_insert(err);
+            continue;
+        }
+        // keys which were changed in the meantime are kept
+        if copy || state.items.get(&key).map(|(_, t)| *t) != Some(tag) {
+            continue;
+        }
+        state.items.remove(&key);
+        state.propagate(vec![BulkString("del".to_string()), BulkString(key)]);
+    }
+    state.aof.flush();
+    match error {
+        Some(err) => SimpleError(format!("ERR Target instance replied with error: {}", err)),
+        None => SimpleString("OK".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_config() {
+        let config = "\
+e7d1eecce10fd6bb5eb35b9f99a514335d9ba9ca 127.0.0.1:30001@40001 myself,master - 0 0 2 connected 0-5460 [5461-<-292f8b365bb7edb5e285caf0b7e6ddc7265d2f4f]
+292f8b365bb7edb5e285caf0b7e6ddc7265d2f4f 127.0.0.1:30002@40002 master,fail - 0 1426238316232 3 disconnected 5461 5463-10922 [0->-x]
+vars currentEpoch 6 lastVoteEpoch 0
+";
+        let cluster =
+            Cluster::from_config(config, PathBuf::from("nodes.conf"), DEFAULT_NODE_TIMEOUT)
+                .unwrap();
+        assert_eq!(cluster.myself, "e7d1eecce10fd6bb5eb35b9f99a514335d9ba9ca");
+        assert_eq!(cluster.current_epoch, 6);
+        assert_eq!(cluster.slot_ranges(&cluster.myself), vec![(0, 5460)]);
+        let other = "292f8b365bb7edb5e285caf0b7e6ddc7265d2f4f";
+        assert_eq!(
+            cluster.slot_ranges(other),
+            vec![(5461, 5461), (5463, 10922)]
+        );
+        assert_eq!(
+            cluster.importing.get(&5461).map(String::as_str),
+            Some(other)
+        );
+        assert_eq!(cluster.migrating.get(&0).map(String::as_str), Some("x"));
+        let node = &cluster.nodes[other];
+        assert_eq!(
+            (node.host.as_str(), node.port, node.bus_port),
+            ("127.0.0.1", 30002, 40002)
+        );
+        assert!(node.fail);
+        assert_eq!(node.config_epoch, 3);
+        assert!(!cluster.ok);
+
+        // the saved configuration reads back the same
+        let saved = cluster.nodes_description() + "vars currentEpoch 6 lastVoteEpoch 0\n";
+        let reread =
+            Cluster::from_config(&saved, PathBuf::from("nodes.conf"), DEFAULT_NODE_TIMEOUT)
+                .unwrap();
+        assert_eq!(reread.slots, cluster.slots);
+        assert_eq!(reread.migrating, cluster.migrating);
+        assert_eq!(reread.importing, cluster.importing);
+
+        assert!(Cluster::from_config(
+            "x 127.0.0.1:1@2 master - 0 0 0 connected\n",
+            PathBuf::new(),
+            DEFAULT_NODE_TIMEOUT
+        )
+        .is_err());
+        assert!(Cluster::from_config(
+            "x 127.0.0.1:1@2 myself,master - 0 0 0 connected 0-99999\n",
+            PathBuf::new(),
+            DEFAULT_NODE_TIMEOUT
+        )
+        .is_err());
+    }
+
+    #[test]
+    pub fn test_message() {
+        let msg = Message {
+            kind: MessageKind::Meet,
+            sender: "a".to_string(),
+            port: 7000,
+            bus_port: 17000,
+            current_epoch: 3,
+            config_epoch: 2,
+            slots: vec![(0, 100), (200, 200)],
+            gossip: vec![Gossip {
+                id: "b".to_string(),
+                host: "127.0.0.1".to_string(),
+                port: 7001,
+                bus_port: 17001,
+                pfail: true,
+                fail: false,
+            }],
+        };
+        let parsed = Message::from_item(msg.to_item()).unwrap();
+        assert_eq!(parsed.kind, MessageKind::Meet);
+        assert_eq!(parsed.sender, "a");
+        assert_eq!((parsed.port, parsed.bus_port), (7000, 17000));
+        assert_eq!((parsed.current_epoch, parsed.config_epoch), (3, 2));
+        assert_eq!(parsed.slots, msg.slots);
+        assert_eq!(parsed.gossip.len(), 1);
+        assert!(parsed.gossip[0].pfail && !parsed.gossip[0].fail);
+        assert_eq!(parsed.gossip[0].port, 7001);
+        assert!(Message::from_item(RedisItem::Array(vec![])).is_none());
+    }
+
+    #[test]
+    pub fn test_migrate_select_error() {
+        use std::io::Read;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port().to_string();
+        let target = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let select = b"*2\r\n$6\r\nselect\r\n$2\r\n99\r\n";
+            let mut received = vec![0; select.len()];
+            stream.read_exact(&mut received).unwrap();
+            stream
+                .write_all(b"-ERR DB index is out of range\r\n")
+                .unwrap();
+            // anything sent after the error arrives before the close
+            stream.read_to_end(&mut received).unwrap();
+            (received, select.to_vec())
+        });
+        let state = RefCell::new(State::new(16));
+        crate::run_command(&state, &mut 0, &["set", "k", "v"]);
+        let reply = crate::run_command(
+            &state,
+            &mut 0,
+            &["migrate", "127.0.0.1", &port, "k", "99", "1000"],
+        );
+        assert_eq!(
+            reply,
+            RedisItem::SimpleError(
+                "ERR Target instance replied with error: ERR DB index is out of range".to_string()
+            )
+        );
+        let (received, select) = target.join().unwrap();
+        assert_eq!(received, select);
+        assert!(state.borrow().items.get("k").is_some());
+    }
+}
@@ -4,11 +4,13 @@ use std::{
     future::Future,
     pin::Pin,
     task::{Context, Poll, Waker},
-    time::Instant, cmp::Reverse,
+    time::{Instant, SystemTime, UNIX_EPOCH}, cmp::Reverse,
 };
 
+use feredis_core::item::RedisItem;
 use smol::Timer;
 
+use crate::value::Value;
 use crate::State;
 
 pub async fn expire_worker(state: &RefCell<State>) {
@@ -18,17 +20,10 @@ pub async fn expire_worker(state: &RefCell<State>) {
         println!("Expire worker wakeup");
         let mut state = state.borrow_mut();
 
-        // pop all expired items
-        while let Some(exp) = state.expire.try_pop() {
-            // if the item is the latest version, remove it
-            if let Some(tag) = state.items.get(&exp.key).map(|it| it.1) {
-                if tag == exp.tag {
-                    println!("Expired: {}", &exp.key);
-                    state.items.remove(&exp.key);
-                } else {
-                    println!("Skipping: {} (not latest)", &exp.key);
-                }
-            }
+        // a replica keeps expired items until its master deletes them, so
+        // that both hold the same keys
+        if !state.repl.is_replica() {
+            remove_expired(&mut state);
         }
         if state.stop {
             break;
@@ -36,6 +31,76 @@ pub async fn expire_worker(state: &RefCell<State>) {
     }
 }
 
+/// Removes all items whose expiry time has passed, propagating each removal
+/// as a deletion.
+pub fn remove_expired(state: &mut State) {
+    // pop all expired items
+    while let Some(exp) = state.expire.try_pop() {
+        // if the item is the latest version, remove it
+        let db = state.db_mut(exp.db);
+        if let Some(tag) = db.get(&exp.key).map(|it| it.1) {
+            if tag == exp.tag {
+                println!("Expired: {}", &exp.key);
+                db.remove(&exp.key);
+                // replicas and the AOF see the expiry as a deletion
+                let args = vec![
+                    RedisItem::BulkString("del".to_string()),
+                    RedisItem::BulkString(exp.key),
+                ];
+                state.propagate_in(exp.db, args);
+            } else {
+                println!("Skipping: {} (not latest)", &exp.key);
+            }
+        }
+    }
+    state.aof.flush();
+}
+
+/// Items taken out of the keyspace while a replica serves a read, along with
+/// their databases.
+pub type Hidden = Vec<(usize, String, (Value, u64))>;
+
+/// Takes the items whose expiry time has passed out of the keyspace. A
+/// replica keeps them until its master deletes them, but reads must not see
+/// them, so they are put back with `restore_hidden` once a read is served.
+pub fn hide_expired(state: &mut State) -> Hidden {
+    let now = Instant::now();
+    let mut passed = Vec::new();
+    while state.expire.items.peek().is_some_and(|exp| exp.0.time <= now) {
+        passed.push(state.expire.items.pop().unwrap().0);
+    }
+    let mut hidden = Vec::new();
+    for exp in passed {
+        let db = state.db_mut(exp.db);
+        if db.get(&exp.key).is_some_and(|(_, tag)| *tag == exp.tag) {
+            let item = db.remove(&exp.key).unwrap();
+            hidden.push((exp.db, exp.key.clone(), item));
+            // the item still expires once this server is a master
+            state.expire.items.push(Reverse(exp));
+        } else {
+            // the item was replaced or deleted since
+            state.expire.expiries.remove(&exp.tag);
+        }
+    }
+    hidden
+}
+
+/// Puts the items taken out by `hide_expired` back.
+pub fn restore_hidden(state: &mut State, hidden: Hidden) {
+    for (db, key, item) in hidden {
+        state.db_mut(db).insert(key, item);
+    }
+}
+
+/// Converts an expiry time into a unix timestamp in milliseconds.
+pub fn to_unix_ms(time: Instant) -> u64 {
+    let remaining = time.saturating_duration_since(Instant::now());
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    (now + remaining).as_millis() as u64
+}
+
 #[derive(Debug)]
 pub struct Expire {
     items: BinaryHeap<Reverse<Expiry>>,
@@ -44,6 +109,12 @@ pub struct Expire {
     updated: bool,
 }
 
+impl Default for Expire {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Expire {
     pub fn new() -> Self {
         Self {
@@ -54,18 +125,25 @@ impl Expire {
         }
     }
 
-    pub fn push(&mut self, key: String, tag: u64, time: Instant) {
+    /// Schedules the item with the given tag, stored at `key` in database
+    /// `db`, to expire at `time`.
+    pub fn push(&mut self, db: usize, key: String, tag: u64, time: Instant) {
         // get the previously closest expiry time
         let prev_exp = self.items.peek().map(|e| e.0.time);
         // add new expire for key
-        self.items.push(Reverse(Expiry { key, time, tag }));
+        self.items.push(Reverse(Expiry { db, key, time, tag }));
         self.expiries.insert(tag, time);
         // if the new expiry time is closer than the previous one, wake the worker
         if prev_exp.map(|e| e > time).unwrap_or(true) {
-            self.updated = true;
-            if let Some(waker) = self.waker.take() {
-                waker.wake();
-            }
+            self.wake();
+        }
+    }
+
+    /// Wakes the worker, which then waits for the closest expiry again.
+    pub fn wake(&mut self) {
+        self.updated = true;
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
         }
     }
 
@@ -81,18 +159,31 @@ impl Expire {
         }
     }
 
+    /// Moves the pending expiries of database `a` to database `b` and the
+    /// other way around, following the keys when the databases are swapped.
+    pub fn swap_dbs(&mut self, a: usize, b: usize) {
+        let mut items = std::mem::take(&mut self.items).into_vec();
+        for Reverse(exp) in &mut items {
+            if exp.db == a {
+                exp.db = b;
+            } else if exp.db == b {
+                exp.db = a;
+            }
+        }
+        self.items = BinaryHeap::from(items);
+    }
+
     pub fn get_expiry(&self, tag: u64) -> Option<Instant> {
         self.expiries.get(&tag).copied()
     }
 }
 
 fn until_expire(state: &RefCell<State>) -> impl Future<Output = ()> + '_ {
-    let timer = state
-        .borrow()
-        .expire
-        .items
-        .peek()
-        .map_or_else(|| Timer::never(), |e| Timer::at(e.0.time));
+    let state = state.borrow();
+    let timer = match state.expire.items.peek() {
+        Some(e) if !state.repl.is_replica() => Timer::at(e.0.time),
+        _ => Timer::never(),
+    };
     async {
         timer.await;
     }
@@ -133,6 +224,7 @@ impl<'a> Future for UpdateFuture<'a> {
 
 #[derive(Debug)]
 struct Expiry {
+    db: usize,
     key: String,
     tag: u64,
     time: Instant,
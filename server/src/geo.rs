@@ -0,0 +1,726 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+
+use feredis_core::item::RedisItem;
+
+use crate::skiplist::ScoreRange;
+use crate::value::{parse_float, parse_int};
+use crate::zset::{self, get_zset, SortedSet};
+use crate::{RedisError, State};
+
+/// The number of bits used for each of longitude and latitude in a geohash.
+const GEO_STEP: u32 = 26;
+const LON_MIN: f64 = -180.0;
+const LON_MAX: f64 = 180.0;
+/// The latitudes covered by the web mercator projection used by redis.
+const LAT_MIN: f64 = -85.05112878;
+const LAT_MAX: f64 = 85.05112878;
+const EARTH_RADIUS: f64 = 6372797.560856;
+const MERCATOR_MAX: f64 = 20037726.37;
+
+const GEO_ALPHABET: &[u8; 32] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+
+/// Spreads the bits of `lat` and `lon` so that latitude bits take the even
+/// positions and longitude bits the odd ones.
+fn interleave(lat: u32, lon: u32) -> u64 {
+    fn spread(val: u32) -> u64 {
+        let mut val = val as u64;
+        val = (val | (val << 16)) & 0x0000_ffff_0000_ffff;
+        val = (val | (val << 8)) & 0x00ff_00ff_00ff_00ff;
+        val = (val | (val << 4)) & 0x0f0f_0f0f_0f0f_0f0f;
+        val = (val | (val << 2)) & 0x3333_3333_3333_3333;
+        (val | (val << 1)) & 0x5555_5555_5555_5555
+    }
+    spread(lat) | (spread(lon) << 1)
+}
+
+fn deinterleave(bits: u64) -> (u32, u32) {
+    fn squash(val: u64) -> u32 {
+        let mut val = val & 0x5555_5555_5555_5555;
+        val = (val | (val >> 1)) & 0x3333_3333_3333_3333;
+        val = (val | (val >> 2)) & 0x0f0f_0f0f_0f0f_0f0f;
+        val = (val | (val >> 4)) & 0x00ff_00ff_00ff_00ff;
+        val = (val | (val >> 8)) & 0x0000_ffff_0000_ffff;
+        ((val | (val >> 16)) & 0x0000_0000_ffff_ffff) as u32
+    }
+    (squash(bits), squash(bits >> 1))
+}
+
+/// Encodes a position into a geohash of `step` bits per coordinate, where
+/// latitudes are mapped from `lat_min..lat_max`.
+fn encode(lon: f64, lat: f64, lat_min: f64, lat_max: f64, step: u32) -> u64 {
+    let scale = (1u64 << step) as f64;
+    let lat_offset = (lat - lat_min) / (lat_max - lat_min) * scale;
+    let lon_offset = (lon - LON_MIN) / (LON_MAX - LON_MIN) * scale;
+    interleave(lat_offset as u32, lon_offset as u32)
+}
+
+/// Returns the sorted set score of a position.
+fn score(lon: f64, lat: f64) -> f64 {
+    encode(lon, lat, LAT_MIN, LAT_MAX, GEO_STEP) as f64
+}
+
+/// Decodes a sorted set score into the center of its geohash cell.
+fn decode(score: f64) -> (f64, f64) {
+    let (lat, lon) = deinterleave(score as u64);
+    let scale = (1u64 << GEO_STEP) as f64;
+    let center = |index: u32, min: f64, max: f64| {
+        let low = min + index as f64 / scale * (max - min);
+        let high = min + (index as f64 + 1.0) / scale * (max - min);
+        ((low + high) / 2.0).clamp(min, max)
+    };
+    (center(lon, LON_MIN, LON_MAX), center(lat, LAT_MIN, LAT_MAX))
+}
+
+/// The great-circle distance between two positions in meters.
+fn distance(lon1: f64, lat1: f64, lon2: f64, lat2: f64) -> f64 {
+    let (lat1, lat2) = (lat1.to_radians(), lat2.to_radians());
+    let u = ((lat2 - lat1) / 2.0).sin();
+    let v = ((lon2.to_radians() - lon1.to_radians()) / 2.0).sin();
+    2.0 * EARTH_RADIUS * (u * u + lat1.cos() * lat2.cos() * v * v).sqrt().asin()
+}
+
+/// Returns the standard 11 character geohash of a score.
+fn geohash_string(score: f64) -> String {
+    let (lon, lat) = decode(score);
+    // unlike the scores, standard geohashes cover all latitudes
+    let bits = encode(lon, lat, -90.0, 90.0, GEO_STEP);
+    (0..11)
+        .map(|i| {
+            let index = if i == 10 {
+                0
+            } else {
+                (bits >> (52 - (i + 1) * 5)) & 0x1f
+            };
+            GEO_ALPHABET[index as usize] as char
+        })
+        .collect()
+}
+
+fn parse_position(lon: &str, lat: &str) -> Result<(f64, f64), RedisItem> {
+    let (Some(lon), Some(lat)) = (parse_float(lon), parse_float(lat)) else {
+        return Err(RedisError::NotFloat.into());
+    };
+    if !(LON_MIN..=LON_MAX).contains(&lon) || !(LAT_MIN..=LAT_MAX).contains(&lat) {
+        return Err(RedisItem::SimpleError(format!(
+            "ERR invalid longitude,latitude pair {:.6},{:.6}",
+            lon, lat
+        )));
+    }
+    Ok((lon, lat))
+}
+
+/// Parses a distance unit into the number of meters it stands for.
+fn parse_unit(unit: &str) -> Result<f64, RedisError> {
+    match unit.to_ascii_lowercase().as_str() {
+        "m" => Ok(1.0),
+        "km" => Ok(1000.0),
+        "ft" => Ok(0.3048),
+        "mi" => Ok(1609.34),
+        _ => Err(RedisError::Custom(
+            "ERR unsupported unit provided. please use M, KM, FT, MI",
+        )),
+    }
+}
+
+/// Formats a distance the way redis replies with it.
+fn distance_reply(distance: f64) -> RedisItem {
+    RedisItem::BulkString(format!("{:.4}", distance))
+}
+
+pub fn do_geoadd(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let Some(key) = args.pop_front() else {
+        return RedisError::InvalidArguments.into();
+    };
+    // the members are added through ZADD, just like redis does
+    let mut zadd_args = VecDeque::from([key]);
+    while let Some(BulkString(arg)) = args.front() {
+        if !matches!(arg.to_ascii_lowercase().as_str(), "nx" | "xx" | "ch") {
+            break;
+        }
+        zadd_args.push_back(args.pop_front().unwrap());
+    }
+    if args.is_empty() || !args.len().is_multiple_of(3) {
+        return RedisError::Syntax.into();
+    }
+    while let (Some(BulkString(lon)), Some(BulkString(lat)), Some(member)) =
+        (args.pop_front(), args.pop_front(), args.pop_front())
+    {
+        match parse_position(&lon, &lat) {
+            Ok((lon, lat)) => {
+                zadd_args.push_back(BulkString((score(lon, lat) as u64).to_string()));
+                zadd_args.push_back(member);
+            }
+            Err(err) => return err,
+        }
+    }
+    if !args.is_empty() {
+        return RedisError::InvalidArguments.into();
+    }
+    zset::do_zadd(zadd_args, state)
+}
+
+pub fn do_geodist(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let (Some(BulkString(key)), Some(BulkString(a)), Some(BulkString(b))) =
+        (args.pop_front(), args.pop_front(), args.pop_front())
+    else {
+        return RedisError::InvalidArguments.into();
+    };
+    let unit = match args.pop_front() {
+        Some(BulkString(unit)) if args.is_empty() => match parse_unit(&unit) {
+            Ok(unit) => unit,
+            Err(err) => return err.into(),
+        },
+        None => 1.0,
+        _ => return RedisError::Syntax.into(),
+    };
+    let state = state.borrow();
+    let zset = match get_zset(&state, &key) {
+        Ok(Some(zset)) => zset,
+        Ok(None) => return Null,
+        Err(err) => return err.into(),
+    };
+    let (Some(a), Some(b)) = (zset.score(&a), zset.score(&b)) else {
+        return Null;
+    };
+    let ((lon1, lat1), (lon2, lat2)) = (decode(a), decode(b));
+    distance_reply(distance(lon1, lat1, lon2, lat2) / unit)
+}
+
+/// Looks up the scores of the members given as arguments, replying with
+/// `reply` for each member which exists and with null for the others.
+fn member_replies(
+    mut args: VecDeque<RedisItem>,
+    state: &RefCell<State>,
+    reply: impl Fn(f64) -> RedisItem,
+) -> RedisItem {
+    use RedisItem::*;
+    let Some(BulkString(key)) = args.pop_front() else {
+        return RedisError::InvalidArguments.into();
+    };
+    let state = state.borrow();
+    let zset = match get_zset(&state, &key) {
+        Ok(zset) => zset,
+        Err(err) => return err.into(),
+    };
+    let mut res = Vec::with_capacity(args.len());
+    for arg in args {
+        let BulkString(member) = arg else {
+            return RedisError::InvalidArguments.into();
+        };
+        res.push(
+            zset.and_then(|zset| zset.score(&member))
+                .map_or(Null, &reply),
+        );
+    }
+    Array(res)
+}
+
+pub fn do_geohash(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    member_replies(args, state, |score| {
+        RedisItem::BulkString(geohash_string(score))
+    })
+}
+
+pub fn do_geopos(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    member_replies(args, state, |score| {
+        let (lon, lat) = decode(score);
+        RedisItem::Array(vec![RedisItem::Double(lon), RedisItem::Double(lat)])
+    })
+}
+
+#[derive(Debug, Clone)]
+enum Center {
+    Member(String),
+    Position(f64, f64),
+}
+
+/// The area searched, in meters.
+#[derive(Debug, Clone, Copy)]
+enum Shape {
+    Radius(f64),
+    Box { width: f64, height: f64 },
+}
+
+/// A parsed `GEOSEARCH`, `GEOSEARCHSTORE` or `GEORADIUS` variant.
+#[derive(Debug, Clone)]
+struct SearchSpec {
+    center: Option<Center>,
+    shape: Option<Shape>,
+    /// the number of meters in the unit of the shape
+    unit: f64,
+    desc: Option<bool>,
+    count: Option<usize>,
+    any: bool,
+    with_coord: bool,
+    with_dist: bool,
+    with_hash: bool,
+    store: Option<String>,
+    store_dist: bool,
+}
+
+/// Which command a search is parsed for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SearchCommand {
+    Search,
+    SearchStore,
+    Radius,
+    RadiusReadOnly,
+}
+
+impl SearchSpec {
+    fn new() -> Self {
+        SearchSpec {
+            center: None,
+            shape: None,
+            unit: 1.0,
+            desc: None,
+            count: None,
+            any: false,
+            with_coord: false,
+            with_dist: false,
+            with_hash: false,
+            store: None,
+            store_dist: false,
+        }
+    }
+
+    fn parse(
+        mut self,
+        mut args: VecDeque<RedisItem>,
+        command: SearchCommand,
+    ) -> Result<Self, RedisItem> {
+        use SearchCommand::*;
+        let next = |args: &mut VecDeque<RedisItem>| match args.pop_front() {
+            Some(RedisItem::BulkString(arg)) => Ok(arg),
+            _ => Err(RedisError::Syntax),
+        };
+        let search = matches!(command, Search | SearchStore);
+        while let Ok(mut arg) = next(&mut args) {
+            arg.make_ascii_lowercase();
+            match arg.as_str() {
+                "frommember" if search && self.center.is_none() => {
+                    self.center = Some(Center::Member(next(&mut args)?));
+                }
+                "fromlonlat" if search && self.center.is_none() => {
+                    let (lon, lat) = parse_position(&next(&mut args)?, &next(&mut args)?)?;
+                    self.center = Some(Center::Position(lon, lat));
+                }
+                "frommember" | "fromlonlat" if search => return Err(RedisError::Custom(
+                    "ERR exactly one of FROMMEMBER or FROMLONLAT can be specified for GEOSEARCH",
+                )
+                .into()),
+                "byradius" if search && self.shape.is_none() => {
+                    let radius = parse_float(&next(&mut args)?).ok_or(RedisError::NotFloat)?;
+                    if radius < 0.0 {
+                        return Err(RedisError::Custom("ERR radius cannot be negative").into());
+                    }
+                    self.unit = parse_unit(&next(&mut args)?)?;
+                    self.shape = Some(Shape::Radius(radius * self.unit));
+                }
+                "bybox" if search && self.shape.is_none() => {
+                    let width = parse_float(&next(&mut args)?).ok_or(RedisError::NotFloat)?;
+                    let height = parse_float(&next(&mut args)?).ok_or(RedisError::NotFloat)?;
+                    if width < 0.0 || height < 0.0 {
+                        return Err(
+                            RedisError::Custom("ERR height or width cannot be negative").into()
+                        );
+                    }
+                    self.unit = parse_unit(&next(&mut args)?)?;
+                    self.shape = Some(Shape::Box {
+                        width: width * self.unit,
+                        height: height * self.unit,
+                    });
+                }
+                "byradius" | "bybox" if search => {
+                    return Err(RedisError::Custom(
+                        "ERR exactly one of BYRADIUS and BYBOX can be specified for GEOSEARCH",
+                    )
+                    .into())
+                }
+                "any" => self.any = true,
+                "asc" => self.desc = Some(false),
+                "desc" => self.desc = Some(true),
+                "count" => {
+                    let count = next(&mut args)?;
+                    match parse_int(&count) {
+                        Some(count) if count > 0 => self.count = Some(count as usize),
+                        Some(_) => return Err(RedisError::Custom("ERR COUNT must be > 0").into()),
+                        None => return Err(RedisError::NotInteger.into()),
+                    }
+                }
+                "withcoord" if command != SearchStore => self.with_coord = true,
+                "withdist" if command != SearchStore => self.with_dist = true,
+                "withhash" if command != SearchStore => self.with_hash = true,
+                "storedist" if command == SearchStore => self.store_dist = true,
+                "store" | "storedist" if command == Radius => {
+                    self.store = Some(next(&mut args)?);
+                    self.store_dist = arg == "storedist";
+                }
+                _ => return Err(RedisError::Syntax.into()),
+            }
+        }
+        if self.center.is_none() {
+            return Err(RedisError::Custom(
+                "ERR exactly one of FROMMEMBER or FROMLONLAT can be specified for GEOSEARCH",
+            )
+            .into());
+        }
+        if self.shape.is_none() {
+            return Err(RedisError::Custom(
+                "ERR exactly one of BYRADIUS and BYBOX can be specified for GEOSEARCH",
+            )
+            .into());
+        }
+        if self.any && self.count.is_none() {
+            return Err(RedisError::Custom("ERR the ANY argument requires COUNT argument").into());
+        }
+        if self.store.is_some() && (self.with_coord || self.with_dist || self.with_hash) {
+            return Err(RedisError::Custom(
+                "ERR STORE option in GEORADIUS is not compatible with WITHDIST, WITHHASH and WITHCOORD options",
+            ).into());
+        }
+        Ok(self)
+    }
+}
+
+/// Estimates the geohash precision at which a cell is about as large as the
+/// given radius.
+fn estimate_step(radius: f64, lat: f64) -> u32 {
+    if radius == 0.0 {
+        return GEO_STEP;
+    }
+    let mut step: i32 = 1;
+    let mut range = radius;
+    while range < MERCATOR_MAX {
+        range *= 2.0;
+        step += 1;
+    }
+    // make sure the radius is included in most cases
+    step -= 2;
+    if lat.abs() > 66.0 {
+        step -= 1;
+        if lat.abs() > 80.0 {
+            step -= 1;
+        }
+    }
+    step.clamp(1, GEO_STEP as i32) as u32
+}
+
+/// Returns the score ranges of the geohash cells covering the bounding box
+/// of `shape` around the given center.
+fn covering_ranges(lon: f64, lat: f64, shape: Shape) -> Vec<ScoreRange> {
+    let (half_width, half_height) = match shape {
+        Shape::Radius(radius) => (radius, radius),
+        Shape::Box { width, height } => (width / 2.0, height / 2.0),
+    };
+    let lat_delta = (half_height / EARTH_RADIUS).to_degrees();
+    // the box is widest at the edge closer to the equator
+    let edge_lat = if lat < 0.0 {
+        lat - lat_delta
+    } else {
+        lat + lat_delta
+    };
+    let lon_delta = (half_width / EARTH_RADIUS / edge_lat.to_radians().cos()).to_degrees();
+    let (min_lat, max_lat) = (lat - lat_delta, lat + lat_delta);
+    let (min_lon, max_lon) = (lon - lon_delta, lon + lon_delta);
+
+    // longitudes past the antimeridian wrap around
+    let lon_spans = if !lon_delta.is_finite() || max_lon - min_lon >= 360.0 {
+        vec![(LON_MIN, LON_MAX)]
+    } else if min_lon < LON_MIN {
+        vec![(min_lon + 360.0, LON_MAX), (LON_MIN, max_lon)]
+    } else if max_lon > LON_MAX {
+        vec![(min_lon, LON_MAX), (LON_MIN, max_lon - 360.0)]
+    } else {
+        vec![(min_lon, max_lon)]
+    };
+
+    let mut step = estimate_step(half_width.max(half_height), lat);
+    loop {
+        let cells = (1u64 << step) as f64;
+        let index = |val: f64, min: f64, max: f64| {
+            ((val - min) / (max - min) * cells).clamp(0.0, cells - 1.0) as i64
+        };
+        // cells next to the box are included as well, so that rounding
+        // never excludes positions on the border
+        let span = |low: f64, high: f64, min: f64, max: f64| {
+            let last = cells as i64 - 1;
+            (index(low, min, max) - 1).max(0)..=(index(high, min, max) + 1).min(last)
+        };
+        let lat_span = span(min_lat, max_lat, LAT_MIN, LAT_MAX);
+        let lon_spans: Vec<_> = lon_spans
+            .iter()
+            .map(|&(low, high)| span(low, high, LON_MIN, LON_MAX))
+            .collect();
+        let lon_cells: usize = lon_spans.iter().map(|span| span.clone().count()).sum();
+        if lat_span.clone().count() * lon_cells > 64 && step > 1 {
+            step -= 1;
+            continue;
+        }
+        let shift = 2 * (GEO_STEP - step);
+        let mut ranges = Vec::new();
+        for lat_index in lat_span {
+            for lon_span in &lon_spans {
+                for lon_index in lon_span.clone() {
+                    let bits = interleave(lat_index as u32, lon_index as u32);
+                    ranges.push(ScoreRange {
+                        min: (bits << shift) as f64,
+                        max: ((bits + 1) << shift) as f64,
+                        min_exclusive: false,
+                        max_exclusive: true,
+                    });
+                }
+            }
+        }
+        return ranges;
+    }
+}
+
+/// A member found by a search.
+struct Found {
+    member: String,
+    score: f64,
+    distance: f64,
+    lon: f64,
+    lat: f64,
+}
+
+/// Finds the members of `zset` within `shape` around the given center.
+fn search(zset: &SortedSet, lon: f64, lat: f64, spec: &SearchSpec) -> Vec<Found> {
+    let shape = spec.shape.unwrap();
+    // without sorting, any matches are good enough
+    let limit = if spec.any { spec.count } else { None };
+    let mut found = Vec::new();
+    'ranges: for range in covering_ranges(lon, lat, shape) {
+        for (member, score) in zset.range_by_score(&range, false, 0, None) {
+            let (member_lon, member_lat) = decode(score);
+            let dist = distance(lon, lat, member_lon, member_lat);
+            let inside = match shape {
+                Shape::Radius(radius) => dist <= radius,
+                Shape::Box { width, height } => {
+                    let lat_dist =
+                        EARTH_RADIUS * (member_lat.to_radians() - lat.to_radians()).abs();
+                    lat_dist <= height / 2.0
+                        && distance(lon, member_lat, member_lon, member_lat) <= width / 2.0
+                }
+            };
+            if inside {
+                found.push(Found {
+                    member,
+                    score,
+                    distance: dist,
+                    lon: member_lon,
+                    lat: member_lat,
+                });
+                if limit.is_some_and(|limit| found.len() >= limit) {
+                    break 'ranges;
+                }
+            }
+        }
+    }
+    // a count without ANY returns the closest members
+    let desc = match spec.desc {
+        None if spec.count.is_some() && !spec.any => Some(false),
+        desc => desc,
+    };
+    if let Some(desc) = desc {
+        found.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+        if desc {
+            found.reverse();
+        }
+    }
+    if let Some(count) = spec.count {
+        found.truncate(count);
+    }
+    found
+}
+
+fn geo_search(key: String, spec: SearchSpec, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let mut state = state.borrow_mut();
+    let zset = match get_zset(&state, &key) {
+        Ok(Some(zset)) => zset,
+        Ok(None) if spec.store.is_some() => return Integer(0),
+        Ok(None) => return Array(Vec::new()),
+        Err(err) => return err.into(),
+    };
+    let (lon, lat) = match spec.center.as_ref().unwrap() {
+        Center::Position(lon, lat) => (*lon, *lat),
+        Center::Member(member) => match zset.score(member) {
+            Some(score) => decode(score),
+            None => return SimpleError("ERR could not decode requested zset member".to_string()),
+        },
+    };
+    let found = search(zset, lon, lat, &spec);
+
+    if let Some(dest) = spec.store {
+        let stored: SortedSet = found
+            .into_iter()
+            .map(|found| {
+                let score = if spec.store_dist {
+                    found.distance / spec.unit
+                } else {
+                    found.score
+                };
+                (found.member, score)
+            })
+            .collect();
+        return Integer(zset::store(&mut state, dest, stored) as i64);
+    }
+    let plain = !(spec.with_dist || spec.with_hash || spec.with_coord);
+    Array(
+        found
+            .into_iter()
+            .map(|found| {
+                if plain {
+                    return BulkString(found.member);
+                }
+                let mut entry = vec![BulkString(found.member)];
+                if spec.with_dist {
+                    entry.push(distance_reply(found.distance / spec.unit));
+                }
+                if spec.with_hash {
+                    entry.push(Integer(found.score as i64));
+                }
+                if spec.with_coord {
+                    entry.push(Array(vec![Double(found.lon), Double(found.lat)]));
+                }
+                Array(entry)
+            })
+            .collect(),
+    )
+}
+
+fn search_command(
+    mut args: VecDeque<RedisItem>,
+    state: &RefCell<State>,
+    command: SearchCommand,
+) -> RedisItem {
+    use RedisItem::*;
+    let mut spec = SearchSpec::new();
+    if command == SearchCommand::SearchStore {
+        let Some(BulkString(dest)) = args.pop_front() else {
+            return RedisError::InvalidArguments.into();
+        };
+        spec.store = Some(dest);
+    }
+    let Some(BulkString(key)) = args.pop_front() else {
+        return RedisError::InvalidArguments.into();
+    };
+    match spec.parse(args, command) {
+        Ok(spec) => geo_search(key, spec, state),
+        Err(err) => err,
+    }
+}
+
+pub fn do_geosearch(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    search_command(args, state, SearchCommand::Search)
+}
+
+pub fn do_geosearchstore(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    search_command(args, state, SearchCommand::SearchStore)
+}
+
+/// Handles the legacy `GEORADIUS` commands, which take the center and the
+/// radius as positional arguments.
+fn radius_command(
+    mut args: VecDeque<RedisItem>,
+    state: &RefCell<State>,
+    by_member: bool,
+    read_only: bool,
+) -> RedisItem {
+    use RedisItem::*;
+    let Some(BulkString(key)) = args.pop_front() else {
+        return RedisError::InvalidArguments.into();
+    };
+    let mut spec = SearchSpec::new();
+    let center = if by_member {
+        match args.pop_front() {
+            Some(BulkString(member)) => Center::Member(member),
+            _ => return RedisError::InvalidArguments.into(),
+        }
+    } else {
+        let (Some(BulkString(lon)), Some(BulkString(lat))) = (args.pop_front(), args.pop_front())
+        else {
+            return RedisError::InvalidArguments.into();
+        };
+        match parse_position(&lon, &lat) {
+            Ok((lon, lat)) => Center::Position(lon, lat),
+            Err(err) => return err,
+        }
+    };
+    let (Some(BulkString(radius)), Some(BulkString(unit))) = (args.pop_front(), args.pop_front())
+    else {
+        return RedisError::InvalidArguments.into();
+    };
+    let Some(radius) = parse_float(&radius) else {
+        return SimpleError("ERR need numeric radius".to_string());
+    };
+    if radius < 0.0 {
+        return SimpleError("ERR radius cannot be negative".to_string());
+    }
+    match parse_unit(&unit) {
+        Ok(unit) => spec.unit = unit,
+        Err(err) => return err.into(),
+    }
+    spec.center = Some(center);
+    spec.shape = Some(Shape::Radius(radius * spec.unit));
+    let command = if read_only {
+        SearchCommand::RadiusReadOnly
+    } else {
+        SearchCommand::Radius
+    };
+    match spec.parse(args, command) {
+        Ok(spec) => geo_search(key, spec, state),
+        Err(err) => err,
+    }
+}
+
+pub fn do_georadius(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    radius_command(args, state, false, false)
+}
+
+pub fn do_georadius_ro(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    radius_command(args, state, false, true)
+}
+
+pub fn do_georadiusbymember(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    radius_command(args, state, true, false)
+}
+
+pub fn do_georadiusbymember_ro(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    radius_command(args, state, true, true)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_interleave() {
+        assert_eq!(interleave(0b11, 0b00), 0b0101);
+        assert_eq!(interleave(0b00, 0b11), 0b1010);
+        assert_eq!(deinterleave(interleave(12345, 67890)), (12345, 67890));
+    }
+
+    #[test]
+    pub fn test_encoding() {
+        // Palermo, from the redis documentation
+        let score = score(13.361389, 38.115556);
+        assert_eq!(score, 3479099956230698.0);
+        let (lon, lat) = decode(score);
+        assert!((lon - 13.361389).abs() < 1e-5 && (lat - 38.115556).abs() < 1e-5);
+        assert_eq!(geohash_string(score), "sqc8b49rny0");
+    }
+
+    #[test]
+    pub fn test_distance() {
+        let palermo = decode(3479099956230698.0);
+        let catania = decode(3479447370796909.0);
+        let dist = distance(palermo.0, palermo.1, catania.0, catania.1);
+        assert_eq!(format!("{:.4}", dist), "166274.1516");
+    }
+}
@@ -0,0 +1,147 @@
+/// Matches `string` against a glob-style `pattern`, following the rules of
+/// redis' `KEYS` command:
+///
+/// - `?` matches any single character
+/// - `*` matches any sequence of characters
+/// - `[abc]`, `[^abc]` and `[a-z]` match character classes
+/// - `\` escapes the following character
+pub fn matches(pattern: &str, string: &str) -> bool {
+    match_bytes(pattern.as_bytes(), string.as_bytes())
+}
+
+fn match_bytes(mut pattern: &[u8], mut string: &[u8]) -> bool {
+    // like redis, only the last star is backtracked to: the pattern after
+    // it and the rest of the string it has not absorbed yet
+    let mut star: Option<(&[u8], &[u8])> = None;
+    loop {
+        if let [b'*', rest @ ..] = pattern {
+            if rest.is_empty() {
+                return true;
+            }
+            pattern = rest;
+            star = Some((pattern, string));
+            continue;
+        }
+        let next = match string.split_first() {
+            Some((&c, rest)) => match_token(pattern, c).map(|pattern| (pattern, rest)),
+            None if pattern.is_empty() => return true,
+            None => None,
+        };
+        match (next, star) {
+            (Some(next), _) => (pattern, string) = next,
+            // the star absorbs one more character
+            (None, Some((star_pattern, [_, rest @ ..]))) => {
+                star = Some((star_pattern, rest));
+                (pattern, string) = (star_pattern, rest);
+            }
+            (None, _) => return false,
+        }
+    }
+}
+
+/// Matches `c` against the token at the start of `pattern`, which is not a
+/// star. Returns the rest of the pattern if it matched.
+fn match_token(pattern: &[u8], c: u8) -> Option<&[u8]> {
+    match pattern {
+        [] => None,
+        [b'?', rest @ ..] => Some(rest),
+        [b'[', class @ ..] => {
+            let (matched, rest) = match_class(class, c);
+            matched.then_some(rest)
+        }
+        [b'\\', escaped, rest @ ..] => (*escaped == c).then_some(rest),
+        [p, rest @ ..] => (*p == c).then_some(rest),
+    }
+}
+
+/// Matches `c` against the character class at the start of `pattern`, which
+/// follows the opening `[`. Returns whether it matched and the rest of the
+/// pattern after the closing `]`.
+fn match_class(mut pattern: &[u8], c: u8) -> (bool, &[u8]) {
+    let negate = pattern.first() == Some(&b'^');
+    if negate {
+        pattern = &pattern[1..];
+    }
+    let mut matched = false;
+    loop {
+        match pattern {
+            [] => break,
+            [b']', rest @ ..] => {
+                pattern = rest;
+                break;
+            }
+            [b'\\', escaped, rest @ ..] => {
+                matched |= *escaped == c;
+                pattern = rest;
+            }
+            [start, b'-', end, rest @ ..] if *end != b']' => {
+                let (low, high) = if start <= end {
+                    (*start, *end)
+                } else {
+                    (*end, *start)
+                };
+                matched |= low <= c && c <= high;
+                pattern = rest;
+            }
+            [other, rest @ ..] => {
+                matched |= *other == c;
+                pattern = rest;
+            }
+        }
+    }
+    (matched != negate, pattern)
+}
+
+#[cfg(test)]
+mod test {
+    use super::matches;
+
+    #[test]
+    pub fn test_literal() {
+        assert!(matches("foo", "foo"));
+        assert!(!matches("foo", "fo"));
+        assert!(!matches("foo", "fooo"));
+    }
+
+    #[test]
+    pub fn test_wildcards() {
+        assert!(matches("*", ""));
+        assert!(matches("h?llo", "hello"));
+        assert!(matches("h*llo", "hllo"));
+        assert!(matches("h*llo", "heeeello"));
+        assert!(matches("*o*o*", "foobar"));
+        assert!(!matches("h?llo", "hllo"));
+        assert!(!matches("*x", "foobar"));
+    }
+
+    #[test]
+    pub fn test_classes() {
+        assert!(matches("h[ae]llo", "hello"));
+        assert!(matches("h[ae]llo", "hallo"));
+        assert!(!matches("h[ae]llo", "hillo"));
+        assert!(matches("h[^e]llo", "hallo"));
+        assert!(!matches("h[^e]llo", "hello"));
+        assert!(matches("h[a-b]llo", "hbllo"));
+        assert!(matches("h[b-a]llo", "hallo"));
+        assert!(!matches("h[a-b]llo", "hcllo"));
+    }
+
+    #[test]
+    pub fn test_escape() {
+        assert!(matches("h\\*llo", "h*llo"));
+        assert!(!matches("h\\*llo", "hello"));
+        assert!(matches("[\\]]", "]"));
+        assert!(matches("a\\", "a\\"));
+    }
+
+    #[test]
+    pub fn test_backtracking() {
+        assert!(matches("*a*b", "xaxxbxab"));
+        assert!(!matches("*a*b", "xaxxbxa"));
+        assert!(matches("a*[0-9]?", "abc12"));
+        // patterns which take exponential time with naive backtracking
+        let string = "a".repeat(100);
+        assert!(!matches(&format!("{}b", "a*".repeat(30)), &string));
+        assert!(matches(&"*a".repeat(30), &string));
+    }
+}
@@ -0,0 +1,581 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+
+use feredis_core::item::RedisItem;
+
+use crate::scan::{cursor_reply, ScanOptions, ScanOrder};
+use crate::set::MAX_RANDOM_COUNT;
+use crate::value::{format_incr_float, parse_float, parse_int, Value};
+use crate::{RedisError, State};
+
+/// The fields and values of a hash. The fields are kept ordered by their
+/// hash as well, for `HSCAN`.
+#[derive(Debug, Clone, Default)]
+pub struct HashValue {
+    fields: HashMap<String, String>,
+    order: ScanOrder,
+}
+
+impl PartialEq for HashValue {
+    fn eq(&self, other: &Self) -> bool {
+        self.fields == other.fields
+    }
+}
+
+impl HashValue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    pub fn get(&self, field: &str) -> Option<&String> {
+        self.fields.get(field)
+    }
+
+    pub fn contains_key(&self, field: &str) -> bool {
+        self.fields.contains_key(field)
+    }
+
+    /// Sets `field` to `val`, returning the previous value.
+    pub fn insert(&mut self, field: String, val: String) -> Option<String> {
+        if !self.fields.contains_key(&field) {
+            self.order.insert(&field);
+        }
+        self.fields.insert(field, val)
+    }
+
+    pub fn remove(&mut self, field: &str) -> Option<String> {
+        let val = self.fields.remove(field)?;
+        self.order.remove(field);
+        Some(val)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &String)> + '_ {
+        self.fields.iter()
+    }
+
+    /// Visits about `count` fields starting at `cursor`, returning the
+    /// cursor to continue with and the fields with their values.
+    pub fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<(&str, &String)>) {
+        let (cursor, fields) = self.order.scan(cursor, count);
+        let fields = fields
+            .into_iter()
+            .map(|field| (field, &self.fields[field]))
+            .collect();
+        (cursor, fields)
+    }
+}
+
+impl FromIterator<(String, String)> for HashValue {
+    fn from_iter<T: IntoIterator<Item = (String, String)>>(iter: T) -> Self {
+        let mut hash = HashValue::new();
+        for (field, val) in iter {
+            hash.insert(field, val);
+        }
+        hash
+    }
+}
+
+/// Returns the hash stored at `key`, or `None` if the key does not exist.
+pub fn get_hash<'a>(
+    state: &'a mut State,
+    key: &str,
+) -> Result<Option<&'a mut HashValue>, RedisError> {
+    match state.items.get_mut(key) {
+        Some((Value::Hash(hash), _)) => Ok(Some(hash)),
+        Some(_) => Err(RedisError::WrongType),
+        None => Ok(None),
+    }
+}
+
+/// Returns the hash stored at `key`, creating an empty one if necessary.
+fn get_or_create_hash<'a>(
+    state: &'a mut State,
+    key: &str,
+) -> Result<&'a mut HashValue, RedisError> {
+    if !state.items.contains_key(key) {
+        let tag = state.next_tag();
+        state
+            .items
+            .insert(key.to_string(), (Value::Hash(HashValue::new()), tag));
+    }
+    get_hash(state, key).map(Option::unwrap)
+}
+
+fn next_key(args: &mut VecDeque<RedisItem>) -> Result<String, RedisError> {
+    match args.pop_front() {
+        Some(RedisItem::BulkString(key)) => Ok(key),
+        _ => Err(RedisError::InvalidArguments),
+    }
+}
+
+fn set_fields(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> Result<i64, RedisError> {
+    let key = next_key(&mut args)?;
+    if args.is_empty() || !args.len().is_multiple_of(2) {
+        return Err(RedisError::InvalidArguments);
+    }
+    let mut pairs = Vec::with_capacity(args.len() / 2);
+    while let Some(field) = args.pop_front() {
+        let (RedisItem::BulkString(field), Some(RedisItem::BulkString(val))) =
+            (field, args.pop_front())
+        else {
+            return Err(RedisError::InvalidArguments);
+        };
+        pairs.push((field, val));
+    }
+    let mut state = state.borrow_mut();
+    let hash = get_or_create_hash(&mut state, &key)?;
+    let mut added = 0;
+    for (field, val) in pairs {
+        if hash.insert(field, val).is_none() {
+            added += 1;
+        }
+    }
+    Ok(added)
+}
+
+pub fn do_hset(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    match set_fields(args, state) {
+        Ok(added) => RedisItem::Integer(added),
+        Err(err) => err.into(),
+    }
+}
+
+pub fn do_hmset(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    match set_fields(args, state) {
+        Ok(_) => RedisItem::SimpleString("OK".to_string()),
+        Err(err) => err.into(),
+    }
+}
+
+pub fn do_hsetnx(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let (Some(BulkString(key)), Some(BulkString(field)), Some(BulkString(val))) =
+        (args.pop_front(), args.pop_front(), args.pop_front())
+    else {
+        return RedisError::InvalidArguments.into();
+    };
+    let mut state = state.borrow_mut();
+    match get_or_create_hash(&mut state, &key) {
+        Ok(hash) if hash.contains_key(&field) => Integer(0),
+        Ok(hash) => {
+            hash.insert(field, val);
+            Integer(1)
+        }
+        Err(err) => err.into(),
+    }
+}
+
+pub fn do_hget(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let (Some(BulkString(key)), Some(BulkString(field))) = (args.pop_front(), args.pop_front())
+    else {
+        return RedisError::InvalidArguments.into();
+    };
+    match get_hash(&mut state.borrow_mut(), &key) {
+        Ok(hash) => hash
+            .and_then(|hash| hash.get(&field))
+            .map_or(Null, |val| BulkString(val.clone())),
+        Err(err) => err.into(),
+    }
+}
+
+pub fn do_hmget(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let Some(BulkString(key)) = args.pop_front() else {
+        return RedisError::InvalidArguments.into();
+    };
+    if args.is_empty() {
+        return RedisError::InvalidArguments.into();
+    }
+    let mut state = state.borrow_mut();
+    let hash = match get_hash(&mut state, &key) {
+        Ok(hash) => hash,
+        Err(err) => return err.into(),
+    };
+    let mut res = Vec::with_capacity(args.len());
+    for field in args {
+        let BulkString(field) = field else {
+            return RedisError::InvalidArguments.into();
+        };
+        let val = hash.as_ref().and_then(|hash| hash.get(&field));
+        res.push(val.map_or(Null, |val| BulkString(val.clone())));
+    }
+    Array(res)
+}
+
+pub fn do_hdel(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let Some(BulkString(key)) = args.pop_front() else {
+        return RedisError::InvalidArguments.into();
+    };
+    if args.is_empty() {
+        return RedisError::InvalidArguments.into();
+    }
+    let mut state = state.borrow_mut();
+    let hash = match get_hash(&mut state, &key) {
+        Ok(Some(hash)) => hash,
+        Ok(None) => return Integer(0),
+        Err(err) => return err.into(),
+    };
+    let mut removed = 0;
+    for field in args {
+        if let BulkString(field) = field {
+            if hash.remove(&field).is_some() {
+                removed += 1;
+            }
+        }
+    }
+    if hash.is_empty() {
+        state.items.remove(&key);
+    }
+    Integer(removed)
+}
+
+pub fn do_hexists(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let (Some(BulkString(key)), Some(BulkString(field))) = (args.pop_front(), args.pop_front())
+    else {
+        return RedisError::InvalidArguments.into();
+    };
+    match get_hash(&mut state.borrow_mut(), &key) {
+        Ok(hash) => Integer(hash.is_some_and(|hash| hash.contains_key(&field)) as i64),
+        Err(err) => err.into(),
+    }
+}
+
+pub fn do_hlen(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let Some(BulkString(key)) = args.pop_front() else {
+        return RedisError::InvalidArguments.into();
+    };
+    match get_hash(&mut state.borrow_mut(), &key) {
+        Ok(hash) => Integer(hash.map_or(0, |hash| hash.len() as i64)),
+        Err(err) => err.into(),
+    }
+}
+
+pub fn do_hstrlen(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let (Some(BulkString(key)), Some(BulkString(field))) = (args.pop_front(), args.pop_front())
+    else {
+        return RedisError::InvalidArguments.into();
+    };
+    match get_hash(&mut state.borrow_mut(), &key) {
+        Ok(hash) => Integer(
+            hash.and_then(|hash| hash.get(&field))
+                .map_or(0, |val| val.len() as i64),
+        ),
+        Err(err) => err.into(),
+    }
+}
+
+/// Collects the fields and/or values of the hash at `key` into an array.
+fn collect(
+    mut args: VecDeque<RedisItem>,
+    state: &RefCell<State>,
+    map: impl Fn(&String, &String) -> RedisItem,
+) -> RedisItem {
+    use RedisItem::*;
+    let Some(BulkString(key)) = args.pop_front() else {
+        return RedisError::InvalidArguments.into();
+    };
+    match get_hash(&mut state.borrow_mut(), &key) {
+        Ok(hash) => Array(hash.map_or_else(Vec::new, |hash| {
+            hash.iter().map(|(field, val)| map(field, val)).collect()
+        })),
+        Err(err) => err.into(),
+    }
+}
+
+pub fn do_hkeys(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    collect(args, state, |field, _| RedisItem::BulkString(field.clone()))
+}
+
+pub fn do_hvals(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    collect(args, state, |_, val| RedisItem::BulkString(val.clone()))
+}
+
+pub fn do_hgetall(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let Some(BulkString(key)) = args.pop_front() else {
+        return RedisError::InvalidArguments.into();
+    };
+    match get_hash(&mut state.borrow_mut(), &key) {
+        Ok(hash) => Map(hash.map_or_else(Vec::new, |hash| {
+            hash.iter()
+                .map(|(field, val)| (BulkString(field.clone()), BulkString(val.clone())))
+                .collect()
+        })),
+        Err(err) => err.into(),
+    }
+}
+
+pub fn do_hincrby(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let (Some(BulkString(key)), Some(BulkString(field)), Some(BulkString(incr))) =
+        (args.pop_front(), args.pop_front(), args.pop_front())
+    else {
+        return RedisError::InvalidArguments.into();
+    };
+    let Some(incr) = parse_int(&incr) else {
+        return RedisError::NotInteger.into();
+    };
+    let mut state = state.borrow_mut();
+    let hash = match get_or_create_hash(&mut state, &key) {
+        Ok(hash) => hash,
+        Err(err) => return err.into(),
+    };
+    let current = match hash.get(&field) {
+        Some(val) => match parse_int(val) {
+            Some(val) => val,
+            None => return SimpleError("ERR hash value is not an integer".to_string()),
+        },
+        None => 0,
+    };
+    let Some(new) = current.checked_add(incr) else {
+        return RedisError::Overflow.into();
+    };
+    hash.insert(field, new.to_string());
+    Integer(new)
+}
+
+pub fn do_hincrbyfloat(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let (Some(BulkString(key)), Some(BulkString(field)), Some(BulkString(incr))) =
+        (args.pop_front(), args.pop_front(), args.pop_front())
+    else {
+        return RedisError::InvalidArguments.into();
+    };
+    let Some(incr) = parse_float(&incr) else {
+        return RedisError::NotFloat.into();
+    };
+    let mut state = state.borrow_mut();
+    let hash = match get_or_create_hash(&mut state, &key) {
+        Ok(hash) => hash,
+        Err(err) => return err.into(),
+    };
+    let current = match hash.get(&field) {
+        Some(val) => match parse_float(val) {
+            Some(val) => val,
+            None => return SimpleError("ERR hash value is not a float".to_string()),
+        },
+        None => 0.0,
+    };
+    let new = current + incr;
+    if !new.is_finite() {
+        return RedisError::NanOrInfinity.into();
+    }
+    let new = format_incr_float(new);
+    hash.insert(field, new.clone());
+    BulkString(new)
+}
+
+pub fn do_hrandfield(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let Some(BulkString(key)) = args.pop_front() else {
+        return RedisError::InvalidArguments.into();
+    };
+    let count = match args.pop_front() {
+        Some(BulkString(count)) => match parse_int(&count) {
+            Some(count) => Some(count),
+            None => return RedisError::NotInteger.into(),
+        },
+        None => None,
+        Some(_) => return RedisError::InvalidArguments.into(),
+    };
+    let with_values = match args.pop_front() {
+        Some(BulkString(arg)) if count.is_some() && arg.eq_ignore_ascii_case("withvalues") => true,
+        None => false,
+        Some(_) => return RedisError::Syntax.into(),
+    };
+    let mut state = state.borrow_mut();
+    let hash = match get_hash(&mut state, &key) {
+        Ok(hash) => hash,
+        Err(err) => return err.into(),
+    };
+    let Some(count) = count else {
+        return match hash {
+            Some(hash) => {
+                let index = fastrand::usize(..hash.len());
+                BulkString(hash.iter().nth(index).unwrap().0.clone())
+            }
+            None => Null,
+        };
+    };
+    let Some(hash) = hash else {
+        return Array(Vec::new());
+    };
+    let entries: Vec<(&String, &String)> = hash.iter().collect();
+    let picked: Vec<(&String, &String)> = if count >= 0 {
+        // distinct fields, in random order
+        let mut entries = entries;
+        fastrand::shuffle(&mut entries);
+        entries.truncate(count as usize);
+        entries
+    } else {
+        // fields may be repeated
+        (0..count.unsigned_abs().min(MAX_RANDOM_COUNT))
+            .map(|_| entries[fastrand::usize(..entries.len())])
+            .collect()
+    };
+    Array(
+        picked
+            .into_iter()
+            .map(|(field, val)| {
+                if with_values {
+                    Array(vec![BulkString(field.clone()), BulkString(val.clone())])
+                } else {
+                    BulkString(field.clone())
+                }
+            })
+            .collect(),
+    )
+}
+
+pub fn do_hscan(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let Some(BulkString(key)) = args.pop_front() else {
+        return RedisError::InvalidArguments.into();
+    };
+    let options = match ScanOptions::parse(&mut args, &["novalues"]) {
+        Ok(options) => options,
+        Err(err) => return err.into(),
+    };
+    let mut state = state.borrow_mut();
+    let hash = match get_hash(&mut state, &key) {
+        Ok(hash) => hash,
+        Err(err) => return err.into(),
+    };
+    let (cursor, fields) = hash.map_or((0, Vec::new()), |hash| {
+        hash.scan(options.cursor, options.count)
+    });
+    let mut items = Vec::new();
+    for (field, val) in fields {
+        if !options.matches(field) {
+            continue;
+        }
+        items.push(BulkString(field.to_string()));
+        if !options.no_values {
+            items.push(BulkString(val.clone()));
+        }
+    }
+    cursor_reply(cursor, items)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::scan::scan_all;
+
+    fn run(state: &RefCell<State>, args: &[&str]) -> RedisItem {
+        crate::run_command(state, &mut 0, args)
+    }
+
+    fn bulk(val: &str) -> RedisItem {
+        RedisItem::BulkString(val.to_string())
+    }
+
+    fn len(reply: RedisItem) -> usize {
+        match reply {
+            RedisItem::Array(items) => items.len(),
+            _ => panic!("unexpected reply {:?}", reply),
+        }
+    }
+
+    #[test]
+    pub fn test_hash_commands() {
+        use RedisItem::*;
+        let state = RefCell::new(State::new(1));
+        assert_eq!(run(&state, &["hset", "h", "a", "1", "b", "2"]), Integer(2));
+        assert_eq!(run(&state, &["hset", "h", "a", "3"]), Integer(0));
+        assert_eq!(run(&state, &["hsetnx", "h", "a", "4"]), Integer(0));
+        assert_eq!(run(&state, &["hget", "h", "a"]), bulk("3"));
+        assert_eq!(
+            run(&state, &["hmget", "h", "a", "c"]),
+            Array(vec![bulk("3"), Null])
+        );
+        assert_eq!(run(&state, &["hstrlen", "h", "b"]), Integer(1));
+        assert_eq!(run(&state, &["hincrby", "h", "b", "5"]), Integer(7));
+        assert_eq!(run(&state, &["hincrbyfloat", "h", "f", "1.5"]), bulk("1.5"));
+        run(&state, &["hincrbyfloat", "h", "g", "0.1"]);
+        assert_eq!(run(&state, &["hincrbyfloat", "h", "g", "0.2"]), bulk("0.3"));
+        run(&state, &["hdel", "h", "g"]);
+        assert!(matches!(
+            run(&state, &["hincrby", "h", "f", "1"]),
+            SimpleError(_)
+        ));
+        assert_eq!(run(&state, &["hlen", "h"]), Integer(3));
+        assert_eq!(run(&state, &["hdel", "h", "a", "b", "f", "x"]), Integer(3));
+        assert_eq!(run(&state, &["hlen", "h"]), Integer(0));
+        assert!(matches!(run(&state, &["hset", "h", "a"]), SimpleError(_)));
+
+        run(&state, &["set", "s", "v"]);
+        assert!(matches!(run(&state, &["hget", "s", "a"]), SimpleError(_)));
+    }
+
+    #[test]
+    pub fn test_hrandfield() {
+        use RedisItem::*;
+        let state = RefCell::new(State::new(1));
+        run(&state, &["hset", "h", "a", "1", "b", "2", "c", "3"]);
+        assert_eq!(len(run(&state, &["hrandfield", "h", "10"])), 3);
+        assert_eq!(len(run(&state, &["hrandfield", "h", "-10"])), 10);
+        let res = run(&state, &["hrandfield", "h", "-2", "withvalues"]);
+        let Array(pairs) = res else {
+            panic!("unexpected reply {:?}", res);
+        };
+        for pair in pairs {
+            assert!([
+                Array(vec![bulk("a"), bulk("1")]),
+                Array(vec![bulk("b"), bulk("2")]),
+                Array(vec![bulk("c"), bulk("3")]),
+            ]
+            .contains(&pair));
+        }
+        // huge counts are clamped
+        let res = run(&state, &["hrandfield", "h", "-9223372036854775808"]);
+        assert_eq!(len(res) as u64, MAX_RANDOM_COUNT);
+        assert_eq!(run(&state, &["hrandfield", "none"]), Null);
+        assert_eq!(
+            run(&state, &["hrandfield", "none", "-3"]),
+            Array(Vec::new())
+        );
+        let res = run(&state, &["hrandfield", "h", "1", "values"]);
+        assert!(matches!(res, SimpleError(_)));
+    }
+
+    #[test]
+    pub fn test_hscan() {
+        let state = RefCell::new(State::new(1));
+        for i in 0..50 {
+            run(&state, &["hset", "h", &format!("f{}", i), "v"]);
+        }
+        // the hash is visited a few fields at a time
+        let (items, calls) = scan_all(&state, &["hscan", "h"], &["count", "5"]);
+        assert_eq!(items.len(), 100);
+        assert!(calls >= 10);
+        let (fields, _) = scan_all(&state, &["hscan", "h"], &["count", "5", "novalues"]);
+        let mut fields: Vec<_> = fields
+            .into_iter()
+            .filter_map(|field| match field {
+                RedisItem::BulkString(field) => Some(field),
+                _ => None,
+            })
+            .collect();
+        fields.sort();
+        fields.dedup();
+        assert_eq!(fields.len(), 50);
+        let (fields, _) = scan_all(&state, &["hscan", "h"], &["match", "f1*", "novalues"]);
+        assert_eq!(fields.len(), 11);
+        let (fields, calls) = scan_all(&state, &["hscan", "none"], &[]);
+        assert!(fields.is_empty() && calls == 1);
+    }
+}
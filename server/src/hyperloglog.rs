@@ -0,0 +1,457 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+
+use feredis_core::item::RedisItem;
+
+use crate::string::get_string;
+use crate::value::Value;
+use crate::{RedisError, State};
+
+/// The number of bits of the hash used to select a register.
+const HLL_P: u32 = 14;
+/// The number of bits of the hash used to count leading zeros.
+const HLL_Q: u32 = 64 - HLL_P;
+const HLL_REGISTERS: usize = 1 << HLL_P;
+const HLL_BITS: usize = 6;
+const HLL_ALPHA_INF: f64 = 0.721_347_520_444_481_7;
+
+const HEADER_LEN: usize = 16;
+const DENSE_LEN: usize = HEADER_LEN + (HLL_REGISTERS * HLL_BITS).div_ceil(8);
+/// Sparse HyperLogLogs are converted to dense ones once they grow past this
+/// size, matching redis' default `hll-sparse-max-bytes`.
+const SPARSE_MAX_LEN: usize = 3000;
+/// The largest register value which can be stored in the sparse encoding.
+const SPARSE_VAL_MAX: u8 = 32;
+
+const DENSE: u8 = 0;
+const SPARSE: u8 = 1;
+
+const INVALID_HLL: &str = "WRONGTYPE Key is not a valid HyperLogLog string value.";
+const CORRUPTED_HLL: &str = "INVALIDOBJ Corrupted HLL object detected";
+
+/// The 64 bit MurmurHash2 variant used by redis to hash elements.
+fn murmur_hash64a(key: &[u8], seed: u64) -> u64 {
+    const M: u64 = 0xc6a4a7935bd1e995;
+    const R: u32 = 47;
+    let mut h = seed ^ (key.len() as u64).wrapping_mul(M);
+    let mut chunks = key.chunks_exact(8);
+    for chunk in &mut chunks {
+        let mut k = u64::from_le_bytes(chunk.try_into().unwrap());
+        k = k.wrapping_mul(M);
+        k ^= k >> R;
+        k = k.wrapping_mul(M);
+        h ^= k;
+        h = h.wrapping_mul(M);
+    }
+    let rest = chunks.remainder();
+    if !rest.is_empty() {
+        for (i, byte) in rest.iter().enumerate() {
+            h ^= (*byte as u64) << (8 * i);
+        }
+        h = h.wrapping_mul(M);
+    }
+    h ^= h >> R;
+    h = h.wrapping_mul(M);
+    h ^= h >> R;
+    h
+}
+
+/// Returns the register an element maps to and the length of the run of
+/// zeros in the rest of its hash, plus one.
+fn pattern(element: &[u8]) -> (usize, u8) {
+    let hash = murmur_hash64a(element, 0xadc83b19);
+    let index = (hash & (HLL_REGISTERS as u64 - 1)) as usize;
+    // the extra bit bounds the run length to `HLL_Q + 1`
+    let rest = (hash >> HLL_P) | (1 << HLL_Q);
+    (index, rest.trailing_zeros() as u8 + 1)
+}
+
+/// A HyperLogLog decoded from the string representation used by redis: a
+/// 16 byte header followed by either the sparse or the dense registers.
+#[derive(Debug, Clone, PartialEq)]
+struct HyperLogLog {
+    registers: Vec<u8>,
+    dense: bool,
+    /// the cached cardinality from the header, where the most significant bit
+    /// marks the cache as invalid
+    cache: [u8; 8],
+}
+
+/// Checks the header of a HyperLogLog string.
+fn check_header(bytes: &[u8]) -> Result<(), RedisError> {
+    let valid = bytes.len() >= HEADER_LEN
+        && bytes.starts_with(b"HYLL")
+        && match bytes[4] {
+            DENSE => bytes.len() == DENSE_LEN,
+            SPARSE => true,
+            _ => false,
+        };
+    if valid {
+        Ok(())
+    } else {
+        Err(RedisError::Custom(INVALID_HLL))
+    }
+}
+
+/// Returns the cached cardinality of a HyperLogLog string, if it is valid.
+fn cached_count(bytes: &[u8]) -> Option<u64> {
+    let cache: [u8; 8] = bytes[8..HEADER_LEN].try_into().unwrap();
+    (cache[7] & 0x80 == 0).then(|| u64::from_le_bytes(cache))
+}
+
+impl HyperLogLog {
+    fn new() -> Self {
+        Self {
+            registers: vec![0; HLL_REGISTERS],
+            dense: false,
+            cache: [0; 8],
+        }
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, RedisError> {
+        check_header(bytes)?;
+        let mut registers = vec![0; HLL_REGISTERS];
+        let data = &bytes[HEADER_LEN..];
+        let dense = bytes[4] == DENSE;
+        if dense {
+            for (i, register) in registers.iter_mut().enumerate() {
+                let (byte, shift) = (i * HLL_BITS / 8, i * HLL_BITS % 8);
+                let low = data[byte] as u16;
+                let high = data.get(byte + 1).copied().unwrap_or(0) as u16;
+                *register = (((low | (high << 8)) >> shift) & 0x3f) as u8;
+            }
+        } else {
+            let mut index = 0;
+            let mut data = data.iter();
+            while let Some(&op) = data.next() {
+                // ZERO is 00xxxxxx, XZERO is 01xxxxxx yyyyyyyy and VAL is
+                // 1vvvvvxx, where the lengths and the value are stored minus one
+                let (len, val) = match op >> 6 {
+                    0 => ((op & 0x3f) as usize + 1, 0),
+                    1 => {
+                        let Some(&low) = data.next() else {
+                            return Err(RedisError::Custom(CORRUPTED_HLL));
+                        };
+                        ((((op & 0x3f) as usize) << 8 | low as usize) + 1, 0)
+                    }
+                    _ => ((op & 0x3) as usize + 1, ((op >> 2) & 0x1f) + 1),
+                };
+                if index + len > HLL_REGISTERS {
+                    return Err(RedisError::Custom(CORRUPTED_HLL));
+                }
+                registers[index..index + len].fill(val);
+                index += len;
+            }
+            if index != HLL_REGISTERS {
+                return Err(RedisError::Custom(CORRUPTED_HLL));
+            }
+        }
+        Ok(Self {
+            registers,
+            dense,
+            cache: bytes[8..HEADER_LEN].try_into().unwrap(),
+        })
+    }
+
+    fn encode_sparse(&self) -> Option<Vec<u8>> {
+        let mut out = Vec::new();
+        let mut index = 0;
+        while index < HLL_REGISTERS {
+            let val = self.registers[index];
+            if val > SPARSE_VAL_MAX {
+                return None;
+            }
+            let run = self.registers[index..]
+                .iter()
+                .take_while(|&&other| other == val)
+                .count();
+            if val == 0 {
+                if run > 64 {
+                    let len = run - 1;
+                    out.push(0x40 | (len >> 8) as u8);
+                    out.push(len as u8);
+                } else {
+                    out.push((run - 1) as u8);
+                }
+                index += run;
+            } else {
+                let run = run.min(4);
+                out.push(0x80 | ((val - 1) << 2) | (run - 1) as u8);
+                index += run;
+            }
+            if HEADER_LEN + out.len() > SPARSE_MAX_LEN {
+                return None;
+            }
+        }
+        Some(out)
+    }
+
+    fn encode_dense(&self) -> Vec<u8> {
+        let mut out = vec![0; DENSE_LEN - HEADER_LEN];
+        for (i, &register) in self.registers.iter().enumerate() {
+            let (byte, shift) = (i * HLL_BITS / 8, i * HLL_BITS % 8);
+            let bits = (register as u16) << shift;
+            out[byte] |= bits as u8;
+            if let Some(next) = out.get_mut(byte + 1) {
+                *next |= (bits >> 8) as u8;
+            }
+        }
+        out
+    }
+
+    /// Encodes the HyperLogLog, switching to the dense encoding if the
+    /// sparse one can not represent it or would grow too large.
+    fn encode(&mut self) -> Vec<u8> {
+        let sparse = if self.dense {
+            None
+        } else {
+            self.encode_sparse()
+        };
+        self.dense = sparse.is_none();
+        let data = sparse.unwrap_or_else(|| self.encode_dense());
+        let mut out = Vec::with_capacity(HEADER_LEN + data.len());
+        out.extend_from_slice(b"HYLL");
+        out.push(if self.dense { DENSE } else { SPARSE });
+        out.extend_from_slice(&[0; 3]);
+        out.extend_from_slice(&self.cache);
+        out.extend_from_slice(&data);
+        out
+    }
+
+    /// Adds an element, returning whether any register changed.
+    fn add(&mut self, element: &[u8]) -> bool {
+        let (index, count) = pattern(element);
+        if self.registers[index] >= count {
+            return false;
+        }
+        self.registers[index] = count;
+        self.cache[7] |= 0x80;
+        true
+    }
+
+    fn merge(&mut self, other: &HyperLogLog) {
+        for (register, &other) in self.registers.iter_mut().zip(&other.registers) {
+            *register = (*register).max(other);
+        }
+        self.dense |= other.dense;
+        self.cache[7] |= 0x80;
+    }
+
+    /// Estimates the cardinality using the improved estimator by Otmar Ertl,
+    /// the same one used by redis.
+    fn count(&self) -> u64 {
+        let m = HLL_REGISTERS as f64;
+        let mut histogram = [0u32; HLL_Q as usize + 2];
+        for &register in &self.registers {
+            histogram[register as usize] += 1;
+        }
+        let q = HLL_Q as usize;
+        let mut z = m * tau((m - histogram[q + 1] as f64) / m);
+        for &count in histogram[1..=q].iter().rev() {
+            z += count as f64;
+            z *= 0.5;
+        }
+        z += m * sigma(histogram[0] as f64 / m);
+        (HLL_ALPHA_INF * m * m / z).round() as u64
+    }
+}
+
+fn sigma(mut x: f64) -> f64 {
+    if x == 1.0 {
+        return f64::INFINITY;
+    }
+    let mut y = 1.0;
+    let mut z = x;
+    loop {
+        x *= x;
+        let prev = z;
+        z += x * y;
+        y += y;
+        if prev == z {
+            return z;
+        }
+    }
+}
+
+fn tau(mut x: f64) -> f64 {
+    if x == 0.0 || x == 1.0 {
+        return 0.0;
+    }
+    let mut y = 1.0;
+    let mut z = 1.0 - x;
+    loop {
+        x = x.sqrt();
+        let prev = z;
+        y *= 0.5;
+        z -= (1.0 - x).powi(2) * y;
+        if prev == z {
+            return z / 3.0;
+        }
+    }
+}
+
+fn get_hll(state: &State, key: &str) -> Result<Option<HyperLogLog>, RedisError> {
+    match get_string(state, key)? {
+        Some(bytes) => HyperLogLog::decode(&bytes).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Stores an encoded HyperLogLog, keeping the expiry of an existing key.
+fn store(state: &mut State, key: String, bytes: Vec<u8>) {
+    if let Some((val, _)) = state.items.get_mut(&key) {
+        *val = Value::String(bytes);
+    } else {
+        let tag = state.next_tag();
+        state.items.insert(key, (Value::String(bytes), tag));
+    }
+}
+
+pub fn do_pfadd(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let Some(BulkString(key)) = args.pop_front() else {
+        return RedisError::InvalidArguments.into();
+    };
+    let mut elements = Vec::with_capacity(args.len());
+    for arg in args {
+        let Some(element) = arg.into_bytes() else {
+            return RedisError::InvalidArguments.into();
+        };
+        elements.push(element);
+    }
+    let mut state = state.borrow_mut();
+    let (mut hll, mut updated) = match get_hll(&state, &key) {
+        Ok(Some(hll)) => (hll, false),
+        Ok(None) => (HyperLogLog::new(), true),
+        Err(err) => return err.into(),
+    };
+    for element in elements {
+        updated |= hll.add(&element);
+    }
+    if updated {
+        let bytes = hll.encode();
+        store(&mut state, key, bytes);
+    }
+    Integer(updated as i64)
+}
+
+pub fn do_pfcount(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let mut keys = Vec::with_capacity(args.len());
+    for arg in args {
+        let BulkString(key) = arg else {
+            return RedisError::InvalidArguments.into();
+        };
+        keys.push(key);
+    }
+    let mut state = state.borrow_mut();
+    if let [key] = keys.as_slice() {
+        let bytes = match get_string(&state, key) {
+            Ok(Some(bytes)) => bytes,
+            Ok(None) => return Integer(0),
+            Err(err) => return err.into(),
+        };
+        if let Err(err) = check_header(&bytes) {
+            return err.into();
+        }
+        if let Some(count) = cached_count(&bytes) {
+            return Integer(count as i64);
+        }
+        let count = match HyperLogLog::decode(&bytes) {
+            Ok(hll) => hll.count(),
+            Err(err) => return err.into(),
+        };
+        // the cardinality is cached in the header until the next update
+        let mut bytes = bytes;
+        bytes[8..HEADER_LEN].copy_from_slice(&count.to_le_bytes());
+        store(&mut state, key.clone(), bytes);
+        return Integer(count as i64);
+    }
+    if keys.is_empty() {
+        return RedisError::InvalidArguments.into();
+    }
+    let mut merged = HyperLogLog::new();
+    for key in &keys {
+        match get_hll(&state, key) {
+            Ok(Some(hll)) => merged.merge(&hll),
+            Ok(None) => {}
+            Err(err) => return err.into(),
+        }
+    }
+    Integer(merged.count() as i64)
+}
+
+pub fn do_pfmerge(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let Some(BulkString(dest)) = args.pop_front() else {
+        return RedisError::InvalidArguments.into();
+    };
+    let mut state = state.borrow_mut();
+    let mut merged = match get_hll(&state, &dest) {
+        Ok(hll) => hll.unwrap_or_else(HyperLogLog::new),
+        Err(err) => return err.into(),
+    };
+    for arg in args {
+        let BulkString(key) = arg else {
+            return RedisError::InvalidArguments.into();
+        };
+        match get_hll(&state, &key) {
+            Ok(Some(hll)) => merged.merge(&hll),
+            Ok(None) => {}
+            Err(err) => return err.into(),
+        }
+    }
+    merged.cache[7] |= 0x80;
+    let bytes = merged.encode();
+    store(&mut state, dest, bytes);
+    SimpleString("OK".to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_encoding() {
+        let mut hll = HyperLogLog::new();
+        let empty = hll.encode();
+        assert_eq!(&empty[..5], b"HYLL\x01");
+        assert_eq!(&empty[HEADER_LEN..], [0x7f, 0xff]);
+        assert_eq!(HyperLogLog::decode(&empty).unwrap(), hll);
+
+        hll.registers[0] = 3;
+        hll.registers[1] = 3;
+        hll.registers[100] = 32;
+        let sparse = hll.encode();
+        assert!(!hll.dense);
+        assert_eq!(&sparse[HEADER_LEN..HEADER_LEN + 3], [0x89, 0x40, 0x61]);
+        assert_eq!(HyperLogLog::decode(&sparse).unwrap(), hll);
+
+        // values above 32 need the dense encoding
+        hll.registers[HLL_REGISTERS - 1] = 33;
+        let dense = hll.encode();
+        assert!(hll.dense);
+        assert_eq!(dense.len(), DENSE_LEN);
+        assert_eq!(HyperLogLog::decode(&dense).unwrap(), hll);
+
+        let mut corrupted = sparse.clone();
+        corrupted.pop();
+        assert!(HyperLogLog::decode(&corrupted).is_err());
+        assert!(HyperLogLog::decode(b"HYLL\x00").is_err());
+    }
+
+    #[test]
+    pub fn test_count() {
+        let mut hll = HyperLogLog::new();
+        assert_eq!(hll.count(), 0);
+        for i in 0..10 {
+            hll.add(i.to_string().as_bytes());
+        }
+        assert_eq!(hll.count(), 10);
+        for i in 0..100_000 {
+            hll.add(i.to_string().as_bytes());
+        }
+        let error = (hll.count() as f64 - 100_000.0).abs() / 100_000.0;
+        assert!(error < 0.02);
+    }
+}
@@ -0,0 +1,83 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt::Write;
+
+use feredis_core::item::RedisItem;
+
+use crate::{cluster, RedisError, State};
+
+/// The redis version whose behavior the server follows, which clients may
+/// check for features.
+const REDIS_VERSION: &str = "7.0.0";
+
+const SECTIONS: &[&str] = &["server", "replication", "cluster", "keyspace"];
+
+pub fn do_info(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    let mut sections = Vec::new();
+    for arg in args {
+        let RedisItem::BulkString(section) = arg else {
+            return RedisError::InvalidArguments.into();
+        };
+        match section.to_ascii_lowercase().as_str() {
+            "all" | "everything" | "default" => sections.extend_from_slice(SECTIONS),
+            section => sections.extend(SECTIONS.iter().filter(|s| **s == section)),
+        }
+    }
+    if sections.is_empty() {
+        sections.extend_from_slice(SECTIONS);
+    }
+    let state = state.borrow();
+    let mut out = String::new();
+    for section in SECTIONS.iter().filter(|section| sections.contains(section)) {
+        if !out.is_empty() {
+            out.push_str("\r\n");
+        }
+        match *section {
+            "server" => write!(
+                out,
+                "# Server\r\n\
+                 redis_version:{}\r\n\
+                 feredis_version:{}\r\n\
+                 redis_mode:{}\r\n\
+                 process_id:{}\r\n\
+                 run_id:{}\r\n\
+                 tcp_port:{}\r\n",
+                REDIS_VERSION,
+                env!("CARGO_PKG_VERSION"),
+                if state.cluster.is_some() {
+                    "cluster"
+                } else {
+                    "standalone"
+                },
+                std::process::id(),
+                state.run_id,
+                state.repl.port(),
+            )
+            .unwrap(),
+            "replication" => state.repl.info(&mut out),
+            "cluster" => cluster::info(&state, &mut out),
+            _ => {
+                out.push_str("# Keyspace\r\n");
+                for db in 0..state.dbs.len() {
+                    let keyspace = state.db(db);
+                    if keyspace.is_empty() {
+                        continue;
+                    }
+                    let expires = keyspace
+                        .iter()
+                        .filter(|(_, (_, tag))| state.expire.get_expiry(*tag).is_some())
+                        .count();
+                    write!(
+                        out,
+                        "db{}:keys={},expires={},avg_ttl=0\r\n",
+                        db,
+                        keyspace.len(),
+                        expires
+                    )
+                    .unwrap();
+                }
+            }
+        }
+    }
+    RedisItem::BulkString(out)
+}
@@ -0,0 +1,169 @@
+/// A sorted set of integers, stored compactly like redis' intset.
+///
+/// All integers are stored with the same width (2, 4 or 8 bytes), which is
+/// upgraded as soon as a value that does not fit is inserted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IntSet {
+    encoding: usize,
+    contents: Vec<u8>,
+}
+
+impl Default for IntSet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn required_encoding(val: i64) -> usize {
+    if val >= i16::MIN as i64 && val <= i16::MAX as i64 {
+        2
+    } else if val >= i32::MIN as i64 && val <= i32::MAX as i64 {
+        4
+    } else {
+        8
+    }
+}
+
+impl IntSet {
+    pub fn new() -> Self {
+        Self {
+            encoding: 2,
+            contents: Vec::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.contents.len() / self.encoding
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.contents.is_empty()
+    }
+
+    /// Returns the integer at `index` in ascending order.
+    pub fn get(&self, index: usize) -> Option<i64> {
+        let start = index * self.encoding;
+        let bytes = self.contents.get(start..start + self.encoding)?;
+        Some(match self.encoding {
+            2 => i16::from_le_bytes(bytes.try_into().unwrap()) as i64,
+            4 => i32::from_le_bytes(bytes.try_into().unwrap()) as i64,
+            _ => i64::from_le_bytes(bytes.try_into().unwrap()),
+        })
+    }
+
+    fn write(&mut self, index: usize, val: i64) {
+        let start = index * self.encoding;
+        let target = &mut self.contents[start..start + self.encoding];
+        match self.encoding {
+            2 => target.copy_from_slice(&(val as i16).to_le_bytes()),
+            4 => target.copy_from_slice(&(val as i32).to_le_bytes()),
+            _ => target.copy_from_slice(&val.to_le_bytes()),
+        }
+    }
+
+    /// Finds `val`, returning its index or the index it would be inserted at.
+    fn search(&self, val: i64) -> Result<usize, usize> {
+        let (mut low, mut high) = (0, self.len());
+        while low < high {
+            let mid = (low + high) / 2;
+            match self.get(mid).unwrap().cmp(&val) {
+                std::cmp::Ordering::Less => low = mid + 1,
+                std::cmp::Ordering::Greater => high = mid,
+                std::cmp::Ordering::Equal => return Ok(mid),
+            }
+        }
+        Err(low)
+    }
+
+    pub fn contains(&self, val: i64) -> bool {
+        required_encoding(val) <= self.encoding && self.search(val).is_ok()
+    }
+
+    /// Inserts `val`, returning `false` if it was already present.
+    pub fn insert(&mut self, val: i64) -> bool {
+        let encoding = required_encoding(val);
+        if encoding > self.encoding {
+            self.upgrade(encoding);
+        }
+        let Err(index) = self.search(val) else {
+            return false;
+        };
+        let start = index * self.encoding;
+        self.contents
+            .splice(start..start, std::iter::repeat_n(0, self.encoding));
+        self.write(index, val);
+        true
+    }
+
+    /// Removes `val`, returning `false` if it was not present.
+    pub fn remove(&mut self, val: i64) -> bool {
+        if !self.contains(val) {
+            return false;
+        }
+        let index = self.search(val).unwrap();
+        self.remove_at(index);
+        true
+    }
+
+    /// Removes and returns the integer at `index`.
+    pub fn remove_at(&mut self, index: usize) -> i64 {
+        let val = self.get(index).unwrap();
+        let start = index * self.encoding;
+        self.contents.drain(start..start + self.encoding);
+        val
+    }
+
+    fn upgrade(&mut self, encoding: usize) {
+        let values: Vec<i64> = self.iter().collect();
+        self.encoding = encoding;
+        self.contents = vec![0; values.len() * encoding];
+        for (index, val) in values.into_iter().enumerate() {
+            self.write(index, val);
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = i64> + '_ {
+        (0..self.len()).map(|index| self.get(index).unwrap())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_insert_sorted() {
+        let mut set = IntSet::new();
+        assert!(set.insert(5));
+        assert!(set.insert(-3));
+        assert!(set.insert(10));
+        assert!(!set.insert(5));
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![-3, 5, 10]);
+        assert_eq!(set.encoding, 2);
+    }
+
+    #[test]
+    pub fn test_upgrade() {
+        let mut set = IntSet::new();
+        set.insert(1);
+        set.insert(100_000);
+        assert_eq!(set.encoding, 4);
+        set.insert(i64::MIN);
+        assert_eq!(set.encoding, 8);
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![i64::MIN, 1, 100_000]);
+        assert!(set.contains(100_000));
+        assert!(!set.contains(2));
+    }
+
+    #[test]
+    pub fn test_remove() {
+        let mut set = IntSet::new();
+        for val in [3, 1, 2] {
+            set.insert(val);
+        }
+        assert!(set.remove(2));
+        assert!(!set.remove(2));
+        assert!(!set.remove(1 << 40));
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![1, 3]);
+    }
+}
@@ -0,0 +1,485 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+
+use feredis_core::item::RedisItem;
+
+use crate::glob;
+use crate::scan::{cursor_reply, ScanOptions, ScanOrder};
+use crate::value::Value;
+use crate::{RedisError, State};
+
+/// The items of a database, together with their tags.
+///
+/// Besides the map itself, the keys are kept ordered by their hash, which
+/// `SCAN` iterates in.
+#[derive(Debug, Default)]
+pub struct Keyspace {
+    items: HashMap<String, (Value, u64)>,
+    order: ScanOrder,
+}
+
+impl Keyspace {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn get(&self, key: &str) -> Option<&(Value, u64)> {
+        self.items.get(key)
+    }
+
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut (Value, u64)> {
+        self.items.get_mut(key)
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.items.contains_key(key)
+    }
+
+    pub fn insert(&mut self, key: String, item: (Value, u64)) -> Option<(Value, u64)> {
+        if !self.items.contains_key(&key) {
+            self.order.insert(&key);
+        }
+        self.items.insert(key, item)
+    }
+
+    /// Returns the item stored at `key`, inserting the result of `f` first
+    /// if there is none.
+    pub fn get_or_insert_with(
+        &mut self,
+        key: String,
+        f: impl FnOnce() -> (Value, u64),
+    ) -> &mut (Value, u64) {
+        if !self.items.contains_key(&key) {
+            self.insert(key.clone(), f());
+        }
+        self.items.get_mut(&key).unwrap()
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<(Value, u64)> {
+        let item = self.items.remove(key)?;
+        self.order.remove(key);
+        Some(item)
+    }
+
+    pub fn clear(&mut self) {
+        self.items.clear();
+        self.order.clear();
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &(Value, u64))> + '_ {
+        self.items.iter().map(|(key, item)| (key.as_str(), item))
+    }
+
+    /// Returns a random key, or `None` if the keyspace is empty.
+    pub fn random_key(&self) -> Option<&str> {
+        self.order.random()
+    }
+
+    /// Visits about `count` keys starting at `cursor`. Returns the cursor
+    /// to continue with, which is zero once the iteration is complete.
+    pub fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<&str>) {
+        self.order.scan(cursor, count)
+    }
+}
+
+/// Counts how many of the keys given as arguments exist. Keys given more
+/// than once are counted every time.
+fn count_existing(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let state = state.borrow();
+    let mut count = 0;
+    for arg in args {
+        let BulkString(key) = arg else {
+            return RedisError::InvalidArguments.into();
+        };
+        count += state.items.contains_key(&key) as i64;
+    }
+    Integer(count)
+}
+
+pub fn do_exists(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    if args.is_empty() {
+        return RedisError::InvalidArguments.into();
+    }
+    count_existing(args, state)
+}
+
+/// There is no eviction, so touching a key only checks for its existence.
+pub fn do_touch(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    if args.is_empty() {
+        return RedisError::InvalidArguments.into();
+    }
+    count_existing(args, state)
+}
+
+pub fn do_type(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let Some(BulkString(key)) = args.pop_front() else {
+        return RedisError::InvalidArguments.into();
+    };
+    let state = state.borrow();
+    let type_name = state
+        .items
+        .get(&key)
+        .map_or("none", |(val, _)| val.type_name());
+    SimpleString(type_name.to_string())
+}
+
+pub fn do_keys(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let (Some(BulkString(pattern)), true) = (args.pop_front(), args.is_empty()) else {
+        return RedisError::InvalidArguments.into();
+    };
+    let state = state.borrow();
+    Array(
+        state
+            .items
+            .iter()
+            .filter(|(key, _)| glob::matches(&pattern, key))
+            .map(|(key, _)| BulkString(key.to_string()))
+            .collect(),
+    )
+}
+
+pub fn do_scan(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let options = match ScanOptions::parse(&mut args, &["type"]) {
+        Ok(options) => options,
+        Err(err) => return err.into(),
+    };
+    let state = state.borrow();
+    let (cursor, keys) = state.items.scan(options.cursor, options.count);
+    // like in redis, the filters apply after the keys have been visited
+    let keys =
+        keys.into_iter()
+            .filter(|key| options.matches(key))
+            .filter(|key| {
+                options.type_name.as_ref().is_none_or(|type_name| {
+                    state.items.get(key).unwrap().0.type_name() == type_name
+                })
+            })
+            .map(|key| BulkString(key.to_string()))
+            .collect();
+    cursor_reply(cursor, keys)
+}
+
+pub fn do_randomkey(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    if !args.is_empty() {
+        return RedisError::InvalidArguments.into();
+    }
+    let state = state.borrow();
+    state
+        .items
+        .random_key()
+        .map_or(Null, |key| BulkString(key.to_string()))
+}
+
+pub fn do_dbsize(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    if !args.is_empty() {
+        return RedisError::InvalidArguments.into();
+    }
+    RedisItem::Integer(state.borrow().items.len() as i64)
+}
+
+pub fn do_copy(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let (Some(BulkString(src)), Some(BulkString(dst))) = (args.pop_front(), args.pop_front())
+    else {
+        return RedisError::InvalidArguments.into();
+    };
+    let mut state = state.borrow_mut();
+    let mut target = state.db;
+    let mut replace = false;
+    while let Some(arg) = args.pop_front() {
+        match arg {
+            BulkString(arg) if arg.eq_ignore_ascii_case("replace") => replace = true,
+            BulkString(arg) if arg.eq_ignore_ascii_case("db") => {
+                let Some(BulkString(db)) = args.pop_front() else {
+                    return RedisError::Syntax.into();
+                };
+                match state.parse_db(&db) {
+                    Ok(db) => target = db,
+                    Err(err) => return err.into(),
+                }
+            }
+            _ => return RedisError::Syntax.into(),
+        }
+    }
+    if src == dst && target == state.db {
+        return SimpleError("ERR source and destination objects are the same".to_string());
+    }
+    let Some((val, tag)) = state.items.get(&src) else {
+        return Integer(0);
+    };
+    let (val, expiry) = (val.clone(), state.expire.get_expiry(*tag));
+    if !replace && state.db_mut(target).contains_key(&dst) {
+        return Integer(0);
+    }
+    // the copy is a separate item, with its own identity
+    let tag = state.next_tag();
+    if let Some(expiry) = expiry {
+        state.expire.push(target, dst.clone(), tag, expiry);
+    }
+    state.blocking.signal_in(target, &dst);
+    state.db_mut(target).insert(dst, (val, tag));
+    Integer(1)
+}
+
+/// Parses the optional `ASYNC` or `SYNC` argument of `FLUSHDB` and
+/// `FLUSHALL`. Both are accepted, but flushing always happens right away.
+fn parse_flush_mode(args: VecDeque<RedisItem>) -> Result<(), RedisError> {
+    match args.front() {
+        Some(RedisItem::BulkString(mode))
+            if args.len() == 1
+                && (mode.eq_ignore_ascii_case("async") || mode.eq_ignore_ascii_case("sync")) =>
+        {
+            Ok(())
+        }
+        None => Ok(()),
+        _ => Err(RedisError::Syntax),
+    }
+}
+
+pub fn do_flushdb(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    if let Err(err) = parse_flush_mode(args) {
+        return err.into();
+    }
+    state.borrow_mut().items.clear();
+    RedisItem::SimpleString("OK".to_string())
+}
+
+pub fn do_flushall(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    if let Err(err) = parse_flush_mode(args) {
+        return err.into();
+    }
+    let mut state = state.borrow_mut();
+    state.items.clear();
+    state.dbs.iter_mut().for_each(Keyspace::clear);
+    RedisItem::SimpleString("OK".to_string())
+}
+
+/// Handles `SELECT`, which changes the database of the connection.
+pub fn do_select(
+    mut args: VecDeque<RedisItem>,
+    state: &RefCell<State>,
+    db: &mut usize,
+) -> RedisItem {
+    use RedisItem::*;
+    let (Some(BulkString(index)), true) = (args.pop_front(), args.is_empty()) else {
+        return RedisError::InvalidArguments.into();
+    };
+    let mut state = state.borrow_mut();
+    match state.parse_db(&index) {
+        Ok(index) => {
+            *db = index;
+            state.select(index);
+            SimpleString("OK".to_string())
+        }
+        Err(err) => err.into(),
+    }
+}
+
+pub fn do_swapdb(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let (Some(BulkString(a)), Some(BulkString(b)), true) =
+        (args.pop_front(), args.pop_front(), args.is_empty())
+    else {
+        return RedisError::InvalidArguments.into();
+    };
+    let mut state = state.borrow_mut();
+    let a = match state.parse_db(&a) {
+        Ok(a) => a,
+        Err(RedisError::NotInteger) => {
+            return SimpleError("ERR invalid first DB index".to_string())
+        }
+        Err(err) => return err.into(),
+    };
+    let b = match state.parse_db(&b) {
+        Ok(b) => b,
+        Err(RedisError::NotInteger) => {
+            return SimpleError("ERR invalid second DB index".to_string())
+        }
+        Err(err) => return err.into(),
+    };
+    state.swap_dbs(a, b);
+    // clients blocked in either database may now find their keys
+    state.blocking.signal_db(a);
+    state.blocking.signal_db(b);
+    SimpleString("OK".to_string())
+}
+
+pub fn do_move(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let (Some(BulkString(key)), Some(BulkString(db)), true) =
+        (args.pop_front(), args.pop_front(), args.is_empty())
+    else {
+        return RedisError::InvalidArguments.into();
+    };
+    let mut state = state.borrow_mut();
+    let target = match state.parse_db(&db) {
+        Ok(target) => target,
+        Err(err) => return err.into(),
+    };
+    if target == state.db {
+        return SimpleError("ERR source and destination objects are the same".to_string());
+    }
+    if !state.items.contains_key(&key) || state.dbs[target].contains_key(&key) {
+        return Integer(0);
+    }
+    let (val, tag) = state.items.remove(&key).unwrap();
+    let expiry = state.expire.get_expiry(tag);
+    let tag = state.next_tag();
+    if let Some(expiry) = expiry {
+        state.expire.push(target, key.clone(), tag, expiry);
+    }
+    state.blocking.signal_in(target, &key);
+    state.dbs[target].insert(key, (val, tag));
+    Integer(1)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn keyspace(keys: impl Iterator<Item = usize>) -> Keyspace {
+        let mut keyspace = Keyspace::new();
+        for key in keys {
+            keyspace.insert(key.to_string(), (Value::Integer(key as i64), 0));
+        }
+        keyspace
+    }
+
+    #[test]
+    pub fn test_scan() {
+        let keyspace = keyspace(0..100);
+        let mut cursor = 0;
+        let mut seen = Vec::new();
+        loop {
+            let (next, keys) = keyspace.scan(cursor, 7);
+            seen.extend(keys.into_iter().map(str::to_string));
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        seen.sort_by_key(|key| key.parse::<usize>().unwrap());
+        assert_eq!(
+            seen,
+            (0..100).map(|key| key.to_string()).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    pub fn test_scan_while_modified() {
+        let mut keyspace = keyspace(0..100);
+        let mut cursor = 0;
+        let mut seen = Vec::new();
+        let mut added = 100;
+        loop {
+            let (next, keys) = keyspace.scan(cursor, 5);
+            seen.extend(keys.into_iter().map(str::to_string));
+            // remove some of the keys beyond 50 and add new ones
+            keyspace.remove(&(added - 40).to_string());
+            for _ in 0..10 {
+                keyspace.insert(added.to_string(), (Value::Integer(0), 0));
+                added += 1;
+            }
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        for key in 0..50 {
+            assert!(seen.contains(&key.to_string()));
+        }
+    }
+
+    #[test]
+    pub fn test_swapdb() {
+        use RedisItem::*;
+        let state = RefCell::new(State::new(16));
+        let (mut db0, mut db1) = (0, 1);
+        crate::run_command(&state, &mut db0, &["set", "a", "0"]);
+        crate::run_command(&state, &mut db1, &["set", "b", "1"]);
+        crate::run_command(&state, &mut db1, &["psetex", "k", "20", "v"]);
+        let res = crate::run_command(&state, &mut db0, &["swapdb", "0", "1"]);
+        assert_eq!(res, SimpleString("OK".to_string()));
+        assert_eq!(
+            crate::run_command(&state, &mut db0, &["get", "b"]),
+            bulk("1")
+        );
+        assert_eq!(
+            crate::run_command(&state, &mut db1, &["get", "a"]),
+            bulk("0")
+        );
+        assert_eq!(crate::run_command(&state, &mut db1, &["get", "b"]), Null);
+
+        // the expiry follows the key into the other database
+        std::thread::sleep(std::time::Duration::from_millis(30));
+        crate::expire::remove_expired(&mut state.borrow_mut());
+        assert_eq!(
+            crate::run_command(&state, &mut db0, &["exists", "k"]),
+            Integer(0)
+        );
+        assert_eq!(
+            crate::run_command(&state, &mut db0, &["get", "b"]),
+            bulk("1")
+        );
+
+        let res = crate::run_command(&state, &mut db0, &["swapdb", "0", "16"]);
+        assert!(matches!(res, SimpleError(_)));
+    }
+
+    #[test]
+    pub fn test_move() {
+        use RedisItem::*;
+        let state = RefCell::new(State::new(16));
+        let mut db = 0;
+        crate::run_command(&state, &mut db, &["psetex", "k", "20", "v"]);
+        crate::run_command(&state, &mut db, &["set", "taken", "0"]);
+        crate::run_command(&state, &mut db, &["select", "1"]);
+        crate::run_command(&state, &mut db, &["set", "taken", "1"]);
+        crate::run_command(&state, &mut db, &["select", "0"]);
+
+        let res = crate::run_command(&state, &mut db, &["move", "k", "1"]);
+        assert_eq!(res, Integer(1));
+        // the key is not moved over an existing one
+        let res = crate::run_command(&state, &mut db, &["move", "taken", "1"]);
+        assert_eq!(res, Integer(0));
+        assert_eq!(
+            crate::run_command(&state, &mut db, &["move", "none", "1"]),
+            Integer(0)
+        );
+        let res = crate::run_command(&state, &mut db, &["move", "taken", "0"]);
+        assert!(matches!(res, SimpleError(_)));
+
+        crate::run_command(&state, &mut db, &["select", "1"]);
+        assert_eq!(
+            crate::run_command(&state, &mut db, &["get", "k"]),
+            bulk("v")
+        );
+        assert_eq!(
+            crate::run_command(&state, &mut db, &["get", "taken"]),
+            bulk("1")
+        );
+        std::thread::sleep(std::time::Duration::from_millis(30));
+        crate::expire::remove_expired(&mut state.borrow_mut());
+        assert_eq!(
+            crate::run_command(&state, &mut db, &["exists", "k"]),
+            Integer(0)
+        );
+    }
+
+    fn bulk(val: &str) -> RedisItem {
+        RedisItem::BulkString(val.to_string())
+    }
+}
@@ -0,0 +1,763 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+
+use feredis_core::item::RedisItem;
+
+use crate::value::{parse_int, Value};
+use crate::{RedisError, State};
+
+/// One of the two ends of a list.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum End {
+    Left,
+    Right,
+}
+
+impl End {
+    pub fn parse(arg: Option<RedisItem>) -> Result<Self, RedisError> {
+        let Some(RedisItem::BulkString(mut arg)) = arg else {
+            return Err(RedisError::InvalidArguments);
+        };
+        arg.make_ascii_lowercase();
+        match arg.as_str() {
+            "left" => Ok(End::Left),
+            "right" => Ok(End::Right),
+            _ => Err(RedisError::Syntax),
+        }
+    }
+}
+
+fn push_end(list: &mut VecDeque<Vec<u8>>, end: End, item: Vec<u8>) {
+    match end {
+        End::Left => list.push_front(item),
+        End::Right => list.push_back(item),
+    }
+}
+
+fn pop_end(list: &mut VecDeque<Vec<u8>>, end: End) -> Option<Vec<u8>> {
+    match end {
+        End::Left => list.pop_front(),
+        End::Right => list.pop_back(),
+    }
+}
+
+/// Returns the list stored at `key`, or `None` if the key does not exist.
+pub fn get_list<'a>(
+    state: &'a mut State,
+    key: &str,
+) -> Result<Option<&'a mut VecDeque<Vec<u8>>>, RedisError> {
+    match state.items.get_mut(key) {
+        Some((Value::List(list), _)) => Ok(Some(list)),
+        Some(_) => Err(RedisError::WrongType),
+        None => Ok(None),
+    }
+}
+
+/// Pops up to `count` items from the list at `key`, removing the key once
+/// the list is empty.
+pub fn pop(
+    state: &mut State,
+    key: &str,
+    end: End,
+    count: usize,
+) -> Result<Option<Vec<Vec<u8>>>, RedisError> {
+    let Some(list) = get_list(state, key)? else {
+        return Ok(None);
+    };
+    // empty lists should not exist
+    assert!(!list.is_empty());
+    let mut res = Vec::new();
+    while res.len() < count {
+        let Some(item) = pop_end(list, end) else {
+            break;
+        };
+        res.push(item);
+    }
+    if list.is_empty() {
+        state.items.remove(key);
+    }
+    Ok(Some(res))
+}
+
+/// Moves a single item from one end of `src` to one end of `dst`.
+pub fn move_item(
+    state: &mut State,
+    src: &str,
+    dst: &str,
+    from: End,
+    to: End,
+) -> Result<Option<Vec<u8>>, RedisError> {
+    let Some(list) = get_list(state, src)? else {
+        return Ok(None);
+    };
+    // a list moved onto itself is rotated in place, keeping its expiry
+    if src == dst {
+        let item = pop_end(list, from).unwrap();
+        push_end(list, to, item.clone());
+        state.blocking.signal(dst);
+        return Ok(Some(item));
+    }
+    // the destination type has to be checked before anything is popped
+    get_list(state, dst)?;
+    let Some(mut items) = pop(state, src, from, 1)? else {
+        return Ok(None);
+    };
+    let item = items.pop().unwrap();
+    let tag = state.next_tag();
+    let (Value::List(list), _) = state
+        .items
+        .get_or_insert_with(dst.to_string(), || (Value::List(VecDeque::new()), tag))
+    else {
+        unreachable!();
+    };
+    push_end(list, to, item.clone());
+    state.blocking.signal(dst);
+    Ok(Some(item))
+}
+
+/// The key of a list and the items popped from it.
+pub type Popped = (String, Vec<Vec<u8>>);
+
+/// Pops up to `count` items from the first non-empty list in `keys`.
+pub fn multi_pop(
+    state: &mut State,
+    keys: &[String],
+    end: End,
+    count: usize,
+) -> Result<Option<Popped>, RedisError> {
+    for key in keys {
+        if let Some(items) = pop(state, key, end, count)? {
+            return Ok(Some((key.clone(), items)));
+        }
+    }
+    Ok(None)
+}
+
+/// Parses the `numkeys key [key ...]` arguments shared by the multi-key pops.
+pub fn parse_keys(args: &mut VecDeque<RedisItem>) -> Result<Vec<String>, RedisError> {
+    let Some(RedisItem::BulkString(numkeys)) = args.pop_front() else {
+        return Err(RedisError::InvalidArguments);
+    };
+    let numkeys = parse_int(&numkeys).ok_or(RedisError::NotInteger)?;
+    if numkeys <= 0 {
+        return Err(RedisError::Custom("ERR numkeys should be greater than 0"));
+    }
+    if numkeys as usize > args.len() {
+        return Err(RedisError::Syntax);
+    }
+    let mut keys = Vec::with_capacity(numkeys as usize);
+    for _ in 0..numkeys {
+        let Some(RedisItem::BulkString(key)) = args.pop_front() else {
+            return Err(RedisError::InvalidArguments);
+        };
+        keys.push(key);
+    }
+    Ok(keys)
+}
+
+/// Parses the optional `COUNT count` suffix shared by the multi-key pops.
+pub fn parse_mpop_count(args: &mut VecDeque<RedisItem>) -> Result<usize, RedisError> {
+    match (args.pop_front(), args.pop_front()) {
+        (None, None) => Ok(1),
+        (Some(RedisItem::BulkString(mut arg)), Some(RedisItem::BulkString(count))) => {
+            arg.make_ascii_lowercase();
+            if arg != "count" || !args.is_empty() {
+                return Err(RedisError::Syntax);
+            }
+            match parse_int(&count) {
+                Some(count) if count > 0 => Ok(count as usize),
+                _ => Err(RedisError::Custom("ERR count should be greater than 0")),
+            }
+        }
+        _ => Err(RedisError::Syntax),
+    }
+}
+
+pub fn mpop_reply(res: Option<Popped>) -> RedisItem {
+    use RedisItem::*;
+    match res {
+        Some((key, items)) => Array(vec![
+            BulkString(key),
+            Array(items.into_iter().map(RedisItem::bulk).collect()),
+        ]),
+        None => Null,
+    }
+}
+
+/// Converts a (possibly negative) index into an offset into a list of `len` items.
+fn normalize_index(index: i64, len: usize) -> Option<usize> {
+    let index = if index < 0 { index + len as i64 } else { index };
+    if index < 0 || index >= len as i64 {
+        None
+    } else {
+        Some(index as usize)
+    }
+}
+
+/// Converts a (possibly negative) inclusive range into offsets into a list
+/// of `len` items. Returns `None` if the range is empty.
+pub fn normalize_range(start: i64, end: i64, len: usize) -> Option<(usize, usize)> {
+    let len = len as i64;
+    let start = if start < 0 {
+        (start + len).max(0)
+    } else {
+        start
+    };
+    let end = if end < 0 { end + len } else { end.min(len - 1) };
+    if start > end || start >= len {
+        None
+    } else {
+        Some((start as usize, end as usize))
+    }
+}
+
+fn push(
+    mut args: VecDeque<RedisItem>,
+    state: &RefCell<State>,
+    end: End,
+    create: bool,
+) -> RedisItem {
+    use RedisItem::*;
+    let Some(BulkString(key)) = args.pop_front() else {
+        return RedisError::InvalidArguments.into();
+    };
+    let mut items = Vec::with_capacity(args.len());
+    for item in args {
+        let Some(item) = item.into_bytes() else {
+            return RedisError::InvalidArguments.into();
+        };
+        items.push(item);
+    }
+    if items.is_empty() {
+        return RedisError::InvalidArguments.into();
+    }
+    let mut state = state.borrow_mut();
+    let state = &mut *state;
+    match get_list(state, &key) {
+        Ok(Some(_)) => {}
+        Ok(None) if create => {
+            let tag = state.next_tag();
+            state
+                .items
+                .insert(key.clone(), (Value::List(VecDeque::new()), tag));
+        }
+        Ok(None) => return Integer(0),
+        Err(err) => return err.into(),
+    }
+    let Ok(Some(list)) = get_list(state, &key) else {
+        unreachable!();
+    };
+    for item in items {
+        push_end(list, end, item);
+    }
+    let len = list.len();
+    state.blocking.signal(&key);
+    Integer(len as i64)
+}
+
+pub fn do_lpush(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    push(args, state, End::Left, true)
+}
+
+pub fn do_rpush(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    push(args, state, End::Right, true)
+}
+
+pub fn do_lpushx(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    push(args, state, End::Left, false)
+}
+
+pub fn do_rpushx(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    push(args, state, End::Right, false)
+}
+
+fn pop_command(mut args: VecDeque<RedisItem>, state: &RefCell<State>, end: End) -> RedisItem {
+    enum PopCount {
+        Single,
+        Count(usize),
+    }
+    use RedisItem::*;
+    let Some(BulkString(key)) = args.pop_front() else {
+        return RedisError::InvalidArguments.into();
+    };
+    let count = match args.pop_front() {
+        Some(BulkString(val)) => match parse_int(&val) {
+            Some(val) if val >= 0 => PopCount::Count(val as usize),
+            _ => return RedisError::NotPositive.into(),
+        },
+        None => PopCount::Single,
+        _ => return RedisError::InvalidArguments.into(),
+    };
+    let mut state = state.borrow_mut();
+    let n = match count {
+        PopCount::Single => 1,
+        PopCount::Count(n) => n,
+    };
+    match pop(&mut state, &key, end, n) {
+        Ok(Some(mut items)) => match count {
+            PopCount::Single => RedisItem::bulk(items.pop().unwrap()),
+            PopCount::Count(_) => Array(items.into_iter().map(RedisItem::bulk).collect()),
+        },
+        Ok(None) => Null,
+        Err(err) => err.into(),
+    }
+}
+
+pub fn do_lpop(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    pop_command(args, state, End::Left)
+}
+
+pub fn do_rpop(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    pop_command(args, state, End::Right)
+}
+
+pub fn do_llen(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let Some(BulkString(key)) = args.pop_front() else {
+        return RedisError::InvalidArguments.into();
+    };
+    match get_list(&mut state.borrow_mut(), &key) {
+        Ok(list) => Integer(list.map_or(0, |list| list.len() as i64)),
+        Err(err) => err.into(),
+    }
+}
+
+pub fn do_lrange(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let (Some(BulkString(key)), Some(BulkString(start)), Some(BulkString(end))) =
+        (args.pop_front(), args.pop_front(), args.pop_front())
+    else {
+        return RedisError::InvalidArguments.into();
+    };
+    let (Some(start), Some(end)) = (parse_int(&start), parse_int(&end)) else {
+        return RedisError::NotInteger.into();
+    };
+    let mut state = state.borrow_mut();
+    let list = match get_list(&mut state, &key) {
+        Ok(Some(list)) => list,
+        Ok(None) => return Array(Vec::new()),
+        Err(err) => return err.into(),
+    };
+    let Some((start, end)) = normalize_range(start, end, list.len()) else {
+        return Array(Vec::new());
+    };
+    Array(
+        list.range(start..=end)
+            .map(|item| RedisItem::bulk(item.clone()))
+            .collect(),
+    )
+}
+
+pub fn do_lindex(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let (Some(BulkString(key)), Some(BulkString(index))) = (args.pop_front(), args.pop_front())
+    else {
+        return RedisError::InvalidArguments.into();
+    };
+    let Some(index) = parse_int(&index) else {
+        return RedisError::NotInteger.into();
+    };
+    let mut state = state.borrow_mut();
+    match get_list(&mut state, &key) {
+        Ok(Some(list)) => match normalize_index(index, list.len()) {
+            Some(index) => RedisItem::bulk(list[index].clone()),
+            None => Null,
+        },
+        Ok(None) => Null,
+        Err(err) => err.into(),
+    }
+}
+
+pub fn do_lset(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let (Some(BulkString(key)), Some(BulkString(index)), Some(item)) =
+        (args.pop_front(), args.pop_front(), args.pop_front())
+    else {
+        return RedisError::InvalidArguments.into();
+    };
+    let Some(item) = item.into_bytes() else {
+        return RedisError::InvalidArguments.into();
+    };
+    let Some(index) = parse_int(&index) else {
+        return RedisError::NotInteger.into();
+    };
+    let mut state = state.borrow_mut();
+    match get_list(&mut state, &key) {
+        Ok(Some(list)) => match normalize_index(index, list.len()) {
+            Some(index) => {
+                list[index] = item;
+                SimpleString("OK".to_string())
+            }
+            None => RedisError::IndexOutOfRange.into(),
+        },
+        Ok(None) => RedisError::NoSuchKey.into(),
+        Err(err) => err.into(),
+    }
+}
+
+pub fn do_linsert(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let (Some(BulkString(key)), Some(BulkString(mut position))) =
+        (args.pop_front(), args.pop_front())
+    else {
+        return RedisError::InvalidArguments.into();
+    };
+    let (Some(pivot), Some(item)) = (
+        args.pop_front().and_then(RedisItem::into_bytes),
+        args.pop_front().and_then(RedisItem::into_bytes),
+    ) else {
+        return RedisError::InvalidArguments.into();
+    };
+    position.make_ascii_lowercase();
+    let offset = match position.as_str() {
+        "before" => 0,
+        "after" => 1,
+        _ => return RedisError::Syntax.into(),
+    };
+    let mut state = state.borrow_mut();
+    let list = match get_list(&mut state, &key) {
+        Ok(Some(list)) => list,
+        Ok(None) => return Integer(0),
+        Err(err) => return err.into(),
+    };
+    match list.iter().position(|it| *it == pivot) {
+        Some(index) => {
+            list.insert(index + offset, item);
+            Integer(list.len() as i64)
+        }
+        None => Integer(-1),
+    }
+}
+
+pub fn do_lrem(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let (Some(BulkString(key)), Some(BulkString(count)), Some(item)) = (
+        args.pop_front(),
+        args.pop_front(),
+        args.pop_front().and_then(RedisItem::into_bytes),
+    ) else {
+        return RedisError::InvalidArguments.into();
+    };
+    let Some(count) = parse_int(&count) else {
+        return RedisError::NotInteger.into();
+    };
+    let mut state = state.borrow_mut();
+    let list = match get_list(&mut state, &key) {
+        Ok(Some(list)) => list,
+        Ok(None) => return Integer(0),
+        Err(err) => return err.into(),
+    };
+    // a count of zero removes all matching items
+    let limit = if count == 0 {
+        usize::MAX
+    } else {
+        count.unsigned_abs() as usize
+    };
+    // removing from the tail skips the matches before the last `limit`
+    let mut skip = if count >= 0 {
+        0
+    } else {
+        let matches = list.iter().filter(|it| **it == item).count();
+        matches.saturating_sub(limit)
+    };
+    let mut removed = 0;
+    list.retain(|it| {
+        if *it != item || removed >= limit {
+            return true;
+        }
+        if skip > 0 {
+            skip -= 1;
+            return true;
+        }
+        removed += 1;
+        false
+    });
+    if list.is_empty() {
+        state.items.remove(&key);
+    }
+    Integer(removed as i64)
+}
+
+pub fn do_ltrim(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let (Some(BulkString(key)), Some(BulkString(start)), Some(BulkString(end))) =
+        (args.pop_front(), args.pop_front(), args.pop_front())
+    else {
+        return RedisError::InvalidArguments.into();
+    };
+    let (Some(start), Some(end)) = (parse_int(&start), parse_int(&end)) else {
+        return RedisError::NotInteger.into();
+    };
+    let mut state = state.borrow_mut();
+    let list = match get_list(&mut state, &key) {
+        Ok(Some(list)) => list,
+        Ok(None) => return SimpleString("OK".to_string()),
+        Err(err) => return err.into(),
+    };
+    match normalize_range(start, end, list.len()) {
+        Some((start, end)) => {
+            list.truncate(end + 1);
+            list.drain(..start);
+        }
+        None => list.clear(),
+    }
+    if list.is_empty() {
+        state.items.remove(&key);
+    }
+    SimpleString("OK".to_string())
+}
+
+pub fn do_lpos(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let (Some(BulkString(key)), Some(item)) = (
+        args.pop_front(),
+        args.pop_front().and_then(RedisItem::into_bytes),
+    ) else {
+        return RedisError::InvalidArguments.into();
+    };
+    let mut rank = 1;
+    let mut count = None;
+    let mut max_len = 0;
+    while let Some(arg) = args.pop_front() {
+        let (BulkString(mut arg), Some(BulkString(val))) = (arg, args.pop_front()) else {
+            return RedisError::Syntax.into();
+        };
+        let Some(val) = parse_int(&val) else {
+            return RedisError::NotInteger.into();
+        };
+        arg.make_ascii_lowercase();
+        match arg.as_str() {
+            "rank" => {
+                if val == 0 || val == i64::MIN {
+                    return SimpleError("ERR RANK can't be zero: use 1 to start from the first match, 2 from the second ... or use negative to start from the end of the list".to_string());
+                }
+                rank = val;
+            }
+            "count" => {
+                if val < 0 {
+                    return SimpleError("ERR COUNT can't be negative".to_string());
+                }
+                count = Some(val as usize);
+            }
+            "maxlen" => {
+                if val < 0 {
+                    return SimpleError("ERR MAXLEN can't be negative".to_string());
+                }
+                max_len = val as usize;
+            }
+            _ => return RedisError::Syntax.into(),
+        }
+    }
+    let mut state = state.borrow_mut();
+    let list = match get_list(&mut state, &key) {
+        Ok(Some(list)) => list,
+        Ok(None) if count.is_some() => return Array(Vec::new()),
+        Ok(None) => return Null,
+        Err(err) => return err.into(),
+    };
+    // a count or max_len of zero means unlimited
+    let limit = match count {
+        Some(0) | None => usize::MAX,
+        Some(n) => n,
+    };
+    let max_len = if max_len == 0 { list.len() } else { max_len };
+    let indices: Box<dyn Iterator<Item = usize>> = if rank > 0 {
+        Box::new(0..list.len())
+    } else {
+        Box::new((0..list.len()).rev())
+    };
+    let mut skip = rank.unsigned_abs() - 1;
+    let mut res = Vec::new();
+    for index in indices.take(max_len) {
+        if list[index] != item {
+            continue;
+        }
+        if skip > 0 {
+            skip -= 1;
+            continue;
+        }
+        res.push(Integer(index as i64));
+        if count.is_none() || res.len() >= limit {
+            break;
+        }
+    }
+    match count {
+        Some(_) => Array(res),
+        None => res.pop().unwrap_or(Null),
+    }
+}
+
+pub fn do_lmove(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let (Some(BulkString(src)), Some(BulkString(dst))) = (args.pop_front(), args.pop_front())
+    else {
+        return RedisError::InvalidArguments.into();
+    };
+    let (from, to) = match (End::parse(args.pop_front()), End::parse(args.pop_front())) {
+        (Ok(from), Ok(to)) => (from, to),
+        (Err(err), _) | (_, Err(err)) => return err.into(),
+    };
+    match move_item(&mut state.borrow_mut(), &src, &dst, from, to) {
+        Ok(item) => item.map_or(Null, RedisItem::bulk),
+        Err(err) => err.into(),
+    }
+}
+
+pub fn do_rpoplpush(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let (Some(BulkString(src)), Some(BulkString(dst))) = (args.pop_front(), args.pop_front())
+    else {
+        return RedisError::InvalidArguments.into();
+    };
+    match move_item(&mut state.borrow_mut(), &src, &dst, End::Right, End::Left) {
+        Ok(item) => item.map_or(Null, RedisItem::bulk),
+        Err(err) => err.into(),
+    }
+}
+
+pub fn do_lmpop(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    let keys = match parse_keys(&mut args) {
+        Ok(keys) => keys,
+        Err(err) => return err.into(),
+    };
+    let end = match End::parse(args.pop_front()) {
+        Ok(end) => end,
+        Err(err) => return err.into(),
+    };
+    let count = match parse_mpop_count(&mut args) {
+        Ok(count) => count,
+        Err(err) => return err.into(),
+    };
+    match multi_pop(&mut state.borrow_mut(), &keys, end, count) {
+        Ok(res) => mpop_reply(res),
+        Err(err) => err.into(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn run(state: &RefCell<State>, args: &[&str]) -> RedisItem {
+        crate::run_command(state, &mut 0, args)
+    }
+
+    fn bulks(args: &[&str]) -> RedisItem {
+        RedisItem::Array(
+            args.iter()
+                .map(|arg| RedisItem::BulkString(arg.to_string()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    pub fn test_push_pop() {
+        use RedisItem::*;
+        let state = RefCell::new(State::new(1));
+        assert_eq!(run(&state, &["rpush", "l", "b", "c"]), Integer(2));
+        assert_eq!(run(&state, &["lpush", "l", "a"]), Integer(3));
+        assert_eq!(run(&state, &["lpushx", "none", "a"]), Integer(0));
+        assert_eq!(
+            run(&state, &["lrange", "l", "0", "-1"]),
+            bulks(&["a", "b", "c"])
+        );
+        assert_eq!(
+            run(&state, &["lindex", "l", "-1"]),
+            BulkString("c".to_string())
+        );
+        assert_eq!(run(&state, &["rpop", "l", "2"]), bulks(&["c", "b"]));
+        assert_eq!(run(&state, &["lpop", "l"]), BulkString("a".to_string()));
+        assert_eq!(run(&state, &["llen", "l"]), Integer(0));
+        assert_eq!(run(&state, &["lpop", "l"]), Null);
+
+        // items are kept as bytes
+        let item = BulkBytes(vec![0xff, 0, b'a']);
+        let args = VecDeque::from(vec![BulkString("l".to_string()), item.clone()]);
+        assert_eq!(do_rpush(args, &state), Integer(1));
+        assert_eq!(
+            run(&state, &["lrange", "l", "0", "0"]),
+            Array(vec![item.clone()])
+        );
+        assert_eq!(run(&state, &["rpop", "l"]), item);
+
+        run(&state, &["set", "s", "v"]);
+        assert!(matches!(run(&state, &["lpush", "s", "a"]), SimpleError(_)));
+    }
+
+    #[test]
+    pub fn test_modify() {
+        use RedisItem::*;
+        let state = RefCell::new(State::new(1));
+        run(&state, &["rpush", "l", "a", "x", "b", "x", "c", "x"]);
+        assert_eq!(run(&state, &["lrem", "l", "-2", "x"]), Integer(2));
+        assert_eq!(
+            run(&state, &["lrange", "l", "0", "-1"]),
+            bulks(&["a", "x", "b", "c"])
+        );
+        run(&state, &["rpush", "l", "x", "x"]);
+        assert_eq!(run(&state, &["lrem", "l", "2", "x"]), Integer(2));
+        assert_eq!(
+            run(&state, &["lrange", "l", "0", "-1"]),
+            bulks(&["a", "b", "c", "x"])
+        );
+        assert_eq!(run(&state, &["lrem", "l", "0", "x"]), Integer(1));
+
+        assert_eq!(
+            run(&state, &["linsert", "l", "after", "b", "y"]),
+            Integer(4)
+        );
+        assert_eq!(
+            run(&state, &["linsert", "l", "before", "z", "y"]),
+            Integer(-1)
+        );
+        assert_eq!(
+            run(&state, &["lset", "l", "0", "A"]),
+            SimpleString("OK".to_string())
+        );
+        assert!(matches!(
+            run(&state, &["lset", "l", "9", "A"]),
+            SimpleError(_)
+        ));
+        assert_eq!(
+            run(&state, &["lrange", "l", "0", "-1"]),
+            bulks(&["A", "b", "y", "c"])
+        );
+        assert_eq!(run(&state, &["lpos", "l", "y"]), Integer(2));
+        assert_eq!(run(&state, &["lpos", "l", "q"]), Null);
+        assert_eq!(
+            run(&state, &["ltrim", "l", "1", "-2"]),
+            SimpleString("OK".to_string())
+        );
+        assert_eq!(run(&state, &["lrange", "l", "0", "-1"]), bulks(&["b", "y"]));
+        run(&state, &["ltrim", "l", "5", "10"]);
+        assert_eq!(run(&state, &["llen", "l"]), Integer(0));
+    }
+
+    #[test]
+    pub fn test_lmove() {
+        use RedisItem::*;
+        let state = RefCell::new(State::new(1));
+        run(&state, &["rpush", "l", "a", "b"]);
+        assert_eq!(
+            run(&state, &["lmove", "l", "d", "left", "right"]),
+            BulkString("a".to_string())
+        );
+        assert_eq!(
+            run(&state, &["rpoplpush", "l", "d"]),
+            BulkString("b".to_string())
+        );
+        assert_eq!(run(&state, &["lrange", "d", "0", "-1"]), bulks(&["b", "a"]));
+        assert_eq!(run(&state, &["llen", "l"]), Integer(0));
+        assert_eq!(run(&state, &["lmove", "l", "d", "left", "right"]), Null);
+
+        // a list moved onto itself is rotated and keeps its expiry
+        run(&state, &["expire", "d", "100"]);
+        run(&state, &["lmove", "d", "d", "left", "right"]);
+        assert_eq!(run(&state, &["lrange", "d", "0", "-1"]), bulks(&["a", "b"]));
+        run(&state, &["rpop", "d"]);
+        run(&state, &["lmove", "d", "d", "left", "right"]);
+        let state = state.borrow();
+        let (_, tag) = state.items.get("d").unwrap();
+        assert!(state.expire.get_expiry(*tag).is_some());
+    }
+}
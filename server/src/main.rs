@@ -1,50 +1,215 @@
+pub mod aof;
+pub mod bitmap;
+pub mod blocking;
+pub mod cluster;
 pub mod expire;
+pub mod geo;
+pub mod glob;
+pub mod hash;
+pub mod hyperloglog;
+pub mod info;
+pub mod intset;
+pub mod keyspace;
+pub mod list;
+pub mod rdb;
+pub mod replication;
+pub mod scan;
+pub mod set;
+pub mod skiplist;
+pub mod sort;
+pub mod stream;
+pub mod string;
+pub mod value;
+pub mod zset;
 
-use std::collections::{HashMap, VecDeque};
+use std::collections::VecDeque;
 
 use std::cell::RefCell;
 use std::net::{TcpListener, TcpStream};
-use std::time::Instant;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
 
-use smol::io::{AsyncWriteExt, BufReader};
+use smol::io::{AsyncBufRead, AsyncBufReadExt, AsyncWriteExt, BufReader};
 use smol::Async;
 use std::io;
 
+use blocking::Blocking;
 use expire::Expire;
 use feredis_core::item::RedisItem;
+use keyspace::Keyspace;
 
 #[derive(Debug)]
 pub struct State {
     stop: bool,
-    items: HashMap<String, (RedisItem, u64)>,
+    /// the keyspace of the selected database
+    items: Keyspace,
+    /// the keyspaces of all databases, where the slot of the selected
+    /// database is left empty while it is taken out into `items`
+    dbs: Vec<Keyspace>,
+    /// the index of the selected database
+    db: usize,
     expire: Expire,
+    blocking: Blocking,
     tag_counter: u64,
-}
-
-struct ItemStore {
-    // keys: HashMap<String, usize>,
-    // items: HashMap<usize, RedisItem>,
-    items: HashMap<String, (RedisItem, u64)>,
-    counter: u64,
+    /// the number of changes made to the dataset
+    dirty: u64,
+    rdb: rdb::Rdb,
+    aof: aof::Aof,
+    repl: replication::Replication,
+    /// the cluster this node belongs to, if cluster mode is enabled
+    cluster: Option<cluster::Cluster>,
+    /// identifies this run of the server
+    run_id: String,
 }
 
 impl State {
-    fn new() -> Self {
+    fn new(databases: usize) -> Self {
         Self {
             stop: false,
-            items: HashMap::new(),
+            items: Keyspace::new(),
+            dbs: (0..databases).map(|_| Keyspace::new()).collect(),
+            db: 0,
             expire: Expire::new(),
+            blocking: Blocking::new(),
             tag_counter: 0,
+            dirty: 0,
+            rdb: rdb::Rdb::default(),
+            aof: aof::Aof::default(),
+            repl: replication::Replication::default(),
+            cluster: None,
+            run_id: replication::random_id(),
+        }
+    }
+
+    /// Makes `db` the database which commands operate on.
+    fn select(&mut self, db: usize) {
+        if db != self.db {
+            std::mem::swap(&mut self.items, &mut self.dbs[self.db]);
+            std::mem::swap(&mut self.items, &mut self.dbs[db]);
+            self.db = db;
+            self.blocking.select(db);
+        }
+    }
+
+    /// Returns the keyspace of database `db`, whether it is selected or not.
+    fn db(&self, db: usize) -> &Keyspace {
+        if db == self.db {
+            &self.items
+        } else {
+            &self.dbs[db]
+        }
+    }
+
+    /// Returns the keyspace of database `db`, whether it is selected or not.
+    fn db_mut(&mut self, db: usize) -> &mut Keyspace {
+        if db == self.db {
+            &mut self.items
+        } else {
+            &mut self.dbs[db]
+        }
+    }
+
+    /// Swaps the contents of databases `a` and `b`.
+    fn swap_dbs(&mut self, a: usize, b: usize) {
+        // put the selected database back in its slot for the swap
+        std::mem::swap(&mut self.items, &mut self.dbs[self.db]);
+        self.dbs.swap(a, b);
+        std::mem::swap(&mut self.items, &mut self.dbs[self.db]);
+        // the pending expiries follow their keys
+        self.expire.swap_dbs(a, b);
+    }
+
+    /// Parses a database index, which has to be in range.
+    fn parse_db(&self, db: &str) -> Result<usize, RedisError> {
+        let db = value::parse_int(db).ok_or(RedisError::NotInteger)?;
+        usize::try_from(db)
+            .ok()
+            .filter(|db| *db < self.dbs.len())
+            .ok_or(RedisError::Custom("ERR DB index is out of range"))
+    }
+
+    /// Records a change to the dataset made by the command `args` in the
+    /// selected database.
+    fn propagate(&mut self, args: Vec<RedisItem>) {
+        self.propagate_in(self.db, args);
+    }
+
+    /// Records a change to the dataset made by the command `args` in database
+    /// `db`, where `args` includes the command name. Every change, whether
+    /// made by a command or an expiry, goes through here to be appended to
+    /// the AOF and fed to replicas. A replica passes on the stream of its
+    /// master instead.
+    fn propagate_in(&mut self, db: usize, args: Vec<RedisItem>) {
+        self.dirty += 1;
+        if !self.repl.is_replica() {
+            self.repl.feed_command(db, &args);
+        }
+        self.aof.append(db, args);
+    }
+
+    /// Returns a fresh tag, used to give an item a new identity.
+    fn next_tag(&mut self) -> u64 {
+        let tag = self.tag_counter;
+        self.tag_counter += 1;
+        tag
+    }
+
+    /// Sets the expiry time of the item stored at `key`.
+    /// Returns `false` if there is no such item.
+    fn set_expiry(&mut self, key: &str, time: Instant) -> bool {
+        let Some(tag) = self.items.get(key).map(|(_, tag)| *tag) else {
+            return false;
+        };
+        if self.expire.get_expiry(tag).is_some() {
+            // the previous expiry must no longer apply to this item
+            let new_tag = self.next_tag();
+            let (_, tag_mut) = self.items.get_mut(key).unwrap();
+            *tag_mut = new_tag;
+            self.expire.push(self.db, key.to_string(), new_tag, time);
+        } else {
+            self.expire.push(self.db, key.to_string(), tag, time);
+        }
+        true
+    }
+
+    /// Removes the expiry of the item stored at `key`.
+    /// Returns `false` if there is no such item or it has no expiry.
+    fn persist(&mut self, key: &str) -> bool {
+        let Some(tag) = self.items.get(key).map(|(_, tag)| *tag) else {
+            return false;
+        };
+        if self.expire.get_expiry(tag).is_none() {
+            return false;
         }
+        // by updating the tag we give the item a new "identity",
+        // preventing it from being expired
+        let new_tag = self.next_tag();
+        let (_, tag_mut) = self.items.get_mut(key).unwrap();
+        *tag_mut = new_tag;
+        true
     }
 }
 
 #[derive(Debug)]
-enum RedisError {
+pub enum RedisError {
     InvalidCommand,
     InvalidArguments,
     WrongType,
     UnknownCommand,
+    NotInteger,
+    NotFloat,
+    Overflow,
+    DecrementOverflow,
+    NanOrInfinity,
+    Syntax,
+    InvalidExpireTime(&'static str),
+    OffsetOutOfRange,
+    StringTooLong,
+    NotPositive,
+    NoSuchKey,
+    IndexOutOfRange,
+    NoGroup { key: String, group: String },
+    Custom(&'static str),
 }
 
 impl From<RedisError> for RedisItem {
@@ -54,56 +219,52 @@ impl From<RedisError> for RedisItem {
         match value {
             InvalidCommand => SimpleError("invalid command".to_string()),
             InvalidArguments => SimpleError("invalid arguments".to_string()),
-            WrongType => SimpleError("WRONGTYPE".to_string()),
+            WrongType => SimpleError(
+                "WRONGTYPE Operation against a key holding the wrong kind of value".to_string(),
+            ),
             UnknownCommand => SimpleError("unknown command".to_string()),
+            NotInteger => SimpleError("ERR value is not an integer or out of range".to_string()),
+            NotFloat => SimpleError("ERR value is not a valid float".to_string()),
+            Overflow => SimpleError("ERR increment or decrement would overflow".to_string()),
+            DecrementOverflow => SimpleError("ERR decrement would overflow".to_string()),
+            NanOrInfinity => SimpleError("ERR increment would produce NaN or Infinity".to_string()),
+            Syntax => SimpleError("ERR syntax error".to_string()),
+            InvalidExpireTime(command) => {
+                SimpleError(format!("ERR invalid expire time in '{}' command", command))
+            }
+            OffsetOutOfRange => SimpleError("ERR offset is out of range".to_string()),
+            StringTooLong => SimpleError(
+                "ERR string exceeds maximum allowed size (proto-max-bulk-len)".to_string(),
+            ),
+            NotPositive => SimpleError("ERR value is out of range, must be positive".to_string()),
+            NoSuchKey => SimpleError("ERR no such key".to_string()),
+            IndexOutOfRange => SimpleError("ERR index out of range".to_string()),
+            NoGroup { key, group } => SimpleError(format!(
+                "NOGROUP No such key '{}' or consumer group '{}'",
+                key, group
+            )),
+            Custom(message) => SimpleError(message.to_string()),
         }
     }
 }
 
 fn do_ping(mut args: VecDeque<RedisItem>, _: &RefCell<State>) -> RedisItem {
     use RedisItem::*;
-    if let Some(BulkString(val)) = args.pop_front() {
-        RedisItem::BulkString(val)
+    if let Some(val @ (BulkString(_) | BulkBytes(_))) = args.pop_front() {
+        val
     } else {
         RedisItem::SimpleString("PONG".to_string())
     }
 }
 
-fn do_set(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
-    use RedisItem::*;
-    let Some(BulkString(key)) = args.pop_front() else {
-        return SimpleError("invalid arguments".to_string());
-    };
-    let Some(val @ BulkString(_)) = args.pop_front() else {
-        return SimpleError("invalid arguments".to_string());
-    };
-    let mut state = state.borrow_mut();
-    let tag = state.tag_counter;
-    state.items.insert(key, (val, tag));
-    state.tag_counter += 1;
-    SimpleString("OK".to_string())
-}
-
-fn do_get(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
-    use RedisItem::*;
-    let Some(BulkString(key)) = args.pop_front() else {
-        return SimpleError("invalid arguments".to_string());
-    };
-    match state.borrow().items.get(&key).map(|(val, _)| val) {
-        Some(BulkString(val)) => BulkString(val.clone()),
-        Some(_) => SimpleError("value is not a string".to_string()),
-        None => Null,
-    }
-}
-
 fn do_del(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
     use RedisItem::*;
     let mut counter = 0;
     while let Some(item) = args.pop_front() {
         let BulkString(key) = item else {
-            return SimpleError("invalid arguments".to_string());
+            return RedisError::InvalidArguments.into();
         };
-        if let Some(_) = state.borrow_mut().items.remove(&key) {
+        if state.borrow_mut().items.remove(&key).is_some() {
             counter += 1;
         }
     }
@@ -113,165 +274,478 @@ fn do_del(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
 fn do_expire(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
     use RedisItem::*;
     let Some(BulkString(key)) = args.pop_front() else {
-        return SimpleError("invalid arguments".to_string());
+        return RedisError::InvalidArguments.into();
     };
     let Some(BulkString(val)) = args.pop_front() else {
-        return SimpleError("invalid arguments".to_string());
+        return RedisError::InvalidArguments.into();
     };
     let Ok(time) = val.parse::<u64>() else {
-        return SimpleError("invalid arguments".to_string());
-    };
-    let Some(tag) = state.borrow().items.get(&key).map(|(_, tag)| *tag) else {
-        return Integer(0)
+        return RedisError::InvalidArguments.into();
     };
     let time = Instant::now() + std::time::Duration::from_secs(time);
-    let mut state = state.borrow_mut();
-    let state = &mut *state;
-    if let Some(_) = state.expire.get_expiry(tag) {
-        let (_, tag_mut) = state.items.get_mut(&key).unwrap();
-        *tag_mut = state.tag_counter;
-        state.tag_counter += 1;
-        state.expire.push(key, *tag_mut, time);
-    } else {
-        state.expire.push(key, tag, time);
-    }
-    Integer(1)
+    Integer(state.borrow_mut().set_expiry(&key, time) as i64)
 }
 
-fn do_persist(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+fn do_pexpireat(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
     use RedisItem::*;
     let Some(BulkString(key)) = args.pop_front() else {
-        return SimpleError("invalid arguments".to_string());
+        return RedisError::InvalidArguments.into();
+    };
+    let Some(BulkString(val)) = args.pop_front() else {
+        return RedisError::InvalidArguments.into();
+    };
+    let time = match string::parse_expire_at(&val, 1, "pexpireat") {
+        Ok(time) => time,
+        Err(err) => return err.into(),
     };
     let mut state = state.borrow_mut();
-    let state = &mut *state;
-    // by updating the tag we give the item a new "identity",
-    // preventing it from being expired
-    if let Some((_, tag)) = state.items.get_mut(&key) {
-        *tag = state.tag_counter;
-        state.tag_counter += 1;
-        Integer(1)
-    } else {
-        Integer(0)
+    // keys whose time has passed are removed right away
+    if time <= Instant::now() {
+        return Integer(state.items.remove(&key).is_some() as i64);
     }
+    Integer(state.set_expiry(&key, time) as i64)
 }
 
-fn do_rename(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+fn do_persist(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
     use RedisItem::*;
     let Some(BulkString(key)) = args.pop_front() else {
-        return SimpleError("invalid arguments".to_string());
+        return RedisError::InvalidArguments.into();
     };
-    let Some(BulkString(new_key)) = args.pop_front() else {
-        return SimpleError("invalid arguments".to_string());
+    Integer(state.borrow_mut().persist(&key) as i64)
+}
+
+/// Returns the remaining time to live of the item at `key` in `unit_ms`
+/// milliseconds, rounded like redis does, -1 if it has no expiry and -2 if
+/// there is no such item.
+fn ttl(mut args: VecDeque<RedisItem>, state: &RefCell<State>, unit_ms: u128) -> RedisItem {
+    use RedisItem::*;
+    let (Some(BulkString(key)), true) = (args.pop_front(), args.is_empty()) else {
+        return RedisError::InvalidArguments.into();
     };
-    let mut state = state.borrow_mut();
-    if let Some((val, tag)) = state.items.remove(&key) {
-        if let Some(exp) = state.expire.get_expiry(tag) {
-            state.expire.push(new_key.clone(), tag, exp);
+    let state = state.borrow();
+    let Some((_, tag)) = state.items.get(&key) else {
+        return Integer(-2);
+    };
+    match state.expire.get_expiry(*tag) {
+        Some(time) => {
+            let millis = time.saturating_duration_since(Instant::now()).as_millis();
+            Integer(((millis + unit_ms / 2) / unit_ms) as i64)
         }
-        state.items.insert(new_key, (val, tag));
-        SimpleString("OK".to_string())
-    } else {
-        SimpleError("no such key".to_string())
+        None => Integer(-1),
     }
 }
 
-fn do_rpush(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+fn do_ttl(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    ttl(args, state, 1000)
+}
+
+fn do_pttl(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    ttl(args, state, 1)
+}
+
+/// Moves the item at `key` to `new_key`, along with its expiry. Unless
+/// `replace` is set, an existing item at `new_key` is left in place and
+/// `false` is returned.
+fn rename(
+    state: &RefCell<State>,
+    key: String,
+    new_key: String,
+    replace: bool,
+) -> Result<bool, RedisError> {
+    let mut state = state.borrow_mut();
+    if !state.items.contains_key(&key) {
+        return Err(RedisError::NoSuchKey);
+    }
+    if !replace && state.items.contains_key(&new_key) {
+        return Ok(false);
+    }
+    let (val, tag) = state.items.remove(&key).unwrap();
+    if let Some(exp) = state.expire.get_expiry(tag) {
+        let db = state.db;
+        state.expire.push(db, new_key.clone(), tag, exp);
+    }
+    state.blocking.signal(&new_key);
+    state.items.insert(new_key, (val, tag));
+    Ok(true)
+}
+
+fn do_rename(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
     use RedisItem::*;
     let Some(BulkString(key)) = args.pop_front() else {
-        return SimpleError("invalid arguments".to_string());
+        return RedisError::InvalidArguments.into();
     };
-    let mut state = state.borrow_mut();
-    let tag = state.tag_counter;
-    state.tag_counter += 1;
-    let entry = state
-        .items
-        .entry(key)
-        .or_insert_with(|| (Array(Vec::new()), tag));
-    let (Array(items), _) = entry else {
-        return SimpleError("WRONGTYPE".to_string());
+    let Some(BulkString(new_key)) = args.pop_front() else {
+        return RedisError::InvalidArguments.into();
     };
-    while let Some(item) = args.pop_front() {
-        items.push(item);
+    match rename(state, key, new_key, true) {
+        Ok(_) => SimpleString("OK".to_string()),
+        Err(err) => err.into(),
     }
-    Integer(items.len() as i64)
 }
 
-fn do_rpop(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
-    enum PopCount {
-        Single,
-        Count(usize),
-    }
+fn do_renamenx(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
     use RedisItem::*;
     let Some(BulkString(key)) = args.pop_front() else {
-        return SimpleError("invalid arguments".to_string());
-    };
-    let count = match args.pop_front() {
-        Some(Integer(val)) => {
-            if val < 0 {
-                return SimpleError("invalid arguments".to_string());
-            }
-            PopCount::Count(val as usize)
-        }
-        Some(BulkString(v) | SimpleString(v)) => {
-            let Ok(val) = v.parse::<usize>() else {
-                return SimpleError("invalid arguments".to_string());
-            };
-            PopCount::Count(val)
-        }
-        None => PopCount::Single,
-        _ => return SimpleError("invalid arguments".to_string()),
+        return RedisError::InvalidArguments.into();
     };
-    let mut state = state.borrow_mut();
-    let Some((Array(items), _)) = state.items.get_mut(&key) else {
-        return Null;
+    let Some(BulkString(new_key)) = args.pop_front() else {
+        return RedisError::InvalidArguments.into();
     };
-    // empty lists should not exist
-    assert!(items.len() > 0);
-    let res = match count {
-        PopCount::Single => items.pop().unwrap(),
-        PopCount::Count(n) => {
-            let mut res = Vec::new();
-            for _ in 0..n {
-                if let Some(item) = items.pop() {
-                    res.push(item);
-                } else {
-                    break;
-                }
-            }
-            Array(res)
+    match rename(state, key, new_key, false) {
+        Ok(renamed) => Integer(renamed as i64),
+        Err(err) => err.into(),
+    }
+}
+
+/// The commands which may modify the dataset.
+const WRITE_COMMANDS: &[&str] = &[
+    "set",
+    "incr",
+    "decr",
+    "incrby",
+    "decrby",
+    "incrbyfloat",
+    "append",
+    "setrange",
+    "getset",
+    "getdel",
+    "getex",
+    "setnx",
+    "setex",
+    "psetex",
+    "mset",
+    "msetnx",
+    "del",
+    "unlink",
+    "expire",
+    "pexpireat",
+    "restore",
+    "restore-asking",
+    "persist",
+    "rename",
+    "renamenx",
+    "copy",
+    "flushdb",
+    "flushall",
+    "swapdb",
+    "move",
+    "sort",
+    "lpush",
+    "rpush",
+    "lpushx",
+    "rpushx",
+    "lpop",
+    "rpop",
+    "lset",
+    "linsert",
+    "lrem",
+    "ltrim",
+    "lmove",
+    "rpoplpush",
+    "lmpop",
+    "blpop",
+    "brpop",
+    "blmpop",
+    "blmove",
+    "brpoplpush",
+    "hset",
+    "hmset",
+    "hsetnx",
+    "hdel",
+    "hincrby",
+    "hincrbyfloat",
+    "sadd",
+    "srem",
+    "spop",
+    "smove",
+    "sinterstore",
+    "sunionstore",
+    "sdiffstore",
+    "zadd",
+    "zincrby",
+    "zrem",
+    "zpopmin",
+    "zpopmax",
+    "zmpop",
+    "bzpopmin",
+    "bzpopmax",
+    "bzmpop",
+    "zremrangebyrank",
+    "zremrangebyscore",
+    "zremrangebylex",
+    "zinterstore",
+    "zunionstore",
+    "zdiffstore",
+    "setbit",
+    "bitop",
+    "bitfield",
+    "pfadd",
+    "pfmerge",
+    "geoadd",
+    "geosearchstore",
+    "georadius",
+    "georadiusbymember",
+    "xadd",
+    "xtrim",
+    "xsetid",
+    "xdel",
+    "xgroup",
+    "xack",
+    "xclaim",
+    "xautoclaim",
+    "xreadgroup",
+];
+
+/// Whether `command` may modify the dataset.
+fn is_write_command(command: &str) -> bool {
+    WRITE_COMMANDS.contains(&command)
+}
+
+/// Returns the lowercase name of the command `items`.
+fn command_name(items: &[RedisItem]) -> Option<String> {
+    match items.first() {
+        Some(RedisItem::BulkString(command) | RedisItem::SimpleString(command)) => {
+            Some(command.to_ascii_lowercase())
         }
-    };
-    if items.is_empty() {
-        state.items.remove(&key);
+        _ => None,
     }
-    res
 }
 
-fn handle_command(command: RedisItem, state: &RefCell<State>) -> RedisItem {
+/// Returns whether `command` is sent by a replica to its master.
+fn is_replication(command: String) -> bool {
+    matches!(command.as_str(), "psync" | "sync" | "replconf")
+}
+
+/// Executes a command sent by a client which has database `db` selected.
+async fn handle_command(command: RedisItem, state: &RefCell<State>, db: &mut usize) -> RedisItem {
     use RedisItem::*;
     match command {
         Array(items) => {
+            state.borrow_mut().select(*db);
             let mut args = VecDeque::from(items);
             let Some(BulkString(mut command) | SimpleString(mut command)) = args.pop_front() else {
-                return SimpleError("invalid command".to_string());
+                return RedisError::InvalidCommand.into();
             };
             command.make_ascii_lowercase();
             let handler = match command.as_str() {
                 "ping" => do_ping,
-                "set" => do_set,
-                "get" => do_get,
-                "del" => do_del,
+                "set" => string::do_set,
+                "get" => string::do_get,
+                "incr" => string::do_incr,
+                "decr" => string::do_decr,
+                "incrby" => string::do_incrby,
+                "decrby" => string::do_decrby,
+                "incrbyfloat" => string::do_incrbyfloat,
+                "append" => string::do_append,
+                "strlen" => string::do_strlen,
+                "getrange" => string::do_getrange,
+                "setrange" => string::do_setrange,
+                "getset" => string::do_getset,
+                "getdel" => string::do_getdel,
+                "getex" => string::do_getex,
+                "setnx" => string::do_setnx,
+                "setex" => string::do_setex,
+                "psetex" => string::do_psetex,
+                "mset" => string::do_mset,
+                "msetnx" => string::do_msetnx,
+                "mget" => string::do_mget,
+                "lcs" => string::do_lcs,
+                "del" | "unlink" => do_del,
                 "expire" => do_expire,
+                "pexpireat" => do_pexpireat,
                 "persist" => do_persist,
+                "ttl" => do_ttl,
+                "pttl" => do_pttl,
                 "rename" => do_rename,
-                "rpush" => do_rpush,
-                "rpop" => do_rpop,
-                _ => return SimpleError("unknown command".to_string()),
+                "renamenx" => do_renamenx,
+                "exists" => keyspace::do_exists,
+                "type" => keyspace::do_type,
+                "keys" => keyspace::do_keys,
+                "scan" => keyspace::do_scan,
+                "randomkey" => keyspace::do_randomkey,
+                "dbsize" => keyspace::do_dbsize,
+                "touch" => keyspace::do_touch,
+                "copy" => keyspace::do_copy,
+                "flushdb" => keyspace::do_flushdb,
+                "flushall" => keyspace::do_flushall,
+                "swapdb" => keyspace::do_swapdb,
+                "move" => keyspace::do_move,
+                "sort" => sort::do_sort,
+                "sort_ro" => sort::do_sort_ro,
+                "select" => return keyspace::do_select(args, state, db),
+                "save" => rdb::do_save,
+                "bgsave" => rdb::do_bgsave,
+                "lastsave" => rdb::do_lastsave,
+                "bgrewriteaof" => aof::do_bgrewriteaof,
+                "dump" => rdb::do_dump,
+                "info" => info::do_info,
+                "replicaof" | "slaveof" => replication::do_replicaof,
+                "restore" | "restore-asking" => rdb::do_restore,
+                "cluster" => cluster::do_cluster,
+                "lpush" => list::do_lpush,
+                "rpush" => list::do_rpush,
+                "lpushx" => list::do_lpushx,
+                "rpushx" => list::do_rpushx,
+                "lpop" => list::do_lpop,
+                "rpop" => list::do_rpop,
+                "llen" => list::do_llen,
+                "lrange" => list::do_lrange,
+                "lindex" => list::do_lindex,
+                "lset" => list::do_lset,
+                "linsert" => list::do_linsert,
+                "lrem" => list::do_lrem,
+                "ltrim" => list::do_ltrim,
+                "lpos" => list::do_lpos,
+                "lmove" => list::do_lmove,
+                "rpoplpush" => list::do_rpoplpush,
+                "lmpop" => list::do_lmpop,
+                "hset" => hash::do_hset,
+                "hmset" => hash::do_hmset,
+                "hsetnx" => hash::do_hsetnx,
+                "hget" => hash::do_hget,
+                "hmget" => hash::do_hmget,
+                "hdel" => hash::do_hdel,
+                "hexists" => hash::do_hexists,
+                "hlen" => hash::do_hlen,
+                "hstrlen" => hash::do_hstrlen,
+                "hkeys" => hash::do_hkeys,
+                "hvals" => hash::do_hvals,
+                "hgetall" => hash::do_hgetall,
+                "hincrby" => hash::do_hincrby,
+                "hincrbyfloat" => hash::do_hincrbyfloat,
+                "hrandfield" => hash::do_hrandfield,
+                "hscan" => hash::do_hscan,
+                "sadd" => set::do_sadd,
+                "srem" => set::do_srem,
+                "smembers" => set::do_smembers,
+                "sismember" => set::do_sismember,
+                "smismember" => set::do_smismember,
+                "scard" => set::do_scard,
+                "spop" => set::do_spop,
+                "srandmember" => set::do_srandmember,
+                "smove" => set::do_smove,
+                "sinter" => set::do_sinter,
+                "sunion" => set::do_sunion,
+                "sdiff" => set::do_sdiff,
+                "sinterstore" => set::do_sinterstore,
+                "sunionstore" => set::do_sunionstore,
+                "sdiffstore" => set::do_sdiffstore,
+                "sintercard" => set::do_sintercard,
+                "sscan" => set::do_sscan,
+                "zadd" => zset::do_zadd,
+                "zincrby" => zset::do_zincrby,
+                "zrem" => zset::do_zrem,
+                "zcard" => zset::do_zcard,
+                "zscore" => zset::do_zscore,
+                "zmscore" => zset::do_zmscore,
+                "zrank" => zset::do_zrank,
+                "zrevrank" => zset::do_zrevrank,
+                "zrange" => zset::do_zrange,
+                "zrevrange" => zset::do_zrevrange,
+                "zrangebyscore" => zset::do_zrangebyscore,
+                "zrevrangebyscore" => zset::do_zrevrangebyscore,
+                "zrangebylex" => zset::do_zrangebylex,
+                "zrevrangebylex" => zset::do_zrevrangebylex,
+                "zcount" => zset::do_zcount,
+                "zlexcount" => zset::do_zlexcount,
+                "zpopmin" => zset::do_zpopmin,
+                "zpopmax" => zset::do_zpopmax,
+                "zmpop" => zset::do_zmpop,
+                "zremrangebyrank" => zset::do_zremrangebyrank,
+                "zremrangebyscore" => zset::do_zremrangebyscore,
+                "zremrangebylex" => zset::do_zremrangebylex,
+                "zinter" => zset::do_zinter,
+                "zunion" => zset::do_zunion,
+                "zdiff" => zset::do_zdiff,
+                "zinterstore" => zset::do_zinterstore,
+                "zunionstore" => zset::do_zunionstore,
+                "zdiffstore" => zset::do_zdiffstore,
+                "zscan" => zset::do_zscan,
+                "setbit" => bitmap::do_setbit,
+                "getbit" => bitmap::do_getbit,
+                "bitcount" => bitmap::do_bitcount,
+                "bitpos" => bitmap::do_bitpos,
+                "bitop" => bitmap::do_bitop,
+                "bitfield" => bitmap::do_bitfield,
+                "bitfield_ro" => bitmap::do_bitfield_ro,
+                "pfadd" => hyperloglog::do_pfadd,
+                "pfcount" => hyperloglog::do_pfcount,
+                "pfmerge" => hyperloglog::do_pfmerge,
+                "xadd" => stream::do_xadd,
+                "xtrim" => stream::do_xtrim,
+                "xsetid" => stream::do_xsetid,
+                "xlen" => stream::do_xlen,
+                "xdel" => stream::do_xdel,
+                "xrange" => stream::do_xrange,
+                "xrevrange" => stream::do_xrevrange,
+                "xgroup" => stream::do_xgroup,
+                "xack" => stream::do_xack,
+                "xpending" => stream::do_xpending,
+                "xclaim" => stream::do_xclaim,
+                "xautoclaim" => stream::do_xautoclaim,
+                "xinfo" => stream::do_xinfo,
+                "geoadd" => geo::do_geoadd,
+                "geodist" => geo::do_geodist,
+                "geohash" => geo::do_geohash,
+                "geopos" => geo::do_geopos,
+                "geosearch" => geo::do_geosearch,
+                "geosearchstore" => geo::do_geosearchstore,
+                "georadius" => geo::do_georadius,
+                "georadius_ro" => geo::do_georadius_ro,
+                "georadiusbymember" => geo::do_georadiusbymember,
+                "georadiusbymember_ro" => geo::do_georadiusbymember_ro,
+                "blpop" | "brpop" | "blmpop" | "blmove" | "brpoplpush" | "bzpopmin"
+                | "bzpopmax" | "bzmpop" | "xread" | "xreadgroup" => {
+                    // served commands are propagated as they are served
+                    let res = blocking::handle_blocking(&command, args, state).await;
+                    state.borrow_mut().aof.flush();
+                    return res;
+                }
+                "wait" => return replication::do_wait(args, state).await,
+                "migrate" => return cluster::do_migrate(args, state, *db).await,
+                _ => return RedisError::UnknownCommand.into(),
             };
-            handler(args, state)
+            let logged = is_write_command(&command).then(|| Vec::from(args.clone()));
+            // a replica hides expired items from reads
+            let hidden = match logged {
+                None if state.borrow().repl.is_replica() => {
+                    expire::hide_expired(&mut state.borrow_mut())
+                }
+                _ => Vec::new(),
+            };
+            let res = handler(args, state);
+            let mut state = state.borrow_mut();
+            expire::restore_hidden(&mut state, hidden);
+            if let Some(args) = logged.filter(|_| !matches!(res, SimpleError(_))) {
+                for args in aof::translate(&state, &command, args, &res) {
+                    state.propagate(args);
+                }
+            }
+            blocking::serve_ready(&mut state);
+            state.aof.flush();
+            res
         }
-        _ => SimpleError("unknown command".to_string()),
+        _ => RedisError::UnknownCommand.into(),
+    }
+}
+
+/// Runs the command `args` the way a client using database `db` would, for
+/// testing the command handlers.
+#[cfg(test)]
+fn run_command(state: &RefCell<State>, db: &mut usize, args: &[&str]) -> RedisItem {
+    let args = args
+        .iter()
+        .map(|arg| RedisItem::BulkString(arg.to_string()))
+        .collect();
+    smol::block_on(handle_command(RedisItem::Array(args), state, db))
+}
+
+/// Resolves once the peer has closed the connection. If the peer sends more
+/// data in the meantime, it never resolves.
+async fn wait_disconnect(reader: &mut (impl AsyncBufRead + Unpin)) {
+    match reader.fill_buf().await {
+        Ok([]) | Err(_) => {}
+        Ok(_) => smol::future::pending().await,
     }
 }
 
@@ -282,9 +756,65 @@ async fn connection_worker(stream: Async<TcpStream>, state: &RefCell<State>) ->
 
     let mut parser = ItemParser::new();
     let mut out_buffer = Vec::new();
+    let mut db = 0;
+    let mut listening_port = None;
+    let mut asking = false;
     loop {
         let res = match parser.parse(&mut reader).await {
-            Ok(command) => handle_command(command, state),
+            Ok(RedisItem::Array(items)) if command_name(&items).is_some_and(is_replication) => {
+                let mut args = VecDeque::from(items);
+                let command = command_name(args.make_contiguous()).unwrap();
+                args.pop_front();
+                if command == "replconf" {
+                    replication::do_replconf(args, &mut listening_port)
+                } else {
+                    // the connection belongs to a replica from now on
+                    return replication::serve_replica(
+                        &command,
+                        args,
+                        state,
+                        &stream,
+                        &mut reader,
+                        &mut parser,
+                        listening_port,
+                    )
+                    .await;
+                }
+            }
+            Ok(RedisItem::Array(items))
+                if command_name(&items).is_some_and(|command| is_write_command(&command))
+                    && state.borrow().repl.is_replica() =>
+            {
+                RedisError::Custom("READONLY You can't write against a read only replica.").into()
+            }
+            Ok(RedisItem::Array(items)) if command_name(&items).as_deref() == Some("asking") => {
+                cluster::do_asking(state, &mut asking)
+            }
+            Ok(command) => {
+                let redirect = match &command {
+                    RedisItem::Array(items) => {
+                        cluster::redirect(&state.borrow(), items, std::mem::take(&mut asking))
+                    }
+                    _ => None,
+                };
+                if let Some(redirect) = redirect {
+                    redirect
+                } else {
+                    // a blocked client must stop waiting once it disconnects
+                    let res = smol::future::or(
+                        async { Some(handle_command(command, state, &mut db).await) },
+                        async {
+                            wait_disconnect(&mut reader).await;
+                            None
+                        },
+                    )
+                    .await;
+                    match res {
+                        Some(res) => res,
+                        None => return Ok(()),
+                    }
+                }
+            }
             Err(ParseError::Incomplete | ParseError::Invalid) => {
                 RedisItem::SimpleError("ERR".to_string())
             }
@@ -302,15 +832,118 @@ fn main() -> io::Result<()> {
     let port = std::env::var("PORT")
         .map_or(Ok(9000), |s| s.parse::<u16>())
         .expect("port must be a number");
+    let databases = std::env::var("DATABASES")
+        .map_or(Ok(16), |s| s.parse::<usize>())
+        .ok()
+        .filter(|databases| *databases > 0)
+        .expect("the number of databases must be a positive number");
+    let save_rules = std::env::var("SAVE")
+        .map_or(Some(vec![(3600, 1), (300, 100), (60, 10000)]), |s| {
+            rdb::parse_save_rules(&s)
+        })
+        .expect("save rules must be pairs of seconds and changes");
+    let dir = std::env::var("DIR").unwrap_or_else(|_| ".".to_string());
+    let db_filename = std::env::var("DBFILENAME").unwrap_or_else(|_| "dump.rdb".to_string());
+    let appendonly = std::env::var("APPENDONLY").map_or(Some(false), |s| match s.as_str() {
+        "yes" => Some(true),
+        "no" => Some(false),
+        _ => None,
+    });
+    let appendonly = appendonly.expect("appendonly must be yes or no");
+    let append_filename =
+        std::env::var("APPENDFILENAME").unwrap_or_else(|_| "appendonly.aof".to_string());
+    let append_dirname =
+        std::env::var("APPENDDIRNAME").unwrap_or_else(|_| "appendonlydir".to_string());
+    let append_fsync = std::env::var("APPENDFSYNC")
+        .map_or(Some(aof::FsyncPolicy::EverySec), |s| {
+            aof::FsyncPolicy::parse(&s)
+        })
+        .expect("appendfsync must be always, everysec or no");
+    let replicaof = std::env::var("REPLICAOF").ok().map(|s| {
+        s.split_once(' ')
+            .and_then(|(host, port)| Some((host.to_string(), port.parse::<u16>().ok()?)))
+            .expect("replicaof must be a host and a port")
+    });
+    let backlog_size = std::env::var("REPLBACKLOGSIZE")
+        .map_or(Ok(replication::DEFAULT_BACKLOG_SIZE), |s| {
+            s.parse::<usize>()
+        })
+        .expect("the backlog size must be a number");
+    let output_limit = std::env::var("REPLOUTPUTLIMIT")
+        .map_or(Ok(replication::DEFAULT_OUTPUT_LIMIT), |s| {
+            s.parse::<usize>()
+        })
+        .expect("the replica output limit must be a number");
+    let replica_priority = std::env::var("REPLICAPRIORITY")
+        .map_or(Ok(100), |s| s.parse::<u32>())
+        .expect("the replica priority must be a number");
+    let cluster_enabled =
+        std::env::var("CLUSTERENABLED").map_or(Some(false), |s| match s.as_str() {
+            "yes" => Some(true),
+            "no" => Some(false),
+            _ => None,
+        });
+    let cluster_enabled = cluster_enabled.expect("cluster-enabled must be yes or no");
+    let cluster_config_file =
+        std::env::var("CLUSTERCONFIGFILE").unwrap_or_else(|_| "nodes.conf".to_string());
+    let cluster_node_timeout = std::env::var("CLUSTERNODETIMEOUT")
+        .map_or(Ok(cluster::DEFAULT_NODE_TIMEOUT), |s| {
+            s.parse::<u64>().map(Duration::from_millis)
+        })
+        .expect("the node timeout must be a number of milliseconds");
+    let cluster_announce_ip =
+        std::env::var("CLUSTERANNOUNCEIP").unwrap_or_else(|_| "127.0.0.1".to_string());
 
-
-    let state = RefCell::new(State::new());
+    let dir = PathBuf::from(dir);
+    let mut state = State::new(databases);
+    state.rdb = rdb::Rdb::new(dir.join(db_filename), save_rules);
+    state.aof = aof::Aof::new(&dir, &append_dirname, &append_filename, append_fsync);
+    state.repl = replication::Replication::new(port, backlog_size, output_limit, replica_priority);
+    if let Some((host, port)) = replicaof {
+        state.repl.replicate(host, port);
+    }
+    if cluster_enabled {
+        state.cluster = Some(cluster::Cluster::open(
+            dir.join(cluster_config_file),
+            &cluster_announce_ip,
+            port,
+            cluster_node_timeout,
+        )?);
+    }
+    let state = RefCell::new(state);
+    // the AOF takes precedence, as it is the more complete record
+    if appendonly {
+        if let Some(commands) = aof::load_and_enable(&state)? {
+            println!("Replayed {} commands from the AOF", commands);
+        }
+    } else if let Some(keys) = rdb::load(&mut state.borrow_mut())? {
+        println!("Loaded {} keys from the snapshot", keys);
+    }
+    state.borrow_mut().dirty = 0;
     let exec = smol::LocalExecutor::new();
     exec.spawn(expire::expire_worker(&state)).detach();
+    exec.spawn(rdb::save_worker(&state)).detach();
+    exec.spawn(aof::aof_worker(&state)).detach();
+    exec.spawn(replication::replica_worker(&state)).detach();
+    exec.spawn(cluster::cluster_worker(&state)).detach();
     smol::block_on(exec.run(async {
         // Create a listener.
         let listener = Async::<TcpListener>::bind(([0, 0, 0, 0], port))?;
         println!("Listening on {}", listener.get_ref().local_addr()?);
+        let bus_port = state
+            .borrow()
+            .cluster
+            .as_ref()
+            .map(cluster::Cluster::bus_port);
+        if let Some(bus_port) = bus_port {
+            let bus_listener = Async::<TcpListener>::bind(([0, 0, 0, 0], bus_port))?;
+            println!(
+                "Cluster bus listening on {}",
+                bus_listener.get_ref().local_addr()?
+            );
+            exec.spawn(cluster::bus_worker(bus_listener, &state))
+                .detach();
+        }
 
         // Accept clients in a loop.
         loop {
@@ -0,0 +1,658 @@
+//! Point-in-time snapshots of the dataset in the RDB format.
+//!
+//! Snapshots are written by `SAVE`, by `BGSAVE` on a background thread, and
+//! automatically once one of the `save <secs> <changes>` rules applies. The
+//! snapshot is loaded again when the server starts.
+//!
+//! `DUMP` and `RESTORE` move single keys between instances, using the same
+//! encoding for their values.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::string::FromUtf8Error;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use feredis_core::item::RedisItem;
+use feredis_core::rdb::{self, Entry, RdbError, RdbReader, RdbValue, RdbWriter, Record};
+use smol::Timer;
+
+use crate::expire;
+use crate::set::SetValue;
+use crate::stream::Stream;
+use crate::value::{parse_int, Value};
+use crate::zset::SortedSet;
+use crate::{RedisError, State};
+
+/// How long to wait before retrying an automatic save which failed.
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// The entries of each database, as captured for a snapshot.
+pub type Snapshot = Vec<Vec<Entry>>;
+
+/// The state of snapshot persistence.
+#[derive(Debug)]
+pub struct Rdb {
+    path: PathBuf,
+    /// the `save <secs> <changes>` rules which trigger automatic saves
+    rules: Vec<(u64, u64)>,
+    /// the value of the change counter when the last successful save started
+    dirty_at_save: u64,
+    /// the unix time in seconds of the last successful save
+    last_save: u64,
+    last_save_ok: bool,
+    last_attempt: Instant,
+    /// the thread of a running `BGSAVE` and the change counter at its start
+    child: Option<(JoinHandle<io::Result<()>>, u64)>,
+    /// whether a `BGSAVE SCHEDULE` is waiting for a running save to finish
+    scheduled: bool,
+}
+
+impl Default for Rdb {
+    fn default() -> Self {
+        Self::new(PathBuf::from("dump.rdb"), Vec::new())
+    }
+}
+
+impl Rdb {
+    pub fn new(path: PathBuf, rules: Vec<(u64, u64)>) -> Self {
+        Self {
+            path,
+            rules,
+            dirty_at_save: 0,
+            last_save: unix_time().as_secs(),
+            last_save_ok: true,
+            last_attempt: Instant::now(),
+            child: None,
+            scheduled: false,
+        }
+    }
+
+    fn finish_save(&mut self, res: &io::Result<()>, dirty: u64) {
+        self.last_save_ok = res.is_ok();
+        match res {
+            Ok(()) => {
+                self.dirty_at_save = dirty;
+                self.last_save = unix_time().as_secs();
+                println!("DB saved on disk");
+            }
+            Err(err) => println!("Error saving DB on disk: {}", err),
+        }
+    }
+}
+
+/// Parses save rules of the form `<secs> <changes> [<secs> <changes> ...]`.
+pub fn parse_save_rules(val: &str) -> Option<Vec<(u64, u64)>> {
+    let parts: Vec<&str> = val.split_whitespace().collect();
+    if !parts.len().is_multiple_of(2) {
+        return None;
+    }
+    parts
+        .chunks(2)
+        .map(|rule| Some((rule[0].parse().ok()?, rule[1].parse().ok()?)))
+        .collect()
+}
+
+fn unix_time() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+fn to_rdb(val: &Value) -> RdbValue {
+    let bytes = |val: &String| val.as_bytes().to_vec();
+    match val {
+        Value::String(_) | Value::Integer(_) => RdbValue::String(val.to_bytes().unwrap()),
+        Value::List(list) => RdbValue::List(list.iter().cloned().collect()),
+        Value::Hash(hash) => RdbValue::Hash(
+            hash.iter()
+                .map(|(field, val)| (bytes(field), bytes(val)))
+                .collect(),
+        ),
+        Value::Set(set) => RdbValue::Set(set.members().iter().map(bytes).collect()),
+        Value::ZSet(zset) => RdbValue::SortedSet(
+            zset.iter()
+                .map(|(member, score)| (member.as_bytes().to_vec(), score))
+                .collect(),
+        ),
+        Value::Stream(stream) => RdbValue::Stream(stream.to_rdb()),
+    }
+}
+
+/// Converts a value read from a snapshot, or returns `None` for an empty
+/// collection. Fails if the value holds data which is not valid UTF-8 where
+/// the keyspace only holds strings.
+fn from_rdb(val: RdbValue) -> Result<Option<Value>, FromUtf8Error> {
+    let string = String::from_utf8;
+    let val = match val {
+        RdbValue::String(val) => return Ok(Some(Value::from_bytes(val))),
+        RdbValue::Stream(stream) => return Ok(Some(Value::Stream(Stream::from_rdb(stream)?))),
+        RdbValue::List(list) => Value::List(list.into()),
+        RdbValue::Set(set) => Value::Set(
+            set.into_iter()
+                .map(string)
+                .collect::<Result<SetValue, _>>()?,
+        ),
+        RdbValue::SortedSet(zset) => Value::ZSet(
+            zset.into_iter()
+                .map(|(member, score)| Ok((string(member)?, score)))
+                .collect::<Result<SortedSet, _>>()?,
+        ),
+        RdbValue::Hash(hash) => Value::Hash(
+            hash.into_iter()
+                .map(|(field, val)| Ok((string(field)?, string(val)?)))
+                .collect::<Result<_, _>>()?,
+        ),
+    };
+    let empty = match &val {
+        Value::List(list) => list.is_empty(),
+        Value::Set(set) => set.is_empty(),
+        Value::ZSet(zset) => zset.is_empty(),
+        Value::Hash(hash) => hash.is_empty(),
+        _ => false,
+    };
+    Ok((!empty).then_some(val))
+}
+
+/// Captures the contents of all databases.
+///
+/// Redis forks to get a point-in-time view which shares memory with the
+/// server until either side writes. Here the whole dataset is copied
+/// instead, on the event loop: no command is served until the copy is
+/// complete, which takes time in proportion to the dataset, and the copy
+/// needs as much memory again. Only encoding and writing it happen in the
+/// background.
+pub fn snapshot(state: &State) -> Snapshot {
+    (0..state.dbs.len())
+        .map(|db| {
+            state
+                .db(db)
+                .iter()
+                .map(|(key, (val, tag))| {
+                    Entry {
+                        key: key.as_bytes().to_vec(),
+                        value: to_rdb(val),
+                        // expiry times are stored as unix timestamps
+                        expire_ms: state.expire.get_expiry(*tag).map(expire::to_unix_ms),
+                    }
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Writes a snapshot to a new file at `path`, which is synced to disk.
+pub fn write_file(path: &Path, snapshot: &Snapshot) -> io::Result<()> {
+    let file = write_to(BufWriter::new(File::create(path)?), snapshot)?
+        .into_inner()
+        .map_err(|err| err.into_error())?;
+    file.sync_all()
+}
+
+/// Encodes a snapshot in memory.
+pub fn encode(snapshot: &Snapshot) -> Vec<u8> {
+    // writing to memory cannot fail
+    write_to(Vec::new(), snapshot).unwrap()
+}
+
+fn write_to<W: Write>(out: W, snapshot: &Snapshot) -> io::Result<W> {
+    let mut writer = RdbWriter::new(out)?;
+    writer.write_aux("redis-bits", b"64")?;
+    writer.write_aux("ctime", unix_time().as_secs().to_string().as_bytes())?;
+    writer.write_aux("feredis-ver", env!("CARGO_PKG_VERSION").as_bytes())?;
+    for (db, entries) in snapshot.iter().enumerate() {
+        if entries.is_empty() {
+            continue;
+        }
+        let expires = entries.iter().filter(|e| e.expire_ms.is_some()).count();
+        writer.select_db(db as u64, entries.len() as u64, expires as u64)?;
+        for entry in entries {
+            writer.write_entry(entry)?;
+        }
+    }
+    writer.finish()
+}
+
+/// Writes a snapshot to a temporary file, which then atomically replaces the
+/// file at `path`.
+fn write_snapshot(path: &Path, snapshot: Snapshot) -> io::Result<()> {
+    let temp_path = path.with_file_name(format!("temp-{}.rdb", std::process::id()));
+    let res = write_file(&temp_path, &snapshot).and_then(|()| fs::rename(&temp_path, path));
+    if res.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    res
+}
+
+/// Starts writing a snapshot on a background thread. The dataset is copied
+/// before this returns, see `snapshot`.
+fn start_bgsave(state: &mut State) {
+    let snapshot = snapshot(state);
+    let path = state.rdb.path.clone();
+    let child = std::thread::spawn(move || write_snapshot(&path, snapshot));
+    state.rdb.child = Some((child, state.dirty));
+    state.rdb.last_attempt = Instant::now();
+    println!("Background saving started");
+}
+
+/// Loads the snapshot at the configured path into the empty databases.
+/// Returns the number of keys loaded, or `None` if there is no snapshot.
+pub fn load(state: &mut State) -> io::Result<Option<usize>> {
+    let data = match fs::read(&state.rdb.path) {
+        Ok(data) => data,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    load_data(state, &data).map(|(keys, _)| Some(keys))
+}
+
+/// Loads the snapshot at the start of `data`, returning the number of keys
+/// loaded and the length of the snapshot.
+/// Keys holding data which is not valid UTF-8 where the keyspace only holds
+/// strings are skipped and reported.
+pub fn load_data(state: &mut State, data: &[u8]) -> io::Result<(usize, usize)> {
+    let (now, unix_now) = (Instant::now(), unix_time().as_millis() as u64);
+    let mut reader = RdbReader::new(data)?;
+    let mut db = 0;
+    let mut loaded = 0;
+    while let Some(record) = reader.next_record()? {
+        let entry = match record {
+            Record::SelectDb(index) => {
+                db = usize::try_from(index)
+                    .ok()
+                    .filter(|db| *db < state.dbs.len())
+                    .ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("database {} is out of range", index),
+                        )
+                    })?;
+                continue;
+            }
+            Record::Entry(entry) => entry,
+            Record::Aux { .. } | Record::ResizeDb { .. } => continue,
+        };
+        // keys which expired while the server was down are skipped
+        if entry.expire_ms.is_some_and(|time| time <= unix_now) {
+            continue;
+        }
+        let (key, val) = match (String::from_utf8(entry.key), from_rdb(entry.value)) {
+            (Ok(key), Ok(Some(val))) => (key, val),
+            (_, Ok(None)) => continue,
+            (key, _) => {
+                let key =
+                    key.unwrap_or_else(|err| String::from_utf8_lossy(err.as_bytes()).into_owned());
+                println!(
+                    "Skipped key {:?}, which holds data that is not valid UTF-8",
+                    key
+                );
+                continue;
+            }
+        };
+        let tag = state.next_tag();
+        if let Some(time) = entry.expire_ms {
+            let time = now + Duration::from_millis(time - unix_now);
+            state.expire.push(db, key.clone(), tag, time);
+        }
+        state.db_mut(db).insert(key, (val, tag));
+        loaded += 1;
+    }
+    Ok((loaded, reader.position()))
+}
+
+/// Periodically checks on background saves and starts one when a save rule
+/// applies.
+pub async fn save_worker(state: &RefCell<State>) {
+    loop {
+        Timer::after(Duration::from_millis(100)).await;
+        let mut state = state.borrow_mut();
+        if state.stop {
+            break;
+        }
+        if let Some((child, _)) = &state.rdb.child {
+            if !child.is_finished() {
+                continue;
+            }
+            let (child, dirty) = state.rdb.child.take().unwrap();
+            let res = child
+                .join()
+                .unwrap_or_else(|_| Err(io::Error::other("background save panicked")));
+            state.rdb.finish_save(&res, dirty);
+        }
+        if state.rdb.scheduled {
+            state.rdb.scheduled = false;
+            start_bgsave(&mut state);
+            continue;
+        }
+        let changes = state.dirty - state.rdb.dirty_at_save;
+        let elapsed = unix_time().as_secs().saturating_sub(state.rdb.last_save);
+        // after a failure, saving is only retried after a delay
+        let may_retry = state.rdb.last_save_ok || state.rdb.last_attempt.elapsed() >= RETRY_DELAY;
+        let rules = &state.rdb.rules;
+        if may_retry
+            && rules
+                .iter()
+                .any(|&(secs, min)| changes >= min && elapsed >= secs)
+        {
+            start_bgsave(&mut state);
+        }
+    }
+}
+
+pub fn do_save(_: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let mut state = state.borrow_mut();
+    if state.rdb.child.is_some() {
+        return RedisError::Custom("ERR Background save already in progress").into();
+    }
+    let res = write_snapshot(&state.rdb.path, snapshot(&state));
+    let dirty = state.dirty;
+    state.rdb.last_attempt = Instant::now();
+    state.rdb.finish_save(&res, dirty);
+    match res {
+        Ok(()) => SimpleString("OK".to_string()),
+        Err(err) => SimpleError(format!("ERR {}", err)),
+    }
+}
+
+pub fn do_bgsave(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let schedule = match args.pop_front() {
+        None => false,
+        Some(BulkString(arg)) if arg.eq_ignore_ascii_case("schedule") => true,
+        Some(_) => return RedisError::Syntax.into(),
+    };
+    let mut state = state.borrow_mut();
+    if state.rdb.child.is_some() {
+        if !schedule {
+            return RedisError::Custom("ERR Background save already in progress").into();
+        }
+        state.rdb.scheduled = true;
+        return SimpleString("Background saving scheduled".to_string());
+    }
+    start_bgsave(&mut state);
+    SimpleString("Background saving started".to_string())
+}
+
+pub fn do_lastsave(_: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    RedisItem::Integer(state.borrow().rdb.last_save as i64)
+}
+
+/// Serializes `val` in the format of `DUMP`.
+pub fn dump(val: &Value) -> Vec<u8> {
+    rdb::encode_dump(&to_rdb(val))
+}
+
+pub fn do_dump(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let Some(BulkString(key)) = args.pop_front() else {
+        return RedisError::InvalidArguments.into();
+    };
+    match state.borrow().items.get(&key) {
+        Some((val, _)) => RedisItem::bulk(dump(val)),
+        None => Null,
+    }
+}
+
+pub fn do_restore(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let (Some(BulkString(key)), Some(BulkString(ttl)), Some(payload)) =
+        (args.pop_front(), args.pop_front(), args.pop_front())
+    else {
+        return RedisError::InvalidArguments.into();
+    };
+    let Some(payload) = payload.into_bytes() else {
+        return RedisError::InvalidArguments.into();
+    };
+    let (mut replace, mut absttl, mut idletime, mut freq) = (false, false, false, false);
+    while let Some(arg) = args.pop_front() {
+        match arg {
+            BulkString(arg) if arg.eq_ignore_ascii_case("replace") => replace = true,
+            BulkString(arg) if arg.eq_ignore_ascii_case("absttl") => absttl = true,
+            // eviction information is checked, but not kept
+            BulkString(arg) if arg.eq_ignore_ascii_case("idletime") && !freq => {
+                let Some(BulkString(val)) = args.pop_front() else {
+                    return RedisError::Syntax.into();
+                };
+                match parse_int(&val) {
+                    Some(idle) if idle >= 0 => idletime = true,
+                    Some(_) => {
+                        return RedisError::Custom("ERR Invalid IDLETIME value, must be >= 0")
+                            .into()
+                    }
+                    None => return RedisError::NotInteger.into(),
+                }
+            }
+            BulkString(arg) if arg.eq_ignore_ascii_case("freq") && !idletime => {
+                let Some(BulkString(val)) = args.pop_front() else {
+                    return RedisError::Syntax.into();
+                };
+                match parse_int(&val) {
+                    Some(0..=255) => freq = true,
+                    Some(_) => {
+                        return RedisError::Custom(
+                            "ERR Invalid FREQ value, must be >= 0 and <= 255",
+                        )
+                        .into()
+                    }
+                    None => return RedisError::NotInteger.into(),
+                }
+            }
+            _ => return RedisError::Syntax.into(),
+        }
+    }
+    let ttl = match parse_int(&ttl) {
+        Some(ttl) if ttl >= 0 => ttl as u64,
+        Some(_) => return RedisError::Custom("ERR Invalid TTL value, must be >= 0").into(),
+        None => return RedisError::NotInteger.into(),
+    };
+    let mut state = state.borrow_mut();
+    if !replace && state.items.contains_key(&key) {
+        return RedisError::Custom("BUSYKEY Target key name already exists.").into();
+    }
+    let val = match rdb::decode_dump(&payload) {
+        Ok(val) => val,
+        Err(RdbError::Truncated | RdbError::UnsupportedVersion(_) | RdbError::ChecksumMismatch) => {
+            return RedisError::Custom("ERR DUMP payload version or checksum are wrong").into()
+        }
+        Err(_) => return RedisError::Custom("ERR Bad data format").into(),
+    };
+    let Ok(Some(val)) = from_rdb(val) else {
+        return RedisError::Custom("ERR Bad data format").into();
+    };
+    let now = Instant::now();
+    let expiry = match (ttl, absttl) {
+        (0, _) => None,
+        (ttl, false) => Some(now + Duration::from_millis(ttl)),
+        (ttl, true) => {
+            // the time left until the unix timestamp, if it is in the future
+            let left = Duration::from_millis(ttl).saturating_sub(unix_time());
+            Some(now + left)
+        }
+    };
+    // a key whose time has passed is not restored at all
+    if expiry.is_some_and(|expiry| expiry <= now) {
+        state.items.remove(&key);
+        return SimpleString("OK".to_string());
+    }
+    let tag = state.next_tag();
+    state.items.insert(key.clone(), (val, tag));
+    if let Some(expiry) = expiry {
+        state.set_expiry(&key, expiry);
+    }
+    state.blocking.signal(&key);
+    SimpleString("OK".to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_parse_save_rules() {
+        assert_eq!(
+            parse_save_rules("3600 1 300 100"),
+            Some(vec![(3600, 1), (300, 100)])
+        );
+        assert_eq!(parse_save_rules(""), Some(vec![]));
+        assert_eq!(parse_save_rules("3600"), None);
+        assert_eq!(parse_save_rules("3600 x"), None);
+    }
+
+    #[test]
+    pub fn test_convert() {
+        let vals = [
+            Value::Integer(-5),
+            Value::List([b"a".to_vec(), b"\xff".to_vec()].into()),
+            Value::Set(["1", "x"].map(String::from).into_iter().collect()),
+            Value::ZSet([("m".to_string(), 2.5)].into_iter().collect()),
+        ];
+        for val in vals {
+            assert_eq!(from_rdb(to_rdb(&val)), Ok(Some(val)));
+        }
+        assert_eq!(from_rdb(RdbValue::List(Vec::new())), Ok(None));
+    }
+
+    #[test]
+    pub fn test_load_not_utf8() {
+        let entry = |key: &[u8], value| Entry {
+            key: key.to_vec(),
+            value,
+            expire_ms: None,
+        };
+        let snapshot = vec![vec![
+            entry(b"list", RdbValue::List(vec![b"\xff".to_vec()])),
+            entry(b"\xff", RdbValue::String(b"v".to_vec())),
+            entry(b"set", RdbValue::Set(vec![b"a".to_vec(), b"\xff".to_vec()])),
+            entry(b"zset", RdbValue::SortedSet(vec![(b"\xff".to_vec(), 1.0)])),
+            entry(
+                b"hash",
+                RdbValue::Hash(vec![(b"f".to_vec(), b"\xff".to_vec())]),
+            ),
+        ]];
+        let path =
+            std::env::temp_dir().join(format!("feredis-not-utf8-{}.rdb", std::process::id()));
+        write_snapshot(&path, snapshot).unwrap();
+        let mut state = State::new(1);
+        state.rdb = Rdb::new(path.clone(), Vec::new());
+        let loaded = load(&mut state);
+        let _ = fs::remove_file(&path);
+        assert_eq!(loaded.unwrap(), Some(1));
+        assert_eq!(state.items.len(), 1);
+        assert!(state.items.contains_key("list"));
+    }
+
+    /// Sorts the unordered parts of a snapshot and drops the consumer times,
+    /// which are not restored by commands.
+    fn normalize(snapshot: &mut Snapshot) {
+        for entries in snapshot {
+            entries.sort_by(|a, b| a.key.cmp(&b.key));
+            for entry in entries {
+                match &mut entry.value {
+                    RdbValue::Set(members) => members.sort(),
+                    RdbValue::Hash(fields) => fields.sort(),
+                    RdbValue::Stream(stream) => {
+                        for group in &mut stream.groups {
+                            for consumer in &mut group.consumers {
+                                consumer.seen_time = 0;
+                                consumer.active_time = None;
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_commands_round_trip() {
+        let state = RefCell::new(State::new(2));
+        let mut db = 0;
+        let expire_at = (unix_time().as_millis() + 3_600_000).to_string();
+        for command in [
+            &["set", "str", "value"][..],
+            &["set", "int", "-12"],
+            &["pexpireat", "int", &expire_at],
+            &["rpush", "list", "a", "b", "a"],
+            &["sadd", "ints", "3", "1", "2"],
+            &["sadd", "set", "x", "y"],
+            &["zadd", "zset", "0.1", "a", "-inf", "b", "1e300", "c"],
+            &["hset", "hash", "f", "v", "g", "w"],
+            &["xadd", "stream", "1-0", "f", "v"],
+            &["xadd", "stream", "2-0", "f", "w"],
+            &["xadd", "stream", "3-0", "f", "x"],
+            &["xdel", "stream", "2-0"],
+            &["xgroup", "create", "stream", "g", "0"],
+            &[
+                "xreadgroup",
+                "group",
+                "g",
+                "a",
+                "count",
+                "2",
+                "streams",
+                "stream",
+                ">",
+            ],
+            &["xgroup", "createconsumer", "stream", "g", "b"],
+            &["xgroup", "create", "stream", "h", "$", "entriesread", "3"],
+            &["xadd", "empty", "5-0", "f", "v"],
+            &["xdel", "empty", "5-0"],
+            &["select", "1"],
+            &["set", "other", "db"],
+        ] {
+            let res = crate::run_command(&state, &mut db, command);
+            assert!(!matches!(res, RedisItem::SimpleError(_)), "{:?}", res);
+        }
+        let mut expected = snapshot(&state.borrow());
+
+        let path =
+            std::env::temp_dir().join(format!("feredis-round-trip-{}.rdb", std::process::id()));
+        write_file(&path, &expected).unwrap();
+        let data = fs::read(&path);
+        let _ = fs::remove_file(&path);
+        let data = data.unwrap();
+        let replayed = RefCell::new(State::new(2));
+        let mut db = 0;
+        let mut reader = RdbReader::new(&data).unwrap();
+        while let Some(record) = reader.next_record().unwrap() {
+            let commands = match record {
+                Record::SelectDb(index) => vec![vec![b"select".to_vec(), index.to_string().into()]],
+                Record::Entry(entry) => entry.commands(),
+                Record::Aux { .. } | Record::ResizeDb { .. } => continue,
+            };
+            for command in commands {
+                let args: Vec<String> = command
+                    .into_iter()
+                    .map(|arg| String::from_utf8(arg).unwrap())
+                    .collect();
+                let args: Vec<&str> = args.iter().map(String::as_str).collect();
+                let res = crate::run_command(&replayed, &mut db, &args);
+                assert!(
+                    !matches!(res, RedisItem::SimpleError(_)),
+                    "{:?}: {:?}",
+                    args,
+                    res
+                );
+            }
+        }
+        let mut actual = snapshot(&replayed.borrow());
+
+        // the expiry goes through the clock twice and may be off by a bit
+        let expiry = |snapshot: &Snapshot| {
+            let entry = snapshot[0].iter().find(|entry| entry.key == b"int");
+            entry.and_then(|entry| entry.expire_ms).unwrap()
+        };
+        assert!(expiry(&actual).abs_diff(expiry(&expected)) <= 5);
+        for entry in actual[0].iter_mut().chain(&mut expected[0]) {
+            entry.expire_ms = entry.expire_ms.map(|_| 0);
+        }
+        normalize(&mut expected);
+        normalize(&mut actual);
+        assert_eq!(actual, expected);
+    }
+}
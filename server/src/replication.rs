@@ -0,0 +1,947 @@
+//! Master-replica replication.
+//!
+//! A master feeds every change to the dataset into the replication stream,
+//! as the same commands which are appended to the AOF. The stream is sent to
+//! the connected replicas, and its most recent part is kept in the backlog.
+//! Positions in the stream are given by the replication offset, the number
+//! of bytes produced so far, along with the replication id which names the
+//! history the offset refers to.
+//!
+//! A replica connects with `PSYNC <replid> <offset>`. If the master shares
+//! its history and the backlog still holds the stream from the offset on,
+//! the replica continues from there. Otherwise it receives a snapshot in the
+//! RDB format, followed by the stream from the point the snapshot was taken.
+//! Replicas acknowledge the offset they processed with `REPLCONF ACK`, which
+//! is what `WAIT` relies on.
+
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::io;
+use std::net::{SocketAddr, TcpStream};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use feredis_core::aof::{AofError, AofReader};
+use feredis_core::item::{ItemParser, ParseError, RedisItem};
+use smol::channel::{self, Sender};
+use smol::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use smol::{Async, Timer};
+
+use crate::value::parse_int;
+use crate::{aof, rdb, RedisError, State};
+
+/// The default size of the backlog, in bytes.
+pub const DEFAULT_BACKLOG_SIZE: usize = 1024 * 1024;
+/// The default number of bytes of the stream which may be queued for a
+/// replica before it is disconnected.
+pub const DEFAULT_OUTPUT_LIMIT: usize = 256 * 1024 * 1024;
+/// How often a replica acknowledges the offset it processed.
+const ACK_PERIOD: Duration = Duration::from_secs(1);
+
+/// Returns a random id of 40 hex digits, as used for replication and run
+/// ids.
+pub fn random_id() -> String {
+    (0..40)
+        .map(|_| char::from_digit(fastrand::u32(..16), 16).unwrap())
+        .collect()
+}
+
+/// The most recent part of the replication stream.
+#[derive(Debug)]
+struct Backlog {
+    buf: VecDeque<u8>,
+    capacity: usize,
+    /// the offset of the first byte held, where the first byte of the
+    /// stream has offset 1
+    start: u64,
+}
+
+impl Backlog {
+    fn push(&mut self, bytes: &[u8]) {
+        self.buf.extend(bytes);
+        let excess = self.buf.len().saturating_sub(self.capacity);
+        self.buf.drain(..excess);
+        self.start += excess as u64;
+    }
+
+    /// Returns the stream from `offset` on, if it is still held.
+    fn since(&self, offset: u64) -> Option<Vec<u8>> {
+        let skip = offset.checked_sub(self.start)? as usize;
+        if skip > self.buf.len() {
+            return None;
+        }
+        Some(self.buf.range(skip..).copied().collect())
+    }
+
+    fn reset(&mut self, start: u64) {
+        self.buf.clear();
+        self.start = start;
+    }
+}
+
+/// A replica connected to this server.
+#[derive(Debug)]
+struct Replica {
+    id: u64,
+    addr: SocketAddr,
+    /// the port the replica accepts clients on
+    listening_port: Option<u16>,
+    /// whether the replica received its snapshot and follows the stream
+    online: bool,
+    ack_offset: u64,
+    ack_time: Instant,
+    /// sends the stream to the connection of the replica
+    tx: Sender<Rc<[u8]>>,
+    /// the number of bytes sent through `tx` which are not written yet
+    queued: Rc<Cell<usize>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LinkStatus {
+    Connecting,
+    /// receiving the snapshot
+    Sync,
+    Connected,
+}
+
+/// The master this server replicates.
+#[derive(Debug)]
+struct Master {
+    host: String,
+    port: u16,
+    status: LinkStatus,
+    last_io: Instant,
+}
+
+#[derive(Debug)]
+pub struct Replication {
+    /// the port this server accepts clients on, announced to the master
+    port: u16,
+    /// the priority of this replica when one is promoted, where 0 means never
+    priority: u32,
+    replid: String,
+    /// the previous replication id, whose history this server shares up to
+    /// `second_offset`, so that replicas of a former master can continue
+    /// after a failover
+    replid2: String,
+    second_offset: Option<u64>,
+    /// the number of bytes of the replication stream produced so far
+    offset: u64,
+    backlog: Backlog,
+    /// how many bytes may be queued for a replica, where 0 means no limit
+    output_limit: usize,
+    /// the database of the last command fed into the stream
+    db: Option<usize>,
+    replicas: Vec<Replica>,
+    next_id: u64,
+    master: Option<Master>,
+    /// changes whenever the master does, which ends the link to the former
+    /// master
+    generation: u64,
+    /// the database selected by the stream from the master
+    master_db: usize,
+    /// clients in `WAIT`, which are notified of acknowledgements
+    ack_waiters: Vec<Sender<()>>,
+}
+
+impl Default for Replication {
+    fn default() -> Self {
+        Self::new(9000, DEFAULT_BACKLOG_SIZE, DEFAULT_OUTPUT_LIMIT, 100)
+    }
+}
+
+impl Replication {
+    pub fn new(port: u16, backlog_size: usize, output_limit: usize, priority: u32) -> Self {
+        Self {
+            port,
+            priority,
+            replid: random_id(),
+            replid2: "0".repeat(40),
+            second_offset: None,
+            offset: 0,
+            backlog: Backlog {
+                buf: VecDeque::new(),
+                capacity: backlog_size,
+                start: 1,
+            },
+            output_limit,
+            db: None,
+            replicas: Vec::new(),
+            next_id: 0,
+            master: None,
+            generation: 0,
+            master_db: 0,
+            ack_waiters: Vec::new(),
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn is_replica(&self) -> bool {
+        self.master.is_some()
+    }
+
+    /// Starts replicating the master at `host` and `port`.
+    pub fn replicate(&mut self, host: String, port: u16) {
+        self.master = Some(Master {
+            host,
+            port,
+            status: LinkStatus::Connecting,
+            last_io: Instant::now(),
+        });
+        self.generation += 1;
+    }
+
+    /// Appends raw data to the stream, sending it to the replicas. Replicas
+    /// which fall behind by more than the output limit are dropped.
+    fn feed(&mut self, bytes: &[u8]) {
+        self.offset += bytes.len() as u64;
+        self.backlog.push(bytes);
+        if self.replicas.is_empty() {
+            return;
+        }
+        let bytes: Rc<[u8]> = Rc::from(bytes);
+        let limit = self.output_limit;
+        self.replicas.retain(|replica| {
+            let queued = replica.queued.get() + bytes.len();
+            if limit > 0 && queued > limit {
+                println!(
+                    "Replica {} exceeded the output buffer limit, disconnecting it",
+                    replica.addr
+                );
+                return false;
+            }
+            replica.queued.set(queued);
+            let _ = replica.tx.try_send(bytes.clone());
+            true
+        });
+    }
+
+    /// Feeds a command executed in database `db` into the stream.
+    pub fn feed_command(&mut self, db: usize, args: &[RedisItem]) {
+        let mut buf = Vec::new();
+        if self.db != Some(db) {
+            let select = vec![
+                RedisItem::BulkString("select".to_string()),
+                RedisItem::BulkString(db.to_string()),
+            ];
+            RedisItem::Array(select).serialize(&mut buf);
+            self.db = Some(db);
+        }
+        buf.extend_from_slice(format!("*{}\r\n", args.len()).as_bytes());
+        for arg in args {
+            arg.serialize(&mut buf);
+        }
+        self.feed(&buf);
+    }
+
+    /// Starts a new history, keeping the current one as the previous one.
+    fn shift_replid(&mut self) {
+        self.replid2 = std::mem::replace(&mut self.replid, random_id());
+        self.second_offset = Some(self.offset + 1);
+    }
+
+    /// Returns the stream a replica which processed the history `replid` up
+    /// to right before `offset` is missing, if the backlog still holds it.
+    fn continuation(&self, replid: &str, offset: u64) -> Option<Vec<u8>> {
+        let shared = replid == self.replid
+            || (replid == self.replid2 && self.second_offset.is_some_and(|end| offset <= end));
+        if !shared {
+            return None;
+        }
+        self.backlog.since(offset)
+    }
+
+    fn ack(&mut self, id: u64, offset: u64) {
+        if let Some(replica) = self.replicas.iter_mut().find(|replica| replica.id == id) {
+            replica.ack_offset = offset;
+            replica.ack_time = Instant::now();
+        }
+        for tx in self.ack_waiters.drain(..) {
+            let _ = tx.try_send(());
+        }
+    }
+
+    /// Returns the number of replicas which acknowledged `offset`.
+    fn acked(&self, offset: u64) -> usize {
+        self.replicas
+            .iter()
+            .filter(|replica| replica.online && replica.ack_offset >= offset)
+            .count()
+    }
+
+    /// Writes the replication section of `INFO`.
+    pub fn info(&self, out: &mut String) {
+        out.push_str("# Replication\r\n");
+        match &self.master {
+            Some(master) => {
+                let up = master.status == LinkStatus::Connected;
+                write!(
+                    out,
+                    "role:slave\r\n\
+                     master_host:{}\r\n\
+                     master_port:{}\r\n\
+                     master_link_status:{}\r\n\
+                     master_last_io_seconds_ago:{}\r\n\
+                     master_sync_in_progress:{}\r\n\
+                     slave_read_repl_offset:{}\r\n\
+                     slave_repl_offset:{}\r\n\
+                     slave_priority:{}\r\n\
+                     slave_read_only:1\r\n\
+                     replica_announced:1\r\n",
+                    master.host,
+                    master.port,
+                    if up { "up" } else { "down" },
+                    if up {
+                        master.last_io.elapsed().as_secs() as i64
+                    } else {
+                        -1
+                    },
+                    (master.status == LinkStatus::Sync) as u8,
+                    self.offset,
+                    self.offset,
+                    self.priority,
+                )
+                .unwrap();
+            }
+            None => out.push_str("role:master\r\n"),
+        }
+        write!(out, "connected_slaves:{}\r\n", self.replicas.len()).unwrap();
+        for (i, replica) in self.replicas.iter().enumerate() {
+            write!(
+                out,
+                "slave{}:ip={},port={},state={},offset={},lag={}\r\n",
+                i,
+                replica.addr.ip(),
+                replica.listening_port.unwrap_or(replica.addr.port()),
+                if replica.online {
+                    "online"
+                } else {
+                    "wait_bgsave"
+                },
+                replica.ack_offset,
+                replica.ack_time.elapsed().as_secs(),
+            )
+            .unwrap();
+        }
+        write!(
+            out,
+            "master_failover_state:no-failover\r\n\
+             master_replid:{}\r\n\
+             master_replid2:{}\r\n\
+             master_repl_offset:{}\r\n\
+             second_repl_offset:{}\r\n\
+             repl_backlog_active:1\r\n\
+             repl_backlog_size:{}\r\n\
+             repl_backlog_first_byte_offset:{}\r\n\
+             repl_backlog_histlen:{}\r\n",
+            self.replid,
+            self.replid2,
+            self.offset,
+            self.second_offset.map_or(-1, |offset| offset as i64),
+            self.backlog.capacity,
+            self.backlog.start,
+            self.backlog.buf.len(),
+        )
+        .unwrap();
+    }
+}
+
+pub fn do_replicaof(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let (Some(BulkString(host)), Some(BulkString(port)), true) =
+        (args.pop_front(), args.pop_front(), args.is_empty())
+    else {
+        return RedisError::InvalidArguments.into();
+    };
+    let mut state = state.borrow_mut();
+    if host.eq_ignore_ascii_case("no") && port.eq_ignore_ascii_case("one") {
+        if state.repl.master.take().is_some() {
+            // the data received so far continues under a new history
+            state.repl.generation += 1;
+            state.repl.shift_replid();
+            // expiring keys is up to this server now
+            state.expire.wake();
+            println!("Stopped replicating, this server is a master now");
+        }
+        return SimpleString("OK".to_string());
+    }
+    let repl = &mut state.repl;
+    let Some(port) = parse_int(&port).and_then(|port| u16::try_from(port).ok()) else {
+        return RedisError::NotInteger.into();
+    };
+    if repl
+        .master
+        .as_ref()
+        .is_some_and(|master| master.host == host && master.port == port)
+    {
+        return SimpleString("OK Already connected to specified master".to_string());
+    }
+    println!("Replicating {}:{}", host, port);
+    repl.replicate(host, port);
+    SimpleString("OK".to_string())
+}
+
+/// Handles `REPLCONF` sent by a replica before it starts syncing.
+pub fn do_replconf(mut args: VecDeque<RedisItem>, listening_port: &mut Option<u16>) -> RedisItem {
+    use RedisItem::*;
+    while let Some(BulkString(option)) = args.pop_front() {
+        let Some(BulkString(val)) = args.pop_front() else {
+            return RedisError::Syntax.into();
+        };
+        match option.to_ascii_lowercase().as_str() {
+            "listening-port" => match parse_int(&val).and_then(|port| u16::try_from(port).ok()) {
+                Some(port) => *listening_port = Some(port),
+                None => return RedisError::NotInteger.into(),
+            },
+            // acknowledgements only matter once the connection is a replica
+            "ip-address" | "capa" | "ack" | "getack" => {}
+            _ => {
+                return SimpleError(format!("ERR Unrecognized REPLCONF option: {}", option));
+            }
+        }
+    }
+    SimpleString("OK".to_string())
+}
+
+pub async fn do_wait(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let (Some(BulkString(replicas)), Some(BulkString(timeout)), true) =
+        (args.pop_front(), args.pop_front(), args.is_empty())
+    else {
+        return RedisError::InvalidArguments.into();
+    };
+    let (Some(replicas), Some(timeout)) = (parse_int(&replicas), parse_int(&timeout)) else {
+        return RedisError::NotInteger.into();
+    };
+    if timeout < 0 {
+        return RedisError::Custom("ERR timeout is negative").into();
+    }
+    if state.borrow().repl.is_replica() {
+        return RedisError::Custom("ERR WAIT cannot be used with replica instances.").into();
+    }
+    let deadline = (timeout > 0).then(|| Instant::now() + Duration::from_millis(timeout as u64));
+    let offset = state.borrow().repl.offset;
+    let mut asked = false;
+    loop {
+        let acked = state.borrow().repl.acked(offset);
+        if acked as i64 >= replicas || deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Integer(acked as i64);
+        }
+        let (tx, rx) = channel::bounded(1);
+        {
+            let mut state = state.borrow_mut();
+            if !asked {
+                // replicas only acknowledge once a second unless asked to
+                let getack =
+                    ["replconf", "getack", "*"].map(|arg| RedisItem::BulkString(arg.into()));
+                let db = state.db;
+                state.repl.feed_command(db, &getack);
+                asked = true;
+            }
+            state.repl.ack_waiters.push(tx);
+        }
+        match deadline {
+            Some(deadline) => {
+                smol::future::or(
+                    async {
+                        let _ = rx.recv().await;
+                    },
+                    async {
+                        Timer::at(deadline).await;
+                    },
+                )
+                .await
+            }
+            None => {
+                let _ = rx.recv().await;
+            }
+        }
+    }
+}
+
+/// Turns a client connection which sent `SYNC` or `PSYNC` into the
+/// connection of a replica, sending it the stream until it disconnects.
+pub async fn serve_replica(
+    command: &str,
+    mut args: VecDeque<RedisItem>,
+    state: &RefCell<State>,
+    stream: &Async<TcpStream>,
+    reader: &mut BufReader<&Async<TcpStream>>,
+    parser: &mut ItemParser,
+    listening_port: Option<u16>,
+) -> io::Result<()> {
+    let mut writer = stream;
+    let addr = stream.get_ref().peer_addr()?;
+    let request = match (command, args.pop_front(), args.pop_front()) {
+        ("psync", Some(RedisItem::BulkString(replid)), Some(RedisItem::BulkString(offset))) => {
+            Some((replid, offset.parse::<u64>().unwrap_or(0)))
+        }
+        ("psync", _, _) => {
+            let mut buf = Vec::new();
+            RedisItem::from(RedisError::InvalidArguments).serialize(&mut buf);
+            return writer.write_all(&buf).await;
+        }
+        _ => None,
+    };
+    let unlinked = state
+        .borrow()
+        .repl
+        .master
+        .as_ref()
+        .is_some_and(|master| master.status != LinkStatus::Connected);
+    if unlinked {
+        return writer
+            .write_all(b"-NOMASTERLINK Can't SYNC while not connected with my master\r\n")
+            .await;
+    }
+    let (tx, rx) = channel::unbounded();
+    let queued = Rc::new(Cell::new(0));
+    let (id, reply, snapshot) = {
+        let mut state = state.borrow_mut();
+        let continuation = request
+            .as_ref()
+            .and_then(|(replid, offset)| state.repl.continuation(replid, *offset));
+        let (reply, snapshot) = match continuation {
+            Some(data) => {
+                let mut reply = format!("+CONTINUE {}\r\n", state.repl.replid).into_bytes();
+                reply.extend(data);
+                (reply, None)
+            }
+            None => {
+                // the stream has to select the database again for the
+                // replica, which starts out in database 0
+                state.repl.db = None;
+                let reply = match request {
+                    Some(_) => format!(
+                        "+FULLRESYNC {} {}\r\n",
+                        state.repl.replid, state.repl.offset
+                    ),
+                    None => String::new(),
+                };
+                (reply.into_bytes(), Some(rdb::snapshot(&state)))
+            }
+        };
+        let repl = &mut state.repl;
+        let id = repl.next_id;
+        repl.next_id += 1;
+        repl.replicas.push(Replica {
+            id,
+            addr,
+            listening_port,
+            online: snapshot.is_none(),
+            ack_offset: 0,
+            ack_time: Instant::now(),
+            tx,
+            queued: queued.clone(),
+        });
+        (id, reply, snapshot)
+    };
+    println!(
+        "Replica {} asked for synchronization, {}",
+        addr,
+        if snapshot.is_some() {
+            "sending a snapshot"
+        } else {
+            "continuing"
+        }
+    );
+    let res = async {
+        writer.write_all(&reply).await?;
+        if let Some(snapshot) = snapshot {
+            let data = smol::unblock(move || rdb::encode(&snapshot)).await;
+            writer
+                .write_all(format!("${}\r\n", data.len()).as_bytes())
+                .await?;
+            writer.write_all(&data).await?;
+            let mut state = state.borrow_mut();
+            if let Some(replica) = state.repl.replicas.iter_mut().find(|r| r.id == id) {
+                replica.online = true;
+            }
+        }
+        let forward = async {
+            while let Ok(bytes) = rx.recv().await {
+                // the channel closes once the replica is dropped, and what
+                // is still queued is not worth sending then
+                if rx.is_closed() {
+                    break;
+                }
+                writer.write_all(&bytes).await?;
+                queued.set(queued.get() - bytes.len());
+            }
+            Ok(())
+        };
+        let read_acks = async {
+            loop {
+                let args = match parser.parse(reader).await {
+                    Ok(RedisItem::Array(args)) => args,
+                    Ok(_) => continue,
+                    Err(ParseError::IoError(err)) => return Err(err),
+                    Err(_) => return Ok(()),
+                };
+                let mut args = args.into_iter().filter_map(RedisItem::into_bytes);
+                let (Some(command), Some(option), Some(offset)) =
+                    (args.next(), args.next(), args.next())
+                else {
+                    continue;
+                };
+                if command.eq_ignore_ascii_case(b"replconf") && option.eq_ignore_ascii_case(b"ack")
+                {
+                    let offset = std::str::from_utf8(&offset)
+                        .ok()
+                        .and_then(|offset| offset.parse().ok());
+                    if let Some(offset) = offset {
+                        state.borrow_mut().repl.ack(id, offset);
+                    }
+                }
+            }
+        };
+        smol::future::or(forward, read_acks).await
+    }
+    .await;
+    state
+        .borrow_mut()
+        .repl
+        .replicas
+        .retain(|replica| replica.id != id);
+    println!("Replica {} disconnected", addr);
+    res
+}
+
+async fn send(writer: &mut smol::net::TcpStream, args: &[&str]) -> io::Result<()> {
+    let mut buf = Vec::new();
+    let args = args
+        .iter()
+        .map(|arg| RedisItem::BulkString(arg.to_string()));
+    RedisItem::Array(args.collect()).serialize(&mut buf);
+    writer.write_all(&buf).await
+}
+
+/// Reads a line of a reply, skipping the empty lines a master sends to keep
+/// the connection alive while it prepares a snapshot.
+async fn read_line(reader: &mut BufReader<smol::net::TcpStream>) -> io::Result<String> {
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let line = line.trim_end();
+        if !line.is_empty() {
+            return Ok(line.to_string());
+        }
+    }
+}
+
+fn link_error(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Replaces the dataset with a snapshot received from the master.
+fn load_snapshot(
+    state: &RefCell<State>,
+    data: &[u8],
+    replid: String,
+    offset: u64,
+) -> io::Result<()> {
+    let mut state_mut = state.borrow_mut();
+    for db in 0..state_mut.dbs.len() {
+        state_mut.db_mut(db).clear();
+    }
+    let (keys, _) = rdb::load_data(&mut state_mut, data)?;
+    let repl = &mut state_mut.repl;
+    repl.replid = replid;
+    repl.replid2 = "0".repeat(40);
+    repl.second_offset = None;
+    repl.offset = offset;
+    repl.backlog.reset(offset + 1);
+    repl.master_db = 0;
+    // replicas of this server have to sync with the new history
+    repl.replicas.clear();
+    println!("Loaded {} keys from the master", keys);
+    // the AOF has to be rewritten, as the snapshot is not part of it
+    let rewrite = state_mut.aof.enabled();
+    drop(state_mut);
+    if rewrite {
+        aof::do_bgrewriteaof(VecDeque::new(), state);
+    }
+    Ok(())
+}
+
+/// Connects to the master, syncs with it and then applies its stream until
+/// the link breaks or the master changes.
+async fn sync_with_master(
+    state: &RefCell<State>,
+    host: &str,
+    port: u16,
+    generation: u64,
+) -> io::Result<()> {
+    let mut writer = smol::net::TcpStream::connect((host, port)).await?;
+    let mut reader = BufReader::new(writer.clone());
+    let listening_port = state.borrow().repl.port.to_string();
+    let handshake = [
+        vec!["ping"],
+        vec!["replconf", "listening-port", &listening_port],
+        vec!["replconf", "capa", "psync2"],
+    ];
+    for command in handshake {
+        send(&mut writer, &command).await?;
+        let reply = read_line(&mut reader).await?;
+        if !reply.starts_with('+') {
+            return Err(link_error(format!("{} failed: {}", command[0], reply)));
+        }
+    }
+    // a server which was a master before asks to continue its own history
+    let (replid, offset) = {
+        let repl = &state.borrow().repl;
+        (repl.replid.clone(), repl.offset)
+    };
+    send(&mut writer, &["psync", &replid, &(offset + 1).to_string()]).await?;
+    let set_status = |status| {
+        let mut state = state.borrow_mut();
+        let repl = &mut state.repl;
+        if repl.generation == generation {
+            if let Some(master) = &mut repl.master {
+                master.status = status;
+                master.last_io = Instant::now();
+            }
+        }
+    };
+    set_status(LinkStatus::Sync);
+    let reply = read_line(&mut reader).await?;
+    let mut words = reply.split(' ');
+    match words.next() {
+        Some("+FULLRESYNC") => {
+            let (Some(replid), Some(offset)) = (
+                words.next(),
+                words.next().and_then(|offset| offset.parse::<u64>().ok()),
+            ) else {
+                return Err(link_error(format!("invalid reply to PSYNC: {}", reply)));
+            };
+            let len = read_line(&mut reader).await?;
+            let Some(len) = len
+                .strip_prefix('$')
+                .and_then(|len| len.parse::<u64>().ok())
+            else {
+                return Err(link_error(format!("invalid snapshot length: {}", len)));
+            };
+            // the buffer only grows as the snapshot arrives, whatever the
+            // length claims
+            let mut data = Vec::new();
+            (&mut reader).take(len).read_to_end(&mut data).await?;
+            if (data.len() as u64) < len {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            if state.borrow().repl.generation != generation {
+                return Ok(());
+            }
+            load_snapshot(state, &data, replid.to_string(), offset)?;
+        }
+        Some("+CONTINUE") => {
+            let mut state = state.borrow_mut();
+            let repl = &mut state.repl;
+            if let Some(replid) = words.next().filter(|replid| *replid != repl.replid) {
+                // the master continues under a new history
+                repl.replid2 = std::mem::replace(&mut repl.replid, replid.to_string());
+                repl.second_offset = Some(repl.offset + 1);
+                repl.replicas.clear();
+            }
+            println!("Continuing the stream from the master at offset {}", offset);
+        }
+        _ => return Err(link_error(format!("PSYNC failed: {}", reply))),
+    }
+    set_status(LinkStatus::Connected);
+    println!("Connected to master {}:{}", host, port);
+
+    let mut buf = Vec::new();
+    let mut chunk = vec![0; 16 * 1024];
+    let mut last_ack = Instant::now();
+    loop {
+        let read = smol::future::or(async { Some(reader.read(&mut chunk).await) }, async {
+            Timer::after(ACK_PERIOD).await;
+            None
+        })
+        .await;
+        match read {
+            Some(Ok(0)) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Some(Ok(len)) => {
+                buf.extend_from_slice(&chunk[..len]);
+                set_status(LinkStatus::Connected);
+            }
+            Some(Err(err)) => return Err(err),
+            None => {}
+        }
+        if state.borrow().repl.generation != generation {
+            return Ok(());
+        }
+        let mut commands = AofReader::new(&buf);
+        let mut consumed = 0;
+        loop {
+            let args = match commands.next_command() {
+                Ok(Some(args)) => args,
+                Ok(None) | Err(AofError::Truncated(_)) => break,
+                Err(AofError::Invalid(_)) => {
+                    return Err(link_error("invalid replication stream".to_string()))
+                }
+            };
+            let end = commands.position();
+            let getack = args.len() > 1
+                && args[0].eq_ignore_ascii_case(b"replconf")
+                && args[1].eq_ignore_ascii_case(b"getack");
+            if getack {
+                let offset = state.borrow().repl.offset.to_string();
+                send(&mut writer, &["replconf", "ack", &offset]).await?;
+                last_ack = Instant::now();
+            } else {
+                let command = RedisItem::Array(args.into_iter().map(RedisItem::bulk).collect());
+                let mut db = state.borrow().repl.master_db;
+                crate::handle_command(command, state, &mut db).await;
+                state.borrow_mut().repl.master_db = db;
+            }
+            // the stream is passed on as is, keeping the offsets in line
+            state.borrow_mut().repl.feed(&buf[consumed..end]);
+            consumed = end;
+        }
+        buf.drain(..consumed);
+        if last_ack.elapsed() >= ACK_PERIOD {
+            let offset = state.borrow().repl.offset.to_string();
+            send(&mut writer, &["replconf", "ack", &offset]).await?;
+            last_ack = Instant::now();
+        }
+    }
+}
+
+/// Keeps the link to the master, if this server is a replica, reconnecting
+/// whenever it breaks.
+pub async fn replica_worker(state: &RefCell<State>) {
+    loop {
+        Timer::after(Duration::from_millis(100)).await;
+        let (host, port, generation) = {
+            let state = state.borrow();
+            if state.stop {
+                break;
+            }
+            match &state.repl.master {
+                Some(master) => (master.host.clone(), master.port, state.repl.generation),
+                None => continue,
+            }
+        };
+        if let Err(err) = sync_with_master(state, &host, port, generation).await {
+            println!("Lost the link to master {}:{}: {}", host, port, err);
+            {
+                let repl = &mut state.borrow_mut().repl;
+                if let Some(master) = repl
+                    .master
+                    .as_mut()
+                    .filter(|_| repl.generation == generation)
+                {
+                    master.status = LinkStatus::Connecting;
+                }
+            }
+            Timer::after(Duration::from_secs(1)).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_backlog() {
+        let mut repl = Replication::new(9000, 8, 0, 100);
+        repl.feed(b"abcde");
+        assert_eq!(repl.backlog.since(1).unwrap(), b"abcde");
+        assert_eq!(repl.backlog.since(6).unwrap(), b"");
+        assert!(repl.backlog.since(7).is_none());
+        repl.feed(b"fghij");
+        assert_eq!(repl.offset, 10);
+        assert_eq!(repl.backlog.start, 3);
+        assert!(repl.backlog.since(2).is_none());
+        assert_eq!(repl.backlog.since(4).unwrap(), b"defghij");
+
+        let replid = repl.replid.clone();
+        assert!(repl.continuation(&replid, 11).is_some());
+        assert!(repl.continuation("other", 11).is_none());
+        repl.shift_replid();
+        repl.feed(b"k");
+        // the previous history is shared up to the shift
+        assert_eq!(repl.continuation(&replid, 11).unwrap(), b"k");
+        assert!(repl.continuation(&replid, 12).is_none());
+    }
+
+    #[test]
+    pub fn test_output_limit() {
+        let mut repl = Replication::new(9000, 8, 8, 100);
+        let (tx, rx) = channel::unbounded();
+        let queued = Rc::new(Cell::new(0));
+        repl.replicas.push(Replica {
+            id: 0,
+            addr: "127.0.0.1:9001".parse().unwrap(),
+            listening_port: None,
+            online: true,
+            ack_offset: 0,
+            ack_time: Instant::now(),
+            tx,
+            queued: queued.clone(),
+        });
+        repl.feed(b"abcde");
+        assert_eq!(queued.get(), 5);
+        // written data no longer counts against the limit
+        assert_eq!(&*rx.try_recv().unwrap(), b"abcde");
+        queued.set(0);
+        repl.feed(b"fgh");
+        repl.feed(b"ijklm");
+        assert_eq!(repl.replicas.len(), 1);
+        repl.feed(b"n");
+        assert!(repl.replicas.is_empty());
+        assert!(rx.is_closed());
+    }
+
+    /// Returns the stream held in the backlog of `state`.
+    fn stream(state: &RefCell<State>) -> Vec<u8> {
+        let backlog = &state.borrow().repl.backlog;
+        backlog.since(backlog.start).unwrap()
+    }
+
+    #[test]
+    pub fn test_expiry_propagation() {
+        let state = RefCell::new(State::new(16));
+        crate::run_command(&state, &mut 2, &["psetex", "k", "10", "v"]);
+        crate::run_command(&state, &mut 0, &["set", "x", "v"]);
+        std::thread::sleep(Duration::from_millis(30));
+        crate::expire::remove_expired(&mut state.borrow_mut());
+        // the deletion selects the database of the key again
+        let del = b"*2\r\n$6\r\nselect\r\n$1\r\n2\r\n*2\r\n$3\r\ndel\r\n$1\r\nk\r\n";
+        assert!(stream(&state).ends_with(del));
+        assert!(state.borrow().db(2).get("k").is_none());
+    }
+
+    #[test]
+    pub fn test_replica_keeps_expired() {
+        let state = RefCell::new(State::new(16));
+        crate::run_command(&state, &mut 0, &["psetex", "k", "10", "v"]);
+        state
+            .borrow_mut()
+            .repl
+            .replicate("127.0.0.1".to_string(), 9001);
+        let exec = smol::LocalExecutor::new();
+        let _worker = exec.spawn(crate::expire::expire_worker(&state));
+        let wait = || smol::block_on(exec.run(Timer::after(Duration::from_millis(30))));
+        wait();
+        // the key stays until the master deletes it, but reads do not see it
+        assert!(state.borrow().db(0).get("k").is_some());
+        let run = |args: &[&str]| crate::run_command(&state, &mut 0, args);
+        assert_eq!(run(&["get", "k"]), RedisItem::Null);
+        assert_eq!(run(&["exists", "k"]), RedisItem::Integer(0));
+        assert_eq!(run(&["keys", "*"]), RedisItem::Array(Vec::new()));
+        assert!(state.borrow().db(0).get("k").is_some());
+
+        crate::run_command(&state, &mut 0, &["replicaof", "no", "one"]);
+        wait();
+        assert!(state.borrow().db(0).get("k").is_none());
+        assert!(stream(&state).ends_with(b"*2\r\n$3\r\ndel\r\n$1\r\nk\r\n"));
+    }
+}
@@ -0,0 +1,176 @@
+use std::collections::{BTreeSet, VecDeque};
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+use feredis_core::item::RedisItem;
+
+use crate::glob;
+use crate::value::parse_int;
+use crate::RedisError;
+
+/// The options shared by the `*SCAN` family of commands.
+#[derive(Debug)]
+pub struct ScanOptions {
+    pub cursor: u64,
+    pub count: usize,
+    pub pattern: Option<String>,
+    pub type_name: Option<String>,
+    pub no_values: bool,
+}
+
+impl ScanOptions {
+    /// Parses `cursor [MATCH pattern] [COUNT count]` and any options in `extra`
+    /// (`NOVALUES`, `TYPE`), which only some of the scan commands accept.
+    pub fn parse(args: &mut VecDeque<RedisItem>, extra: &[&str]) -> Result<Self, RedisError> {
+        let Some(RedisItem::BulkString(cursor)) = args.pop_front() else {
+            return Err(RedisError::InvalidArguments);
+        };
+        let cursor = cursor
+            .parse::<u64>()
+            .map_err(|_| RedisError::Custom("ERR invalid cursor"))?;
+        let mut options = ScanOptions {
+            cursor,
+            count: 10,
+            pattern: None,
+            type_name: None,
+            no_values: false,
+        };
+        while let Some(arg) = args.pop_front() {
+            let RedisItem::BulkString(mut arg) = arg else {
+                return Err(RedisError::InvalidArguments);
+            };
+            arg.make_ascii_lowercase();
+            match arg.as_str() {
+                "match" => {
+                    let Some(RedisItem::BulkString(pattern)) = args.pop_front() else {
+                        return Err(RedisError::Syntax);
+                    };
+                    // matching everything is the same as not matching at all
+                    options.pattern = Some(pattern).filter(|pattern| pattern != "*");
+                }
+                "count" => {
+                    let Some(RedisItem::BulkString(count)) = args.pop_front() else {
+                        return Err(RedisError::Syntax);
+                    };
+                    let count = parse_int(&count).ok_or(RedisError::NotInteger)?;
+                    if count < 1 {
+                        return Err(RedisError::Syntax);
+                    }
+                    options.count = count as usize;
+                }
+                "type" if extra.contains(&"type") => {
+                    let Some(RedisItem::BulkString(type_name)) = args.pop_front() else {
+                        return Err(RedisError::Syntax);
+                    };
+                    options.type_name = Some(type_name.to_ascii_lowercase());
+                }
+                "novalues" if extra.contains(&"novalues") => options.no_values = true,
+                _ => return Err(RedisError::Syntax),
+            }
+        }
+        Ok(options)
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        self.pattern
+            .as_ref()
+            .is_none_or(|pattern| glob::matches(pattern, name))
+    }
+}
+
+/// Builds the reply of a scan from the cursor to continue with and the
+/// elements found.
+pub fn cursor_reply(cursor: u64, items: Vec<RedisItem>) -> RedisItem {
+    RedisItem::Array(vec![
+        RedisItem::BulkString(cursor.to_string()),
+        RedisItem::Array(items),
+    ])
+}
+
+/// The hash which orders `name` in a scan. The hasher is not randomly
+/// seeded, so that cursors stay meaningful.
+fn scan_hash(name: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    name.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// The names of a keyspace or collection, ordered by their hash. A scan
+/// iterates in that order and uses the next hash to visit as its cursor,
+/// which stays valid no matter how the names change in between calls:
+/// every name present for the whole iteration is returned.
+#[derive(Debug, Clone, Default)]
+pub struct ScanOrder(BTreeSet<(u64, String)>);
+
+impl ScanOrder {
+    pub fn insert(&mut self, name: &str) {
+        self.0.insert((scan_hash(name), name.to_string()));
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.0.remove(&(scan_hash(name), name.to_string()));
+    }
+
+    pub fn clear(&mut self) {
+        self.0.clear();
+    }
+
+    /// Returns the first name at or after a random hash, or `None` if there
+    /// are no names.
+    pub fn random(&self) -> Option<&str> {
+        let start = (fastrand::u64(..), String::new());
+        self.0
+            .range(start..)
+            .next()
+            .or_else(|| self.0.first())
+            .map(|(_, name)| name.as_str())
+    }
+
+    /// Visits about `count` names starting at `cursor`. Returns the cursor
+    /// to continue with, which is zero once the iteration is complete.
+    pub fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<&str>) {
+        let mut names = Vec::new();
+        let mut last = None;
+        for (hash, name) in self.0.range((cursor, String::new())..) {
+            // names with the same hash can not be told apart by the cursor,
+            // so they are always returned together
+            if names.len() >= count && last != Some(*hash) {
+                return (*hash, names);
+            }
+            names.push(name.as_str());
+            last = Some(*hash);
+        }
+        (0, names)
+    }
+}
+
+/// Runs a scan command to the end, where `command` holds the command and
+/// key and `options` what follows the cursor. Returns the elements found and
+/// the number of calls it took.
+#[cfg(test)]
+pub fn scan_all(
+    state: &std::cell::RefCell<crate::State>,
+    command: &[&str],
+    options: &[&str],
+) -> (Vec<RedisItem>, usize) {
+    let mut cursor = "0".to_string();
+    let mut items = Vec::new();
+    let mut calls = 0;
+    loop {
+        let mut args = command.to_vec();
+        args.push(&cursor);
+        args.extend(options);
+        let reply = crate::run_command(state, &mut 0, &args);
+        let RedisItem::Array(reply) = reply else {
+            panic!("unexpected reply {:?}", reply);
+        };
+        let [RedisItem::BulkString(next), RedisItem::Array(found)] = &reply[..] else {
+            panic!("unexpected reply {:?}", reply);
+        };
+        items.extend(found.iter().cloned());
+        calls += 1;
+        if next == "0" {
+            return (items, calls);
+        }
+        cursor = next.clone();
+    }
+}
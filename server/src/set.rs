@@ -0,0 +1,880 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::rc::Rc;
+
+use feredis_core::item::RedisItem;
+
+use crate::intset::IntSet;
+use crate::list::parse_keys;
+use crate::scan::{cursor_reply, ScanOptions, ScanOrder};
+use crate::value::{parse_int, Value};
+use crate::{RedisError, State};
+
+/// The maximum number of members of a set stored as an intset,
+/// matching redis' default `set-max-intset-entries`.
+const MAX_INTSET_ENTRIES: usize = 512;
+
+/// The most members `SRANDMEMBER` and `HRANDFIELD` return for a negative
+/// count, where members may repeat. Larger counts are clamped to it, as
+/// every member of the reply is built in memory.
+pub const MAX_RANDOM_COUNT: u64 = 1 << 20;
+
+/// The members of a hash table encoded set. They are kept in a vector as
+/// well, so that a random member can be picked in constant time, and
+/// ordered by their hash for `SSCAN`.
+#[derive(Debug, Clone, Default)]
+pub struct Members {
+    members: Vec<Rc<str>>,
+    /// the position of each member in `members`
+    positions: HashMap<Rc<str>, usize>,
+    order: ScanOrder,
+}
+
+impl Members {
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    pub fn contains(&self, member: &str) -> bool {
+        self.positions.contains_key(member)
+    }
+
+    /// Inserts `member`, returning `false` if it was already present.
+    pub fn insert(&mut self, member: String) -> bool {
+        if self.contains(&member) {
+            return false;
+        }
+        self.order.insert(&member);
+        let member: Rc<str> = member.into();
+        self.positions.insert(member.clone(), self.members.len());
+        self.members.push(member);
+        true
+    }
+
+    /// Removes `member`, returning `false` if it was not present.
+    pub fn remove(&mut self, member: &str) -> bool {
+        match self.positions.get(member) {
+            Some(&index) => {
+                self.remove_at(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the member at `index`, where the order is arbitrary.
+    pub fn get(&self, index: usize) -> Option<&str> {
+        self.members.get(index).map(|member| &**member)
+    }
+
+    /// Removes the member at `index`, moving the last member into its place.
+    pub fn remove_at(&mut self, index: usize) -> String {
+        let member = self.members.swap_remove(index);
+        self.positions.remove(&member);
+        self.order.remove(&member);
+        if let Some(moved) = self.members.get(index) {
+            self.positions.insert(moved.clone(), index);
+        }
+        member.to_string()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        self.members.iter().map(|member| &**member)
+    }
+
+    /// Visits about `count` members starting at `cursor`, returning the
+    /// cursor to continue with.
+    pub fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<&str>) {
+        self.order.scan(cursor, count)
+    }
+}
+
+impl PartialEq for Members {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && self.iter().all(|member| other.contains(member))
+    }
+}
+
+impl FromIterator<String> for Members {
+    fn from_iter<T: IntoIterator<Item = String>>(iter: T) -> Self {
+        let mut members = Members::default();
+        for member in iter {
+            members.insert(member);
+        }
+        members
+    }
+}
+
+/// A set of strings. Sets which only contain integers are stored as an
+/// `IntSet` until they grow too large.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SetValue {
+    IntSet(IntSet),
+    Hash(Members),
+}
+
+impl Default for SetValue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SetValue {
+    pub fn new() -> Self {
+        SetValue::IntSet(IntSet::new())
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            SetValue::IntSet(set) => set.len(),
+            SetValue::Hash(set) => set.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        match self {
+            SetValue::IntSet(set) => set.is_empty(),
+            SetValue::Hash(set) => set.is_empty(),
+        }
+    }
+
+    pub fn contains(&self, member: &str) -> bool {
+        match self {
+            SetValue::IntSet(set) => parse_int(member).is_some_and(|int| set.contains(int)),
+            SetValue::Hash(set) => set.contains(member),
+        }
+    }
+
+    /// Inserts `member`, returning `false` if it was already present.
+    pub fn insert(&mut self, member: String) -> bool {
+        if let SetValue::IntSet(set) = self {
+            match parse_int(&member) {
+                Some(int) if set.contains(int) => return false,
+                Some(int) if set.len() < MAX_INTSET_ENTRIES => return set.insert(int),
+                _ => self.convert(),
+            }
+        }
+        let SetValue::Hash(set) = self else {
+            unreachable!();
+        };
+        set.insert(member)
+    }
+
+    /// Removes `member`, returning `false` if it was not present.
+    pub fn remove(&mut self, member: &str) -> bool {
+        match self {
+            SetValue::IntSet(set) => parse_int(member).is_some_and(|int| set.remove(int)),
+            SetValue::Hash(set) => set.remove(member),
+        }
+    }
+
+    /// Converts an intset into the hash table encoding.
+    fn convert(&mut self) {
+        if let SetValue::IntSet(set) = self {
+            *self = SetValue::Hash(set.iter().map(|int| int.to_string()).collect());
+        }
+    }
+
+    pub fn members(&self) -> Vec<String> {
+        match self {
+            SetValue::IntSet(set) => set.iter().map(|int| int.to_string()).collect(),
+            SetValue::Hash(set) => set.iter().map(str::to_string).collect(),
+        }
+    }
+
+    /// Visits about `count` members starting at `cursor`, returning the
+    /// cursor to continue with. Intsets are small, so they are returned
+    /// whole, as redis does for its compact encodings.
+    pub fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<String>) {
+        match self {
+            SetValue::IntSet(_) => (0, self.members()),
+            SetValue::Hash(set) => {
+                let (cursor, members) = set.scan(cursor, count);
+                (cursor, members.into_iter().map(str::to_string).collect())
+            }
+        }
+    }
+
+    /// Returns the member at `index`, where the order is arbitrary.
+    fn get(&self, index: usize) -> Option<String> {
+        match self {
+            SetValue::IntSet(set) => set.get(index).map(|int| int.to_string()),
+            SetValue::Hash(set) => set.get(index).map(str::to_string),
+        }
+    }
+
+    /// Returns a random member, or `None` if the set is empty.
+    pub fn random(&self) -> Option<String> {
+        if self.is_empty() {
+            return None;
+        }
+        self.get(fastrand::usize(..self.len()))
+    }
+
+    /// Returns `count` distinct random members, or all members if there are
+    /// fewer, in random order.
+    pub fn random_distinct(&self, count: usize) -> Vec<String> {
+        let len = self.len();
+        if count >= len {
+            let mut members = self.members();
+            fastrand::shuffle(&mut members);
+            return members;
+        }
+        // Floyd's algorithm picks distinct positions without visiting the
+        // others
+        let mut picked = HashSet::with_capacity(count);
+        let mut members = Vec::with_capacity(count);
+        for last in len - count..len {
+            let mut index = fastrand::usize(..=last);
+            if !picked.insert(index) {
+                picked.insert(last);
+                index = last;
+            }
+            members.extend(self.get(index));
+        }
+        fastrand::shuffle(&mut members);
+        members
+    }
+
+    /// Removes and returns `count` distinct random members, or all members
+    /// if there are fewer.
+    pub fn pop_random_many(&mut self, count: usize) -> Vec<String> {
+        if count >= self.len() {
+            return std::mem::take(self).members();
+        }
+        (0..count).filter_map(|_| self.pop_random()).collect()
+    }
+
+    /// Removes and returns a random member, or `None` if the set is empty.
+    pub fn pop_random(&mut self) -> Option<String> {
+        if self.is_empty() {
+            return None;
+        }
+        let index = fastrand::usize(..self.len());
+        Some(match self {
+            SetValue::IntSet(set) => set.remove_at(index).to_string(),
+            SetValue::Hash(set) => set.remove_at(index),
+        })
+    }
+}
+
+impl FromIterator<String> for SetValue {
+    fn from_iter<T: IntoIterator<Item = String>>(iter: T) -> Self {
+        let mut set = SetValue::new();
+        for member in iter {
+            set.insert(member);
+        }
+        set
+    }
+}
+
+/// Returns the set stored at `key`, or `None` if the key does not exist.
+pub fn get_set<'a>(state: &'a State, key: &str) -> Result<Option<&'a SetValue>, RedisError> {
+    match state.items.get(key) {
+        Some((Value::Set(set), _)) => Ok(Some(set)),
+        Some(_) => Err(RedisError::WrongType),
+        None => Ok(None),
+    }
+}
+
+fn get_set_mut<'a>(
+    state: &'a mut State,
+    key: &str,
+) -> Result<Option<&'a mut SetValue>, RedisError> {
+    match state.items.get_mut(key) {
+        Some((Value::Set(set), _)) => Ok(Some(set)),
+        Some(_) => Err(RedisError::WrongType),
+        None => Ok(None),
+    }
+}
+
+/// Replaces whatever is stored at `key` with `set`, deleting the key if the
+/// set is empty. Returns the size of the stored set.
+fn store(state: &mut State, key: String, set: SetValue) -> usize {
+    let len = set.len();
+    if set.is_empty() {
+        state.items.remove(&key);
+    } else {
+        let tag = state.next_tag();
+        state.items.insert(key, (Value::Set(set), tag));
+    }
+    len
+}
+
+fn members_reply(members: impl IntoIterator<Item = String>) -> RedisItem {
+    RedisItem::Array(members.into_iter().map(RedisItem::BulkString).collect())
+}
+
+fn collect_keys(args: VecDeque<RedisItem>) -> Result<Vec<String>, RedisError> {
+    let mut keys = Vec::with_capacity(args.len());
+    for arg in args {
+        let RedisItem::BulkString(key) = arg else {
+            return Err(RedisError::InvalidArguments);
+        };
+        keys.push(key);
+    }
+    if keys.is_empty() {
+        return Err(RedisError::InvalidArguments);
+    }
+    Ok(keys)
+}
+
+pub fn do_sadd(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let Some(BulkString(key)) = args.pop_front() else {
+        return RedisError::InvalidArguments.into();
+    };
+    let members = match collect_keys(args) {
+        Ok(members) => members,
+        Err(err) => return err.into(),
+    };
+    let mut state = state.borrow_mut();
+    let state = &mut *state;
+    let set = match get_set_mut(state, &key) {
+        Ok(Some(set)) => set,
+        Ok(None) => {
+            let tag = state.next_tag();
+            let (Value::Set(set), _) = state
+                .items
+                .get_or_insert_with(key, || (Value::Set(SetValue::new()), tag))
+            else {
+                unreachable!();
+            };
+            set
+        }
+        Err(err) => return err.into(),
+    };
+    let added = members
+        .into_iter()
+        .filter(|member| set.insert(member.clone()))
+        .count();
+    Integer(added as i64)
+}
+
+pub fn do_srem(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let Some(BulkString(key)) = args.pop_front() else {
+        return RedisError::InvalidArguments.into();
+    };
+    let members = match collect_keys(args) {
+        Ok(members) => members,
+        Err(err) => return err.into(),
+    };
+    let mut state = state.borrow_mut();
+    let set = match get_set_mut(&mut state, &key) {
+        Ok(Some(set)) => set,
+        Ok(None) => return Integer(0),
+        Err(err) => return err.into(),
+    };
+    let removed = members.iter().filter(|member| set.remove(member)).count();
+    if set.is_empty() {
+        state.items.remove(&key);
+    }
+    Integer(removed as i64)
+}
+
+pub fn do_smembers(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let Some(BulkString(key)) = args.pop_front() else {
+        return RedisError::InvalidArguments.into();
+    };
+    match get_set(&state.borrow(), &key) {
+        Ok(set) => members_reply(set.map(SetValue::members).unwrap_or_default()),
+        Err(err) => err.into(),
+    }
+}
+
+pub fn do_sismember(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let (Some(BulkString(key)), Some(BulkString(member))) = (args.pop_front(), args.pop_front())
+    else {
+        return RedisError::InvalidArguments.into();
+    };
+    match get_set(&state.borrow(), &key) {
+        Ok(set) => Integer(set.is_some_and(|set| set.contains(&member)) as i64),
+        Err(err) => err.into(),
+    }
+}
+
+pub fn do_smismember(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let Some(BulkString(key)) = args.pop_front() else {
+        return RedisError::InvalidArguments.into();
+    };
+    let members = match collect_keys(args) {
+        Ok(members) => members,
+        Err(err) => return err.into(),
+    };
+    match get_set(&state.borrow(), &key) {
+        Ok(set) => Array(
+            members
+                .iter()
+                .map(|member| Integer(set.is_some_and(|set| set.contains(member)) as i64))
+                .collect(),
+        ),
+        Err(err) => err.into(),
+    }
+}
+
+pub fn do_scard(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let Some(BulkString(key)) = args.pop_front() else {
+        return RedisError::InvalidArguments.into();
+    };
+    match get_set(&state.borrow(), &key) {
+        Ok(set) => Integer(set.map_or(0, |set| set.len() as i64)),
+        Err(err) => err.into(),
+    }
+}
+
+/// Parses the optional count of `SPOP` and `SRANDMEMBER`.
+fn parse_count(args: &mut VecDeque<RedisItem>) -> Result<Option<i64>, RedisError> {
+    let count = match args.pop_front() {
+        Some(RedisItem::BulkString(count)) => {
+            Some(parse_int(&count).ok_or(RedisError::NotInteger)?)
+        }
+        None => None,
+        Some(_) => return Err(RedisError::InvalidArguments),
+    };
+    if !args.is_empty() {
+        return Err(RedisError::Syntax);
+    }
+    Ok(count)
+}
+
+pub fn do_spop(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let Some(BulkString(key)) = args.pop_front() else {
+        return RedisError::InvalidArguments.into();
+    };
+    let count = match parse_count(&mut args) {
+        Ok(Some(count)) if count < 0 => return RedisError::NotPositive.into(),
+        Ok(count) => count,
+        Err(err) => return err.into(),
+    };
+    let mut state = state.borrow_mut();
+    let set = match get_set_mut(&mut state, &key) {
+        Ok(Some(set)) => set,
+        Ok(None) if count.is_some() => return Array(Vec::new()),
+        Ok(None) => return Null,
+        Err(err) => return err.into(),
+    };
+    let res = match count {
+        Some(count) => members_reply(set.pop_random_many(count as usize)),
+        None => set.pop_random().map_or(Null, BulkString),
+    };
+    if set.is_empty() {
+        state.items.remove(&key);
+    }
+    res
+}
+
+pub fn do_srandmember(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let Some(BulkString(key)) = args.pop_front() else {
+        return RedisError::InvalidArguments.into();
+    };
+    let count = match parse_count(&mut args) {
+        Ok(count) => count,
+        Err(err) => return err.into(),
+    };
+    let state = state.borrow();
+    let set = match get_set(&state, &key) {
+        Ok(set) => set,
+        Err(err) => return err.into(),
+    };
+    let Some(count) = count else {
+        return set.and_then(SetValue::random).map_or(Null, BulkString);
+    };
+    let Some(set) = set else {
+        return Array(Vec::new());
+    };
+    if count >= 0 {
+        members_reply(set.random_distinct(count as usize))
+    } else {
+        // members may be repeated
+        let count = count.unsigned_abs().min(MAX_RANDOM_COUNT);
+        members_reply((0..count).filter_map(|_| set.random()))
+    }
+}
+
+pub fn do_smove(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let (Some(BulkString(src)), Some(BulkString(dst)), Some(BulkString(member))) =
+        (args.pop_front(), args.pop_front(), args.pop_front())
+    else {
+        return RedisError::InvalidArguments.into();
+    };
+    let mut state = state.borrow_mut();
+    let state = &mut *state;
+    // a missing source is not an error, whatever the destination holds
+    if !state.items.contains_key(&src) {
+        return Integer(0);
+    }
+    let contained = match (get_set(state, &src), get_set(state, &dst)) {
+        (Ok(src), Ok(_)) => src.is_some_and(|set| set.contains(&member)),
+        (Err(err), _) | (_, Err(err)) => return err.into(),
+    };
+    if !contained {
+        return Integer(0);
+    }
+    if src == dst {
+        return Integer(1);
+    }
+    let set = get_set_mut(state, &src).unwrap().unwrap();
+    set.remove(&member);
+    if set.is_empty() {
+        state.items.remove(&src);
+    }
+    let tag = state.next_tag();
+    let (Value::Set(set), _) = state
+        .items
+        .get_or_insert_with(dst, || (Value::Set(SetValue::new()), tag))
+    else {
+        unreachable!();
+    };
+    set.insert(member);
+    Integer(1)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum SetOp {
+    Inter,
+    Union,
+    Diff,
+}
+
+/// Computes the intersection, union or difference of the sets at `keys`,
+/// treating missing keys as empty sets.
+fn compute(state: &State, keys: &[String], op: SetOp) -> Result<SetValue, RedisError> {
+    let mut sets = Vec::with_capacity(keys.len());
+    for key in keys {
+        sets.push(get_set(state, key)?);
+    }
+    Ok(match op {
+        SetOp::Inter => {
+            if sets.iter().any(Option::is_none) {
+                return Ok(SetValue::new());
+            }
+            let mut sets: Vec<&SetValue> = sets.into_iter().flatten().collect();
+            // checking the members of the smallest set is cheapest
+            sets.sort_by_key(|set| set.len());
+            sets[0]
+                .members()
+                .into_iter()
+                .filter(|member| sets[1..].iter().all(|set| set.contains(member)))
+                .collect()
+        }
+        SetOp::Union => sets
+            .into_iter()
+            .flatten()
+            .flat_map(SetValue::members)
+            .collect(),
+        SetOp::Diff => {
+            let Some(first) = sets[0] else {
+                return Ok(SetValue::new());
+            };
+            first
+                .members()
+                .into_iter()
+                .filter(|member| sets[1..].iter().flatten().all(|set| !set.contains(member)))
+                .collect()
+        }
+    })
+}
+
+fn algebra(args: VecDeque<RedisItem>, state: &RefCell<State>, op: SetOp) -> RedisItem {
+    let keys = match collect_keys(args) {
+        Ok(keys) => keys,
+        Err(err) => return err.into(),
+    };
+    match compute(&state.borrow(), &keys, op) {
+        Ok(set) => members_reply(set.members()),
+        Err(err) => err.into(),
+    }
+}
+
+fn algebra_store(mut args: VecDeque<RedisItem>, state: &RefCell<State>, op: SetOp) -> RedisItem {
+    let Some(RedisItem::BulkString(dst)) = args.pop_front() else {
+        return RedisError::InvalidArguments.into();
+    };
+    let keys = match collect_keys(args) {
+        Ok(keys) => keys,
+        Err(err) => return err.into(),
+    };
+    let mut state = state.borrow_mut();
+    match compute(&state, &keys, op) {
+        Ok(set) => RedisItem::Integer(store(&mut state, dst, set) as i64),
+        Err(err) => err.into(),
+    }
+}
+
+pub fn do_sinter(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    algebra(args, state, SetOp::Inter)
+}
+
+pub fn do_sunion(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    algebra(args, state, SetOp::Union)
+}
+
+pub fn do_sdiff(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    algebra(args, state, SetOp::Diff)
+}
+
+pub fn do_sinterstore(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    algebra_store(args, state, SetOp::Inter)
+}
+
+pub fn do_sunionstore(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    algebra_store(args, state, SetOp::Union)
+}
+
+pub fn do_sdiffstore(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    algebra_store(args, state, SetOp::Diff)
+}
+
+pub fn do_sintercard(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let keys = match parse_keys(&mut args) {
+        Ok(keys) => keys,
+        Err(err) => return err.into(),
+    };
+    let limit = match (args.pop_front(), args.pop_front()) {
+        (None, None) => 0,
+        (Some(BulkString(arg)), Some(BulkString(limit))) if arg.eq_ignore_ascii_case("limit") => {
+            match parse_int(&limit) {
+                Some(limit) if limit >= 0 => limit as usize,
+                Some(_) => return SimpleError("ERR LIMIT can't be negative".to_string()),
+                None => return RedisError::NotInteger.into(),
+            }
+        }
+        _ => return RedisError::Syntax.into(),
+    };
+    match compute(&state.borrow(), &keys, SetOp::Inter) {
+        // a limit of zero means unlimited
+        Ok(set) if limit > 0 => Integer(set.len().min(limit) as i64),
+        Ok(set) => Integer(set.len() as i64),
+        Err(err) => err.into(),
+    }
+}
+
+pub fn do_sscan(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let Some(BulkString(key)) = args.pop_front() else {
+        return RedisError::InvalidArguments.into();
+    };
+    let options = match ScanOptions::parse(&mut args, &[]) {
+        Ok(options) => options,
+        Err(err) => return err.into(),
+    };
+    match get_set(&state.borrow(), &key) {
+        Ok(set) => {
+            let (cursor, members) = set.map_or((0, Vec::new()), |set| {
+                set.scan(options.cursor, options.count)
+            });
+            let members = members
+                .into_iter()
+                .filter(|member| options.matches(member))
+                .map(BulkString);
+            cursor_reply(cursor, members.collect())
+        }
+        Err(err) => err.into(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::scan::scan_all;
+
+    fn run(state: &RefCell<State>, args: &[&str]) -> RedisItem {
+        crate::run_command(state, &mut 0, args)
+    }
+
+    fn string(item: RedisItem) -> String {
+        let RedisItem::BulkString(item) = item else {
+            panic!("unexpected item {:?}", item);
+        };
+        item
+    }
+
+    /// Returns the members in a reply, sorted.
+    fn sorted(reply: RedisItem) -> Vec<String> {
+        let RedisItem::Array(items) = reply else {
+            panic!("unexpected reply {:?}", reply);
+        };
+        let mut members: Vec<String> = items.into_iter().map(string).collect();
+        members.sort();
+        members
+    }
+
+    #[test]
+    pub fn test_set_ops() {
+        use RedisItem::*;
+        let state = RefCell::new(State::new(1));
+        assert_eq!(run(&state, &["sadd", "a", "1", "2", "3", "x"]), Integer(4));
+        assert_eq!(run(&state, &["sadd", "a", "1"]), Integer(0));
+        assert_eq!(run(&state, &["sadd", "b", "2", "3", "4"]), Integer(3));
+        assert_eq!(sorted(run(&state, &["sinter", "a", "b"])), ["2", "3"]);
+        assert_eq!(sorted(run(&state, &["sdiff", "a", "b"])), ["1", "x"]);
+        let union = sorted(run(&state, &["sunion", "a", "b", "none"]));
+        assert_eq!(union, ["1", "2", "3", "4", "x"]);
+        let res = run(&state, &["sintercard", "2", "a", "b", "limit", "1"]);
+        assert_eq!(res, Integer(1));
+        assert_eq!(run(&state, &["sinterstore", "c", "a", "none"]), Integer(0));
+        assert_eq!(run(&state, &["scard", "c"]), Integer(0));
+        assert_eq!(run(&state, &["srem", "a", "1", "x", "5"]), Integer(2));
+        assert_eq!(run(&state, &["scard", "a"]), Integer(2));
+
+        run(&state, &["set", "s", "v"]);
+        assert!(matches!(run(&state, &["sadd", "s", "1"]), SimpleError(_)));
+        assert!(matches!(run(&state, &["sinter", "a", "s"]), SimpleError(_)));
+    }
+
+    #[test]
+    pub fn test_spop() {
+        use RedisItem::*;
+        let state = RefCell::new(State::new(1));
+        let members: Vec<String> = (0..1000).map(|i| format!("m{}", i)).collect();
+        let mut args = vec!["sadd", "s"];
+        args.extend(members.iter().map(String::as_str));
+        run(&state, &args);
+
+        let popped = sorted(run(&state, &["spop", "s", "400"]));
+        assert_eq!(popped.len(), 400);
+        assert!(popped.windows(2).all(|pair| pair[0] != pair[1]));
+        assert_eq!(run(&state, &["scard", "s"]), Integer(600));
+        let mut rest = sorted(run(&state, &["spop", "s", "100000"]));
+        assert_eq!(rest.len(), 600);
+        rest.extend(popped);
+        rest.sort();
+        let mut expected = members;
+        expected.sort();
+        assert_eq!(rest, expected);
+        assert_eq!(run(&state, &["scard", "s"]), Integer(0));
+
+        assert_eq!(run(&state, &["spop", "s"]), Null);
+        assert_eq!(run(&state, &["spop", "s", "3"]), Array(Vec::new()));
+        assert!(matches!(run(&state, &["spop", "s", "-1"]), SimpleError(_)));
+        run(&state, &["sadd", "ints", "1", "2", "3"]);
+        assert_eq!(sorted(run(&state, &["spop", "ints", "2"])).len(), 2);
+        assert_eq!(run(&state, &["scard", "ints"]), Integer(1));
+    }
+
+    #[test]
+    pub fn test_srandmember() {
+        use RedisItem::*;
+        let state = RefCell::new(State::new(1));
+        run(&state, &["sadd", "s", "a", "b", "c"]);
+        assert_eq!(
+            sorted(run(&state, &["srandmember", "s", "10"])),
+            ["a", "b", "c"]
+        );
+        assert_eq!(sorted(run(&state, &["srandmember", "s", "2"])).len(), 2);
+        let repeated = sorted(run(&state, &["srandmember", "s", "-10"]));
+        assert_eq!(repeated.len(), 10);
+        assert!(repeated
+            .iter()
+            .all(|member| ["a", "b", "c"].contains(&&**member)));
+        // huge counts are clamped
+        let res = sorted(run(&state, &["srandmember", "s", "-9223372036854775808"]));
+        assert_eq!(res.len() as u64, MAX_RANDOM_COUNT);
+        assert_eq!(
+            run(&state, &["srandmember", "none", "-5"]),
+            Array(Vec::new())
+        );
+        assert_eq!(run(&state, &["scard", "s"]), Integer(3));
+
+        // distinct members are sampled from large sets as well
+        let members: Vec<String> = (0..1000).map(|i| format!("m{}", i)).collect();
+        let mut args = vec!["sadd", "big"];
+        args.extend(members.iter().map(String::as_str));
+        run(&state, &args);
+        let sample = sorted(run(&state, &["srandmember", "big", "300"]));
+        assert_eq!(sample.len(), 300);
+        assert!(sample.windows(2).all(|pair| pair[0] != pair[1]));
+        assert!(sample.iter().all(|member| members.contains(member)));
+    }
+
+    #[test]
+    pub fn test_sscan() {
+        let state = RefCell::new(State::new(1));
+        let members: Vec<String> = (0..200).map(|i| format!("m{}", i)).collect();
+        let mut args = vec!["sadd", "s"];
+        args.extend(members.iter().map(String::as_str));
+        run(&state, &args);
+
+        // members present for the whole scan are returned, whatever changes
+        // in between the calls
+        let mut cursor = "0".to_string();
+        let mut found = Vec::new();
+        for i in 0.. {
+            let reply = run(&state, &["sscan", "s", &cursor, "count", "10"]);
+            let RedisItem::Array(mut reply) = reply else {
+                panic!("unexpected reply {:?}", reply);
+            };
+            found.extend(sorted(reply.pop().unwrap()));
+            cursor = string(reply[0].clone());
+            if cursor == "0" {
+                break;
+            }
+            run(&state, &["srem", "s", &format!("m{}", 199 - i)]);
+            run(&state, &["sadd", "s", &format!("new{}", i)]);
+        }
+        let kept = run(&state, &["smembers", "s"]);
+        for member in sorted(kept).iter().filter(|m| m.starts_with('m')) {
+            assert!(found.contains(member), "{} was not scanned", member);
+        }
+
+        // intsets are returned whole
+        run(&state, &["sadd", "ints", "1", "2", "3"]);
+        let (items, calls) = scan_all(&state, &["sscan", "ints"], &["count", "1"]);
+        assert_eq!((items.len(), calls), (3, 1));
+        let (items, _) = scan_all(&state, &["sscan", "s"], &["match", "m1?"]);
+        assert_eq!(items.len(), 10);
+    }
+
+    #[test]
+    pub fn test_members() {
+        let mut set: Members = ["a", "b", "c", "d"].map(String::from).into_iter().collect();
+        assert!(!set.insert("b".to_string()));
+        assert!(set.remove("b"));
+        assert!(!set.remove("b"));
+        // the last member takes the place of the removed one
+        assert_eq!(set.get(1), Some("d"));
+        assert_eq!(set.remove_at(0), "a");
+        assert_eq!(set.get(0), Some("c"));
+        assert!(set.remove("c") && set.remove("d"));
+        assert!(set.is_empty());
+        assert!(set.positions.is_empty());
+    }
+
+    #[test]
+    pub fn test_smove() {
+        use RedisItem::*;
+        let state = RefCell::new(State::new(1));
+        run(&state, &["sadd", "a", "x", "y"]);
+        run(&state, &["set", "str", "v"]);
+        assert_eq!(run(&state, &["smove", "a", "b", "x"]), Integer(1));
+        assert_eq!(run(&state, &["smove", "a", "b", "z"]), Integer(0));
+        assert_eq!(run(&state, &["smove", "a", "a", "y"]), Integer(1));
+        assert_eq!(run(&state, &["smove", "a", "b", "y"]), Integer(1));
+        assert_eq!(run(&state, &["scard", "a"]), Integer(0));
+        assert_eq!(sorted(run(&state, &["smembers", "b"])), ["x", "y"]);
+
+        // a missing source is not checked against the destination
+        assert_eq!(run(&state, &["smove", "none", "str", "x"]), Integer(0));
+        assert!(matches!(
+            run(&state, &["smove", "b", "str", "x"]),
+            SimpleError(_)
+        ));
+        assert!(matches!(
+            run(&state, &["smove", "str", "b", "x"]),
+            SimpleError(_)
+        ));
+    }
+}
@@ -0,0 +1,543 @@
+use std::cmp::Ordering;
+
+/// The maximum number of levels of a node, as in redis.
+const MAX_LEVEL: usize = 32;
+/// The probability of a node having one more level.
+const LEVEL_PROBABILITY: f64 = 0.25;
+
+const HEAD: usize = 0;
+
+/// A range of scores, where either end may be exclusive.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScoreRange {
+    pub min: f64,
+    pub max: f64,
+    pub min_exclusive: bool,
+    pub max_exclusive: bool,
+}
+
+impl ScoreRange {
+    fn gte_min(&self, score: f64) -> bool {
+        if self.min_exclusive {
+            score > self.min
+        } else {
+            score >= self.min
+        }
+    }
+
+    fn lte_max(&self, score: f64) -> bool {
+        if self.max_exclusive {
+            score < self.max
+        } else {
+            score <= self.max
+        }
+    }
+
+    pub fn contains(&self, score: f64) -> bool {
+        self.gte_min(score) && self.lte_max(score)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.min > self.max || (self.min == self.max && (self.min_exclusive || self.max_exclusive))
+    }
+}
+
+/// One end of a lexicographical range.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LexBound {
+    NegInf,
+    PosInf,
+    Inclusive(String),
+    Exclusive(String),
+}
+
+/// A lexicographical range of members.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LexRange {
+    pub min: LexBound,
+    pub max: LexBound,
+}
+
+impl LexRange {
+    fn gte_min(&self, member: &str) -> bool {
+        match &self.min {
+            LexBound::NegInf => true,
+            LexBound::PosInf => false,
+            LexBound::Inclusive(min) => member >= min.as_str(),
+            LexBound::Exclusive(min) => member > min.as_str(),
+        }
+    }
+
+    fn lte_max(&self, member: &str) -> bool {
+        match &self.max {
+            LexBound::NegInf => false,
+            LexBound::PosInf => true,
+            LexBound::Inclusive(max) => member <= max.as_str(),
+            LexBound::Exclusive(max) => member < max.as_str(),
+        }
+    }
+
+    pub fn contains(&self, member: &str) -> bool {
+        self.gte_min(member) && self.lte_max(member)
+    }
+
+    fn is_empty(&self) -> bool {
+        use LexBound::*;
+        match (&self.min, &self.max) {
+            (PosInf, _) | (_, NegInf) => true,
+            (NegInf, _) | (_, PosInf) => false,
+            (Inclusive(min), Inclusive(max)) => min > max,
+            (Inclusive(min) | Exclusive(min), Inclusive(max) | Exclusive(max)) => min >= max,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Level {
+    forward: Option<usize>,
+    /// the number of nodes skipped by following `forward`
+    span: usize,
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    member: String,
+    score: f64,
+    backward: Option<usize>,
+    levels: Vec<Level>,
+}
+
+impl Node {
+    fn cmp(&self, score: f64, member: &str) -> Ordering {
+        self.score
+            .partial_cmp(&score)
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| self.member.as_str().cmp(member))
+    }
+}
+
+/// A skiplist ordered by score and then member, as used by redis to implement
+/// sorted sets. Nodes are stored in an arena and refer to each other by index.
+///
+/// Every level keeps track of the number of nodes it skips over, which allows
+/// computing the rank of a node in logarithmic time.
+#[derive(Debug, Clone)]
+pub struct SkipList {
+    nodes: Vec<Node>,
+    free: Vec<usize>,
+    tail: Option<usize>,
+    len: usize,
+    level: usize,
+}
+
+impl Default for SkipList {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn random_level() -> usize {
+    let mut level = 1;
+    while level < MAX_LEVEL && fastrand::f64() < LEVEL_PROBABILITY {
+        level += 1;
+    }
+    level
+}
+
+impl SkipList {
+    pub fn new() -> Self {
+        let head = Node {
+            member: String::new(),
+            score: 0.0,
+            backward: None,
+            levels: vec![
+                Level {
+                    forward: None,
+                    span: 0
+                };
+                MAX_LEVEL
+            ],
+        };
+        Self {
+            nodes: vec![head],
+            free: Vec::new(),
+            tail: None,
+            len: 0,
+            level: 1,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the member and score of a node.
+    pub fn entry(&self, node: usize) -> (&str, f64) {
+        let node = &self.nodes[node];
+        (&node.member, node.score)
+    }
+
+    /// The node following `node`.
+    pub fn next(&self, node: usize) -> Option<usize> {
+        self.nodes[node].levels[0].forward
+    }
+
+    /// The node preceding `node`.
+    pub fn prev(&self, node: usize) -> Option<usize> {
+        self.nodes[node].backward
+    }
+
+    pub fn first(&self) -> Option<usize> {
+        self.next(HEAD)
+    }
+
+    pub fn last(&self) -> Option<usize> {
+        self.tail
+    }
+
+    fn forward(&self, node: usize, level: usize) -> Option<usize> {
+        self.nodes[node].levels[level].forward
+    }
+
+    fn alloc(&mut self, node: Node) -> usize {
+        match self.free.pop() {
+            Some(index) => {
+                self.nodes[index] = node;
+                index
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        }
+    }
+
+    /// Inserts a member, which must not already be in the list.
+    pub fn insert(&mut self, score: f64, member: String) {
+        let mut update = [HEAD; MAX_LEVEL];
+        let mut rank = [0; MAX_LEVEL];
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            rank[i] = if i == self.level - 1 { 0 } else { rank[i + 1] };
+            while let Some(next) = self.forward(x, i) {
+                if self.nodes[next].cmp(score, &member) != Ordering::Less {
+                    break;
+                }
+                rank[i] += self.nodes[x].levels[i].span;
+                x = next;
+            }
+            update[i] = x;
+        }
+        let level = random_level();
+        if level > self.level {
+            for i in self.level..level {
+                rank[i] = 0;
+                update[i] = HEAD;
+                self.nodes[HEAD].levels[i].span = self.len;
+            }
+            self.level = level;
+        }
+        let node = self.alloc(Node {
+            member,
+            score,
+            backward: None,
+            levels: vec![
+                Level {
+                    forward: None,
+                    span: 0
+                };
+                level
+            ],
+        });
+        for i in 0..level {
+            let prev = self.nodes[update[i]].levels[i];
+            self.nodes[node].levels[i] = Level {
+                forward: prev.forward,
+                span: prev.span - (rank[0] - rank[i]),
+            };
+            self.nodes[update[i]].levels[i] = Level {
+                forward: Some(node),
+                span: rank[0] - rank[i] + 1,
+            };
+        }
+        for (i, &prev) in update.iter().enumerate().take(self.level).skip(level) {
+            self.nodes[prev].levels[i].span += 1;
+        }
+        self.nodes[node].backward = (update[0] != HEAD).then_some(update[0]);
+        match self.forward(node, 0) {
+            Some(next) => self.nodes[next].backward = Some(node),
+            None => self.tail = Some(node),
+        }
+        self.len += 1;
+    }
+
+    /// Removes a member with the given score. Returns `false` if no such
+    /// member exists.
+    pub fn remove(&mut self, score: f64, member: &str) -> bool {
+        let mut update = [HEAD; MAX_LEVEL];
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.forward(x, i) {
+                if self.nodes[next].cmp(score, member) != Ordering::Less {
+                    break;
+                }
+                x = next;
+            }
+            update[i] = x;
+        }
+        match self.forward(x, 0) {
+            Some(node) if self.nodes[node].cmp(score, member) == Ordering::Equal => {
+                self.remove_node(node, &update);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn remove_node(&mut self, node: usize, update: &[usize; MAX_LEVEL]) {
+        for (i, &prev) in update.iter().enumerate().take(self.level) {
+            if self.forward(prev, i) == Some(node) {
+                let removed = self.nodes[node].levels[i];
+                let level = &mut self.nodes[prev].levels[i];
+                level.span += removed.span;
+                level.span -= 1;
+                level.forward = removed.forward;
+            } else {
+                self.nodes[prev].levels[i].span -= 1;
+            }
+        }
+        let backward = self.nodes[node].backward;
+        match self.forward(node, 0) {
+            Some(next) => self.nodes[next].backward = backward,
+            None => self.tail = backward,
+        }
+        while self.level > 1 && self.forward(HEAD, self.level - 1).is_none() {
+            self.level -= 1;
+        }
+        self.len -= 1;
+        // release the member's memory right away
+        self.nodes[node].member = String::new();
+        self.nodes[node].levels = Vec::new();
+        self.free.push(node);
+    }
+
+    /// Returns the 0-based rank of a member with the given score.
+    pub fn rank(&self, score: f64, member: &str) -> Option<usize> {
+        let mut rank = 0;
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.forward(x, i) {
+                if self.nodes[next].cmp(score, member) == Ordering::Greater {
+                    break;
+                }
+                rank += self.nodes[x].levels[i].span;
+                x = next;
+            }
+            if x != HEAD && self.nodes[x].member == member {
+                return Some(rank - 1);
+            }
+        }
+        None
+    }
+
+    /// Returns the node with the given 0-based rank.
+    pub fn by_rank(&self, rank: usize) -> Option<usize> {
+        let rank = rank + 1;
+        let mut traversed = 0;
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.forward(x, i) {
+                if traversed + self.nodes[x].levels[i].span > rank {
+                    break;
+                }
+                traversed += self.nodes[x].levels[i].span;
+                x = next;
+            }
+            if traversed == rank {
+                return Some(x);
+            }
+        }
+        None
+    }
+
+    /// Returns the first node which satisfies `after_start`, assuming that
+    /// all nodes which do are ordered after all nodes which do not.
+    fn first_where(&self, after_start: impl Fn(&Node) -> bool) -> Option<usize> {
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.forward(x, i) {
+                if after_start(&self.nodes[next]) {
+                    break;
+                }
+                x = next;
+            }
+        }
+        self.forward(x, 0)
+    }
+
+    /// Returns the last node which satisfies `before_end`, assuming that
+    /// all nodes which do are ordered before all nodes which do not.
+    fn last_where(&self, before_end: impl Fn(&Node) -> bool) -> Option<usize> {
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.forward(x, i) {
+                if !before_end(&self.nodes[next]) {
+                    break;
+                }
+                x = next;
+            }
+        }
+        (x != HEAD).then_some(x)
+    }
+
+    /// Returns the first node with a score in `range`.
+    pub fn first_in_score_range(&self, range: &ScoreRange) -> Option<usize> {
+        if range.is_empty() {
+            return None;
+        }
+        let node = self.first_where(|node| range.gte_min(node.score))?;
+        range.lte_max(self.nodes[node].score).then_some(node)
+    }
+
+    /// Returns the last node with a score in `range`.
+    pub fn last_in_score_range(&self, range: &ScoreRange) -> Option<usize> {
+        if range.is_empty() {
+            return None;
+        }
+        let node = self.last_where(|node| range.lte_max(node.score))?;
+        range.gte_min(self.nodes[node].score).then_some(node)
+    }
+
+    /// Returns the first node with a member in `range`. This assumes that
+    /// all members have the same score.
+    pub fn first_in_lex_range(&self, range: &LexRange) -> Option<usize> {
+        if range.is_empty() {
+            return None;
+        }
+        let node = self.first_where(|node| range.gte_min(&node.member))?;
+        range.lte_max(&self.nodes[node].member).then_some(node)
+    }
+
+    /// Returns the last node with a member in `range`. This assumes that
+    /// all members have the same score.
+    pub fn last_in_lex_range(&self, range: &LexRange) -> Option<usize> {
+        if range.is_empty() {
+            return None;
+        }
+        let node = self.last_where(|node| range.lte_max(&node.member))?;
+        range.gte_min(&self.nodes[node].member).then_some(node)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn collect(list: &SkipList) -> Vec<(String, f64)> {
+        let mut res = Vec::new();
+        let mut node = list.first();
+        while let Some(n) = node {
+            let (member, score) = list.entry(n);
+            res.push((member.to_string(), score));
+            node = list.next(n);
+        }
+        res
+    }
+
+    fn sample() -> SkipList {
+        let mut list = SkipList::new();
+        for (i, member) in ["e", "b", "d", "a", "c"].iter().enumerate() {
+            list.insert(i as f64, member.to_string());
+        }
+        list
+    }
+
+    #[test]
+    pub fn test_insert_ordered() {
+        let list = sample();
+        let members: Vec<_> = collect(&list).into_iter().map(|(m, _)| m).collect();
+        assert_eq!(members, vec!["e", "b", "d", "a", "c"]);
+        assert_eq!(list.len(), 5);
+        assert_eq!(list.entry(list.last().unwrap()), ("c", 4.0));
+    }
+
+    #[test]
+    pub fn test_rank() {
+        let mut list = SkipList::new();
+        for i in 0..1000 {
+            list.insert((i / 2) as f64, format!("{:04}", i));
+        }
+        for i in 0..1000 {
+            let member = format!("{:04}", i);
+            assert_eq!(list.rank((i / 2) as f64, &member), Some(i));
+            let node = list.by_rank(i).unwrap();
+            assert_eq!(list.entry(node), (member.as_str(), (i / 2) as f64));
+        }
+        assert_eq!(list.rank(0.0, "nope"), None);
+        assert_eq!(list.by_rank(1000), None);
+    }
+
+    #[test]
+    pub fn test_remove() {
+        let mut list = sample();
+        assert!(list.remove(2.0, "d"));
+        assert!(!list.remove(2.0, "d"));
+        assert!(!list.remove(0.0, "a"));
+        assert!(list.remove(4.0, "c"));
+        assert_eq!(list.len(), 3);
+        assert_eq!(list.rank(3.0, "a"), Some(2));
+        assert_eq!(list.entry(list.last().unwrap()), ("a", 3.0));
+        assert_eq!(list.prev(list.last().unwrap()), list.by_rank(1));
+        // freed nodes are reused
+        list.insert(10.0, "z".to_string());
+        assert_eq!(list.nodes.len(), 6);
+        assert_eq!(list.rank(10.0, "z"), Some(3));
+    }
+
+    #[test]
+    pub fn test_score_range() {
+        let list = sample();
+        let range = ScoreRange {
+            min: 1.0,
+            max: 3.0,
+            min_exclusive: true,
+            max_exclusive: false,
+        };
+        assert_eq!(
+            list.entry(list.first_in_score_range(&range).unwrap()).0,
+            "d"
+        );
+        assert_eq!(list.entry(list.last_in_score_range(&range).unwrap()).0, "a");
+        let empty = ScoreRange {
+            min: 4.5,
+            max: 10.0,
+            min_exclusive: false,
+            max_exclusive: false,
+        };
+        assert_eq!(list.first_in_score_range(&empty), None);
+        assert_eq!(list.last_in_score_range(&empty), None);
+    }
+
+    #[test]
+    pub fn test_lex_range() {
+        let mut list = SkipList::new();
+        for member in ["a", "b", "c", "d"] {
+            list.insert(0.0, member.to_string());
+        }
+        let range = LexRange {
+            min: LexBound::Exclusive("a".to_string()),
+            max: LexBound::Inclusive("c".to_string()),
+        };
+        assert_eq!(list.entry(list.first_in_lex_range(&range).unwrap()).0, "b");
+        assert_eq!(list.entry(list.last_in_lex_range(&range).unwrap()).0, "c");
+        let all = LexRange {
+            min: LexBound::NegInf,
+            max: LexBound::PosInf,
+        };
+        assert_eq!(list.entry(list.last_in_lex_range(&all).unwrap()).0, "d");
+    }
+}
@@ -0,0 +1,267 @@
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+
+use feredis_core::item::RedisItem;
+
+use crate::value::{parse_float, parse_int, Value};
+use crate::{RedisError, State};
+
+/// A parsed `SORT` or `SORT_RO` command.
+#[derive(Debug)]
+struct SortSpec {
+    by: Option<String>,
+    get: Vec<String>,
+    /// offset and count
+    limit: Option<(i64, i64)>,
+    desc: bool,
+    alpha: bool,
+    store: Option<String>,
+}
+
+impl SortSpec {
+    fn parse(mut args: VecDeque<RedisItem>, read_only: bool) -> Result<Self, RedisError> {
+        let mut next = || match args.pop_front() {
+            Some(RedisItem::BulkString(arg)) => Ok(Some(arg)),
+            Some(_) => Err(RedisError::InvalidArguments),
+            None => Ok(None),
+        };
+        let mut spec = SortSpec {
+            by: None,
+            get: Vec::new(),
+            limit: None,
+            desc: false,
+            alpha: false,
+            store: None,
+        };
+        while let Some(mut arg) = next()? {
+            arg.make_ascii_lowercase();
+            match arg.as_str() {
+                "asc" => spec.desc = false,
+                "desc" => spec.desc = true,
+                "alpha" => spec.alpha = true,
+                "by" => spec.by = Some(next()?.ok_or(RedisError::Syntax)?),
+                "get" => spec.get.push(next()?.ok_or(RedisError::Syntax)?),
+                "limit" => {
+                    let (Some(offset), Some(count)) = (next()?, next()?) else {
+                        return Err(RedisError::Syntax);
+                    };
+                    let (Some(offset), Some(count)) = (parse_int(&offset), parse_int(&count))
+                    else {
+                        return Err(RedisError::NotInteger);
+                    };
+                    spec.limit = Some((offset, count));
+                }
+                "store" if !read_only => spec.store = Some(next()?.ok_or(RedisError::Syntax)?),
+                _ => return Err(RedisError::Syntax),
+            }
+        }
+        Ok(spec)
+    }
+
+    /// Whether the elements are left in their original order, which is
+    /// requested with a `BY` pattern which does not refer to the elements.
+    fn no_sort(&self) -> bool {
+        self.by.as_ref().is_some_and(|by| !by.contains('*'))
+    }
+}
+
+/// Looks up the value a `BY` or `GET` pattern refers to for `element`.
+///
+/// The first `*` of the pattern is replaced with the element, giving the
+/// key of a string. A pattern of the form `key_*->field` refers to a field
+/// of the hash at `key_<element>` instead. The pattern `#` refers to the
+/// element itself.
+fn lookup(state: &State, pattern: &str, element: &[u8]) -> Option<Vec<u8>> {
+    if pattern == "#" {
+        return Some(element.to_vec());
+    }
+    let star = pattern.find('*')?;
+    let (key_pattern, field) = match pattern[star + 1..].find("->") {
+        Some(arrow) if star + arrow + 3 < pattern.len() => (
+            &pattern[..star + 1 + arrow],
+            Some(&pattern[star + arrow + 3..]),
+        ),
+        _ => (pattern, None),
+    };
+    let key = key_pattern.replacen('*', &String::from_utf8_lossy(element), 1);
+    let (val, _) = state.items.get(&key)?;
+    match (val, field) {
+        (Value::Hash(hash), Some(field)) => hash.get(field).map(|val| val.as_bytes().to_vec()),
+        (_, Some(_)) => None,
+        (val, None) => val.to_bytes(),
+    }
+}
+
+/// An element and the value it is sorted by.
+struct Sortable {
+    element: Vec<u8>,
+    weight: Weight,
+}
+
+enum Weight {
+    None,
+    Number(f64),
+    Bytes(Option<Vec<u8>>),
+}
+
+impl Sortable {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let ord = match (&self.weight, &other.weight) {
+            (Weight::Number(a), Weight::Number(b)) => a.total_cmp(b),
+            // missing values are sorted first
+            (Weight::Bytes(a), Weight::Bytes(b)) => a.cmp(b),
+            _ => Ordering::Equal,
+        };
+        // elements with the same weight are ordered by themselves, so that
+        // the result is deterministic
+        ord.then_with(|| self.element.cmp(&other.element))
+    }
+}
+
+fn sort(mut args: VecDeque<RedisItem>, state: &RefCell<State>, read_only: bool) -> RedisItem {
+    use RedisItem::*;
+    let Some(BulkString(key)) = args.pop_front() else {
+        return RedisError::InvalidArguments.into();
+    };
+    let spec = match SortSpec::parse(args, read_only) {
+        Ok(spec) => spec,
+        Err(err) => return err.into(),
+    };
+    let mut state = state.borrow_mut();
+    let (mut no_sort, mut alpha, mut by) = (spec.no_sort(), spec.alpha, spec.by.as_deref());
+    let elements: Vec<Vec<u8>> = match state.items.get(&key) {
+        Some((Value::List(list), _)) => list.iter().cloned().collect(),
+        Some((Value::Set(set), _)) => {
+            // the order of a set is arbitrary, so a stored result is sorted
+            // by the members to keep it deterministic
+            if no_sort && spec.store.is_some() {
+                (no_sort, alpha, by) = (false, true, None);
+            }
+            set.members().into_iter().map(String::into_bytes).collect()
+        }
+        Some((Value::ZSet(zset), _)) => {
+            let mut members: Vec<Vec<u8>> = zset
+                .iter()
+                .map(|(member, _)| member.as_bytes().to_vec())
+                .collect();
+            // unsorted sorted sets are still returned in the requested order
+            if no_sort && spec.desc {
+                members.reverse();
+            }
+            members
+        }
+        Some(_) => return RedisError::WrongType.into(),
+        None => Vec::new(),
+    };
+
+    let mut sortables = Vec::with_capacity(elements.len());
+    for element in elements {
+        let weight = if no_sort {
+            Weight::None
+        } else {
+            let val = match by {
+                Some(by) => lookup(&state, by, &element),
+                None => Some(element.clone()),
+            };
+            if alpha {
+                Weight::Bytes(val)
+            } else {
+                // missing values count as zero
+                let val = val.map_or(Some(0.0), |val| {
+                    std::str::from_utf8(&val).ok().and_then(parse_float)
+                });
+                match val {
+                    Some(val) => Weight::Number(val),
+                    None => {
+                        return SimpleError(
+                            "ERR One or more scores can't be converted into double".to_string(),
+                        )
+                    }
+                }
+            }
+        };
+        sortables.push(Sortable { element, weight });
+    }
+    if !no_sort {
+        sortables.sort_by(|a, b| a.cmp(b));
+        if spec.desc {
+            sortables.reverse();
+        }
+    }
+
+    let (offset, count) = spec.limit.unwrap_or((0, -1));
+    let offset = (offset.max(0) as usize).min(sortables.len());
+    let count = if count < 0 {
+        sortables.len()
+    } else {
+        count as usize
+    };
+    let selected = sortables.into_iter().skip(offset).take(count);
+
+    let mut res: Vec<Option<Vec<u8>>> = Vec::new();
+    for sortable in selected {
+        if spec.get.is_empty() {
+            res.push(Some(sortable.element));
+            continue;
+        }
+        for pattern in &spec.get {
+            res.push(lookup(&state, pattern, &sortable.element));
+        }
+    }
+
+    match spec.store {
+        Some(dst) => {
+            let len = res.len();
+            if res.is_empty() {
+                state.items.remove(&dst);
+            } else {
+                // missing values are stored as empty strings
+                let list = res.into_iter().map(Option::unwrap_or_default).collect();
+                let tag = state.next_tag();
+                state.blocking.signal(&dst);
+                state.items.insert(dst, (Value::List(list), tag));
+            }
+            Integer(len as i64)
+        }
+        None => Array(
+            res.into_iter()
+                .map(|val| val.map_or(Null, RedisItem::bulk))
+                .collect(),
+        ),
+    }
+}
+
+pub fn do_sort(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    sort(args, state, false)
+}
+
+pub fn do_sort_ro(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    sort(args, state, true)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_lookup() {
+        let mut state = State::new(1);
+        state
+            .items
+            .insert("weight_a".to_string(), (Value::Integer(3), 0));
+        let hash = [("name".to_string(), "x".to_string())]
+            .into_iter()
+            .collect();
+        state
+            .items
+            .insert("obj_a".to_string(), (Value::Hash(hash), 1));
+        assert_eq!(lookup(&state, "#", b"a"), Some(b"a".to_vec()));
+        assert_eq!(lookup(&state, "weight_*", b"a"), Some(b"3".to_vec()));
+        assert_eq!(lookup(&state, "weight_*", b"b"), None);
+        assert_eq!(lookup(&state, "obj_*->name", b"a"), Some(b"x".to_vec()));
+        assert_eq!(lookup(&state, "obj_*->other", b"a"), None);
+        // a field lookup on a string finds nothing
+        assert_eq!(lookup(&state, "weight_*->name", b"a"), None);
+    }
+}
@@ -0,0 +1,2003 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::ops::Bound;
+use std::string::FromUtf8Error;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use feredis_core::item::RedisItem;
+use feredis_core::rdb;
+
+use crate::value::{parse_int, Value};
+use crate::{RedisError, State};
+
+const INVALID_ID: &str = "ERR Invalid stream ID specified as stream command argument";
+
+/// The id of a stream entry: a millisecond timestamp and a sequence number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    pub const MIN: StreamId = StreamId { ms: 0, seq: 0 };
+    pub const MAX: StreamId = StreamId {
+        ms: u64::MAX,
+        seq: u64::MAX,
+    };
+
+    /// Parses `ms-seq`, or just `ms` in which case the sequence number is
+    /// `default_seq`.
+    pub fn parse(val: &str, default_seq: u64) -> Option<Self> {
+        match val.split_once('-') {
+            Some((ms, seq)) => Some(StreamId {
+                ms: ms.parse().ok()?,
+                seq: seq.parse().ok()?,
+            }),
+            None => Some(StreamId {
+                ms: val.parse().ok()?,
+                seq: default_seq,
+            }),
+        }
+    }
+
+    fn next(self) -> Option<Self> {
+        if self.seq < u64::MAX {
+            Some(StreamId {
+                ms: self.ms,
+                seq: self.seq + 1,
+            })
+        } else if self.ms < u64::MAX {
+            Some(StreamId {
+                ms: self.ms + 1,
+                seq: 0,
+            })
+        } else {
+            None
+        }
+    }
+
+    fn prev(self) -> Option<Self> {
+        if self.seq > 0 {
+            Some(StreamId {
+                ms: self.ms,
+                seq: self.seq - 1,
+            })
+        } else if self.ms > 0 {
+            Some(StreamId {
+                ms: self.ms - 1,
+                seq: u64::MAX,
+            })
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+/// The field-value pairs of a stream entry.
+pub type Fields = Vec<(String, String)>;
+
+/// An entry which was delivered to a consumer but not acknowledged yet.
+#[derive(Debug, Clone, PartialEq)]
+struct PendingEntry {
+    consumer: String,
+    delivery_time: u64,
+    delivery_count: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Consumer {
+    seen_time: u64,
+    active_time: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct ConsumerGroup {
+    last_id: StreamId,
+    /// the number of entries read by the group, if it is known
+    entries_read: Option<u64>,
+    /// the pending entries list, shared by all consumers of the group
+    pending: BTreeMap<StreamId, PendingEntry>,
+    consumers: BTreeMap<String, Consumer>,
+}
+
+impl ConsumerGroup {
+    fn new(last_id: StreamId, entries_read: Option<u64>) -> Self {
+        Self {
+            last_id,
+            entries_read,
+            pending: BTreeMap::new(),
+            consumers: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the consumer called `name`, creating it if needed, and
+    /// records that it was seen.
+    fn consumer(&mut self, name: &str, now: u64) -> &mut Consumer {
+        let consumer = self.consumers.entry(name.to_string()).or_insert(Consumer {
+            seen_time: now,
+            active_time: None,
+        });
+        consumer.seen_time = now;
+        consumer
+    }
+
+    fn pending_count(&self, consumer: &str) -> usize {
+        self.pending
+            .values()
+            .filter(|entry| entry.consumer == consumer)
+            .count()
+    }
+}
+
+/// A stream of entries ordered by id, with its consumer groups.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Stream {
+    entries: BTreeMap<StreamId, Fields>,
+    last_id: StreamId,
+    max_deleted_id: StreamId,
+    entries_added: u64,
+    groups: BTreeMap<String, ConsumerGroup>,
+}
+
+/// How a stream is trimmed by `XADD` and `XTRIM`.
+#[derive(Debug, Clone, Copy)]
+enum TrimStrategy {
+    MaxLen(usize),
+    MinId(StreamId),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Trim {
+    strategy: TrimStrategy,
+    /// the maximum number of entries removed at once
+    limit: Option<usize>,
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_millis() as u64)
+}
+
+impl Stream {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn first_id(&self) -> StreamId {
+        self.entries
+            .first_key_value()
+            .map_or(StreamId::MIN, |(id, _)| *id)
+    }
+
+    /// Generates the id of a new entry, using the current time unless the
+    /// milliseconds are given explicitly.
+    fn next_id(&self, ms: Option<u64>) -> Result<StreamId, RedisError> {
+        let last = self.last_id;
+        match ms {
+            None if now_ms() > last.ms => Ok(StreamId {
+                ms: now_ms(),
+                seq: 0,
+            }),
+            None => last.next().ok_or(RedisError::Custom(
+                "ERR The stream has exhausted the last possible ID, unable to add more items",
+            )),
+            Some(ms) if ms > last.ms => Ok(StreamId { ms, seq: 0 }),
+            Some(ms) if ms == last.ms && last.seq < u64::MAX => Ok(StreamId {
+                ms,
+                seq: last.seq + 1,
+            }),
+            Some(_) => Err(RedisError::Custom(
+                "ERR The ID specified in XADD is equal or smaller than the target stream top item",
+            )),
+        }
+    }
+
+    fn add(&mut self, id: StreamId, fields: Fields) {
+        self.entries.insert(id, fields);
+        self.last_id = id;
+        self.entries_added += 1;
+    }
+
+    fn remove(&mut self, id: StreamId) -> bool {
+        if self.entries.remove(&id).is_none() {
+            return false;
+        }
+        self.max_deleted_id = self.max_deleted_id.max(id);
+        true
+    }
+
+    fn trim(&mut self, trim: Trim) -> usize {
+        let mut removed = 0;
+        while let Some((&id, _)) = self.entries.first_key_value() {
+            let done = match trim.strategy {
+                TrimStrategy::MaxLen(len) => self.entries.len() <= len,
+                TrimStrategy::MinId(min) => id >= min,
+            };
+            if done || trim.limit.is_some_and(|limit| removed >= limit) {
+                break;
+            }
+            self.entries.pop_first();
+            removed += 1;
+        }
+        removed
+    }
+
+    fn range(
+        &self,
+        start: StreamId,
+        end: StreamId,
+    ) -> impl DoubleEndedIterator<Item = (&StreamId, &Fields)> {
+        if start > end {
+            self.entries.range(StreamId::MIN..StreamId::MIN)
+        } else {
+            self.entries.range(start..=end)
+        }
+    }
+
+    /// Whether entries with an id of at least `id` have been deleted.
+    fn has_tombstones_after(&self, id: StreamId) -> bool {
+        self.max_deleted_id != StreamId::MIN && self.max_deleted_id >= id
+    }
+
+    /// Estimates the number of entries added up to and including `id`,
+    /// which is only possible if no entries were deleted in between.
+    fn estimate_entries_read(&self, id: StreamId) -> Option<u64> {
+        if self.entries_added == 0 {
+            return Some(0);
+        }
+        if self.entries.is_empty() || id >= self.last_id {
+            return Some(self.entries_added);
+        }
+        let first = self.first_id();
+        if (self.max_deleted_id == StreamId::MIN || self.max_deleted_id < first) && id < first {
+            return Some(self.entries_added - self.entries.len() as u64);
+        }
+        None
+    }
+
+    /// The number of entries which have not been delivered to a group yet.
+    fn lag(&self, group: &ConsumerGroup) -> Option<u64> {
+        if self.entries_added == 0 {
+            return Some(0);
+        }
+        match group.entries_read {
+            Some(read)
+                if !self.has_tombstones_after(group.last_id) && group.last_id <= self.last_id =>
+            {
+                Some(self.entries_added - read)
+            }
+            _ => self
+                .estimate_entries_read(group.last_id)
+                .map(|read| self.entries_added - read),
+        }
+    }
+
+    /// Delivers up to `count` new entries to `consumer` of `group`, adding
+    /// them to the pending entries list unless `no_ack` is set.
+    fn deliver_new(
+        &mut self,
+        group: &str,
+        consumer: &str,
+        count: Option<usize>,
+        no_ack: bool,
+    ) -> Vec<(StreamId, Fields)> {
+        let now = now_ms();
+        let cg = &self.groups[group];
+        let delivered: Vec<(StreamId, Fields)> = self
+            .entries
+            .range((Bound::Excluded(cg.last_id), Bound::Unbounded))
+            .take(count.unwrap_or(usize::MAX))
+            .map(|(id, fields)| (*id, fields.clone()))
+            .collect();
+        let mut entries_read = cg.entries_read;
+        for (id, _) in &delivered {
+            entries_read = match entries_read {
+                Some(read) if !self.has_tombstones_after(*id) => Some(read + 1),
+                _ => self.estimate_entries_read(*id),
+            };
+        }
+        let cg = self.groups.get_mut(group).unwrap();
+        if let Some((id, _)) = delivered.last() {
+            cg.last_id = *id;
+            cg.entries_read = entries_read;
+            cg.consumer(consumer, now).active_time = Some(now);
+        }
+        if !no_ack {
+            for (id, _) in &delivered {
+                cg.pending.insert(
+                    *id,
+                    PendingEntry {
+                        consumer: consumer.to_string(),
+                        delivery_time: now,
+                        delivery_count: 1,
+                    },
+                );
+            }
+        }
+        delivered
+    }
+
+    /// Delivers the entries pending for `consumer` with an id greater than
+    /// `after` again. Entries which were deleted are returned as `None`.
+    fn deliver_pending(
+        &mut self,
+        group: &str,
+        consumer: &str,
+        after: StreamId,
+        count: Option<usize>,
+    ) -> Vec<(StreamId, Option<Fields>)> {
+        let now = now_ms();
+        let Stream {
+            entries, groups, ..
+        } = self;
+        let cg = groups.get_mut(group).unwrap();
+        let mut delivered = Vec::new();
+        for (id, pending) in cg
+            .pending
+            .range_mut((Bound::Excluded(after), Bound::Unbounded))
+            .filter(|(_, pending)| pending.consumer == consumer)
+            .take(count.unwrap_or(usize::MAX))
+        {
+            let fields = entries.get(id).cloned();
+            if fields.is_some() {
+                pending.delivery_time = now;
+                pending.delivery_count += 1;
+            }
+            delivered.push((*id, fields));
+        }
+        delivered
+    }
+
+    /// Converts the stream into its representation in snapshots.
+    pub fn to_rdb(&self) -> rdb::Stream {
+        let id = |id: StreamId| rdb::StreamId {
+            ms: id.ms,
+            seq: id.seq,
+        };
+        let bytes = |val: &String| val.as_bytes().to_vec();
+        rdb::Stream {
+            entries: self
+                .entries
+                .iter()
+                .map(|(entry_id, fields)| {
+                    let fields = fields
+                        .iter()
+                        .map(|(field, val)| (bytes(field), bytes(val)))
+                        .collect();
+                    (id(*entry_id), fields)
+                })
+                .collect(),
+            last_id: id(self.last_id),
+            max_deleted_id: id(self.max_deleted_id),
+            entries_added: self.entries_added,
+            groups: self
+                .groups
+                .iter()
+                .map(|(name, group)| rdb::StreamGroup {
+                    name: bytes(name),
+                    last_id: id(group.last_id),
+                    entries_read: group.entries_read,
+                    pending: group
+                        .pending
+                        .iter()
+                        .map(|(entry_id, pending)| rdb::StreamPendingEntry {
+                            id: id(*entry_id),
+                            consumer: bytes(&pending.consumer),
+                            delivery_time: pending.delivery_time,
+                            delivery_count: pending.delivery_count,
+                        })
+                        .collect(),
+                    consumers: group
+                        .consumers
+                        .iter()
+                        .map(|(name, consumer)| rdb::StreamConsumer {
+                            name: bytes(name),
+                            seen_time: consumer.seen_time,
+                            active_time: consumer.active_time,
+                        })
+                        .collect(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Restores a stream from a snapshot. Fails if it holds data which is
+    /// not valid UTF-8.
+    pub fn from_rdb(stream: rdb::Stream) -> Result<Self, FromUtf8Error> {
+        let id = |id: rdb::StreamId| StreamId {
+            ms: id.ms,
+            seq: id.seq,
+        };
+        let string = String::from_utf8;
+        Ok(Stream {
+            entries: stream
+                .entries
+                .into_iter()
+                .map(|(entry_id, fields)| {
+                    let fields = fields
+                        .into_iter()
+                        .map(|(field, val)| Ok((string(field)?, string(val)?)))
+                        .collect::<Result<_, _>>()?;
+                    Ok((id(entry_id), fields))
+                })
+                .collect::<Result<_, _>>()?,
+            last_id: id(stream.last_id),
+            max_deleted_id: id(stream.max_deleted_id),
+            entries_added: stream.entries_added,
+            groups: stream
+                .groups
+                .into_iter()
+                .map(|group| {
+                    let cg = ConsumerGroup {
+                        last_id: id(group.last_id),
+                        entries_read: group.entries_read,
+                        pending: group
+                            .pending
+                            .into_iter()
+                            .map(|pending| {
+                                let entry = PendingEntry {
+                                    consumer: string(pending.consumer)?,
+                                    delivery_time: pending.delivery_time,
+                                    delivery_count: pending.delivery_count,
+                                };
+                                Ok((id(pending.id), entry))
+                            })
+                            .collect::<Result<_, _>>()?,
+                        consumers: group
+                            .consumers
+                            .into_iter()
+                            .map(|consumer| {
+                                let state = Consumer {
+                                    seen_time: consumer.seen_time,
+                                    active_time: consumer.active_time,
+                                };
+                                Ok((string(consumer.name)?, state))
+                            })
+                            .collect::<Result<_, _>>()?,
+                    };
+                    Ok((string(group.name)?, cg))
+                })
+                .collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+/// Returns the stream stored at `key`, or `None` if the key does not exist.
+pub fn get_stream<'a>(state: &'a State, key: &str) -> Result<Option<&'a Stream>, RedisError> {
+    match state.items.get(key) {
+        Some((Value::Stream(stream), _)) => Ok(Some(stream)),
+        Some(_) => Err(RedisError::WrongType),
+        None => Ok(None),
+    }
+}
+
+fn get_stream_mut<'a>(
+    state: &'a mut State,
+    key: &str,
+) -> Result<Option<&'a mut Stream>, RedisError> {
+    match state.items.get_mut(key) {
+        Some((Value::Stream(stream), _)) => Ok(Some(stream)),
+        Some(_) => Err(RedisError::WrongType),
+        None => Ok(None),
+    }
+}
+
+/// Returns the stream at `key` if it has a consumer group called `group`.
+fn get_group_stream<'a>(
+    state: &'a mut State,
+    key: &str,
+    group: &str,
+) -> Result<&'a mut Stream, RedisError> {
+    match get_stream_mut(state, key)? {
+        Some(stream) if stream.groups.contains_key(group) => Ok(stream),
+        _ => Err(RedisError::NoGroup {
+            key: key.to_string(),
+            group: group.to_string(),
+        }),
+    }
+}
+
+fn parse_id(val: &str, default_seq: u64) -> Result<StreamId, RedisError> {
+    StreamId::parse(val, default_seq).ok_or(RedisError::Custom(INVALID_ID))
+}
+
+/// Parses one end of a range, which may be `-`, `+` or an id prefixed with
+/// `(` to make it exclusive.
+fn parse_range_bound(val: &str, start: bool) -> Result<StreamId, RedisError> {
+    match val {
+        "-" => Ok(StreamId::MIN),
+        "+" => Ok(StreamId::MAX),
+        _ => {
+            let default_seq = if start { 0 } else { u64::MAX };
+            match val.strip_prefix('(') {
+                Some(id) if start => parse_id(id, default_seq)?
+                    .next()
+                    .ok_or(RedisError::Custom("ERR invalid start ID for the interval")),
+                Some(id) => parse_id(id, default_seq)?
+                    .prev()
+                    .ok_or(RedisError::Custom("ERR invalid end ID for the interval")),
+                None => parse_id(val, default_seq),
+            }
+        }
+    }
+}
+
+fn next_arg(args: &mut VecDeque<RedisItem>) -> Result<String, RedisError> {
+    match args.pop_front() {
+        Some(RedisItem::BulkString(arg)) => Ok(arg),
+        _ => Err(RedisError::InvalidArguments),
+    }
+}
+
+fn parse_count(arg: &str) -> Result<usize, RedisError> {
+    let count = parse_int(arg).ok_or(RedisError::NotInteger)?;
+    Ok(count.max(0) as usize)
+}
+
+fn fields_reply(fields: &Fields) -> RedisItem {
+    RedisItem::Array(
+        fields
+            .iter()
+            .flat_map(|(field, value)| {
+                [
+                    RedisItem::BulkString(field.clone()),
+                    RedisItem::BulkString(value.clone()),
+                ]
+            })
+            .collect(),
+    )
+}
+
+fn entry_reply(id: StreamId, fields: Option<&Fields>) -> RedisItem {
+    RedisItem::Array(vec![
+        RedisItem::BulkString(id.to_string()),
+        fields.map_or(RedisItem::Null, fields_reply),
+    ])
+}
+
+fn entries_reply<'a>(entries: impl Iterator<Item = (&'a StreamId, &'a Fields)>) -> RedisItem {
+    RedisItem::Array(
+        entries
+            .map(|(id, fields)| entry_reply(*id, Some(fields)))
+            .collect(),
+    )
+}
+
+/// Parses `MAXLEN|MINID [=|~] threshold [LIMIT count]`, where the strategy
+/// has already been consumed.
+fn parse_trim(args: &mut VecDeque<RedisItem>, strategy: &str) -> Result<Trim, RedisError> {
+    let mut threshold = next_arg(args).map_err(|_| RedisError::Syntax)?;
+    let mut approximate = false;
+    if threshold == "=" || threshold == "~" {
+        approximate = threshold == "~";
+        threshold = next_arg(args).map_err(|_| RedisError::Syntax)?;
+    }
+    let strategy = if strategy == "maxlen" {
+        let len = parse_int(&threshold).ok_or(RedisError::NotInteger)?;
+        if len < 0 {
+            return Err(RedisError::Custom("ERR The MAXLEN argument must be >= 0."));
+        }
+        TrimStrategy::MaxLen(len as usize)
+    } else {
+        TrimStrategy::MinId(parse_id(&threshold, 0)?)
+    };
+    let mut limit = None;
+    if matches!(args.front(), Some(RedisItem::BulkString(arg)) if arg.eq_ignore_ascii_case("limit"))
+    {
+        args.pop_front();
+        let count = parse_int(&next_arg(args)?).ok_or(RedisError::NotInteger)?;
+        if count < 0 {
+            return Err(RedisError::Custom("ERR The LIMIT argument must be >= 0."));
+        }
+        if !approximate {
+            return Err(RedisError::Custom(
+                "ERR syntax error, LIMIT cannot be used without the special ~ option",
+            ));
+        }
+        // a limit of zero means unlimited
+        limit = (count > 0).then_some(count as usize);
+    }
+    Ok(Trim { strategy, limit })
+}
+
+pub fn do_xadd(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let Some(BulkString(key)) = args.pop_front() else {
+        return RedisError::InvalidArguments.into();
+    };
+    let mut no_mkstream = false;
+    let mut trim = None;
+    let id = loop {
+        let Some(BulkString(arg)) = args.pop_front() else {
+            return RedisError::InvalidArguments.into();
+        };
+        match arg.to_ascii_lowercase().as_str() {
+            "nomkstream" => no_mkstream = true,
+            strategy @ ("maxlen" | "minid") => match parse_trim(&mut args, strategy) {
+                Ok(parsed) => trim = Some(parsed),
+                Err(err) => return err.into(),
+            },
+            _ => break arg,
+        }
+    };
+    if args.is_empty() || !args.len().is_multiple_of(2) {
+        return SimpleError("ERR wrong number of arguments for 'xadd' command".to_string());
+    }
+    // every pair is checked before the entry is added
+    let mut fields = Vec::with_capacity(args.len() / 2);
+    while let Some(field) = args.pop_front() {
+        let (BulkString(field), Some(BulkString(value))) = (field, args.pop_front()) else {
+            return RedisError::InvalidArguments.into();
+        };
+        fields.push((field, value));
+    }
+    // `None` requests an automatically generated id or sequence number
+    let (ms, explicit) = if id == "*" {
+        (None, None)
+    } else if let Some(ms) = id.strip_suffix("-*") {
+        match ms.parse::<u64>() {
+            Ok(ms) => (Some(ms), None),
+            Err(_) => return RedisError::Custom(INVALID_ID).into(),
+        }
+    } else {
+        match parse_id(&id, 0) {
+            Ok(StreamId::MIN) => {
+                return SimpleError(
+                    "ERR The ID specified in XADD must be greater than 0-0".to_string(),
+                )
+            }
+            Ok(id) => (None, Some(id)),
+            Err(err) => return err.into(),
+        }
+    };
+
+    let mut state = state.borrow_mut();
+    let state = &mut *state;
+    let empty = Stream::new();
+    let stream = match get_stream(state, &key) {
+        Ok(Some(stream)) => stream,
+        Ok(None) if no_mkstream => return Null,
+        Ok(None) => &empty,
+        Err(err) => return err.into(),
+    };
+    let id =
+        match explicit {
+            Some(id) if id <= stream.last_id => return SimpleError(
+                "ERR The ID specified in XADD is equal or smaller than the target stream top item"
+                    .to_string(),
+            ),
+            Some(id) => id,
+            None => match stream.next_id(ms) {
+                Ok(id) => id,
+                Err(err) => return err.into(),
+            },
+        };
+    if !state.items.contains_key(&key) {
+        let tag = state.next_tag();
+        state
+            .items
+            .insert(key.clone(), (Value::Stream(Stream::new()), tag));
+    }
+    let Ok(Some(stream)) = get_stream_mut(state, &key) else {
+        unreachable!();
+    };
+    stream.add(id, fields);
+    if let Some(trim) = trim {
+        stream.trim(trim);
+    }
+    state.blocking.signal(&key);
+    BulkString(id.to_string())
+}
+
+pub fn do_xtrim(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let (Some(BulkString(key)), Some(BulkString(strategy))) = (args.pop_front(), args.pop_front())
+    else {
+        return RedisError::InvalidArguments.into();
+    };
+    let strategy = strategy.to_ascii_lowercase();
+    if strategy != "maxlen" && strategy != "minid" {
+        return RedisError::Syntax.into();
+    }
+    let trim = match parse_trim(&mut args, &strategy) {
+        Ok(trim) if args.is_empty() => trim,
+        Ok(_) => return RedisError::Syntax.into(),
+        Err(err) => return err.into(),
+    };
+    match get_stream_mut(&mut state.borrow_mut(), &key) {
+        Ok(stream) => Integer(stream.map_or(0, |stream| stream.trim(trim) as i64)),
+        Err(err) => err.into(),
+    }
+}
+
+pub fn do_xlen(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let Some(BulkString(key)) = args.pop_front() else {
+        return RedisError::InvalidArguments.into();
+    };
+    match get_stream(&state.borrow(), &key) {
+        Ok(stream) => Integer(stream.map_or(0, |stream| stream.len() as i64)),
+        Err(err) => err.into(),
+    }
+}
+
+pub fn do_xdel(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let Some(BulkString(key)) = args.pop_front() else {
+        return RedisError::InvalidArguments.into();
+    };
+    let mut ids = Vec::with_capacity(args.len());
+    for arg in args {
+        let BulkString(id) = arg else {
+            return RedisError::InvalidArguments.into();
+        };
+        match parse_id(&id, 0) {
+            Ok(id) => ids.push(id),
+            Err(err) => return err.into(),
+        }
+    }
+    if ids.is_empty() {
+        return RedisError::InvalidArguments.into();
+    }
+    match get_stream_mut(&mut state.borrow_mut(), &key) {
+        Ok(Some(stream)) => Integer(ids.into_iter().filter(|id| stream.remove(*id)).count() as i64),
+        Ok(None) => Integer(0),
+        Err(err) => err.into(),
+    }
+}
+
+pub fn do_xsetid(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let (Some(BulkString(key)), Some(BulkString(id))) = (args.pop_front(), args.pop_front()) else {
+        return RedisError::InvalidArguments.into();
+    };
+    let id = match parse_id(&id, 0) {
+        Ok(id) => id,
+        Err(err) => return err.into(),
+    };
+    let mut entries_added = None;
+    let mut max_deleted_id = None;
+    while let Some(arg) = args.pop_front() {
+        let (BulkString(mut arg), Some(BulkString(value))) = (arg, args.pop_front()) else {
+            return RedisError::Syntax.into();
+        };
+        arg.make_ascii_lowercase();
+        match arg.as_str() {
+            "entriesadded" => match parse_int(&value) {
+                Some(count) if count >= 0 => entries_added = Some(count as u64),
+                Some(_) => return SimpleError("ERR entries_added must be positive".to_string()),
+                None => return RedisError::NotInteger.into(),
+            },
+            "maxdeletedid" => match parse_id(&value, 0) {
+                Ok(max_id) if max_id > id => {
+                    return SimpleError(
+                        "ERR The ID specified in XSETID is smaller than the provided max_deleted_entry_id"
+                            .to_string(),
+                    )
+                }
+                Ok(max_id) => max_deleted_id = Some(max_id),
+                Err(err) => return err.into(),
+            },
+            _ => return RedisError::Syntax.into(),
+        }
+    }
+    let mut state = state.borrow_mut();
+    let stream = match get_stream_mut(&mut state, &key) {
+        Ok(Some(stream)) => stream,
+        Ok(None) => return RedisError::NoSuchKey.into(),
+        Err(err) => return err.into(),
+    };
+    if stream
+        .entries
+        .last_key_value()
+        .is_some_and(|(top, _)| *top > id)
+    {
+        return SimpleError(
+            "ERR The ID specified in XSETID is smaller than the target stream top item".to_string(),
+        );
+    }
+    if entries_added.is_some_and(|count| count < stream.len() as u64) {
+        return SimpleError(
+            "ERR The entries_added specified in XSETID is smaller than the target stream length"
+                .to_string(),
+        );
+    }
+    stream.last_id = id;
+    if let Some(count) = entries_added {
+        stream.entries_added = count;
+    }
+    if let Some(max_id) = max_deleted_id {
+        stream.max_deleted_id = max_id;
+    }
+    SimpleString("OK".to_string())
+}
+
+fn range(mut args: VecDeque<RedisItem>, state: &RefCell<State>, rev: bool) -> RedisItem {
+    use RedisItem::*;
+    let (Some(BulkString(key)), Some(BulkString(first)), Some(BulkString(second))) =
+        (args.pop_front(), args.pop_front(), args.pop_front())
+    else {
+        return RedisError::InvalidArguments.into();
+    };
+    let (start, end) = if rev {
+        (second, first)
+    } else {
+        (first, second)
+    };
+    let (start, end) = match (
+        parse_range_bound(&start, true),
+        parse_range_bound(&end, false),
+    ) {
+        (Ok(start), Ok(end)) => (start, end),
+        (Err(err), _) | (_, Err(err)) => return err.into(),
+    };
+    let count = match (args.pop_front(), args.pop_front()) {
+        (None, None) => usize::MAX,
+        (Some(BulkString(arg)), Some(BulkString(count)))
+            if arg.eq_ignore_ascii_case("count") && args.is_empty() =>
+        {
+            match parse_count(&count) {
+                Ok(count) => count,
+                Err(err) => return err.into(),
+            }
+        }
+        _ => return RedisError::Syntax.into(),
+    };
+    let state = state.borrow();
+    let stream = match get_stream(&state, &key) {
+        Ok(Some(stream)) => stream,
+        Ok(None) => return Array(Vec::new()),
+        Err(err) => return err.into(),
+    };
+    let entries = stream.range(start, end);
+    if rev {
+        entries_reply(entries.rev().take(count))
+    } else {
+        entries_reply(entries.take(count))
+    }
+}
+
+pub fn do_xrange(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    range(args, state, false)
+}
+
+pub fn do_xrevrange(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    range(args, state, true)
+}
+
+/// The position a stream is read from by `XREAD` and `XREADGROUP`.
+#[derive(Debug, Clone, Copy)]
+enum ReadId {
+    /// entries with a greater id
+    After(StreamId),
+    /// entries never delivered to the consumer group, written as `>`
+    Undelivered,
+}
+
+#[derive(Debug, Clone)]
+struct GroupRead {
+    group: String,
+    consumer: String,
+    no_ack: bool,
+}
+
+/// A parsed `XREAD` or `XREADGROUP`, which may be blocked on.
+#[derive(Debug, Clone)]
+pub struct ReadOp {
+    group: Option<GroupRead>,
+    keys: Vec<String>,
+    ids: Vec<ReadId>,
+    count: Option<usize>,
+}
+
+/// Parses `XREAD` or `XREADGROUP`. Also returns the timeout if the command
+/// blocks, where `Some(None)` means blocking forever.
+///
+/// `$` is resolved to the last id of the stream right away, so that a blocked
+/// client only receives entries added after it blocked.
+pub fn parse_read(
+    mut args: VecDeque<RedisItem>,
+    state: &mut State,
+    grouped: bool,
+) -> Result<(ReadOp, Option<Option<Duration>>), RedisError> {
+    let mut group = None;
+    let mut count = None;
+    let mut block = None;
+    let mut no_ack = false;
+    loop {
+        let mut arg = next_arg(&mut args)?;
+        arg.make_ascii_lowercase();
+        match arg.as_str() {
+            "count" => count = Some(parse_count(&next_arg(&mut args)?)?).filter(|&count| count > 0),
+            "block" => {
+                let timeout = parse_int(&next_arg(&mut args)?).ok_or(RedisError::Custom(
+                    "ERR timeout is not an integer or out of range",
+                ))?;
+                if timeout < 0 {
+                    return Err(RedisError::Custom("ERR timeout is negative"));
+                }
+                block = Some((timeout > 0).then(|| Duration::from_millis(timeout as u64)));
+            }
+            "group" if grouped => {
+                let name = next_arg(&mut args)?;
+                let consumer = next_arg(&mut args)?;
+                group = Some((name, consumer));
+            }
+            "noack" if grouped => no_ack = true,
+            "streams" => break,
+            _ => return Err(RedisError::Syntax),
+        }
+    }
+    if grouped && group.is_none() {
+        return Err(RedisError::Custom(
+            "ERR Missing GROUP option for XREADGROUP",
+        ));
+    }
+    if args.is_empty() || !args.len().is_multiple_of(2) {
+        return Err(RedisError::Custom(
+            "ERR Unbalanced 'xread' list of streams: for each stream key an ID or '$' must be specified.",
+        ));
+    }
+    let mut keys = Vec::with_capacity(args.len() / 2);
+    for _ in 0..args.len() / 2 {
+        keys.push(next_arg(&mut args)?);
+    }
+    let now = now_ms();
+    let mut ids = Vec::with_capacity(keys.len());
+    for key in &keys {
+        let id = next_arg(&mut args)?;
+        if let Some((name, consumer)) = &group {
+            let stream = get_group_stream(state, key, name)?;
+            stream.groups.get_mut(name).unwrap().consumer(consumer, now);
+            ids.push(match id.as_str() {
+                ">" => ReadId::Undelivered,
+                "$" => {
+                    return Err(RedisError::Custom(
+                        "ERR The $ ID is meaningless in the context of XREADGROUP: you want to read the history of this consumer by specifying a proper ID, or use the > ID to get new messages. The $ ID would just return an empty result set.",
+                    ))
+                }
+                _ => ReadId::After(parse_id(&id, 0)?),
+            });
+        } else {
+            ids.push(match id.as_str() {
+                ">" => {
+                    return Err(RedisError::Custom(
+                        "ERR The > ID can be specified only when calling XREADGROUP using the GROUP <group> <consumer> option.",
+                    ))
+                }
+                "$" => ReadId::After(
+                    get_stream(state, key)?.map_or(StreamId::MIN, |stream| stream.last_id),
+                ),
+                _ => ReadId::After(parse_id(&id, 0)?),
+            });
+        }
+    }
+    let group = group.map(|(group, consumer)| GroupRead {
+        group,
+        consumer,
+        no_ack,
+    });
+    // reading the history of a consumer never blocks
+    if ids.iter().any(|id| matches!(id, ReadId::After(_))) && group.is_some() {
+        block = None;
+    }
+    Ok((
+        ReadOp {
+            group,
+            keys,
+            ids,
+            count,
+        },
+        block,
+    ))
+}
+
+impl ReadOp {
+    pub fn keys(&self) -> &[String] {
+        &self.keys
+    }
+
+    /// Returns the arguments of an `XREADGROUP` which performs the read
+    /// without blocking, or `None` for an `XREAD`, which modifies nothing.
+    pub fn to_command(&self) -> Option<Vec<String>> {
+        let read = self.group.as_ref()?;
+        let mut args = vec![
+            "xreadgroup".to_string(),
+            "group".to_string(),
+            read.group.clone(),
+            read.consumer.clone(),
+        ];
+        if let Some(count) = self.count {
+            args.extend(["count".to_string(), count.to_string()]);
+        }
+        if read.no_ack {
+            args.push("noack".to_string());
+        }
+        args.push("streams".to_string());
+        args.extend(self.keys.iter().cloned());
+        args.extend(self.ids.iter().map(|id| match id {
+            ReadId::After(id) => id.to_string(),
+            ReadId::Undelivered => ">".to_string(),
+        }));
+        Some(args)
+    }
+
+    /// Reads from the streams, returning `None` if there is nothing to read.
+    /// A key holding another type is a `WrongType` error, on which a blocked
+    /// reader keeps waiting.
+    pub fn serve(&self, state: &mut State) -> Result<Option<RedisItem>, RedisError> {
+        use RedisItem::*;
+        let mut res = Vec::new();
+        for (key, id) in self.keys.iter().zip(&self.ids) {
+            let reply = match (&self.group, *id) {
+                (None, ReadId::After(id)) => {
+                    let Some(stream) = get_stream(state, key)? else {
+                        continue;
+                    };
+                    let entries: Vec<_> = stream
+                        .entries
+                        .range((Bound::Excluded(id), Bound::Unbounded))
+                        .take(self.count.unwrap_or(usize::MAX))
+                        .collect();
+                    if entries.is_empty() {
+                        continue;
+                    }
+                    entries_reply(entries.into_iter())
+                }
+                (Some(read), ReadId::Undelivered) => {
+                    let stream = get_group_stream(state, key, &read.group)?;
+                    let delivered =
+                        stream.deliver_new(&read.group, &read.consumer, self.count, read.no_ack);
+                    if delivered.is_empty() {
+                        continue;
+                    }
+                    Array(
+                        delivered
+                            .iter()
+                            .map(|(id, fields)| entry_reply(*id, Some(fields)))
+                            .collect(),
+                    )
+                }
+                (Some(read), ReadId::After(id)) => {
+                    let stream = get_group_stream(state, key, &read.group)?;
+                    let delivered =
+                        stream.deliver_pending(&read.group, &read.consumer, id, self.count);
+                    Array(
+                        delivered
+                            .iter()
+                            .map(|(id, fields)| entry_reply(*id, fields.as_ref()))
+                            .collect(),
+                    )
+                }
+                (None, ReadId::Undelivered) => unreachable!(),
+            };
+            res.push((BulkString(key.clone()), reply));
+        }
+        Ok((!res.is_empty()).then_some(Map(res)))
+    }
+}
+
+pub fn do_xgroup(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let (Some(BulkString(mut subcommand)), Some(BulkString(key)), Some(BulkString(group))) =
+        (args.pop_front(), args.pop_front(), args.pop_front())
+    else {
+        return RedisError::InvalidArguments.into();
+    };
+    subcommand.make_ascii_lowercase();
+    let mut state = state.borrow_mut();
+    let state = &mut *state;
+    match subcommand.as_str() {
+        "create" | "setid" => {
+            let Some(BulkString(id)) = args.pop_front() else {
+                return RedisError::InvalidArguments.into();
+            };
+            let mut mkstream = false;
+            let mut entries_read = None;
+            while let Some(BulkString(mut arg)) = args.pop_front() {
+                arg.make_ascii_lowercase();
+                match arg.as_str() {
+                    "mkstream" if subcommand == "create" => mkstream = true,
+                    "entriesread" => {
+                        let Some(BulkString(count)) = args.pop_front() else {
+                            return RedisError::Syntax.into();
+                        };
+                        match parse_int(&count) {
+                            Some(count) if count >= 0 => entries_read = Some(count as u64),
+                            Some(-1) => entries_read = None,
+                            _ => {
+                                return SimpleError(
+                                    "ERR value for ENTRIESREAD must be positive or -1".to_string(),
+                                )
+                            }
+                        }
+                    }
+                    _ => return RedisError::Syntax.into(),
+                }
+            }
+            let stream = match get_stream_mut(state, &key) {
+                Ok(Some(stream)) => stream,
+                Ok(None) if mkstream => {
+                    let tag = state.next_tag();
+                    state
+                        .items
+                        .insert(key.clone(), (Value::Stream(Stream::new()), tag));
+                    get_stream_mut(state, &key).unwrap().unwrap()
+                }
+                Ok(None) if subcommand == "create" => {
+                    return SimpleError("ERR The XGROUP subcommand requires the key to exist. Note that for CREATE you may want to use the MKSTREAM option to create an empty stream automatically.".to_string());
+                }
+                Ok(None) => return RedisError::NoGroup { key, group }.into(),
+                Err(err) => return err.into(),
+            };
+            let id = if id == "$" {
+                stream.last_id
+            } else {
+                match parse_id(&id, 0) {
+                    Ok(id) => id,
+                    Err(err) => return err.into(),
+                }
+            };
+            if subcommand == "create" {
+                if stream.groups.contains_key(&group) {
+                    return SimpleError("BUSYGROUP Consumer Group name already exists".to_string());
+                }
+                stream
+                    .groups
+                    .insert(group, ConsumerGroup::new(id, entries_read));
+            } else {
+                let Some(cg) = stream.groups.get_mut(&group) else {
+                    return RedisError::NoGroup { key, group }.into();
+                };
+                cg.last_id = id;
+                cg.entries_read = entries_read;
+            }
+            SimpleString("OK".to_string())
+        }
+        "destroy" => match get_stream_mut(state, &key) {
+            Ok(stream) => {
+                Integer(stream.is_some_and(|stream| stream.groups.remove(&group).is_some()) as i64)
+            }
+            Err(err) => err.into(),
+        },
+        "createconsumer" | "delconsumer" => {
+            let (Some(BulkString(consumer)), true) = (args.pop_front(), args.is_empty()) else {
+                return RedisError::InvalidArguments.into();
+            };
+            let stream = match get_group_stream(state, &key, &group) {
+                Ok(stream) => stream,
+                Err(err) => return err.into(),
+            };
+            let cg = stream.groups.get_mut(&group).unwrap();
+            if subcommand == "createconsumer" {
+                if cg.consumers.contains_key(&consumer) {
+                    return Integer(0);
+                }
+                cg.consumer(&consumer, now_ms());
+                Integer(1)
+            } else {
+                let pending = cg.pending_count(&consumer);
+                cg.pending.retain(|_, entry| entry.consumer != consumer);
+                cg.consumers.remove(&consumer);
+                Integer(pending as i64)
+            }
+        }
+        _ => RedisError::Syntax.into(),
+    }
+}
+
+pub fn do_xack(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let (Some(BulkString(key)), Some(BulkString(group))) = (args.pop_front(), args.pop_front())
+    else {
+        return RedisError::InvalidArguments.into();
+    };
+    let mut ids = Vec::with_capacity(args.len());
+    for arg in args {
+        let BulkString(id) = arg else {
+            return RedisError::InvalidArguments.into();
+        };
+        match parse_id(&id, 0) {
+            Ok(id) => ids.push(id),
+            Err(err) => return err.into(),
+        }
+    }
+    if ids.is_empty() {
+        return RedisError::InvalidArguments.into();
+    }
+    let mut state = state.borrow_mut();
+    let cg = match get_stream_mut(&mut state, &key) {
+        Ok(stream) => stream.and_then(|stream| stream.groups.get_mut(&group)),
+        Err(err) => return err.into(),
+    };
+    let Some(cg) = cg else {
+        return Integer(0);
+    };
+    Integer(
+        ids.into_iter()
+            .filter(|id| cg.pending.remove(id).is_some())
+            .count() as i64,
+    )
+}
+
+pub fn do_xpending(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let (Some(BulkString(key)), Some(BulkString(group))) = (args.pop_front(), args.pop_front())
+    else {
+        return RedisError::InvalidArguments.into();
+    };
+    let mut state = state.borrow_mut();
+    let stream = match get_group_stream(&mut state, &key, &group) {
+        Ok(stream) => stream,
+        Err(err) => return err.into(),
+    };
+    let cg = &stream.groups[&group];
+    if args.is_empty() {
+        let (Some((first, _)), Some((last, _))) =
+            (cg.pending.first_key_value(), cg.pending.last_key_value())
+        else {
+            return Array(vec![Integer(0), Null, Null, Null]);
+        };
+        let mut consumers: BTreeMap<&str, usize> = BTreeMap::new();
+        for entry in cg.pending.values() {
+            *consumers.entry(&entry.consumer).or_default() += 1;
+        }
+        return Array(vec![
+            Integer(cg.pending.len() as i64),
+            BulkString(first.to_string()),
+            BulkString(last.to_string()),
+            Array(
+                consumers
+                    .into_iter()
+                    .map(|(consumer, count)| {
+                        Array(vec![
+                            BulkString(consumer.to_string()),
+                            BulkString(count.to_string()),
+                        ])
+                    })
+                    .collect(),
+            ),
+        ]);
+    }
+
+    let mut min_idle = 0;
+    if matches!(args.front(), Some(BulkString(arg)) if arg.eq_ignore_ascii_case("idle")) {
+        args.pop_front();
+        match args.pop_front() {
+            Some(BulkString(idle)) => match parse_int(&idle) {
+                Some(idle) => min_idle = idle.max(0) as u64,
+                None => return RedisError::NotInteger.into(),
+            },
+            _ => return RedisError::Syntax.into(),
+        }
+    }
+    let (Some(BulkString(start)), Some(BulkString(end)), Some(BulkString(count))) =
+        (args.pop_front(), args.pop_front(), args.pop_front())
+    else {
+        return RedisError::Syntax.into();
+    };
+    let consumer = match args.pop_front() {
+        Some(BulkString(consumer)) if args.is_empty() => Some(consumer),
+        None => None,
+        _ => return RedisError::Syntax.into(),
+    };
+    let (start, end) = match (
+        parse_range_bound(&start, true),
+        parse_range_bound(&end, false),
+    ) {
+        (Ok(start), Ok(end)) => (start, end),
+        (Err(err), _) | (_, Err(err)) => return err.into(),
+    };
+    let count = match parse_count(&count) {
+        Ok(count) => count,
+        Err(err) => return err.into(),
+    };
+    if start > end {
+        return Array(Vec::new());
+    }
+    let now = now_ms();
+    Array(
+        cg.pending
+            .range(start..=end)
+            .filter(|(_, entry)| consumer.as_ref().is_none_or(|c| *c == entry.consumer))
+            .filter(|(_, entry)| now.saturating_sub(entry.delivery_time) >= min_idle)
+            .take(count)
+            .map(|(id, entry)| {
+                Array(vec![
+                    BulkString(id.to_string()),
+                    BulkString(entry.consumer.clone()),
+                    Integer(now.saturating_sub(entry.delivery_time) as i64),
+                    Integer(entry.delivery_count as i64),
+                ])
+            })
+            .collect(),
+    )
+}
+
+pub fn do_xclaim(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let (
+        Some(BulkString(key)),
+        Some(BulkString(group)),
+        Some(BulkString(consumer)),
+        Some(BulkString(min_idle)),
+    ) = (
+        args.pop_front(),
+        args.pop_front(),
+        args.pop_front(),
+        args.pop_front(),
+    )
+    else {
+        return RedisError::InvalidArguments.into();
+    };
+    let Some(min_idle) = parse_int(&min_idle) else {
+        return SimpleError("ERR Invalid min-idle-time argument for XCLAIM".to_string());
+    };
+    let min_idle = min_idle.max(0) as u64;
+    let mut ids = Vec::new();
+    while let Some(Ok(id)) = args.front().map(|arg| match arg {
+        BulkString(arg) => StreamId::parse(arg, 0).ok_or(()),
+        _ => Err(()),
+    }) {
+        args.pop_front();
+        ids.push(id);
+    }
+    if ids.is_empty() {
+        return RedisError::Custom(INVALID_ID).into();
+    }
+    let now = now_ms();
+    let mut delivery_time = now;
+    let mut retry_count = None;
+    let (mut force, mut just_id) = (false, false);
+    let mut last_id = None;
+    while let Some(BulkString(mut arg)) = args.pop_front() {
+        arg.make_ascii_lowercase();
+        let value = match arg.as_str() {
+            "force" => {
+                force = true;
+                continue;
+            }
+            "justid" => {
+                just_id = true;
+                continue;
+            }
+            _ => match args.pop_front() {
+                Some(BulkString(value)) => value,
+                _ => return RedisError::Syntax.into(),
+            },
+        };
+        match arg.as_str() {
+            "idle" | "time" | "retrycount" => {
+                let Some(value) = parse_int(&value) else {
+                    return RedisError::NotInteger.into();
+                };
+                let value = value.max(0) as u64;
+                match arg.as_str() {
+                    "idle" => delivery_time = now.saturating_sub(value),
+                    "time" => delivery_time = value,
+                    _ => retry_count = Some(value),
+                }
+            }
+            "lastid" => match parse_id(&value, 0) {
+                Ok(id) => last_id = Some(id),
+                Err(err) => return err.into(),
+            },
+            _ => return RedisError::Syntax.into(),
+        }
+    }
+
+    let mut state = state.borrow_mut();
+    let stream = match get_group_stream(&mut state, &key, &group) {
+        Ok(stream) => stream,
+        Err(err) => return err.into(),
+    };
+    let Stream {
+        entries, groups, ..
+    } = stream;
+    let cg = groups.get_mut(&group).unwrap();
+    if let Some(last_id) = last_id {
+        cg.last_id = cg.last_id.max(last_id);
+    }
+    let mut claimed = Vec::new();
+    for id in ids {
+        let Some(fields) = entries.get(&id) else {
+            // entries deleted from the stream can not be claimed anymore
+            cg.pending.remove(&id);
+            continue;
+        };
+        if force && !cg.pending.contains_key(&id) {
+            cg.pending.insert(
+                id,
+                PendingEntry {
+                    consumer: consumer.clone(),
+                    delivery_time: now,
+                    delivery_count: 0,
+                },
+            );
+        }
+        let Some(pending) = cg.pending.get_mut(&id) else {
+            continue;
+        };
+        if min_idle > 0 && now.saturating_sub(pending.delivery_time) < min_idle {
+            continue;
+        }
+        pending.consumer = consumer.clone();
+        pending.delivery_time = delivery_time;
+        match retry_count {
+            Some(count) => pending.delivery_count = count,
+            None if !just_id => pending.delivery_count += 1,
+            None => {}
+        }
+        claimed.push(if just_id {
+            BulkString(id.to_string())
+        } else {
+            entry_reply(id, Some(fields))
+        });
+    }
+    let active = cg.consumer(&consumer, now);
+    if !claimed.is_empty() {
+        active.active_time = Some(now);
+    }
+    Array(claimed)
+}
+
+pub fn do_xautoclaim(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let (
+        Some(BulkString(key)),
+        Some(BulkString(group)),
+        Some(BulkString(consumer)),
+        Some(BulkString(min_idle)),
+        Some(BulkString(start)),
+    ) = (
+        args.pop_front(),
+        args.pop_front(),
+        args.pop_front(),
+        args.pop_front(),
+        args.pop_front(),
+    )
+    else {
+        return RedisError::InvalidArguments.into();
+    };
+    let Some(min_idle) = parse_int(&min_idle) else {
+        return SimpleError("ERR Invalid min-idle-time argument for XAUTOCLAIM".to_string());
+    };
+    let min_idle = min_idle.max(0) as u64;
+    let start = match parse_range_bound(&start, true) {
+        Ok(start) => start,
+        Err(err) => return err.into(),
+    };
+    let mut count = 100;
+    let mut just_id = false;
+    while let Some(BulkString(mut arg)) = args.pop_front() {
+        arg.make_ascii_lowercase();
+        match arg.as_str() {
+            "count" => {
+                let Some(BulkString(value)) = args.pop_front() else {
+                    return RedisError::Syntax.into();
+                };
+                match parse_int(&value) {
+                    Some(value) if (1..=i64::MAX / 10).contains(&value) => count = value as usize,
+                    _ => return SimpleError("ERR COUNT must be > 0".to_string()),
+                }
+            }
+            "justid" => just_id = true,
+            _ => return RedisError::Syntax.into(),
+        }
+    }
+
+    let mut state = state.borrow_mut();
+    let stream = match get_group_stream(&mut state, &key, &group) {
+        Ok(stream) => stream,
+        Err(err) => return err.into(),
+    };
+    let Stream {
+        entries, groups, ..
+    } = stream;
+    let cg = groups.get_mut(&group).unwrap();
+    let now = now_ms();
+    // like redis, only a limited number of pending entries are examined
+    let mut attempts = count * 10;
+    let mut claimed = Vec::new();
+    let mut deleted = Vec::new();
+    let mut cursor = StreamId::MIN;
+    // the id following the examined entries is the cursor
+    let ids: Vec<StreamId> = cg
+        .pending
+        .range(start..)
+        .map(|(id, _)| *id)
+        .take(attempts + 1)
+        .collect();
+    let mut ids = ids.into_iter().peekable();
+    while let Some(id) = ids.next() {
+        if attempts == 0 || claimed.len() >= count {
+            cursor = id;
+            break;
+        }
+        attempts -= 1;
+        let Some(fields) = entries.get(&id) else {
+            cg.pending.remove(&id);
+            deleted.push(BulkString(id.to_string()));
+            continue;
+        };
+        let pending = cg.pending.get_mut(&id).unwrap();
+        if now.saturating_sub(pending.delivery_time) < min_idle {
+            continue;
+        }
+        pending.consumer = consumer.clone();
+        pending.delivery_time = now;
+        if !just_id {
+            pending.delivery_count += 1;
+        }
+        claimed.push(if just_id {
+            BulkString(id.to_string())
+        } else {
+            entry_reply(id, Some(fields))
+        });
+        if claimed.len() >= count {
+            cursor = ids.peek().copied().unwrap_or(StreamId::MIN);
+            break;
+        }
+    }
+    let active = cg.consumer(&consumer, now);
+    if !claimed.is_empty() {
+        active.active_time = Some(now);
+    }
+    Array(vec![
+        BulkString(cursor.to_string()),
+        Array(claimed),
+        Array(deleted),
+    ])
+}
+
+fn group_info(stream: &Stream, name: &str, cg: &ConsumerGroup) -> Vec<(RedisItem, RedisItem)> {
+    use RedisItem::*;
+    vec![
+        (BulkString("name".to_string()), BulkString(name.to_string())),
+        (
+            BulkString("consumers".to_string()),
+            Integer(cg.consumers.len() as i64),
+        ),
+        (
+            BulkString("pending".to_string()),
+            Integer(cg.pending.len() as i64),
+        ),
+        (
+            BulkString("last-delivered-id".to_string()),
+            BulkString(cg.last_id.to_string()),
+        ),
+        (
+            BulkString("entries-read".to_string()),
+            cg.entries_read.map_or(Null, |read| Integer(read as i64)),
+        ),
+        (
+            BulkString("lag".to_string()),
+            stream.lag(cg).map_or(Null, |lag| Integer(lag as i64)),
+        ),
+    ]
+}
+
+fn stream_info(stream: &Stream, full: Option<usize>) -> RedisItem {
+    use RedisItem::*;
+    let mut info = vec![
+        (
+            BulkString("length".to_string()),
+            Integer(stream.len() as i64),
+        ),
+        (
+            BulkString("last-generated-id".to_string()),
+            BulkString(stream.last_id.to_string()),
+        ),
+        (
+            BulkString("max-deleted-entry-id".to_string()),
+            BulkString(stream.max_deleted_id.to_string()),
+        ),
+        (
+            BulkString("entries-added".to_string()),
+            Integer(stream.entries_added as i64),
+        ),
+        (
+            BulkString("recorded-first-entry-id".to_string()),
+            BulkString(stream.first_id().to_string()),
+        ),
+    ];
+    let Some(count) = full else {
+        info.push((
+            BulkString("groups".to_string()),
+            Integer(stream.groups.len() as i64),
+        ));
+        let entry = |entry: Option<(&StreamId, &Fields)>| {
+            entry.map_or(Null, |(id, fields)| entry_reply(*id, Some(fields)))
+        };
+        info.push((
+            BulkString("first-entry".to_string()),
+            entry(stream.entries.first_key_value()),
+        ));
+        info.push((
+            BulkString("last-entry".to_string()),
+            entry(stream.entries.last_key_value()),
+        ));
+        return Map(info);
+    };
+    info.push((
+        BulkString("entries".to_string()),
+        entries_reply(stream.entries.iter().take(count)),
+    ));
+    let groups = stream
+        .groups
+        .iter()
+        .map(|(name, cg)| {
+            let mut group = group_info(stream, name, cg);
+            // the full form has no consumer count and reports the pending
+            // entries in detail
+            group.remove(1);
+            group[1].0 = BulkString("pel-count".to_string());
+            group.push((
+                BulkString("pending".to_string()),
+                Array(
+                    cg.pending
+                        .iter()
+                        .take(count)
+                        .map(|(id, entry)| {
+                            Array(vec![
+                                BulkString(id.to_string()),
+                                BulkString(entry.consumer.clone()),
+                                Integer(entry.delivery_time as i64),
+                                Integer(entry.delivery_count as i64),
+                            ])
+                        })
+                        .collect(),
+                ),
+            ));
+            let consumers = cg
+                .consumers
+                .iter()
+                .map(|(consumer, info)| {
+                    let pending: Vec<_> = cg
+                        .pending
+                        .iter()
+                        .filter(|(_, entry)| entry.consumer == *consumer)
+                        .collect();
+                    Map(vec![
+                        (BulkString("name".to_string()), BulkString(consumer.clone())),
+                        (
+                            BulkString("seen-time".to_string()),
+                            Integer(info.seen_time as i64),
+                        ),
+                        (
+                            BulkString("active-time".to_string()),
+                            Integer(info.active_time.map_or(-1, |time| time as i64)),
+                        ),
+                        (
+                            BulkString("pel-count".to_string()),
+                            Integer(pending.len() as i64),
+                        ),
+                        (
+                            BulkString("pending".to_string()),
+                            Array(
+                                pending
+                                    .into_iter()
+                                    .take(count)
+                                    .map(|(id, entry)| {
+                                        Array(vec![
+                                            BulkString(id.to_string()),
+                                            Integer(entry.delivery_time as i64),
+                                            Integer(entry.delivery_count as i64),
+                                        ])
+                                    })
+                                    .collect(),
+                            ),
+                        ),
+                    ])
+                })
+                .collect();
+            group.push((BulkString("consumers".to_string()), Array(consumers)));
+            Map(group)
+        })
+        .collect();
+    info.push((BulkString("groups".to_string()), Array(groups)));
+    Map(info)
+}
+
+pub fn do_xinfo(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let (Some(BulkString(mut subcommand)), Some(BulkString(key))) =
+        (args.pop_front(), args.pop_front())
+    else {
+        return RedisError::InvalidArguments.into();
+    };
+    subcommand.make_ascii_lowercase();
+    let state = state.borrow();
+    let stream = match get_stream(&state, &key) {
+        Ok(Some(stream)) => stream,
+        Ok(None) => return RedisError::NoSuchKey.into(),
+        Err(err) => return err.into(),
+    };
+    match subcommand.as_str() {
+        "stream" => {
+            let full = match (args.pop_front(), args.pop_front(), args.pop_front()) {
+                (None, _, _) => None,
+                (Some(BulkString(arg)), None, None) if arg.eq_ignore_ascii_case("full") => Some(10),
+                (Some(BulkString(arg)), Some(BulkString(count_arg)), Some(BulkString(count)))
+                    if arg.eq_ignore_ascii_case("full")
+                        && count_arg.eq_ignore_ascii_case("count")
+                        && args.is_empty() =>
+                {
+                    match parse_count(&count) {
+                        // a count of zero returns everything
+                        Ok(0) => Some(usize::MAX),
+                        Ok(count) => Some(count),
+                        Err(err) => return err.into(),
+                    }
+                }
+                _ => return RedisError::Syntax.into(),
+            };
+            stream_info(stream, full)
+        }
+        "groups" => Array(
+            stream
+                .groups
+                .iter()
+                .map(|(name, cg)| Map(group_info(stream, name, cg)))
+                .collect(),
+        ),
+        "consumers" => {
+            let Some(BulkString(group)) = args.pop_front() else {
+                return RedisError::InvalidArguments.into();
+            };
+            let Some(cg) = stream.groups.get(&group) else {
+                return RedisError::NoGroup { key, group }.into();
+            };
+            let now = now_ms();
+            Array(
+                cg.consumers
+                    .iter()
+                    .map(|(name, consumer)| {
+                        Map(vec![
+                            (BulkString("name".to_string()), BulkString(name.clone())),
+                            (
+                                BulkString("pending".to_string()),
+                                Integer(cg.pending_count(name) as i64),
+                            ),
+                            (
+                                BulkString("idle".to_string()),
+                                Integer(now.saturating_sub(consumer.seen_time) as i64),
+                            ),
+                            (
+                                BulkString("inactive".to_string()),
+                                Integer(
+                                    consumer
+                                        .active_time
+                                        .map_or(-1, |time| now.saturating_sub(time) as i64),
+                                ),
+                            ),
+                        ])
+                    })
+                    .collect(),
+            )
+        }
+        _ => RedisError::Syntax.into(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_parse_id() {
+        assert_eq!(StreamId::parse("5-3", 0), Some(StreamId { ms: 5, seq: 3 }));
+        assert_eq!(
+            StreamId::parse("5", u64::MAX),
+            Some(StreamId {
+                ms: 5,
+                seq: u64::MAX
+            })
+        );
+        assert_eq!(StreamId::parse("5-", 0), None);
+        assert_eq!(StreamId::parse("abc", 0), None);
+        assert_eq!(StreamId { ms: 1, seq: 2 }.to_string(), "1-2");
+        assert_eq!(
+            StreamId { ms: 1, seq: 0 }.prev(),
+            Some(StreamId {
+                ms: 0,
+                seq: u64::MAX
+            })
+        );
+        assert_eq!(StreamId::MAX.next(), None);
+    }
+
+    #[test]
+    pub fn test_next_id() {
+        let mut stream = Stream::new();
+        assert_eq!(stream.next_id(Some(0)).unwrap(), StreamId { ms: 0, seq: 1 });
+        stream.add(StreamId { ms: 5, seq: 2 }, Vec::new());
+        assert_eq!(stream.next_id(Some(5)).unwrap(), StreamId { ms: 5, seq: 3 });
+        assert_eq!(stream.next_id(Some(7)).unwrap(), StreamId { ms: 7, seq: 0 });
+        assert!(stream.next_id(Some(4)).is_err());
+        assert!(stream.next_id(None).unwrap() > StreamId { ms: 5, seq: 2 });
+    }
+
+    #[test]
+    pub fn test_trim() {
+        let mut stream = Stream::new();
+        for ms in 1..=10 {
+            stream.add(StreamId { ms, seq: 0 }, Vec::new());
+        }
+        let trim = |strategy, limit| Trim { strategy, limit };
+        assert_eq!(stream.trim(trim(TrimStrategy::MaxLen(8), None)), 2);
+        assert_eq!(stream.trim(trim(TrimStrategy::MaxLen(0), Some(3))), 3);
+        let min = StreamId { ms: 7, seq: 0 };
+        assert_eq!(stream.trim(trim(TrimStrategy::MinId(min), None)), 1);
+        assert_eq!(stream.first_id(), min);
+        assert_eq!(stream.entries_added, 10);
+    }
+
+    fn run(state: &RefCell<State>, args: &[&str]) -> RedisItem {
+        crate::run_command(state, &mut 0, args)
+    }
+
+    fn bulks(args: &[&str]) -> Vec<RedisItem> {
+        args.iter()
+            .map(|arg| RedisItem::BulkString(arg.to_string()))
+            .collect()
+    }
+
+    #[test]
+    pub fn test_xsetid() {
+        use RedisItem::*;
+        let state = RefCell::new(State::new(1));
+        assert_eq!(
+            run(&state, &["xsetid", "s", "5-0"]),
+            RedisError::NoSuchKey.into()
+        );
+        run(&state, &["xadd", "s", "3-0", "f", "v"]);
+        assert!(matches!(
+            run(&state, &["xsetid", "s", "2-0"]),
+            SimpleError(_)
+        ));
+        let res = run(&state, &["xsetid", "s", "5-0", "entriesadded", "0"]);
+        assert!(matches!(res, SimpleError(_)));
+        let res = run(&state, &["xsetid", "s", "5-0", "maxdeletedid", "6-0"]);
+        assert!(matches!(res, SimpleError(_)));
+        let res = run(&state, &["xsetid", "s", "5-0", "entriesadded"]);
+        assert_eq!(res, RedisError::Syntax.into());
+
+        let args = [
+            "xsetid",
+            "s",
+            "5-0",
+            "entriesadded",
+            "7",
+            "maxdeletedid",
+            "4-0",
+        ];
+        assert_eq!(run(&state, &args), SimpleString("OK".to_string()));
+        let state = state.borrow();
+        let stream = get_stream(&state, "s").unwrap().unwrap();
+        assert_eq!(stream.last_id, StreamId { ms: 5, seq: 0 });
+        assert_eq!(stream.max_deleted_id, StreamId { ms: 4, seq: 0 });
+        assert_eq!(stream.entries_added, 7);
+    }
+
+    #[test]
+    pub fn test_xautoclaim() {
+        use RedisItem::*;
+        let state = RefCell::new(State::new(1));
+        for ms in 1..=5 {
+            run(&state, &["xadd", "s", &format!("{}-0", ms), "f", "v"]);
+        }
+        run(&state, &["xgroup", "create", "s", "g", "0"]);
+        run(
+            &state,
+            &["xreadgroup", "group", "g", "a", "streams", "s", ">"],
+        );
+        run(&state, &["xdel", "s", "2-0"]);
+
+        let claim = [
+            "xautoclaim",
+            "s",
+            "g",
+            "b",
+            "0",
+            "0",
+            "count",
+            "2",
+            "justid",
+        ];
+        let res = run(&state, &claim);
+        assert_eq!(
+            res,
+            Array(vec![
+                bulk("4-0"),
+                Array(bulks(&["1-0", "3-0"])),
+                Array(bulks(&["2-0"])),
+            ])
+        );
+        let res = run(
+            &state,
+            &["xautoclaim", "s", "g", "b", "0", "4-0", "count", "2"],
+        );
+        let Array(res) = res else {
+            panic!("unexpected reply {:?}", res);
+        };
+        assert_eq!(res[0], bulk("0-0"));
+        assert!(matches!(&res[1], Array(claimed) if claimed.len() == 2));
+
+        // entries which have not been idle for long enough stay with their
+        // consumer
+        let res = run(
+            &state,
+            &["xautoclaim", "s", "g", "c", "60000", "0", "justid"],
+        );
+        assert_eq!(
+            res,
+            Array(vec![bulk("0-0"), Array(Vec::new()), Array(Vec::new())])
+        );
+        let res = run(
+            &state,
+            &["xautoclaim", "s", "g", "c", "0", "0", "count", "0"],
+        );
+        assert!(matches!(res, SimpleError(_)));
+    }
+
+    #[test]
+    pub fn test_read_blocked_on_other_type() {
+        use RedisItem::*;
+        let state = RefCell::new(State::new(1));
+        let exec = smol::LocalExecutor::new();
+        let command = Array(bulks(&["xread", "block", "0", "streams", "s", "$"]));
+        let read = exec.spawn(async { crate::handle_command(command, &state, &mut 0).await });
+        while exec.try_tick() {}
+
+        // the reader keeps waiting while the key is not a stream
+        run(&state, &["set", "s", "v"]);
+        while exec.try_tick() {}
+        assert!(!read.is_finished());
+        run(&state, &["del", "s"]);
+        run(&state, &["xadd", "s", "1-0", "f", "v"]);
+        while exec.try_tick() {}
+        let entry = Array(vec![bulk("1-0"), Array(bulks(&["f", "v"]))]);
+        assert_eq!(
+            smol::block_on(read),
+            Map(vec![(bulk("s"), Array(vec![entry]))])
+        );
+
+        run(&state, &["set", "s", "v"]);
+        let res = run(&state, &["xread", "streams", "s", "0"]);
+        assert!(matches!(res, SimpleError(_)));
+    }
+
+    #[test]
+    pub fn test_xadd_invalid_pair() {
+        use RedisItem::*;
+        let state = RefCell::new(State::new(1));
+        let mut args = bulks(&["xadd", "s", "1-0", "f", "v", "g"]);
+        args.push(Integer(5));
+        let res = smol::block_on(crate::handle_command(Array(args), &state, &mut 0));
+        assert!(matches!(res, SimpleError(_)));
+        // nothing is added, not even the valid pairs
+        assert_eq!(run(&state, &["xlen", "s"]), Integer(0));
+    }
+
+    fn bulk(val: &str) -> RedisItem {
+        RedisItem::BulkString(val.to_string())
+    }
+}
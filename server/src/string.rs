@@ -0,0 +1,886 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use feredis_core::item::RedisItem;
+
+use crate::value::{format_incr_float, parse_float, parse_int, Value};
+use crate::{RedisError, State};
+
+pub fn do_set(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let Some(BulkString(key)) = args.pop_front() else {
+        return RedisError::InvalidArguments.into();
+    };
+    let Some(val) = args.pop_front().and_then(RedisItem::into_bytes) else {
+        return RedisError::InvalidArguments.into();
+    };
+    let mut expire_at = None;
+    let (mut keep_ttl, mut get) = (false, false);
+    // `Some(true)` for NX, `Some(false)` for XX
+    let mut if_missing = None;
+    while let Some(arg) = args.pop_front() {
+        let BulkString(mut arg) = arg else {
+            return RedisError::InvalidArguments.into();
+        };
+        arg.make_ascii_lowercase();
+        let time = match arg.as_str() {
+            "nx" | "xx" if if_missing.is_none() => {
+                if_missing = Some(arg == "nx");
+                continue;
+            }
+            "get" => {
+                get = true;
+                continue;
+            }
+            "keepttl" if expire_at.is_none() => {
+                keep_ttl = true;
+                continue;
+            }
+            "ex" | "px" | "exat" | "pxat" if !keep_ttl && expire_at.is_none() => {
+                let Some(BulkString(amount)) = args.pop_front() else {
+                    return RedisError::Syntax.into();
+                };
+                match arg.as_str() {
+                    "ex" => parse_expire_time(&amount, 1000, "set"),
+                    "px" => parse_expire_time(&amount, 1, "set"),
+                    "exat" => parse_expire_at(&amount, 1000, "set"),
+                    _ => parse_expire_at(&amount, 1, "set"),
+                }
+            }
+            _ => return RedisError::Syntax.into(),
+        };
+        match time {
+            Ok(time) => expire_at = Some(time),
+            Err(err) => return err.into(),
+        }
+    }
+    let mut state = state.borrow_mut();
+    // only GET requires the old value to be a string
+    let reply = match get.then(|| get_string(&state, &key)) {
+        Some(Ok(old)) => old.map_or(Null, RedisItem::bulk),
+        Some(Err(err)) => return err.into(),
+        None => SimpleString("OK".to_string()),
+    };
+    let exists = state.items.contains_key(&key);
+    if if_missing.is_some_and(|if_missing| if_missing == exists) {
+        return if get { reply } else { Null };
+    }
+    let tag = match state.items.get(&key) {
+        Some((_, tag)) if keep_ttl => *tag,
+        _ => state.next_tag(),
+    };
+    state
+        .items
+        .insert(key.clone(), (Value::from_bytes(val), tag));
+    match expire_at {
+        // keys whose time has passed are removed right away
+        Some(time) if time <= Instant::now() => {
+            state.items.remove(&key);
+        }
+        Some(time) => {
+            state.set_expiry(&key, time);
+        }
+        None => {}
+    }
+    reply
+}
+
+pub fn do_get(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let Some(BulkString(key)) = args.pop_front() else {
+        return RedisError::InvalidArguments.into();
+    };
+    match state.borrow().items.get(&key).map(|(val, _)| val) {
+        Some(val) => val
+            .to_bulk()
+            .unwrap_or_else(|| RedisError::WrongType.into()),
+        None => Null,
+    }
+}
+
+/// Adds `incr` to the integer stored at `key`, creating it if necessary.
+/// The expiry of an existing key is preserved.
+fn incr_by(key: String, incr: i64, state: &RefCell<State>) -> RedisItem {
+    let mut state = state.borrow_mut();
+    let state = &mut *state;
+    let Some((val, _)) = state.items.get_mut(&key) else {
+        let tag = state.next_tag();
+        state.items.insert(key, (Value::Integer(incr), tag));
+        return RedisItem::Integer(incr);
+    };
+    let current = match val {
+        Value::Integer(int) => *int,
+        Value::String(s) => match std::str::from_utf8(s).ok().and_then(parse_int) {
+            Some(int) => int,
+            None => return RedisError::NotInteger.into(),
+        },
+        _ => return RedisError::WrongType.into(),
+    };
+    let Some(new) = current.checked_add(incr) else {
+        return RedisError::Overflow.into();
+    };
+    *val = Value::Integer(new);
+    RedisItem::Integer(new)
+}
+
+fn parse_increment(arg: Option<RedisItem>) -> Result<i64, RedisError> {
+    let Some(RedisItem::BulkString(val)) = arg else {
+        return Err(RedisError::InvalidArguments);
+    };
+    parse_int(&val).ok_or(RedisError::NotInteger)
+}
+
+pub fn do_incr(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    let Some(RedisItem::BulkString(key)) = args.pop_front() else {
+        return RedisError::InvalidArguments.into();
+    };
+    incr_by(key, 1, state)
+}
+
+pub fn do_decr(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    let Some(RedisItem::BulkString(key)) = args.pop_front() else {
+        return RedisError::InvalidArguments.into();
+    };
+    incr_by(key, -1, state)
+}
+
+pub fn do_incrby(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    let Some(RedisItem::BulkString(key)) = args.pop_front() else {
+        return RedisError::InvalidArguments.into();
+    };
+    match parse_increment(args.pop_front()) {
+        Ok(incr) => incr_by(key, incr, state),
+        Err(err) => err.into(),
+    }
+}
+
+pub fn do_decrby(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    let Some(RedisItem::BulkString(key)) = args.pop_front() else {
+        return RedisError::InvalidArguments.into();
+    };
+    match parse_increment(args.pop_front()) {
+        Ok(decr) => match decr.checked_neg() {
+            Some(incr) => incr_by(key, incr, state),
+            None => RedisError::DecrementOverflow.into(),
+        },
+        Err(err) => err.into(),
+    }
+}
+
+pub fn do_incrbyfloat(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let Some(BulkString(key)) = args.pop_front() else {
+        return RedisError::InvalidArguments.into();
+    };
+    let Some(BulkString(incr)) = args.pop_front() else {
+        return RedisError::InvalidArguments.into();
+    };
+    let Some(incr) = parse_float(&incr) else {
+        return RedisError::NotFloat.into();
+    };
+    let mut state = state.borrow_mut();
+    let state = &mut *state;
+    let current = match state.items.get(&key).map(|(val, _)| val) {
+        Some(Value::Integer(int)) => *int as f64,
+        Some(Value::String(s)) => match std::str::from_utf8(s).ok().and_then(parse_float) {
+            Some(float) => float,
+            None => return RedisError::NotFloat.into(),
+        },
+        Some(_) => return RedisError::WrongType.into(),
+        None => 0.0,
+    };
+    let new = current + incr;
+    if !new.is_finite() {
+        return RedisError::NanOrInfinity.into();
+    }
+    let new = format_incr_float(new);
+    let value = Value::from_bytes(new.clone().into_bytes());
+    if let Some((val, _)) = state.items.get_mut(&key) {
+        *val = value;
+    } else {
+        let tag = state.next_tag();
+        state.items.insert(key, (value, tag));
+    }
+    BulkString(new)
+}
+
+/// The maximum length of a string value, matching redis' default `proto-max-bulk-len`.
+const MAX_STRING_LEN: i64 = 512 * 1024 * 1024;
+
+/// Reads the string stored at `key`. Missing keys are returned as `None`.
+pub fn get_string(state: &State, key: &str) -> Result<Option<Vec<u8>>, RedisError> {
+    match state.items.get(key) {
+        Some((val, _)) => val.to_bytes().map(Some).ok_or(RedisError::WrongType),
+        None => Ok(None),
+    }
+}
+
+/// Parses an expiry given as `amount` in `unit_ms` milliseconds into an instant.
+fn parse_expire_time(
+    amount: &str,
+    unit_ms: u64,
+    command: &'static str,
+) -> Result<Instant, RedisError> {
+    let amount = parse_int(amount).ok_or(RedisError::NotInteger)?;
+    if amount <= 0 {
+        return Err(RedisError::InvalidExpireTime(command));
+    }
+    let millis = (amount as u64)
+        .checked_mul(unit_ms)
+        .ok_or(RedisError::InvalidExpireTime(command))?;
+    Instant::now()
+        .checked_add(Duration::from_millis(millis))
+        .ok_or(RedisError::InvalidExpireTime(command))
+}
+
+/// Parses an absolute unix timestamp given in `unit_ms` milliseconds into an instant.
+pub fn parse_expire_at(
+    amount: &str,
+    unit_ms: u64,
+    command: &'static str,
+) -> Result<Instant, RedisError> {
+    let amount = parse_int(amount).ok_or(RedisError::NotInteger)?;
+    if amount <= 0 {
+        return Err(RedisError::InvalidExpireTime(command));
+    }
+    let millis = (amount as u64)
+        .checked_mul(unit_ms)
+        .ok_or(RedisError::InvalidExpireTime(command))?;
+    let target = UNIX_EPOCH + Duration::from_millis(millis);
+    let now = Instant::now();
+    // timestamps in the past expire immediately
+    Ok(match target.duration_since(SystemTime::now()) {
+        Ok(remaining) => now
+            .checked_add(remaining)
+            .ok_or(RedisError::InvalidExpireTime(command))?,
+        Err(_) => now,
+    })
+}
+
+pub fn do_append(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let Some(BulkString(key)) = args.pop_front() else {
+        return RedisError::InvalidArguments.into();
+    };
+    let Some(suffix) = args.pop_front().and_then(RedisItem::into_bytes) else {
+        return RedisError::InvalidArguments.into();
+    };
+    let mut state = state.borrow_mut();
+    let state = &mut *state;
+    match state.items.get_mut(&key) {
+        Some((val, _)) => {
+            let Some(mut current) = val.to_bytes() else {
+                return RedisError::WrongType.into();
+            };
+            if current.len() as i64 + suffix.len() as i64 > MAX_STRING_LEN {
+                return RedisError::StringTooLong.into();
+            }
+            current.extend_from_slice(&suffix);
+            let len = current.len();
+            *val = Value::String(current);
+            Integer(len as i64)
+        }
+        None => {
+            let len = suffix.len();
+            let tag = state.next_tag();
+            state.items.insert(key, (Value::from_bytes(suffix), tag));
+            Integer(len as i64)
+        }
+    }
+}
+
+pub fn do_strlen(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let Some(BulkString(key)) = args.pop_front() else {
+        return RedisError::InvalidArguments.into();
+    };
+    match get_string(&state.borrow(), &key) {
+        Ok(val) => Integer(val.map_or(0, |val| val.len() as i64)),
+        Err(err) => err.into(),
+    }
+}
+
+pub fn do_getrange(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let Some(BulkString(key)) = args.pop_front() else {
+        return RedisError::InvalidArguments.into();
+    };
+    let (Some(BulkString(start)), Some(BulkString(end))) = (args.pop_front(), args.pop_front())
+    else {
+        return RedisError::InvalidArguments.into();
+    };
+    let (Some(mut start), Some(mut end)) = (parse_int(&start), parse_int(&end)) else {
+        return RedisError::NotInteger.into();
+    };
+    let val = match get_string(&state.borrow(), &key) {
+        Ok(val) => val.unwrap_or_default(),
+        Err(err) => return err.into(),
+    };
+    let len = val.len() as i64;
+    if start < 0 && end < 0 && start > end {
+        return BulkString(String::new());
+    }
+    if start < 0 {
+        start += len;
+    }
+    if end < 0 {
+        end += len;
+    }
+    start = start.max(0);
+    end = end.max(0).min(len - 1);
+    if start > end || len == 0 {
+        return BulkString(String::new());
+    }
+    RedisItem::bulk(val[start as usize..=end as usize].to_vec())
+}
+
+pub fn do_setrange(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let Some(BulkString(key)) = args.pop_front() else {
+        return RedisError::InvalidArguments.into();
+    };
+    let (Some(BulkString(offset)), Some(patch)) = (
+        args.pop_front(),
+        args.pop_front().and_then(RedisItem::into_bytes),
+    ) else {
+        return RedisError::InvalidArguments.into();
+    };
+    let Some(offset) = parse_int(&offset) else {
+        return RedisError::NotInteger.into();
+    };
+    if offset < 0 {
+        return RedisError::OffsetOutOfRange.into();
+    }
+    let mut state = state.borrow_mut();
+    let state = &mut *state;
+    let current = match get_string(state, &key) {
+        Ok(val) => val,
+        Err(err) => return err.into(),
+    };
+    // empty patches never create or modify a key
+    if patch.is_empty() {
+        return Integer(current.map_or(0, |val| val.len() as i64));
+    }
+    if offset + patch.len() as i64 > MAX_STRING_LEN {
+        return RedisError::StringTooLong.into();
+    }
+    let offset = offset as usize;
+    let mut bytes = current.unwrap_or_default();
+    if bytes.len() < offset + patch.len() {
+        bytes.resize(offset + patch.len(), 0);
+    }
+    bytes[offset..offset + patch.len()].copy_from_slice(&patch);
+    let len = bytes.len();
+    let value = Value::String(bytes);
+    if let Some((val, _)) = state.items.get_mut(&key) {
+        *val = value;
+    } else {
+        let tag = state.next_tag();
+        state.items.insert(key, (value, tag));
+    }
+    Integer(len as i64)
+}
+
+pub fn do_getset(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let (Some(BulkString(key)), Some(val)) = (
+        args.pop_front(),
+        args.pop_front().and_then(RedisItem::into_bytes),
+    ) else {
+        return RedisError::InvalidArguments.into();
+    };
+    let mut state = state.borrow_mut();
+    let old = match get_string(&state, &key) {
+        Ok(old) => old,
+        Err(err) => return err.into(),
+    };
+    let tag = state.next_tag();
+    state.items.insert(key, (Value::from_bytes(val), tag));
+    old.map_or(Null, RedisItem::bulk)
+}
+
+pub fn do_getdel(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let Some(BulkString(key)) = args.pop_front() else {
+        return RedisError::InvalidArguments.into();
+    };
+    let mut state = state.borrow_mut();
+    match get_string(&state, &key) {
+        Ok(Some(val)) => {
+            state.items.remove(&key);
+            RedisItem::bulk(val)
+        }
+        Ok(None) => Null,
+        Err(err) => err.into(),
+    }
+}
+
+pub fn do_getex(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    enum ExpireOption {
+        Keep,
+        At(Instant),
+        Persist,
+    }
+    let Some(BulkString(key)) = args.pop_front() else {
+        return RedisError::InvalidArguments.into();
+    };
+    let mut option = ExpireOption::Keep;
+    while let Some(arg) = args.pop_front() {
+        let BulkString(mut arg) = arg else {
+            return RedisError::InvalidArguments.into();
+        };
+        arg.make_ascii_lowercase();
+        if !matches!(option, ExpireOption::Keep) {
+            return RedisError::Syntax.into();
+        }
+        let time = match (arg.as_str(), args.pop_front()) {
+            ("persist", None) => {
+                option = ExpireOption::Persist;
+                continue;
+            }
+            ("ex", Some(BulkString(amount))) => parse_expire_time(&amount, 1000, "getex"),
+            ("px", Some(BulkString(amount))) => parse_expire_time(&amount, 1, "getex"),
+            ("exat", Some(BulkString(amount))) => parse_expire_at(&amount, 1000, "getex"),
+            ("pxat", Some(BulkString(amount))) => parse_expire_at(&amount, 1, "getex"),
+            _ => return RedisError::Syntax.into(),
+        };
+        match time {
+            Ok(time) => option = ExpireOption::At(time),
+            Err(err) => return err.into(),
+        }
+    }
+    let mut state = state.borrow_mut();
+    let val = match get_string(&state, &key) {
+        Ok(Some(val)) => val,
+        Ok(None) => return Null,
+        Err(err) => return err.into(),
+    };
+    match option {
+        ExpireOption::Keep => {}
+        ExpireOption::At(time) => {
+            state.set_expiry(&key, time);
+        }
+        ExpireOption::Persist => {
+            state.persist(&key);
+        }
+    }
+    RedisItem::bulk(val)
+}
+
+pub fn do_setnx(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let (Some(BulkString(key)), Some(val)) = (
+        args.pop_front(),
+        args.pop_front().and_then(RedisItem::into_bytes),
+    ) else {
+        return RedisError::InvalidArguments.into();
+    };
+    let mut state = state.borrow_mut();
+    if state.items.contains_key(&key) {
+        return Integer(0);
+    }
+    let tag = state.next_tag();
+    state.items.insert(key, (Value::from_bytes(val), tag));
+    Integer(1)
+}
+
+fn set_with_expiry(
+    mut args: VecDeque<RedisItem>,
+    state: &RefCell<State>,
+    unit_ms: u64,
+    command: &'static str,
+) -> RedisItem {
+    use RedisItem::*;
+    let (Some(BulkString(key)), Some(BulkString(amount)), Some(val)) = (
+        args.pop_front(),
+        args.pop_front(),
+        args.pop_front().and_then(RedisItem::into_bytes),
+    ) else {
+        return RedisError::InvalidArguments.into();
+    };
+    let time = match parse_expire_time(&amount, unit_ms, command) {
+        Ok(time) => time,
+        Err(err) => return err.into(),
+    };
+    let mut state = state.borrow_mut();
+    let tag = state.next_tag();
+    state
+        .items
+        .insert(key.clone(), (Value::from_bytes(val), tag));
+    state.set_expiry(&key, time);
+    SimpleString("OK".to_string())
+}
+
+pub fn do_setex(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    set_with_expiry(args, state, 1000, "setex")
+}
+
+pub fn do_psetex(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    set_with_expiry(args, state, 1, "psetex")
+}
+
+/// Collects the key-value pairs of an `MSET`-style command.
+fn parse_pairs(args: VecDeque<RedisItem>) -> Result<Vec<(String, Vec<u8>)>, RedisError> {
+    if args.is_empty() || !args.len().is_multiple_of(2) {
+        return Err(RedisError::InvalidArguments);
+    }
+    let mut args = args.into_iter();
+    let mut pairs = Vec::with_capacity(args.len() / 2);
+    while let (Some(key), Some(val)) = (args.next(), args.next()) {
+        let (RedisItem::BulkString(key), Some(val)) = (key, val.into_bytes()) else {
+            return Err(RedisError::Syntax);
+        };
+        pairs.push((key, val));
+    }
+    Ok(pairs)
+}
+
+pub fn do_mset(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    let pairs = match parse_pairs(args) {
+        Ok(pairs) => pairs,
+        Err(err) => return err.into(),
+    };
+    let mut state = state.borrow_mut();
+    for (key, val) in pairs {
+        let tag = state.next_tag();
+        state.items.insert(key, (Value::from_bytes(val), tag));
+    }
+    RedisItem::SimpleString("OK".to_string())
+}
+
+pub fn do_msetnx(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    let pairs = match parse_pairs(args) {
+        Ok(pairs) => pairs,
+        Err(err) => return err.into(),
+    };
+    let mut state = state.borrow_mut();
+    if pairs.iter().any(|(key, _)| state.items.contains_key(key)) {
+        return RedisItem::Integer(0);
+    }
+    for (key, val) in pairs {
+        let tag = state.next_tag();
+        state.items.insert(key, (Value::from_bytes(val), tag));
+    }
+    RedisItem::Integer(1)
+}
+
+pub fn do_mget(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    if args.is_empty() {
+        return RedisError::InvalidArguments.into();
+    }
+    let state = state.borrow();
+    let mut res = Vec::with_capacity(args.len());
+    for arg in args {
+        let BulkString(key) = arg else {
+            return RedisError::InvalidArguments.into();
+        };
+        // values of other types are reported as missing
+        let val = state.items.get(&key).and_then(|(val, _)| val.to_bulk());
+        res.push(val.unwrap_or(Null));
+    }
+    Array(res)
+}
+
+pub fn do_lcs(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let (Some(BulkString(key_a)), Some(BulkString(key_b))) = (args.pop_front(), args.pop_front())
+    else {
+        return RedisError::InvalidArguments.into();
+    };
+    let mut get_len = false;
+    let mut get_idx = false;
+    let mut with_match_len = false;
+    let mut min_match_len = 0;
+    while let Some(arg) = args.pop_front() {
+        let BulkString(mut arg) = arg else {
+            return RedisError::InvalidArguments.into();
+        };
+        arg.make_ascii_lowercase();
+        match arg.as_str() {
+            "len" => get_len = true,
+            "idx" => get_idx = true,
+            "withmatchlen" => with_match_len = true,
+            "minmatchlen" => {
+                let Some(BulkString(len)) = args.pop_front() else {
+                    return RedisError::Syntax.into();
+                };
+                let Some(len) = parse_int(&len) else {
+                    return RedisError::NotInteger.into();
+                };
+                min_match_len = len.max(0) as usize;
+            }
+            _ => return RedisError::Syntax.into(),
+        }
+    }
+    if get_len && get_idx {
+        return SimpleError(
+            "ERR If you want both the length and indexes, please just use IDX.".to_string(),
+        );
+    }
+    let (a, b) = {
+        let state = state.borrow();
+        match (get_string(&state, &key_a), get_string(&state, &key_b)) {
+            (Ok(a), Ok(b)) => (a.unwrap_or_default(), b.unwrap_or_default()),
+            _ => {
+                return SimpleError("ERR The specified keys must contain string values".to_string())
+            }
+        }
+    };
+    let (a, b) = (a.as_slice(), b.as_slice());
+
+    // lcs[i][j] is the length of the LCS of a[..i] and b[..j]
+    let width = b.len() + 1;
+    let mut lcs = vec![0u32; (a.len() + 1) * width];
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            lcs[i * width + j] = if a[i - 1] == b[j - 1] {
+                lcs[(i - 1) * width + j - 1] + 1
+            } else {
+                lcs[(i - 1) * width + j].max(lcs[i * width + j - 1])
+            };
+        }
+    }
+    let len = lcs[a.len() * width + b.len()] as usize;
+    if get_len {
+        return Integer(len as i64);
+    }
+
+    // walk back through the table, collecting the common subsequence and
+    // the matching ranges from the end of the strings towards the start
+    let mut result = vec![0u8; len];
+    let mut matches = Vec::new();
+    let mut range: Option<(usize, usize, usize, usize)> = None;
+    let (mut i, mut j, mut idx) = (a.len(), b.len(), len);
+    while i > 0 && j > 0 {
+        let mut emit = false;
+        if a[i - 1] == b[j - 1] {
+            result[idx - 1] = a[i - 1];
+            range = match range {
+                Some((a_start, a_end, b_start, b_end)) if a_start == i && b_start == j => {
+                    Some((a_start - 1, a_end, b_start - 1, b_end))
+                }
+                Some(range) => {
+                    emit = true;
+                    Some(range)
+                }
+                None => Some((i - 1, i - 1, j - 1, j - 1)),
+            };
+            if let Some((a_start, _, b_start, _)) = range {
+                emit |= a_start == 0 || b_start == 0;
+            }
+            idx -= 1;
+            i -= 1;
+            j -= 1;
+        } else {
+            if lcs[(i - 1) * width + j] > lcs[i * width + j - 1] {
+                i -= 1;
+            } else {
+                j -= 1;
+            }
+            emit = range.is_some();
+        }
+        if emit {
+            if let Some((a_start, a_end, b_start, b_end)) = range.take() {
+                let match_len = a_end - a_start + 1;
+                if match_len >= min_match_len {
+                    let mut entry = vec![
+                        Array(vec![Integer(a_start as i64), Integer(a_end as i64)]),
+                        Array(vec![Integer(b_start as i64), Integer(b_end as i64)]),
+                    ];
+                    if with_match_len {
+                        entry.push(Integer(match_len as i64));
+                    }
+                    matches.push(Array(entry));
+                }
+            }
+        }
+    }
+
+    if get_idx {
+        Array(vec![
+            BulkString("matches".to_string()),
+            Array(matches),
+            BulkString("len".to_string()),
+            Integer(len as i64),
+        ])
+    } else {
+        RedisItem::bulk(result)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn run(state: &RefCell<State>, args: &[&str]) -> RedisItem {
+        crate::run_command(state, &mut 0, args)
+    }
+
+    fn bulk(val: &str) -> RedisItem {
+        RedisItem::BulkString(val.to_string())
+    }
+
+    fn is_error(res: RedisItem) -> bool {
+        matches!(res, RedisItem::SimpleError(_))
+    }
+
+    #[test]
+    pub fn test_counters() {
+        use RedisItem::*;
+        let state = RefCell::new(State::new(1));
+        assert_eq!(run(&state, &["incr", "n"]), Integer(1));
+        assert_eq!(run(&state, &["incrby", "n", "10"]), Integer(11));
+        assert_eq!(run(&state, &["decr", "n"]), Integer(10));
+        assert_eq!(run(&state, &["decrby", "n", "-5"]), Integer(15));
+        assert_eq!(run(&state, &["incrbyfloat", "n", "0.5"]), bulk("15.5"));
+        assert!(is_error(run(&state, &["incr", "n"])));
+        assert_eq!(run(&state, &["get", "n"]), bulk("15.5"));
+
+        run(&state, &["set", "max", "9223372036854775807"]);
+        assert!(is_error(run(&state, &["incr", "max"])));
+        assert!(is_error(run(&state, &["incrby", "max", "x"])));
+        assert!(is_error(run(&state, &["incrbyfloat", "max", "inf"])));
+        run(&state, &["rpush", "l", "a"]);
+        assert!(is_error(run(&state, &["incr", "l"])));
+    }
+
+    #[test]
+    pub fn test_ranges() {
+        use RedisItem::*;
+        let state = RefCell::new(State::new(1));
+        assert_eq!(run(&state, &["append", "s", "Hello"]), Integer(5));
+        assert_eq!(run(&state, &["append", "s", " World"]), Integer(11));
+        assert_eq!(run(&state, &["strlen", "s"]), Integer(11));
+        assert_eq!(run(&state, &["getrange", "s", "-5", "-1"]), bulk("World"));
+        assert_eq!(run(&state, &["getrange", "s", "5", "2"]), bulk(""));
+        assert_eq!(run(&state, &["setrange", "s", "6", "Redis"]), Integer(11));
+        assert_eq!(run(&state, &["get", "s"]), bulk("Hello Redis"));
+        assert_eq!(run(&state, &["setrange", "p", "2", "x"]), Integer(3));
+        assert_eq!(run(&state, &["get", "p"]), bulk("\0\0x"));
+        assert!(is_error(run(&state, &["setrange", "p", "-1", "x"])));
+        assert_eq!(run(&state, &["strlen", "none"]), Integer(0));
+
+        run(&state, &["mset", "a", "ohmytext", "b", "mynewtext"]);
+        assert_eq!(run(&state, &["lcs", "a", "b"]), bulk("mytext"));
+        assert_eq!(run(&state, &["lcs", "a", "b", "len"]), Integer(6));
+    }
+
+    #[test]
+    pub fn test_get_and_set() {
+        use RedisItem::*;
+        let state = RefCell::new(State::new(1));
+        assert_eq!(run(&state, &["getset", "k", "a"]), Null);
+        assert_eq!(run(&state, &["getset", "k", "b"]), bulk("a"));
+        assert_eq!(run(&state, &["setnx", "k", "c"]), Integer(0));
+        assert_eq!(run(&state, &["setnx", "n", "c"]), Integer(1));
+        assert_eq!(run(&state, &["getdel", "n"]), bulk("c"));
+        assert_eq!(run(&state, &["get", "n"]), Null);
+
+        assert_eq!(
+            run(&state, &["setex", "t", "100", "v"]),
+            SimpleString("OK".to_string())
+        );
+        assert!(is_error(run(&state, &["setex", "t", "0", "v"])));
+        assert!(is_error(run(&state, &["psetex", "t", "-1", "v"])));
+        let expiry = |key: &str| {
+            let state = state.borrow();
+            let (_, tag) = state.items.get(key).unwrap();
+            state.expire.get_expiry(*tag)
+        };
+        assert!(expiry("t").is_some());
+        assert_eq!(run(&state, &["getex", "t", "persist"]), bulk("v"));
+        assert!(expiry("t").is_none());
+        assert_eq!(run(&state, &["getex", "t", "px", "5000"]), bulk("v"));
+        assert!(expiry("t").is_some());
+        // setting a value drops the expiry
+        run(&state, &["getset", "t", "w"]);
+        assert!(expiry("t").is_none());
+    }
+
+    #[test]
+    pub fn test_set_options() {
+        use RedisItem::*;
+        let state = RefCell::new(State::new(1));
+        let ok = || SimpleString("OK".to_string());
+        assert_eq!(run(&state, &["set", "k", "a", "xx"]), Null);
+        assert_eq!(run(&state, &["set", "k", "a", "nx"]), ok());
+        assert_eq!(run(&state, &["set", "k", "b", "nx"]), Null);
+        assert_eq!(run(&state, &["set", "k", "b", "nx", "get"]), bulk("a"));
+        assert_eq!(run(&state, &["set", "k", "b", "xx", "get"]), bulk("a"));
+        assert_eq!(run(&state, &["get", "k"]), bulk("b"));
+        assert_eq!(run(&state, &["set", "n", "c", "get"]), Null);
+
+        assert_eq!(run(&state, &["ttl", "k"]), Integer(-1));
+        assert_eq!(run(&state, &["ttl", "missing"]), Integer(-2));
+        assert_eq!(run(&state, &["set", "k", "c", "EX", "100"]), ok());
+        assert_eq!(run(&state, &["ttl", "k"]), Integer(100));
+        assert!(matches!(
+            run(&state, &["pttl", "k"]),
+            Integer(ttl) if ttl > 99_000 && ttl <= 100_000
+        ));
+        assert_eq!(run(&state, &["set", "k", "d", "keepttl"]), ok());
+        assert_eq!(run(&state, &["ttl", "k"]), Integer(100));
+        assert_eq!(run(&state, &["set", "k", "e"]), ok());
+        assert_eq!(run(&state, &["ttl", "k"]), Integer(-1));
+        run(&state, &["set", "k", "f", "px", "1600"]);
+        assert_eq!(run(&state, &["ttl", "k"]), Integer(2));
+        let at = (SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + 50)
+            .to_string();
+        run(&state, &["set", "k", "g", "exat", &at]);
+        assert!(matches!(run(&state, &["ttl", "k"]), Integer(49..=50)));
+        // a time in the past removes the key
+        assert_eq!(run(&state, &["set", "k", "h", "pxat", "1"]), ok());
+        assert_eq!(run(&state, &["get", "k"]), Null);
+
+        for args in [
+            &["set", "k", "v", "nx", "xx"][..],
+            &["set", "k", "v", "ex", "10", "px", "10"],
+            &["set", "k", "v", "ex", "10", "keepttl"],
+            &["set", "k", "v", "ex"],
+            &["set", "k", "v", "foo"],
+        ] {
+            assert_eq!(run(&state, args), RedisError::Syntax.into());
+        }
+        assert!(is_error(run(&state, &["set", "k", "v", "ex", "0"])));
+        assert!(is_error(run(&state, &["set", "k", "v", "ex", "x"])));
+        run(&state, &["rpush", "l", "a"]);
+        assert_eq!(
+            run(&state, &["set", "l", "v", "get"]),
+            RedisError::WrongType.into()
+        );
+        assert_eq!(run(&state, &["set", "l", "v"]), ok());
+    }
+
+    #[test]
+    pub fn test_mset() {
+        use RedisItem::*;
+        let state = RefCell::new(State::new(1));
+        assert_eq!(
+            run(&state, &["mset", "a", "1", "b", "2"]),
+            SimpleString("OK".to_string())
+        );
+        assert_eq!(
+            run(&state, &["mget", "a", "b", "c"]),
+            Array(vec![bulk("1"), bulk("2"), Null])
+        );
+        assert_eq!(run(&state, &["msetnx", "c", "3", "a", "4"]), Integer(0));
+        assert_eq!(run(&state, &["get", "c"]), Null);
+        assert_eq!(run(&state, &["msetnx", "c", "3", "d", "4"]), Integer(1));
+        assert!(is_error(run(&state, &["mset", "a"])));
+        assert!(is_error(run(&state, &["mset"])));
+
+        // pairs which are not bulk strings are rejected as a whole
+        let args = VecDeque::from(vec![bulk("x"), bulk("1"), Integer(5), bulk("2")]);
+        assert!(is_error(do_mset(args, &state)));
+        let args = VecDeque::from(vec![bulk("x"), bulk("1"), bulk("y"), Null]);
+        assert!(is_error(do_msetnx(args, &state)));
+        assert_eq!(run(&state, &["mget", "x", "y"]), Array(vec![Null, Null]));
+    }
+}
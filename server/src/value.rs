@@ -0,0 +1,122 @@
+use std::collections::VecDeque;
+
+use feredis_core::item::RedisItem;
+
+use crate::hash::HashValue;
+use crate::set::SetValue;
+use crate::stream::Stream;
+use crate::zset::SortedSet;
+
+/// A value stored in the keyspace.
+///
+/// Strings which hold a canonical 64 bit integer are stored in the `Integer`
+/// encoding, so that counters can be updated in place without re-parsing.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(Vec<u8>),
+    Integer(i64),
+    List(VecDeque<Vec<u8>>),
+    Hash(HashValue),
+    Set(SetValue),
+    ZSet(SortedSet),
+    Stream(Stream),
+}
+
+impl Value {
+    /// Creates a string value, choosing the integer encoding if possible.
+    pub fn from_bytes(val: Vec<u8>) -> Self {
+        match std::str::from_utf8(&val).ok().and_then(parse_int) {
+            Some(int) => Value::Integer(int),
+            None => Value::String(val),
+        }
+    }
+
+    /// Returns the contents of a string value, or `None` if the value is of
+    /// another type.
+    pub fn to_bytes(&self) -> Option<Vec<u8>> {
+        match self {
+            Value::String(val) => Some(val.clone()),
+            Value::Integer(val) => Some(val.to_string().into_bytes()),
+            _ => None,
+        }
+    }
+
+    /// Returns the name of the type of the value, as reported by `TYPE`.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) | Value::Integer(_) => "string",
+            Value::List(_) => "list",
+            Value::Hash(_) => "hash",
+            Value::Set(_) => "set",
+            Value::ZSet(_) => "zset",
+            Value::Stream(_) => "stream",
+        }
+    }
+
+    /// Converts a string value into a bulk string reply.
+    pub fn to_bulk(&self) -> Option<RedisItem> {
+        self.to_bytes().map(RedisItem::bulk)
+    }
+}
+
+/// Parses a 64 bit integer using the same rules as redis: no leading `+`,
+/// no leading zeros, no whitespace and no `-0`.
+pub fn parse_int(val: &str) -> Option<i64> {
+    let int = val.parse::<i64>().ok()?;
+    // only canonical representations are accepted
+    if int.to_string() == val {
+        Some(int)
+    } else {
+        None
+    }
+}
+
+/// Parses a float argument. Unlike `str::parse`, this rejects NaN.
+pub fn parse_float(val: &str) -> Option<f64> {
+    match val.parse::<f64>() {
+        Ok(float) if !float.is_nan() => Some(float),
+        _ => None,
+    }
+}
+
+/// Formats a float the way redis replies with it in human readable form.
+pub fn format_float(val: f64) -> String {
+    if val.is_infinite() {
+        if val > 0.0 { "inf" } else { "-inf" }.to_string()
+    } else if val == 0.0 {
+        // avoids printing negative zero
+        "0".to_string()
+    } else {
+        val.to_string()
+    }
+}
+
+/// Formats the result of `INCRBYFLOAT` and `HINCRBYFLOAT`. Redis computes
+/// them in long double precision and prints at most 17 significant digits,
+/// so rounding noise like in 0.1 + 0.2 never shows up. A double has only 15
+/// reliable digits, which are used instead unless that changes the value by
+/// more than the noise.
+pub fn format_incr_float(val: f64) -> String {
+    let rounded: f64 = format!("{:.14e}", val).parse().unwrap();
+    if (rounded - val).abs() <= 2.0 * f64::EPSILON * val.abs() {
+        format_float(rounded)
+    } else {
+        format_float(val)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_format_incr_float() {
+        assert_eq!(format_incr_float(0.1 + 0.2), "0.3");
+        assert_eq!(format_incr_float(10.5 + 0.1), "10.6");
+        assert_eq!(format_incr_float(3.0), "3");
+        assert_eq!(format_incr_float(-0.0), "0");
+        assert_eq!(format_incr_float(1e-20), "0.00000000000000000001");
+        // digits beyond the noise are kept
+        assert_eq!(format_incr_float(1.2345678901234567), "1.2345678901234567");
+    }
+}
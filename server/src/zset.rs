@@ -0,0 +1,1460 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+
+use feredis_core::item::RedisItem;
+
+use crate::list::{normalize_range, parse_keys, parse_mpop_count};
+use crate::scan::{cursor_reply, ScanOptions, ScanOrder};
+use crate::set::SetOp;
+use crate::skiplist::{LexBound, LexRange, ScoreRange, SkipList};
+use crate::value::{format_float, parse_float, parse_int, Value};
+use crate::{RedisError, State};
+
+/// A member of a sorted set and its score.
+pub type Entry = (String, f64);
+
+/// A sorted set. Members are indexed by a hash map for score lookups and by a
+/// skiplist for everything that depends on the order, and ordered by their
+/// hash for `ZSCAN`.
+#[derive(Debug, Clone, Default)]
+pub struct SortedSet {
+    scores: HashMap<String, f64>,
+    list: SkipList,
+    order: ScanOrder,
+}
+
+impl PartialEq for SortedSet {
+    fn eq(&self, other: &Self) -> bool {
+        self.scores == other.scores
+    }
+}
+
+impl SortedSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    pub fn score(&self, member: &str) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    /// Sets the score of `member`, returning `true` if it was newly added.
+    pub fn insert(&mut self, member: String, score: f64) -> bool {
+        match self.scores.get_mut(&member) {
+            Some(current) => {
+                if *current != score {
+                    self.list.remove(*current, &member);
+                    self.list.insert(score, member);
+                    *current = score;
+                }
+                false
+            }
+            None => {
+                self.list.insert(score, member.clone());
+                self.order.insert(&member);
+                self.scores.insert(member, score);
+                true
+            }
+        }
+    }
+
+    /// Removes `member`, returning `false` if it was not present.
+    pub fn remove(&mut self, member: &str) -> bool {
+        match self.scores.remove(member) {
+            Some(score) => {
+                self.list.remove(score, member);
+                self.order.remove(member);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the 0-based rank of `member`, counting from the highest score
+    /// if `rev` is set.
+    pub fn rank(&self, member: &str, rev: bool) -> Option<usize> {
+        let rank = self.list.rank(self.score(member)?, member)?;
+        Some(if rev { self.len() - 1 - rank } else { rank })
+    }
+
+    fn walk(&self, start: Option<usize>, rev: bool) -> impl Iterator<Item = (&str, f64)> + '_ {
+        std::iter::successors(start, move |&node| {
+            if rev {
+                self.list.prev(node)
+            } else {
+                self.list.next(node)
+            }
+        })
+        .map(|node| self.list.entry(node))
+    }
+
+    /// Iterates over all members in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, f64)> + '_ {
+        self.walk(self.list.first(), false)
+    }
+
+    /// Returns the members with a rank between `start` and `end` inclusive.
+    pub fn range_by_rank(&self, start: usize, end: usize, rev: bool) -> Vec<Entry> {
+        let first = if rev {
+            self.list.by_rank(self.len() - 1 - start)
+        } else {
+            self.list.by_rank(start)
+        };
+        to_owned(self.walk(first, rev).take(end - start + 1))
+    }
+
+    /// Returns the members with a score in `range`, skipping `offset` of them
+    /// and returning at most `limit`.
+    pub fn range_by_score(
+        &self,
+        range: &ScoreRange,
+        rev: bool,
+        offset: usize,
+        limit: Option<usize>,
+    ) -> Vec<Entry> {
+        let first = if rev {
+            self.list.last_in_score_range(range)
+        } else {
+            self.list.first_in_score_range(range)
+        };
+        to_owned(
+            self.walk(first, rev)
+                .take_while(|&(_, score)| range.contains(score))
+                .skip(offset)
+                .take(limit.unwrap_or(usize::MAX)),
+        )
+    }
+
+    /// Returns the members in the lexicographical `range`, skipping `offset`
+    /// of them and returning at most `limit`.
+    pub fn range_by_lex(
+        &self,
+        range: &LexRange,
+        rev: bool,
+        offset: usize,
+        limit: Option<usize>,
+    ) -> Vec<Entry> {
+        let first = if rev {
+            self.list.last_in_lex_range(range)
+        } else {
+            self.list.first_in_lex_range(range)
+        };
+        to_owned(
+            self.walk(first, rev)
+                .take_while(|&(member, _)| range.contains(member))
+                .skip(offset)
+                .take(limit.unwrap_or(usize::MAX)),
+        )
+    }
+
+    /// Counts the members between two nodes using their ranks.
+    fn count_between(&self, first: Option<usize>, last: Option<usize>) -> usize {
+        let (Some(first), Some(last)) = (first, last) else {
+            return 0;
+        };
+        let (first_member, first_score) = self.list.entry(first);
+        let (last_member, last_score) = self.list.entry(last);
+        let first = self.list.rank(first_score, first_member).unwrap();
+        let last = self.list.rank(last_score, last_member).unwrap();
+        last + 1 - first
+    }
+
+    pub fn count_by_score(&self, range: &ScoreRange) -> usize {
+        self.count_between(
+            self.list.first_in_score_range(range),
+            self.list.last_in_score_range(range),
+        )
+    }
+
+    pub fn count_by_lex(&self, range: &LexRange) -> usize {
+        self.count_between(
+            self.list.first_in_lex_range(range),
+            self.list.last_in_lex_range(range),
+        )
+    }
+
+    /// Visits about `count` members starting at `cursor`, returning the
+    /// cursor to continue with and the members with their scores.
+    pub fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<(&str, f64)>) {
+        let (cursor, members) = self.order.scan(cursor, count);
+        let entries = members
+            .into_iter()
+            .map(|member| (member, self.scores[member]))
+            .collect();
+        (cursor, entries)
+    }
+
+    /// Removes and returns the member with the lowest (or highest) score.
+    pub fn pop(&mut self, max: bool) -> Option<(String, f64)> {
+        let node = if max {
+            self.list.last()
+        } else {
+            self.list.first()
+        }?;
+        let (member, score) = self.list.entry(node);
+        let member = member.to_string();
+        self.remove(&member);
+        Some((member, score))
+    }
+}
+
+impl FromIterator<(String, f64)> for SortedSet {
+    fn from_iter<T: IntoIterator<Item = (String, f64)>>(iter: T) -> Self {
+        let mut zset = SortedSet::new();
+        for (member, score) in iter {
+            zset.insert(member, score);
+        }
+        zset
+    }
+}
+
+fn to_owned<'a>(entries: impl Iterator<Item = (&'a str, f64)>) -> Vec<Entry> {
+    entries
+        .map(|(member, score)| (member.to_string(), score))
+        .collect()
+}
+
+/// Returns the sorted set stored at `key`, or `None` if the key does not exist.
+pub fn get_zset<'a>(state: &'a State, key: &str) -> Result<Option<&'a SortedSet>, RedisError> {
+    match state.items.get(key) {
+        Some((Value::ZSet(zset), _)) => Ok(Some(zset)),
+        Some(_) => Err(RedisError::WrongType),
+        None => Ok(None),
+    }
+}
+
+fn get_zset_mut<'a>(
+    state: &'a mut State,
+    key: &str,
+) -> Result<Option<&'a mut SortedSet>, RedisError> {
+    match state.items.get_mut(key) {
+        Some((Value::ZSet(zset), _)) => Ok(Some(zset)),
+        Some(_) => Err(RedisError::WrongType),
+        None => Ok(None),
+    }
+}
+
+fn get_or_create_zset<'a>(
+    state: &'a mut State,
+    key: &str,
+) -> Result<&'a mut SortedSet, RedisError> {
+    if !state.items.contains_key(key) {
+        let tag = state.next_tag();
+        state
+            .items
+            .insert(key.to_string(), (Value::ZSet(SortedSet::new()), tag));
+    }
+    get_zset_mut(state, key).map(Option::unwrap)
+}
+
+/// Replaces whatever is stored at `key` with `zset`, deleting the key if the
+/// sorted set is empty. Returns the size of the stored sorted set.
+pub fn store(state: &mut State, key: String, zset: SortedSet) -> usize {
+    let len = zset.len();
+    if zset.is_empty() {
+        state.items.remove(&key);
+    } else {
+        let tag = state.next_tag();
+        state.blocking.signal(&key);
+        state.items.insert(key, (Value::ZSet(zset), tag));
+    }
+    len
+}
+
+/// Builds the reply for a list of members, pairing each member with its
+/// score if `with_scores` is set.
+fn entries_reply(entries: Vec<Entry>, with_scores: bool) -> RedisItem {
+    use RedisItem::*;
+    Array(
+        entries
+            .into_iter()
+            .map(|(member, score)| {
+                if with_scores {
+                    Array(vec![BulkString(member), Double(score)])
+                } else {
+                    BulkString(member)
+                }
+            })
+            .collect(),
+    )
+}
+
+fn parse_score(val: &str) -> Result<f64, RedisError> {
+    parse_float(val).ok_or(RedisError::NotFloat)
+}
+
+/// Parses a score range such as `(1 5` or `-inf +inf`.
+fn parse_score_range(min: &str, max: &str) -> Result<ScoreRange, RedisError> {
+    fn parse_bound(bound: &str) -> Option<(f64, bool)> {
+        match bound.strip_prefix('(') {
+            Some(bound) => Some((parse_float(bound)?, true)),
+            None => Some((parse_float(bound)?, false)),
+        }
+    }
+    let (Some((min, min_exclusive)), Some((max, max_exclusive))) =
+        (parse_bound(min), parse_bound(max))
+    else {
+        return Err(RedisError::Custom("ERR min or max is not a float"));
+    };
+    Ok(ScoreRange {
+        min,
+        max,
+        min_exclusive,
+        max_exclusive,
+    })
+}
+
+/// Parses a lexicographical range such as `[a (c` or `- +`.
+fn parse_lex_range(min: String, max: String) -> Result<LexRange, RedisError> {
+    fn parse_bound(mut bound: String) -> Option<LexBound> {
+        match bound.chars().next()? {
+            '-' if bound.len() == 1 => Some(LexBound::NegInf),
+            '+' if bound.len() == 1 => Some(LexBound::PosInf),
+            '[' => {
+                bound.remove(0);
+                Some(LexBound::Inclusive(bound))
+            }
+            '(' => {
+                bound.remove(0);
+                Some(LexBound::Exclusive(bound))
+            }
+            _ => None,
+        }
+    }
+    let (Some(min), Some(max)) = (parse_bound(min), parse_bound(max)) else {
+        return Err(RedisError::Custom(
+            "ERR min or max not valid string range item",
+        ));
+    };
+    Ok(LexRange { min, max })
+}
+
+pub fn do_zadd(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let Some(BulkString(key)) = args.pop_front() else {
+        return RedisError::InvalidArguments.into();
+    };
+    let (mut nx, mut xx, mut gt, mut lt, mut ch, mut incr) =
+        (false, false, false, false, false, false);
+    while let Some(BulkString(arg)) = args.front() {
+        match arg.to_ascii_lowercase().as_str() {
+            "nx" => nx = true,
+            "xx" => xx = true,
+            "gt" => gt = true,
+            "lt" => lt = true,
+            "ch" => ch = true,
+            "incr" => incr = true,
+            _ => break,
+        }
+        args.pop_front();
+    }
+    if args.is_empty() || !args.len().is_multiple_of(2) {
+        return RedisError::Syntax.into();
+    }
+    if nx && xx {
+        return SimpleError(
+            "ERR XX and NX options at the same time are not compatible".to_string(),
+        );
+    }
+    if (gt && lt) || (nx && (gt || lt)) {
+        return SimpleError(
+            "ERR GT, LT, and/or NX options at the same time are not compatible".to_string(),
+        );
+    }
+    if incr && args.len() > 2 {
+        return SimpleError("ERR INCR option supports a single increment-element pair".to_string());
+    }
+    // every pair is checked before anything is written
+    let mut pairs = Vec::with_capacity(args.len() / 2);
+    while let Some(score) = args.pop_front() {
+        let (BulkString(score), Some(BulkString(member))) = (score, args.pop_front()) else {
+            return RedisError::InvalidArguments.into();
+        };
+        match parse_score(&score) {
+            Ok(score) => pairs.push((score, member)),
+            Err(err) => return err.into(),
+        }
+    }
+
+    let mut state = state.borrow_mut();
+    let state = &mut *state;
+    let zset = match get_zset_mut(state, &key) {
+        // nothing can be added, so the key must not be created
+        Ok(None) if xx => return if incr { Null } else { Integer(0) },
+        Ok(_) => get_or_create_zset(state, &key).unwrap(),
+        Err(err) => return err.into(),
+    };
+    let (mut added, mut changed) = (0, 0);
+    let mut result = None;
+    for (score, member) in pairs {
+        match zset.score(&member) {
+            Some(current) => {
+                if nx {
+                    continue;
+                }
+                let score = if incr { current + score } else { score };
+                if score.is_nan() {
+                    return SimpleError("ERR resulting score is not a number (NaN)".to_string());
+                }
+                if (gt && score <= current) || (lt && score >= current) {
+                    continue;
+                }
+                if score != current {
+                    zset.insert(member, score);
+                    changed += 1;
+                }
+                result = Some(score);
+            }
+            None => {
+                if xx {
+                    continue;
+                }
+                zset.insert(member, score);
+                added += 1;
+                result = Some(score);
+            }
+        }
+    }
+    if added > 0 {
+        state.blocking.signal(&key);
+    }
+    if incr {
+        result.map_or(Null, Double)
+    } else if ch {
+        Integer(added + changed)
+    } else {
+        Integer(added)
+    }
+}
+
+pub fn do_zincrby(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let (Some(BulkString(key)), Some(BulkString(increment)), Some(BulkString(member))) =
+        (args.pop_front(), args.pop_front(), args.pop_front())
+    else {
+        return RedisError::InvalidArguments.into();
+    };
+    let increment = match parse_score(&increment) {
+        Ok(increment) => increment,
+        Err(err) => return err.into(),
+    };
+    let mut state = state.borrow_mut();
+    let state = &mut *state;
+    let zset = match get_or_create_zset(state, &key) {
+        Ok(zset) => zset,
+        Err(err) => return err.into(),
+    };
+    let score = zset.score(&member).unwrap_or(0.0) + increment;
+    if score.is_nan() {
+        return SimpleError("ERR resulting score is not a number (NaN)".to_string());
+    }
+    zset.insert(member, score);
+    state.blocking.signal(&key);
+    Double(score)
+}
+
+pub fn do_zrem(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let Some(BulkString(key)) = args.pop_front() else {
+        return RedisError::InvalidArguments.into();
+    };
+    if args.is_empty() {
+        return RedisError::InvalidArguments.into();
+    }
+    let mut state = state.borrow_mut();
+    let zset = match get_zset_mut(&mut state, &key) {
+        Ok(Some(zset)) => zset,
+        Ok(None) => return Integer(0),
+        Err(err) => return err.into(),
+    };
+    let mut removed = 0;
+    for arg in args {
+        let BulkString(member) = arg else {
+            return RedisError::InvalidArguments.into();
+        };
+        removed += zset.remove(&member) as i64;
+    }
+    if zset.is_empty() {
+        state.items.remove(&key);
+    }
+    Integer(removed)
+}
+
+pub fn do_zcard(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let Some(BulkString(key)) = args.pop_front() else {
+        return RedisError::InvalidArguments.into();
+    };
+    match get_zset(&state.borrow(), &key) {
+        Ok(zset) => Integer(zset.map_or(0, |zset| zset.len() as i64)),
+        Err(err) => err.into(),
+    }
+}
+
+pub fn do_zscore(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let (Some(BulkString(key)), Some(BulkString(member))) = (args.pop_front(), args.pop_front())
+    else {
+        return RedisError::InvalidArguments.into();
+    };
+    match get_zset(&state.borrow(), &key) {
+        Ok(zset) => zset
+            .and_then(|zset| zset.score(&member))
+            .map_or(Null, Double),
+        Err(err) => err.into(),
+    }
+}
+
+pub fn do_zmscore(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let Some(BulkString(key)) = args.pop_front() else {
+        return RedisError::InvalidArguments.into();
+    };
+    if args.is_empty() {
+        return RedisError::InvalidArguments.into();
+    }
+    let state = state.borrow();
+    let zset = match get_zset(&state, &key) {
+        Ok(zset) => zset,
+        Err(err) => return err.into(),
+    };
+    let mut scores = Vec::with_capacity(args.len());
+    for arg in args {
+        let BulkString(member) = arg else {
+            return RedisError::InvalidArguments.into();
+        };
+        scores.push(
+            zset.and_then(|zset| zset.score(&member))
+                .map_or(Null, Double),
+        );
+    }
+    Array(scores)
+}
+
+fn rank(mut args: VecDeque<RedisItem>, state: &RefCell<State>, rev: bool) -> RedisItem {
+    use RedisItem::*;
+    let (Some(BulkString(key)), Some(BulkString(member))) = (args.pop_front(), args.pop_front())
+    else {
+        return RedisError::InvalidArguments.into();
+    };
+    let with_score = match args.pop_front() {
+        None => false,
+        Some(BulkString(arg)) if arg.eq_ignore_ascii_case("withscore") && args.is_empty() => true,
+        _ => return RedisError::Syntax.into(),
+    };
+    let state = state.borrow();
+    let zset = match get_zset(&state, &key) {
+        Ok(Some(zset)) => zset,
+        Ok(None) => return Null,
+        Err(err) => return err.into(),
+    };
+    match zset.rank(&member, rev) {
+        Some(rank) if with_score => Array(vec![
+            Integer(rank as i64),
+            Double(zset.score(&member).unwrap()),
+        ]),
+        Some(rank) => Integer(rank as i64),
+        None => Null,
+    }
+}
+
+pub fn do_zrank(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    rank(args, state, false)
+}
+
+pub fn do_zrevrank(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    rank(args, state, true)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RangeBy {
+    Rank,
+    Score,
+    Lex,
+}
+
+/// The options of `ZRANGE` and its older variants.
+#[derive(Debug, Clone, Copy)]
+struct RangeSpec {
+    by: RangeBy,
+    rev: bool,
+    limit: Option<(i64, i64)>,
+    with_scores: bool,
+}
+
+impl RangeSpec {
+    fn new(by: RangeBy, rev: bool) -> Self {
+        Self {
+            by,
+            rev,
+            limit: None,
+            with_scores: false,
+        }
+    }
+
+    /// Parses the trailing options. `BYSCORE`, `BYLEX` and `REV` are only
+    /// accepted by `ZRANGE` itself.
+    fn parse(mut self, args: &mut VecDeque<RedisItem>, modern: bool) -> Result<Self, RedisError> {
+        while let Some(arg) = args.pop_front() {
+            let RedisItem::BulkString(mut arg) = arg else {
+                return Err(RedisError::InvalidArguments);
+            };
+            arg.make_ascii_lowercase();
+            match arg.as_str() {
+                "byscore" if modern => self.by = RangeBy::Score,
+                "bylex" if modern => self.by = RangeBy::Lex,
+                "rev" if modern => self.rev = true,
+                "withscores" => self.with_scores = true,
+                "limit" => {
+                    let (Some(RedisItem::BulkString(offset)), Some(RedisItem::BulkString(count))) =
+                        (args.pop_front(), args.pop_front())
+                    else {
+                        return Err(RedisError::Syntax);
+                    };
+                    let (Some(offset), Some(count)) = (parse_int(&offset), parse_int(&count))
+                    else {
+                        return Err(RedisError::NotInteger);
+                    };
+                    self.limit = Some((offset, count));
+                }
+                _ => return Err(RedisError::Syntax),
+            }
+        }
+        if self.limit.is_some() && self.by == RangeBy::Rank {
+            return Err(RedisError::Custom(
+                "ERR syntax error, LIMIT is only supported in combination with either BYSCORE or BYLEX",
+            ));
+        }
+        if self.with_scores && self.by == RangeBy::Lex {
+            return Err(RedisError::Custom(
+                "ERR syntax error, WITHSCORES not supported in combination with BYLEX",
+            ));
+        }
+        Ok(self)
+    }
+
+    /// Returns the members of `zset` between `start` and `stop`. With `REV`,
+    /// score and lex ranges are given from the highest to the lowest end.
+    fn select(
+        &self,
+        zset: &SortedSet,
+        start: String,
+        stop: String,
+    ) -> Result<Vec<Entry>, RedisError> {
+        // a negative offset selects nothing, a negative count everything
+        let (offset, limit) = match self.limit {
+            Some((offset, _)) if offset < 0 => return Ok(Vec::new()),
+            Some((offset, count)) => (offset as usize, (count >= 0).then_some(count as usize)),
+            None => (0, None),
+        };
+        let (min, max) = if self.rev {
+            (stop, start)
+        } else {
+            (start, stop)
+        };
+        Ok(match self.by {
+            RangeBy::Rank => {
+                let (Some(start), Some(stop)) = (parse_int(&min), parse_int(&max)) else {
+                    return Err(RedisError::NotInteger);
+                };
+                let (start, stop) = if self.rev {
+                    (stop, start)
+                } else {
+                    (start, stop)
+                };
+                match normalize_range(start, stop, zset.len()) {
+                    Some((start, stop)) => zset.range_by_rank(start, stop, self.rev),
+                    None => Vec::new(),
+                }
+            }
+            RangeBy::Score => {
+                let range = parse_score_range(&min, &max)?;
+                zset.range_by_score(&range, self.rev, offset, limit)
+            }
+            RangeBy::Lex => {
+                let range = parse_lex_range(min, max)?;
+                zset.range_by_lex(&range, self.rev, offset, limit)
+            }
+        })
+    }
+}
+
+fn range(
+    mut args: VecDeque<RedisItem>,
+    state: &RefCell<State>,
+    spec: RangeSpec,
+    modern: bool,
+) -> RedisItem {
+    use RedisItem::*;
+    let (Some(BulkString(key)), Some(BulkString(start)), Some(BulkString(stop))) =
+        (args.pop_front(), args.pop_front(), args.pop_front())
+    else {
+        return RedisError::InvalidArguments.into();
+    };
+    let spec = match spec.parse(&mut args, modern) {
+        Ok(spec) => spec,
+        Err(err) => return err.into(),
+    };
+    let state = state.borrow();
+    let empty = SortedSet::new();
+    let zset = match get_zset(&state, &key) {
+        Ok(zset) => zset.unwrap_or(&empty),
+        Err(err) => return err.into(),
+    };
+    match spec.select(zset, start, stop) {
+        Ok(entries) => entries_reply(entries, spec.with_scores),
+        Err(err) => err.into(),
+    }
+}
+
+pub fn do_zrange(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    range(args, state, RangeSpec::new(RangeBy::Rank, false), true)
+}
+
+pub fn do_zrevrange(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    range(args, state, RangeSpec::new(RangeBy::Rank, true), false)
+}
+
+pub fn do_zrangebyscore(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    range(args, state, RangeSpec::new(RangeBy::Score, false), false)
+}
+
+pub fn do_zrevrangebyscore(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    range(args, state, RangeSpec::new(RangeBy::Score, true), false)
+}
+
+pub fn do_zrangebylex(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    range(args, state, RangeSpec::new(RangeBy::Lex, false), false)
+}
+
+pub fn do_zrevrangebylex(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    range(args, state, RangeSpec::new(RangeBy::Lex, true), false)
+}
+
+pub fn do_zcount(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let (Some(BulkString(key)), Some(BulkString(min)), Some(BulkString(max))) =
+        (args.pop_front(), args.pop_front(), args.pop_front())
+    else {
+        return RedisError::InvalidArguments.into();
+    };
+    let range = match parse_score_range(&min, &max) {
+        Ok(range) => range,
+        Err(err) => return err.into(),
+    };
+    match get_zset(&state.borrow(), &key) {
+        Ok(zset) => Integer(zset.map_or(0, |zset| zset.count_by_score(&range) as i64)),
+        Err(err) => err.into(),
+    }
+}
+
+pub fn do_zlexcount(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let (Some(BulkString(key)), Some(BulkString(min)), Some(BulkString(max))) =
+        (args.pop_front(), args.pop_front(), args.pop_front())
+    else {
+        return RedisError::InvalidArguments.into();
+    };
+    let range = match parse_lex_range(min, max) {
+        Ok(range) => range,
+        Err(err) => return err.into(),
+    };
+    match get_zset(&state.borrow(), &key) {
+        Ok(zset) => Integer(zset.map_or(0, |zset| zset.count_by_lex(&range) as i64)),
+        Err(err) => err.into(),
+    }
+}
+
+/// Pops up to `count` members with the lowest (or highest) scores from the
+/// sorted set at `key`. Returns `None` if the key does not exist.
+pub fn pop(
+    state: &mut State,
+    key: &str,
+    max: bool,
+    count: usize,
+) -> Result<Option<Vec<Entry>>, RedisError> {
+    let Some(zset) = get_zset_mut(state, key)? else {
+        return Ok(None);
+    };
+    let popped = (0..count).map_while(|_| zset.pop(max)).collect();
+    if zset.is_empty() {
+        state.items.remove(key);
+    }
+    Ok(Some(popped))
+}
+
+fn pop_command(mut args: VecDeque<RedisItem>, state: &RefCell<State>, max: bool) -> RedisItem {
+    use RedisItem::*;
+    let Some(BulkString(key)) = args.pop_front() else {
+        return RedisError::InvalidArguments.into();
+    };
+    let count = match args.pop_front() {
+        None => None,
+        Some(BulkString(count)) if args.is_empty() => match parse_int(&count) {
+            Some(count) if count >= 0 => Some(count as usize),
+            Some(_) => return RedisError::NotPositive.into(),
+            None => return RedisError::NotInteger.into(),
+        },
+        _ => return RedisError::Syntax.into(),
+    };
+    match pop(&mut state.borrow_mut(), &key, max, count.unwrap_or(1)) {
+        // without a count, the single member and its score are not nested
+        Ok(Some(mut popped)) if count.is_none() && !popped.is_empty() => {
+            let (member, score) = popped.remove(0);
+            Array(vec![BulkString(member), Double(score)])
+        }
+        Ok(popped) => entries_reply(popped.unwrap_or_default(), true),
+        Err(err) => err.into(),
+    }
+}
+
+pub fn do_zpopmin(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    pop_command(args, state, false)
+}
+
+pub fn do_zpopmax(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    pop_command(args, state, true)
+}
+
+/// Pops up to `count` members from the first non-empty sorted set in `keys`.
+pub fn multi_pop(
+    state: &mut State,
+    keys: &[String],
+    max: bool,
+    count: usize,
+) -> Result<Option<(String, Vec<Entry>)>, RedisError> {
+    for key in keys {
+        if let Some(popped) = pop(state, key, max, count)? {
+            return Ok(Some((key.clone(), popped)));
+        }
+    }
+    Ok(None)
+}
+
+/// Parses the `MIN` or `MAX` argument of the multi-key pops, returning
+/// `true` for `MAX`.
+pub fn parse_min_max(arg: Option<RedisItem>) -> Result<bool, RedisError> {
+    let Some(RedisItem::BulkString(mut arg)) = arg else {
+        return Err(RedisError::InvalidArguments);
+    };
+    arg.make_ascii_lowercase();
+    match arg.as_str() {
+        "min" => Ok(false),
+        "max" => Ok(true),
+        _ => Err(RedisError::Syntax),
+    }
+}
+
+pub fn mpop_reply(res: Option<(String, Vec<Entry>)>) -> RedisItem {
+    use RedisItem::*;
+    match res {
+        Some((key, popped)) => Array(vec![BulkString(key), entries_reply(popped, true)]),
+        None => Null,
+    }
+}
+
+pub fn do_zmpop(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    let keys = match parse_keys(&mut args) {
+        Ok(keys) => keys,
+        Err(err) => return err.into(),
+    };
+    let max = match parse_min_max(args.pop_front()) {
+        Ok(max) => max,
+        Err(err) => return err.into(),
+    };
+    let count = match parse_mpop_count(&mut args) {
+        Ok(count) => count,
+        Err(err) => return err.into(),
+    };
+    match multi_pop(&mut state.borrow_mut(), &keys, max, count) {
+        Ok(res) => mpop_reply(res),
+        Err(err) => err.into(),
+    }
+}
+
+fn remove_range(mut args: VecDeque<RedisItem>, state: &RefCell<State>, by: RangeBy) -> RedisItem {
+    use RedisItem::*;
+    let (Some(BulkString(key)), Some(BulkString(start)), Some(BulkString(stop))) =
+        (args.pop_front(), args.pop_front(), args.pop_front())
+    else {
+        return RedisError::InvalidArguments.into();
+    };
+    let mut state = state.borrow_mut();
+    let empty = SortedSet::new();
+    let entries = match get_zset(&state, &key)
+        .and_then(|zset| RangeSpec::new(by, false).select(zset.unwrap_or(&empty), start, stop))
+    {
+        Ok(entries) => entries,
+        Err(err) => return err.into(),
+    };
+    if let Ok(Some(zset)) = get_zset_mut(&mut state, &key) {
+        for (member, _) in &entries {
+            zset.remove(member);
+        }
+        if zset.is_empty() {
+            state.items.remove(&key);
+        }
+    }
+    Integer(entries.len() as i64)
+}
+
+pub fn do_zremrangebyrank(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    remove_range(args, state, RangeBy::Rank)
+}
+
+pub fn do_zremrangebyscore(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    remove_range(args, state, RangeBy::Score)
+}
+
+pub fn do_zremrangebylex(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    remove_range(args, state, RangeBy::Lex)
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Aggregate {
+    Sum,
+    Min,
+    Max,
+}
+
+impl Aggregate {
+    fn apply(self, a: f64, b: f64) -> f64 {
+        match self {
+            // inf + -inf is treated as zero, like in redis
+            Aggregate::Sum => zero_if_nan(a + b),
+            Aggregate::Min => a.min(b),
+            Aggregate::Max => a.max(b),
+        }
+    }
+}
+
+fn zero_if_nan(val: f64) -> f64 {
+    if val.is_nan() {
+        0.0
+    } else {
+        val
+    }
+}
+
+/// The options of the sorted set algebra commands.
+struct AlgebraSpec {
+    keys: Vec<String>,
+    weights: Vec<f64>,
+    aggregate: Aggregate,
+    with_scores: bool,
+}
+
+impl AlgebraSpec {
+    fn parse(args: &mut VecDeque<RedisItem>, op: SetOp, store: bool) -> Result<Self, RedisError> {
+        let keys = parse_keys(args)?;
+        let mut spec = AlgebraSpec {
+            weights: vec![1.0; keys.len()],
+            keys,
+            aggregate: Aggregate::Sum,
+            with_scores: false,
+        };
+        let weighted = !matches!(op, SetOp::Diff);
+        while let Some(arg) = args.pop_front() {
+            let RedisItem::BulkString(mut arg) = arg else {
+                return Err(RedisError::InvalidArguments);
+            };
+            arg.make_ascii_lowercase();
+            match arg.as_str() {
+                "weights" if weighted => {
+                    for weight in spec.weights.iter_mut() {
+                        let Some(RedisItem::BulkString(arg)) = args.pop_front() else {
+                            return Err(RedisError::Syntax);
+                        };
+                        *weight = parse_float(&arg)
+                            .ok_or(RedisError::Custom("ERR weight value is not a float"))?;
+                    }
+                }
+                "aggregate" if weighted => {
+                    let Some(RedisItem::BulkString(mut arg)) = args.pop_front() else {
+                        return Err(RedisError::Syntax);
+                    };
+                    arg.make_ascii_lowercase();
+                    spec.aggregate = match arg.as_str() {
+                        "sum" => Aggregate::Sum,
+                        "min" => Aggregate::Min,
+                        "max" => Aggregate::Max,
+                        _ => return Err(RedisError::Syntax),
+                    };
+                }
+                "withscores" if !store => spec.with_scores = true,
+                _ => return Err(RedisError::Syntax),
+            }
+        }
+        Ok(spec)
+    }
+
+    /// Reads the sources of the operation. Plain sets are treated as sorted
+    /// sets where every member has a score of 1.
+    fn sources(&self, state: &State) -> Result<Vec<Option<HashMap<String, f64>>>, RedisError> {
+        let mut sources = Vec::with_capacity(self.keys.len());
+        for (key, &weight) in self.keys.iter().zip(&self.weights) {
+            let source: Option<Vec<Entry>> = match state.items.get(key) {
+                Some((Value::ZSet(zset), _)) => Some(to_owned(zset.iter())),
+                Some((Value::Set(set), _)) => Some(
+                    set.members()
+                        .into_iter()
+                        .map(|member| (member, 1.0))
+                        .collect(),
+                ),
+                Some(_) => return Err(RedisError::WrongType),
+                None => None,
+            };
+            sources.push(source.map(|entries| {
+                entries
+                    .into_iter()
+                    .map(|(member, score)| (member, zero_if_nan(score * weight)))
+                    .collect()
+            }));
+        }
+        Ok(sources)
+    }
+
+    fn compute(&self, state: &State, op: SetOp) -> Result<SortedSet, RedisError> {
+        let sources = self.sources(state)?;
+        Ok(match op {
+            SetOp::Inter => {
+                if sources.iter().any(Option::is_none) {
+                    return Ok(SortedSet::new());
+                }
+                let mut sources: Vec<_> = sources.into_iter().flatten().collect();
+                let first = sources.remove(0);
+                first
+                    .into_iter()
+                    .filter_map(|(member, score)| {
+                        let mut score = score;
+                        for source in &sources {
+                            score = self.aggregate.apply(score, *source.get(&member)?);
+                        }
+                        Some((member, score))
+                    })
+                    .collect()
+            }
+            SetOp::Union => {
+                let mut result: HashMap<String, f64> = HashMap::new();
+                for (member, score) in sources.into_iter().flatten().flatten() {
+                    result
+                        .entry(member)
+                        .and_modify(|current| *current = self.aggregate.apply(*current, score))
+                        .or_insert(score);
+                }
+                result.into_iter().collect()
+            }
+            SetOp::Diff => {
+                let mut sources = sources.into_iter();
+                let Some(Some(first)) = sources.next() else {
+                    return Ok(SortedSet::new());
+                };
+                let rest: Vec<_> = sources.flatten().collect();
+                first
+                    .into_iter()
+                    .filter(|(member, _)| rest.iter().all(|source| !source.contains_key(member)))
+                    .collect()
+            }
+        })
+    }
+}
+
+fn algebra(mut args: VecDeque<RedisItem>, state: &RefCell<State>, op: SetOp) -> RedisItem {
+    let spec = match AlgebraSpec::parse(&mut args, op, false) {
+        Ok(spec) => spec,
+        Err(err) => return err.into(),
+    };
+    match spec.compute(&state.borrow(), op) {
+        Ok(zset) => entries_reply(to_owned(zset.iter()), spec.with_scores),
+        Err(err) => err.into(),
+    }
+}
+
+fn algebra_store(mut args: VecDeque<RedisItem>, state: &RefCell<State>, op: SetOp) -> RedisItem {
+    let Some(RedisItem::BulkString(dst)) = args.pop_front() else {
+        return RedisError::InvalidArguments.into();
+    };
+    let spec = match AlgebraSpec::parse(&mut args, op, true) {
+        Ok(spec) => spec,
+        Err(err) => return err.into(),
+    };
+    let mut state = state.borrow_mut();
+    match spec.compute(&state, op) {
+        Ok(zset) => RedisItem::Integer(store(&mut state, dst, zset) as i64),
+        Err(err) => err.into(),
+    }
+}
+
+pub fn do_zinter(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    algebra(args, state, SetOp::Inter)
+}
+
+pub fn do_zunion(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    algebra(args, state, SetOp::Union)
+}
+
+pub fn do_zdiff(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    algebra(args, state, SetOp::Diff)
+}
+
+pub fn do_zinterstore(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    algebra_store(args, state, SetOp::Inter)
+}
+
+pub fn do_zunionstore(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    algebra_store(args, state, SetOp::Union)
+}
+
+pub fn do_zdiffstore(args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    algebra_store(args, state, SetOp::Diff)
+}
+
+pub fn do_zscan(mut args: VecDeque<RedisItem>, state: &RefCell<State>) -> RedisItem {
+    use RedisItem::*;
+    let Some(BulkString(key)) = args.pop_front() else {
+        return RedisError::InvalidArguments.into();
+    };
+    let options = match ScanOptions::parse(&mut args, &[]) {
+        Ok(options) => options,
+        Err(err) => return err.into(),
+    };
+    match get_zset(&state.borrow(), &key) {
+        Ok(zset) => {
+            let (cursor, entries) = zset.map_or((0, Vec::new()), |zset| {
+                zset.scan(options.cursor, options.count)
+            });
+            let items = entries
+                .into_iter()
+                .filter(|(member, _)| options.matches(member))
+                .flat_map(|(member, score)| {
+                    [
+                        BulkString(member.to_string()),
+                        BulkString(format_float(score)),
+                    ]
+                });
+            cursor_reply(cursor, items.collect())
+        }
+        Err(err) => err.into(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::scan::scan_all;
+
+    fn run(state: &RefCell<State>, args: &[&str]) -> RedisItem {
+        crate::run_command(state, &mut 0, args)
+    }
+
+    fn entry(member: &str, score: f64) -> RedisItem {
+        RedisItem::Array(vec![
+            RedisItem::BulkString(member.to_string()),
+            RedisItem::Double(score),
+        ])
+    }
+
+    fn bulks(args: &[&str]) -> RedisItem {
+        RedisItem::Array(
+            args.iter()
+                .map(|arg| RedisItem::BulkString(arg.to_string()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    pub fn test_zadd() {
+        use RedisItem::*;
+        let state = RefCell::new(State::new(1));
+        assert_eq!(run(&state, &["zadd", "z", "1", "a", "2", "b"]), Integer(2));
+        assert_eq!(
+            run(&state, &["zadd", "z", "nx", "5", "a", "3", "c"]),
+            Integer(1)
+        );
+        assert_eq!(run(&state, &["zscore", "z", "a"]), Double(1.0));
+        assert_eq!(
+            run(&state, &["zadd", "z", "xx", "ch", "5", "a", "4", "d"]),
+            Integer(1)
+        );
+        assert_eq!(run(&state, &["zscore", "z", "d"]), Null);
+        assert_eq!(
+            run(&state, &["zadd", "z", "gt", "ch", "1", "a", "6", "b"]),
+            Integer(1)
+        );
+        assert_eq!(
+            run(&state, &["zadd", "z", "lt", "ch", "7", "a"]),
+            Integer(0)
+        );
+        assert_eq!(run(&state, &["zadd", "z", "incr", "2", "c"]), Double(5.0));
+        assert_eq!(run(&state, &["zadd", "z", "xx", "incr", "1", "x"]), Null);
+        assert_eq!(
+            run(&state, &["zrange", "z", "0", "-1", "withscores"]),
+            Array(vec![entry("a", 5.0), entry("c", 5.0), entry("b", 6.0)])
+        );
+        assert_eq!(run(&state, &["zadd", "new", "xx", "1", "a"]), Integer(0));
+        assert_eq!(run(&state, &["zcard", "new"]), Integer(0));
+
+        for args in [
+            &["zadd", "z", "nx", "xx", "1", "a"][..],
+            &["zadd", "z", "gt", "lt", "1", "a"],
+            &["zadd", "z", "incr", "1", "a", "2", "b"],
+            &["zadd", "z", "1"],
+            &["zadd", "z", "x", "a"],
+        ] {
+            assert!(matches!(run(&state, args), SimpleError(_)));
+        }
+        // a bad pair rejects the whole command
+        let Array(mut args) = bulks(&["zadd", "y", "1", "a", "2"]) else {
+            unreachable!();
+        };
+        args.push(Integer(5));
+        let res = smol::block_on(crate::handle_command(Array(args), &state, &mut 0));
+        assert!(matches!(res, SimpleError(_)));
+        assert_eq!(run(&state, &["zcard", "y"]), Integer(0));
+    }
+
+    #[test]
+    pub fn test_ranges() {
+        use RedisItem::*;
+        let state = RefCell::new(State::new(1));
+        run(
+            &state,
+            &[
+                "zadd", "z", "1", "a", "2", "b", "3", "c", "4", "d", "5", "e",
+            ],
+        );
+        assert_eq!(run(&state, &["zrange", "z", "1", "2"]), bulks(&["b", "c"]));
+        assert_eq!(
+            run(&state, &["zrange", "z", "0", "1", "rev"]),
+            bulks(&["e", "d"])
+        );
+        assert_eq!(
+            run(&state, &["zrevrange", "z", "-2", "-1"]),
+            bulks(&["b", "a"])
+        );
+        assert_eq!(
+            run(&state, &["zrange", "z", "(1", "3", "byscore"]),
+            bulks(&["b", "c"])
+        );
+        assert_eq!(
+            run(
+                &state,
+                &["zrange", "z", "+inf", "2", "byscore", "rev", "limit", "1", "2"]
+            ),
+            bulks(&["d", "c"])
+        );
+        assert_eq!(
+            run(
+                &state,
+                &["zrangebyscore", "z", "-inf", "+inf", "limit", "3", "-1"]
+            ),
+            bulks(&["d", "e"])
+        );
+        assert_eq!(
+            run(&state, &["zrevrangebyscore", "z", "2", "1", "withscores"]),
+            Array(vec![entry("b", 2.0), entry("a", 1.0)])
+        );
+        assert_eq!(
+            run(&state, &["zrange", "z", "[b", "(d", "bylex"]),
+            bulks(&["b", "c"])
+        );
+        assert_eq!(
+            run(
+                &state,
+                &["zrange", "z", "+", "-", "bylex", "rev", "limit", "0", "2"]
+            ),
+            bulks(&["e", "d"])
+        );
+        assert_eq!(run(&state, &["zrangebylex", "z", "(d", "+"]), bulks(&["e"]));
+        assert_eq!(run(&state, &["zcount", "z", "(1", "3"]), Integer(2));
+        assert_eq!(run(&state, &["zcount", "z", "-inf", "+inf"]), Integer(5));
+        assert_eq!(run(&state, &["zlexcount", "z", "[b", "[d"]), Integer(3));
+        assert_eq!(run(&state, &["zlexcount", "z", "-", "(a"]), Integer(0));
+
+        for args in [
+            &["zrange", "z", "0", "1", "limit", "0", "1"][..],
+            &["zrange", "z", "a", "b", "bylex", "withscores"],
+            &["zrange", "z", "x", "1", "byscore"],
+            &["zrange", "z", "a", "c", "bylex"],
+            &["zrevrange", "z", "0", "1", "byscore"],
+        ] {
+            assert!(matches!(run(&state, args), SimpleError(_)));
+        }
+    }
+
+    #[test]
+    pub fn test_remove_ranges() {
+        use RedisItem::*;
+        let state = RefCell::new(State::new(1));
+        run(
+            &state,
+            &[
+                "zadd", "z", "1", "a", "2", "b", "3", "c", "4", "d", "5", "e",
+            ],
+        );
+        assert_eq!(
+            run(&state, &["zremrangebyrank", "z", "-1", "-1"]),
+            Integer(1)
+        );
+        assert_eq!(
+            run(&state, &["zremrangebyscore", "z", "(1", "2"]),
+            Integer(1)
+        );
+        assert_eq!(run(&state, &["zremrangebylex", "z", "[c", "+"]), Integer(2));
+        assert_eq!(run(&state, &["zrange", "z", "0", "-1"]), bulks(&["a"]));
+        assert_eq!(run(&state, &["zremrangebyrank", "z", "0", "0"]), Integer(1));
+        assert_eq!(run(&state, &["zcard", "z"]), Integer(0));
+        assert_eq!(
+            run(&state, &["zremrangebyscore", "z", "0", "1"]),
+            Integer(0)
+        );
+    }
+
+    #[test]
+    pub fn test_algebra() {
+        use RedisItem::*;
+        let state = RefCell::new(State::new(1));
+        run(&state, &["zadd", "x", "1", "a", "2", "b", "3", "c"]);
+        run(&state, &["zadd", "y", "10", "b", "20", "c", "30", "d"]);
+        run(&state, &["sadd", "s", "c", "d"]);
+        assert_eq!(
+            run(&state, &["zunion", "2", "x", "y", "withscores"]),
+            Array(vec![
+                entry("a", 1.0),
+                entry("b", 12.0),
+                entry("c", 23.0),
+                entry("d", 30.0)
+            ])
+        );
+        let args = [
+            "zinter",
+            "2",
+            "x",
+            "y",
+            "weights",
+            "2",
+            "1",
+            "aggregate",
+            "max",
+            "withscores",
+        ];
+        assert_eq!(
+            run(&state, &args),
+            Array(vec![entry("b", 10.0), entry("c", 20.0)])
+        );
+        // plain sets count as a score of 1
+        assert_eq!(
+            run(
+                &state,
+                &["zinter", "2", "y", "s", "aggregate", "min", "withscores"]
+            ),
+            Array(vec![entry("c", 1.0), entry("d", 1.0)])
+        );
+        assert_eq!(run(&state, &["zdiff", "2", "x", "y"]), bulks(&["a"]));
+        assert_eq!(
+            run(&state, &["zinter", "2", "x", "none"]),
+            Array(Vec::new())
+        );
+
+        assert_eq!(
+            run(&state, &["zunionstore", "u", "2", "x", "s"]),
+            Integer(4)
+        );
+        assert_eq!(run(&state, &["zscore", "u", "c"]), Double(4.0));
+        assert_eq!(
+            run(&state, &["zinterstore", "i", "2", "x", "y"]),
+            Integer(2)
+        );
+        assert_eq!(run(&state, &["zdiffstore", "d", "2", "y", "x"]), Integer(1));
+        assert_eq!(
+            run(&state, &["zrange", "d", "0", "-1", "withscores"]),
+            Array(vec![entry("d", 30.0)])
+        );
+        // an empty result removes the destination
+        assert_eq!(run(&state, &["zdiffstore", "d", "2", "x", "x"]), Integer(0));
+        assert_eq!(run(&state, &["zcard", "d"]), Integer(0));
+
+        run(&state, &["set", "str", "v"]);
+        assert!(matches!(
+            run(&state, &["zunion", "2", "x", "str"]),
+            SimpleError(_)
+        ));
+        let res = run(&state, &["zdiff", "2", "x", "y", "weights", "1", "1"]);
+        assert!(matches!(res, SimpleError(_)));
+        let res = run(&state, &["zunionstore", "u", "1", "x", "withscores"]);
+        assert!(matches!(res, SimpleError(_)));
+    }
+
+    #[test]
+    pub fn test_zscan() {
+        let state = RefCell::new(State::new(1));
+        let mut args = vec!["zadd".to_string(), "z".to_string()];
+        for i in 0..100 {
+            args.extend([i.to_string(), format!("m{}", i)]);
+        }
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        run(&state, &args);
+        let (items, calls) = scan_all(&state, &["zscan", "z"], &["count", "10"]);
+        assert_eq!(items.len(), 200);
+        assert!(calls >= 10);
+        let string = |item: &RedisItem| match item {
+            RedisItem::BulkString(item) => item.clone(),
+            _ => panic!("unexpected item {:?}", item),
+        };
+        let mut members: Vec<_> = items.chunks(2).map(|pair| string(&pair[0])).collect();
+        members.sort();
+        members.dedup();
+        assert_eq!(members.len(), 100);
+        // scores follow their members
+        assert!(items
+            .chunks(2)
+            .all(|pair| pair[1] == RedisItem::BulkString(string(&pair[0])[1..].to_string())));
+    }
+
+    #[test]
+    pub fn test_pops() {
+        use RedisItem::*;
+        let state = RefCell::new(State::new(1));
+        run(
+            &state,
+            &["zadd", "z", "1", "a", "2", "b", "3", "c", "4", "d"],
+        );
+        assert_eq!(run(&state, &["zpopmin", "z"]), entry("a", 1.0));
+        assert_eq!(
+            run(&state, &["zpopmax", "z", "2"]),
+            Array(vec![entry("d", 4.0), entry("c", 3.0)])
+        );
+        assert_eq!(run(&state, &["zpopmin", "none"]), Array(Vec::new()));
+        assert!(matches!(
+            run(&state, &["zpopmin", "z", "-1"]),
+            SimpleError(_)
+        ));
+
+        let res = run(&state, &["zmpop", "2", "none", "z", "min", "count", "5"]);
+        assert_eq!(
+            res,
+            Array(vec![
+                BulkString("z".to_string()),
+                Array(vec![entry("b", 2.0)])
+            ])
+        );
+        // the last member takes the key with it
+        assert_eq!(run(&state, &["zcard", "z"]), Integer(0));
+        assert_eq!(run(&state, &["zmpop", "1", "z", "max"]), Null);
+        assert!(matches!(
+            run(&state, &["zmpop", "1", "z", "mid"]),
+            SimpleError(_)
+        ));
+
+        run(&state, &["set", "s", "v"]);
+        assert!(matches!(run(&state, &["zpopmin", "s"]), SimpleError(_)));
+    }
+}